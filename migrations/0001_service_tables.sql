@@ -0,0 +1,110 @@
+-- Tables owned by the service, kept in the tta schema next to the indexer one. Run with tta
+-- first in the search_path, see `SqlClient::migrate`.
+
+-- Deployments from before the migrations created these in public, keep their data.
+ALTER TABLE IF EXISTS public.tta_ft_metadata SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_ft_metadata_overrides SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_account_activity_days SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_account_activity SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_report_rows SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_report_jobs SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_watchlist SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_balance_alerts SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_balance_snapshots SET SCHEMA tta;
+ALTER TABLE IF EXISTS public.tta_token_prices SET SCHEMA tta;
+
+CREATE TABLE IF NOT EXISTS tta_ft_metadata (
+    token_id TEXT PRIMARY KEY,
+    metadata JSONB NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE IF NOT EXISTS tta_ft_metadata_overrides (
+    token_id TEXT PRIMARY KEY,
+    symbol TEXT,
+    decimals SMALLINT,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- See `tta::activity`. Days are listed on their own so that days without any activity are
+-- known to be materialized too. Report rows are stored as JSON, per receipt.
+CREATE TABLE IF NOT EXISTS tta_account_activity_days (
+    account_id TEXT NOT NULL,
+    day DATE NOT NULL,
+    row_count BIGINT NOT NULL,
+    PRIMARY KEY (account_id, day)
+);
+
+CREATE TABLE IF NOT EXISTS tta_account_activity (
+    account_id TEXT NOT NULL,
+    day DATE NOT NULL,
+    token TEXT NOT NULL,
+    total_in DOUBLE PRECISION NOT NULL,
+    total_out DOUBLE PRECISION NOT NULL,
+    tx_count BIGINT NOT NULL,
+    PRIMARY KEY (account_id, day, token)
+);
+
+CREATE TABLE IF NOT EXISTS tta_report_rows (
+    account_id TEXT NOT NULL,
+    receipt_id TEXT NOT NULL,
+    block_timestamp NUMERIC(20, 0) NOT NULL,
+    rows JSONB NOT NULL,
+    PRIMARY KEY (account_id, receipt_id)
+);
+
+CREATE INDEX IF NOT EXISTS tta_report_rows_block_timestamp
+ON tta_report_rows (account_id, block_timestamp);
+
+CREATE TABLE IF NOT EXISTS tta_report_jobs (
+    id TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    error TEXT,
+    filename TEXT NOT NULL,
+    progress JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL,
+    finished_at TIMESTAMPTZ NOT NULL,
+    result BYTEA
+);
+
+CREATE TABLE IF NOT EXISTS tta_watchlist (
+    account_id TEXT PRIMARY KEY,
+    min_amount DOUBLE PRECISION,
+    tokens TEXT[] NOT NULL,
+    webhook_urls TEXT[] NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tta_balance_alerts (
+    id BIGSERIAL PRIMARY KEY,
+    account_id TEXT NOT NULL,
+    token_id TEXT NOT NULL,
+    threshold DOUBLE PRECISION NOT NULL,
+    webhook_urls TEXT[] NOT NULL,
+    triggered_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS tta_balance_snapshots (
+    account_id TEXT NOT NULL,
+    day DATE NOT NULL,
+    token_id TEXT NOT NULL,
+    symbol TEXT NOT NULL,
+    block_id BIGINT NOT NULL,
+    balance DOUBLE PRECISION,
+    spam BOOLEAN NOT NULL,
+    PRIMARY KEY (account_id, day, token_id)
+);
+
+-- Added after the table, NULL for snapshots taken before and for tokens.
+ALTER TABLE tta_balance_snapshots
+    ADD COLUMN IF NOT EXISTS storage_usage BIGINT,
+    ADD COLUMN IF NOT EXISTS locked DOUBLE PRECISION;
+
+-- Daily USD prices, see `pricing`. A NULL price records a day the backend has no price for.
+CREATE TABLE IF NOT EXISTS tta_token_prices (
+    token_id TEXT NOT NULL,
+    day DATE NOT NULL,
+    usd_price DOUBLE PRECISION,
+    PRIMARY KEY (token_id, day)
+);
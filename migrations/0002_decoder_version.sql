@@ -0,0 +1,4 @@
+-- The decoder version stored rows were materialized with, see `activity::DECODER_VERSION`. Rows
+-- from before it was kept are from version 0 and get materialized again.
+ALTER TABLE tta_account_activity_days ADD COLUMN IF NOT EXISTS decoder_version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE tta_report_rows ADD COLUMN IF NOT EXISTS decoder_version INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Totals are kept per token contract, symbols can be shared. Days stored before are materialized
+-- again, see `activity::DECODER_VERSION`.
+ALTER TABLE tta_account_activity ADD COLUMN IF NOT EXISTS token_contract TEXT NOT NULL DEFAULT '';
+ALTER TABLE tta_account_activity DROP CONSTRAINT IF EXISTS tta_account_activity_pkey;
+ALTER TABLE tta_account_activity ADD PRIMARY KEY (account_id, day, token, token_contract);
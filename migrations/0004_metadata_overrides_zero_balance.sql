@@ -0,0 +1,6 @@
+-- Tokens whose balance calls misbehave are reported with a zero balance without calling them.
+ALTER TABLE tta_ft_metadata_overrides ADD COLUMN IF NOT EXISTS zero_balance BOOLEAN NOT NULL DEFAULT FALSE;
+
+INSERT INTO tta_ft_metadata_overrides (token_id, zero_balance)
+VALUES ('kusama-airdrop.near', TRUE)
+ON CONFLICT (token_id) DO UPDATE SET zero_balance = TRUE, updated_at = NOW();
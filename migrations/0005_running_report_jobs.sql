@@ -0,0 +1,2 @@
+-- Jobs are saved once started, they have no finish date while running.
+ALTER TABLE tta_report_jobs ALTER COLUMN finished_at DROP NOT NULL;
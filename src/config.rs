@@ -0,0 +1,361 @@
+use std::{collections::HashSet, env, str::FromStr};
+
+use tracing::warn;
+use tta_rust::{
+    idempotency::IDEMPOTENCY_MAX_BODY_BYTES, rate_limiter::AdaptiveRateLimiter,
+    url_policy::UrlPolicy, DEFAULT_EXCLUDED_ACCOUNTS,
+};
+
+// Runtime configuration, read from the environment (and `.env`) on start.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub quotas: QuotaConfig,
+    pub concurrency: ConcurrencyConfig,
+    pub likely_tokens_ttl_secs: i64,
+    pub likely_tokens_stale_secs: i64,
+    pub likely_tokens_db: LikelyTokensDb,
+    pub token_filter: TokenFilter,
+    // Tokens without any holder in the indexer DB are taken for spam, off by default.
+    pub spam_check_holders: bool,
+    // Dropped from the accounts of every request, always including the protocol accounts.
+    pub excluded_accounts: HashSet<String>,
+    pub gas_refund: GasRefundRule,
+    // Reports estimated to have more rows are rejected, 0 disables the check.
+    pub max_report_rows: u64,
+    // Reports estimated above this are spooled to disk while generated, 0 disables spooling.
+    pub spool_threshold_rows: u64,
+    // Reports looking up more balances, one archival RPC call or more each, are aborted. 0
+    // disables the cap.
+    pub max_report_rpc_calls: u64,
+    // Finished /tta jobs and their results are deleted after this many days.
+    pub job_retention_days: i64,
+    // How long responses are replayed for a repeated `Idempotency-Key`.
+    pub idempotency_window_secs: u64,
+    // Responses over this size are not replayed, the request runs again.
+    pub idempotency_max_body_bytes: u64,
+    // Transaction queries slower than this are explained and logged, 0 disables diagnostics.
+    pub slow_query_ms: u64,
+    // Initial size of the DB pool, it can be resized at runtime through `/admin/pool`.
+    pub pool_size: u32,
+    // Bearer token of `PUT /admin/pool`, the route is disabled without one.
+    pub admin_token: Option<String>,
+    // Accounts whose daily activity is materialized in the background, none by default.
+    pub activity_accounts: HashSet<String>,
+    // How far back the activity of a newly listed account is materialized.
+    pub activity_backfill_days: i64,
+    pub activity_refresh_secs: u64,
+    // Accounts watched with the defaults, in addition to those saved through `/watchlist`.
+    pub watchlist: HashSet<String>,
+    pub webhook_urls: HashSet<String>,
+    // Webhook URLs given through the API.
+    pub webhook_url_policy: UrlPolicy,
+    pub webhook_secret: Option<String>,
+    // Rows moving less than this amount of any token are not notified.
+    pub webhook_min_amount: f64,
+    pub balance_alert_interval_secs: u64,
+    // Accounts whose end of day balances are snapshotted in the background, none by default.
+    pub snapshot_accounts: HashSet<String>,
+    pub snapshot_backfill_days: i64,
+    // Materialized activity and balance snapshots older than this are deleted, 0 keeps them.
+    pub activity_retention_days: i64,
+    pub snapshot_retention_days: i64,
+    pub janitor_interval_secs: u64,
+    // CoinGecko API for USD valuations, the key is sent as `x-cg-pro-api-key` when set.
+    pub pricing_api_url: String,
+    pub pricing_api_key: Option<String>,
+    // Frankfurter API for the ECB exchange rates of non USD valuations.
+    pub fx_api_url: String,
+    // Documents passed by `metadata_url` over this size are rejected, the others cached this long.
+    pub metadata_url_max_bytes: u64,
+    pub metadata_url_cache_secs: u64,
+    // Hosts `metadata_url` may point to, over https. None are by default.
+    pub metadata_url_hosts: HashSet<String>,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            quotas: QuotaConfig::from_env(),
+            concurrency: ConcurrencyConfig::from_env(),
+            likely_tokens_ttl_secs: env_or("LIKELY_TOKENS_TTL_SECS", LIKELY_TOKENS_TTL_SECS),
+            likely_tokens_stale_secs: env_or("LIKELY_TOKENS_STALE_SECS", LIKELY_TOKENS_STALE_SECS),
+            likely_tokens_db: env_or("LIKELY_TOKENS_DB", LikelyTokensDb::Fallback),
+            token_filter: TokenFilter::from_env(),
+            spam_check_holders: env_or("SPAM_CHECK_HOLDERS", false),
+            excluded_accounts: env_list("EXCLUDED_ACCOUNTS")
+                .unwrap_or_default()
+                .into_iter()
+                .chain(DEFAULT_EXCLUDED_ACCOUNTS.map(String::from))
+                .collect(),
+            gas_refund: GasRefundRule::from_env(),
+            max_report_rows: env_or("MAX_REPORT_ROWS", MAX_REPORT_ROWS),
+            spool_threshold_rows: env_or("SPOOL_THRESHOLD_ROWS", SPOOL_THRESHOLD_ROWS),
+            max_report_rpc_calls: env_or("MAX_REPORT_RPC_CALLS", MAX_REPORT_RPC_CALLS),
+            job_retention_days: env_or("JOB_RETENTION_DAYS", JOB_RETENTION_DAYS),
+            idempotency_window_secs: env_or("IDEMPOTENCY_WINDOW_SECS", IDEMPOTENCY_WINDOW_SECS),
+            idempotency_max_body_bytes: env_or(
+                "IDEMPOTENCY_MAX_BODY_BYTES",
+                IDEMPOTENCY_MAX_BODY_BYTES,
+            ),
+            slow_query_ms: env_or("SLOW_QUERY_MS", 0),
+            pool_size: env_or("POOL_SIZE", POOL_SIZE),
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            activity_accounts: env_list("ACTIVITY_ACCOUNTS").unwrap_or_default(),
+            activity_backfill_days: env_or("ACTIVITY_BACKFILL_DAYS", ACTIVITY_BACKFILL_DAYS),
+            activity_refresh_secs: env_or("ACTIVITY_REFRESH_SECS", ACTIVITY_REFRESH_SECS),
+            watchlist: env_list("WATCHLIST").unwrap_or_default(),
+            webhook_urls: env_list("WEBHOOK_URLS").unwrap_or_default(),
+            webhook_url_policy: UrlPolicy {
+                schemes: env_list("WEBHOOK_URL_SCHEMES")
+                    .unwrap_or_else(|| UrlPolicy::default().schemes),
+                hosts: env_list("WEBHOOK_URL_HOSTS"),
+            },
+            webhook_secret: env::var("WEBHOOK_SECRET")
+                .ok()
+                .filter(|secret| !secret.is_empty()),
+            webhook_min_amount: env_or("WEBHOOK_MIN_AMOUNT", 0.0),
+            balance_alert_interval_secs: env_or(
+                "BALANCE_ALERT_INTERVAL_SECS",
+                BALANCE_ALERT_INTERVAL_SECS,
+            ),
+            snapshot_accounts: env_list("SNAPSHOT_ACCOUNTS").unwrap_or_default(),
+            snapshot_backfill_days: env_or("SNAPSHOT_BACKFILL_DAYS", SNAPSHOT_BACKFILL_DAYS),
+            activity_retention_days: env_or("ACTIVITY_RETENTION_DAYS", RETENTION_DAYS),
+            snapshot_retention_days: env_or("SNAPSHOT_RETENTION_DAYS", RETENTION_DAYS),
+            janitor_interval_secs: env_or("JANITOR_INTERVAL_SECS", JANITOR_INTERVAL_SECS),
+            pricing_api_url: env::var("PRICING_API_URL")
+                .unwrap_or_else(|_| PRICING_API_URL.to_string()),
+            pricing_api_key: env::var("PRICING_API_KEY").ok(),
+            fx_api_url: env::var("FX_API_URL").unwrap_or_else(|_| FX_API_URL.to_string()),
+            metadata_url_max_bytes: env_or("METADATA_URL_MAX_BYTES", METADATA_URL_MAX_BYTES),
+            metadata_url_cache_secs: env_or("METADATA_URL_CACHE_SECS", METADATA_URL_CACHE_SECS),
+            metadata_url_hosts: env_list("METADATA_URL_HOSTS").unwrap_or_default(),
+        }
+    }
+}
+
+// How the indexer DB is used as a likely tokens source, next to the external APIs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LikelyTokensDb {
+    // Queried first, the APIs are only used if the query fails.
+    Primary,
+    // Only queried when every API failed.
+    Fallback,
+    Off,
+}
+
+impl FromStr for LikelyTokensDb {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(LikelyTokensDb::Primary),
+            "fallback" => Ok(LikelyTokensDb::Fallback),
+            "off" => Ok(LikelyTokensDb::Off),
+            _ => anyhow::bail!("Unknown likely tokens db mode: {}", s),
+        }
+    }
+}
+
+// Token contracts to skip, typically scam airdrops whose contracts error or hang. When an
+// allowlist is configured, every token not on it is skipped as well.
+#[derive(Debug, Clone, Default)]
+pub struct TokenFilter {
+    pub denylist: HashSet<String>,
+    pub allowlist: Option<HashSet<String>>,
+}
+
+impl TokenFilter {
+    fn from_env() -> Self {
+        Self {
+            denylist: env_list("TOKEN_DENYLIST").unwrap_or_default(),
+            allowlist: env_list("TOKEN_ALLOWLIST"),
+        }
+    }
+
+    pub fn is_allowed(&self, token_id: &str) -> bool {
+        !self.denylist.contains(token_id)
+            && self
+                .allowlist
+                .as_ref()
+                .map_or(true, |allowlist| allowlist.contains(token_id))
+    }
+}
+
+// Incoming NEAR transfers under `max_near` are taken for gas refunds and left out of reports, only
+// those from `system` unless `system_only` is off. With 0 every refund is reported.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GasRefundRule {
+    pub max_near: f64,
+    pub system_only: bool,
+}
+
+impl Default for GasRefundRule {
+    fn default() -> Self {
+        Self {
+            max_near: GAS_REFUND_MAX_NEAR,
+            system_only: true,
+        }
+    }
+}
+
+impl GasRefundRule {
+    fn from_env() -> Self {
+        Self {
+            max_near: env_or("GAS_REFUND_MAX_NEAR", GAS_REFUND_MAX_NEAR),
+            system_only: env_or("GAS_REFUND_SYSTEM_ONLY", true),
+        }
+    }
+
+    pub fn is_refund(&self, near: f64, predecessor: &str) -> bool {
+        near < self.max_near && (!self.system_only || predecessor == "system")
+    }
+}
+
+pub const LIKELY_TOKENS_TTL_SECS: i64 = 60;
+pub const LIKELY_TOKENS_STALE_SECS: i64 = 60 * 60;
+pub const MAX_REPORT_ROWS: u64 = 0;
+pub const SPOOL_THRESHOLD_ROWS: u64 = 50_000;
+pub const MAX_REPORT_RPC_CALLS: u64 = 20_000;
+pub const GAS_REFUND_MAX_NEAR: f64 = 0.5;
+pub const JOB_RETENTION_DAYS: i64 = 30;
+pub const IDEMPOTENCY_WINDOW_SECS: u64 = 60 * 60;
+pub const POOL_SIZE: u32 = 500;
+pub const ACTIVITY_BACKFILL_DAYS: i64 = 90;
+pub const ACTIVITY_REFRESH_SECS: u64 = 60 * 60;
+pub const BALANCE_ALERT_INTERVAL_SECS: u64 = 5 * 60;
+pub const SNAPSHOT_BACKFILL_DAYS: i64 = 30;
+pub const RETENTION_DAYS: i64 = 365;
+pub const JANITOR_INTERVAL_SECS: u64 = 6 * 60 * 60;
+pub const PRICING_API_URL: &str = "https://api.coingecko.com/api/v3";
+pub const FX_API_URL: &str = "https://api.frankfurter.app";
+pub const METADATA_URL_MAX_BYTES: u64 = 100 * 1024 * 1024;
+pub const METADATA_URL_CACHE_SECS: u64 = 10 * 60;
+
+pub const SEMAPHORE_SIZE: usize = 50;
+pub const TXNS_CHANNEL_SIZE: usize = 100;
+pub const RPC_PIPELINE_SIZE: usize = 16;
+pub const EPOCH_ID_CONCURRENCY: usize = 16;
+
+pub const FASTNEAR_QUOTA: ProviderQuota = ProviderQuota::new(1, 4, 20);
+pub const KITWALLET_QUOTA: ProviderQuota = ProviderQuota::new(1, 4, 10);
+pub const ARCHIVAL_RPC_QUOTA: ProviderQuota = ProviderQuota::new(1, 20, 500);
+pub const PIKESPEAK_QUOTA: ProviderQuota = ProviderQuota::new(1, 2, 5);
+pub const PRICING_QUOTA: ProviderQuota = ProviderQuota::new(1, 5, 10);
+pub const NEAR_SOCIAL_QUOTA: ProviderQuota = ProviderQuota::new(1, 5, 20);
+
+// Request quotas per external dependency.
+#[derive(Debug, Clone)]
+pub struct QuotaConfig {
+    pub fastnear: ProviderQuota,
+    pub kitwallet: ProviderQuota,
+    pub pikespeak: ProviderQuota,
+    pub archival_rpc: ProviderQuota,
+    pub pricing: ProviderQuota,
+    pub near_social: ProviderQuota,
+}
+
+impl QuotaConfig {
+    fn from_env() -> Self {
+        Self {
+            fastnear: ProviderQuota::from_env("QUOTA_FASTNEAR", FASTNEAR_QUOTA),
+            kitwallet: ProviderQuota::from_env("QUOTA_KITWALLET", KITWALLET_QUOTA),
+            pikespeak: ProviderQuota::from_env("QUOTA_PIKESPEAK", PIKESPEAK_QUOTA),
+            archival_rpc: ProviderQuota::from_env("QUOTA_ARCHIVAL_RPC", ARCHIVAL_RPC_QUOTA),
+            pricing: ProviderQuota::from_env("QUOTA_PRICING", PRICING_QUOTA),
+            near_social: ProviderQuota::from_env("QUOTA_NEAR_SOCIAL", NEAR_SOCIAL_QUOTA),
+        }
+    }
+}
+
+// Concurrency of report generation and balance lookups.
+#[derive(Debug, Clone, Copy)]
+pub struct ConcurrencyConfig {
+    // Transaction queries running at once, across all reports.
+    pub semaphore_size: usize,
+    // Transactions buffered between a report's query and the processing of its rows.
+    pub txns_channel_size: usize,
+    // View calls in flight for a single batched balance lookup.
+    pub rpc_pipeline_size: usize,
+    // Block queries in flight when resolving epoch ids.
+    pub epoch_id_concurrency: usize,
+}
+
+impl Default for ConcurrencyConfig {
+    fn default() -> Self {
+        Self {
+            semaphore_size: SEMAPHORE_SIZE,
+            txns_channel_size: TXNS_CHANNEL_SIZE,
+            rpc_pipeline_size: RPC_PIPELINE_SIZE,
+            epoch_id_concurrency: EPOCH_ID_CONCURRENCY,
+        }
+    }
+}
+
+impl ConcurrencyConfig {
+    fn from_env() -> Self {
+        Self {
+            semaphore_size: env_or("SEMAPHORE_SIZE", SEMAPHORE_SIZE),
+            txns_channel_size: env_or("TXNS_CHANNEL_SIZE", TXNS_CHANNEL_SIZE),
+            rpc_pipeline_size: env_or("RPC_PIPELINE_SIZE", RPC_PIPELINE_SIZE),
+            epoch_id_concurrency: env_or("EPOCH_ID_CONCURRENCY", EPOCH_ID_CONCURRENCY),
+        }
+    }
+}
+
+// Requests per second bounds for an adaptive rate limiter. The limiter starts at `initial_rps`
+// and moves between `min_rps` and `max_rps` depending on the provider's feedback.
+#[derive(Debug, Clone, Copy)]
+pub struct ProviderQuota {
+    pub min_rps: u32,
+    pub initial_rps: u32,
+    pub max_rps: u32,
+}
+
+impl ProviderQuota {
+    pub const fn new(min_rps: u32, initial_rps: u32, max_rps: u32) -> Self {
+        Self {
+            min_rps,
+            initial_rps,
+            max_rps,
+        }
+    }
+
+    pub fn rate_limiter(&self, provider: &str) -> AdaptiveRateLimiter {
+        AdaptiveRateLimiter::new(provider, self.min_rps, self.initial_rps, self.max_rps)
+    }
+
+    fn from_env(prefix: &str, default: ProviderQuota) -> Self {
+        Self {
+            min_rps: env_or(&format!("{prefix}_MIN_RPS"), default.min_rps),
+            initial_rps: env_or(&format!("{prefix}_RPS"), default.initial_rps),
+            max_rps: env_or(&format!("{prefix}_MAX_RPS"), default.max_rps),
+        }
+    }
+}
+
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(value) => match value.parse() {
+            Ok(v) => v,
+            Err(_) => {
+                warn!("Invalid value for {}: {}, using default", key, value);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// Comma separated list, None when the variable is not set.
+fn env_list(key: &str) -> Option<HashSet<String>> {
+    env::var(key).ok().map(|value| {
+        value
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect()
+    })
+}
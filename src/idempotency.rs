@@ -0,0 +1,168 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{boxed, Body, Bytes, Full},
+    extract::State,
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::body::HttpBody;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use tracing::error;
+
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+pub const IDEMPOTENCY_MAX_BODY_BYTES: u64 = 10 * 1024 * 1024;
+
+// Successful responses by `Idempotency-Key`, replayed to duplicate submissions within `window`.
+// A duplicate arriving while the first request is still running waits for its response instead
+// of starting a second computation. Keys are scoped to the endpoint, and reusing one for another
+// query or body is rejected. Streamed responses and those over `max_body_bytes` are not kept.
+#[derive(Clone)]
+pub struct IdempotencyCache {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    window: Duration,
+    max_body_bytes: u64,
+}
+
+struct Entry {
+    created_at: Instant,
+    // Of the query and body of the request the key was first sent with.
+    fingerprint: [u8; 32],
+    response: Arc<OnceCell<CachedResponse>>,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl CachedResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(boxed(Full::from(self.body)));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+impl IdempotencyCache {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            window,
+            max_body_bytes: IDEMPOTENCY_MAX_BODY_BYTES,
+        }
+    }
+
+    pub fn with_max_body_bytes(mut self, max_body_bytes: u64) -> Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+
+    // None when the key was first sent with another request.
+    fn cell(&self, key: String, fingerprint: [u8; 32]) -> Option<Arc<OnceCell<CachedResponse>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let window = self.window;
+        entries.retain(|_, entry| entry.created_at.elapsed() < window);
+        let entry = entries.entry(key).or_insert_with(|| Entry {
+            created_at: Instant::now(),
+            fingerprint,
+            response: Arc::new(OnceCell::new()),
+        });
+
+        (entry.fingerprint == fingerprint).then(|| entry.response.clone())
+    }
+}
+
+fn fingerprint(query: Option<&str>, body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(query.unwrap_or_default());
+    hasher.update([0]);
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+// Middleware for the endpoints accepting an `Idempotency-Key` header.
+pub async fn idempotent(
+    State(cache): State<IdempotencyCache>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(key) = request
+        .headers()
+        .get(IDEMPOTENCY_KEY)
+        .and_then(|key| key.to_str().ok())
+        .map(|key| format!("{} {} {}", request.method(), request.uri().path(), key))
+    else {
+        return next.run(request).await;
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => {
+            error!(?e, "Error buffering request");
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+    let Some(cell) = cache.cell(key, fingerprint(parts.uri.query(), &body)) else {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("{} was already used for another request", IDEMPOTENCY_KEY),
+        )
+            .into_response();
+    };
+    let request = Request::from_parts(parts, Body::from(body));
+
+    let mut ran = false;
+    // Failed requests are not cached, the caller gets its own response and a retry runs again.
+    // Neither are streamed and large responses, they are too big to hold on to.
+    let result = cell
+        .get_or_try_init(|| async {
+            ran = true;
+            let response = next.run(request).await;
+            if !response.status().is_success() {
+                return Err(response);
+            }
+            match response.body().size_hint().exact() {
+                Some(len) if len <= cache.max_body_bytes => {}
+                _ => return Err(response),
+            }
+
+            let (parts, body) = response.into_parts();
+            match hyper::body::to_bytes(body).await {
+                Ok(body) => Ok(CachedResponse {
+                    status: parts.status,
+                    headers: parts.headers,
+                    body,
+                }),
+                Err(e) => {
+                    error!(?e, "Error buffering response");
+                    Err(StatusCode::INTERNAL_SERVER_ERROR.into_response())
+                }
+            }
+        })
+        .await;
+
+    match result {
+        Ok(cached) => {
+            let mut response = cached.clone().into_response();
+            if !ran {
+                response
+                    .headers_mut()
+                    .insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+            }
+            response
+        }
+        Err(response) => response,
+    }
+}
@@ -0,0 +1,79 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use tracing::{error, info};
+
+use crate::{
+    config::{Config, JANITOR_INTERVAL_SECS, RETENTION_DAYS},
+    jobs::JobStore,
+    tta::sql::sql_queries::SqlClient,
+};
+
+// Trims the service owned tables periodically: report jobs past `JOB_RETENTION_DAYS`, and the
+// materialized activity and balance snapshots past their own retention. Token metadata is
+// immutable and kept, watches and alerts are only deleted through the API.
+#[derive(Clone)]
+pub struct Janitor {
+    sql_client: SqlClient,
+    jobs: JobStore,
+    interval: std::time::Duration,
+    activity_retention_days: i64,
+    snapshot_retention_days: i64,
+}
+
+impl Janitor {
+    pub fn new(sql_client: SqlClient, jobs: JobStore) -> Self {
+        Self {
+            sql_client,
+            jobs,
+            interval: std::time::Duration::from_secs(JANITOR_INTERVAL_SECS),
+            activity_retention_days: RETENTION_DAYS,
+            snapshot_retention_days: RETENTION_DAYS,
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.interval = std::time::Duration::from_secs(config.janitor_interval_secs);
+        self.activity_retention_days = config.activity_retention_days;
+        self.snapshot_retention_days = config.snapshot_retention_days;
+        self
+    }
+
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.interval).await;
+                if let Err(e) = self.run().await {
+                    error!(?e, "Error trimming the service tables");
+                }
+            }
+        });
+    }
+
+    async fn run(&self) -> Result<()> {
+        self.jobs.delete_expired().await?;
+
+        let today = Utc::now().date_naive();
+        if self.activity_retention_days > 0 {
+            let before = today - Duration::days(self.activity_retention_days);
+            let deleted = self
+                .sql_client
+                .delete_account_activity_before(before)
+                .await?;
+            if deleted > 0 {
+                info!(%before, "Deleted {} days of account activity", deleted);
+            }
+        }
+        if self.snapshot_retention_days > 0 {
+            let before = today - Duration::days(self.snapshot_retention_days);
+            let deleted = self
+                .sql_client
+                .delete_balance_snapshots_before(before)
+                .await?;
+            if deleted > 0 {
+                info!(%before, "Deleted {} balance snapshots", deleted);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::types::Json;
+use tokio::sync::RwLock;
+use tracing::{error, info};
+
+use crate::{
+    config::{Config, JOB_RETENTION_DAYS},
+    tta::{
+        progress::{ProgressSnapshot, ReportProgress},
+        sql::{models::ReportJobRecord, sql_queries::SqlClient},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for JobState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "running" => Ok(JobState::Running),
+            "done" => Ok(JobState::Done),
+            "failed" => Ok(JobState::Failed),
+            _ => anyhow::bail!("Unknown job state: {}", s),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Job {
+    state: JobState,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    finished_at: Option<DateTime<Utc>>,
+    progress: Arc<ReportProgress>,
+    filename: String,
+    result: Option<Arc<Vec<u8>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub id: String,
+    pub state: JobState,
+    pub error: Option<String>,
+    pub filename: String,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub progress: ProgressSnapshot,
+}
+
+impl JobStatus {
+    fn from_record(record: ReportJobRecord) -> Self {
+        Self {
+            state: record.state.parse().unwrap_or(JobState::Failed),
+            id: record.id,
+            error: record.error,
+            filename: record.filename,
+            created_at: record.created_at,
+            finished_at: record.finished_at,
+            progress: record.progress.0,
+        }
+    }
+}
+
+// Reports run in the background, for requests too large to be served within a request timeout.
+// Running jobs live in memory. Once finished, they are moved to the DB when a store is set, so
+// results can be downloaded again until the retention period is over, restarts included. Jobs
+// are saved as running when they start too, those a restart interrupts are marked failed.
+#[derive(Clone)]
+pub struct JobStore {
+    jobs: Arc<RwLock<HashMap<String, Job>>>,
+    store: Option<SqlClient>,
+    retention: Duration,
+}
+
+impl Default for JobStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl JobStore {
+    pub fn new() -> Self {
+        Self {
+            jobs: Arc::new(RwLock::new(HashMap::new())),
+            store: None,
+            retention: Duration::days(JOB_RETENTION_DAYS),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.retention = Duration::days(config.job_retention_days);
+        self
+    }
+
+    pub fn with_store(mut self, sql_client: SqlClient) -> Self {
+        self.store = Some(sql_client);
+        self
+    }
+
+    pub async fn init(&self) -> Result<()> {
+        if let Some(store) = &self.store {
+            let failed = store
+                .fail_running_report_jobs("Interrupted by a restart")
+                .await?;
+            if failed > 0 {
+                info!("Marked {} interrupted report jobs failed", failed);
+            }
+        }
+        self.delete_expired().await
+    }
+
+    pub async fn delete_expired(&self) -> Result<()> {
+        let expired_before = Utc::now() - self.retention;
+        self.jobs.write().await.retain(|_, job| {
+            job.finished_at
+                .map_or(true, |finished_at| finished_at > expired_before)
+        });
+
+        if let Some(store) = &self.store {
+            let deleted = store.delete_report_jobs_before(expired_before).await?;
+            if deleted > 0 {
+                info!("Deleted {} expired report jobs", deleted);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Registers a running job, its progress is to be updated by the report.
+    pub async fn create(&self, filename: String) -> (String, Arc<ReportProgress>) {
+        if let Err(e) = self.delete_expired().await {
+            error!(?e, "Error deleting expired report jobs");
+        }
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let progress = Arc::new(ReportProgress::default());
+        let job = Job {
+            state: JobState::Running,
+            error: None,
+            created_at: Utc::now(),
+            finished_at: None,
+            progress: progress.clone(),
+            filename,
+            result: None,
+        };
+        if let Some(store) = &self.store {
+            if let Err(e) = store.save_report_job(&Self::record(&id, &job), None).await {
+                error!(?e, id, "Error persisting report job");
+            }
+        }
+        self.jobs.write().await.insert(id.clone(), job);
+
+        (id, progress)
+    }
+
+    pub async fn finish(&self, id: &str, result: Result<Vec<u8>>) {
+        let (record, result) = {
+            let mut jobs = self.jobs.write().await;
+            let Some(job) = jobs.get_mut(id) else {
+                return;
+            };
+            job.finished_at = Some(Utc::now());
+            match result {
+                Ok(result) => {
+                    job.state = JobState::Done;
+                    job.result = Some(Arc::new(result));
+                }
+                Err(e) => {
+                    job.state = JobState::Failed;
+                    job.error = Some(e.to_string());
+                }
+            }
+            (Self::record(id, job), job.result.clone())
+        };
+
+        let Some(store) = &self.store else {
+            return;
+        };
+        // Not under the lock, the other jobs are served while the result is written. Kept in
+        // memory if it can't be persisted, until the retention period is over.
+        match store
+            .save_report_job(&record, result.as_deref().map(Vec::as_slice))
+            .await
+        {
+            Ok(()) => {
+                self.jobs.write().await.remove(id);
+            }
+            Err(e) => error!(?e, id, "Error persisting report job"),
+        }
+    }
+
+    fn record(id: &str, job: &Job) -> ReportJobRecord {
+        ReportJobRecord {
+            id: id.to_string(),
+            state: job.state.as_str().to_string(),
+            error: job.error.clone(),
+            filename: job.filename.clone(),
+            progress: Json(job.progress.snapshot()),
+            created_at: job.created_at,
+            finished_at: job.finished_at,
+        }
+    }
+
+    pub async fn status(&self, id: &str) -> Result<Option<JobStatus>> {
+        if let Some(job) = self.jobs.read().await.get(id) {
+            return Ok(Some(Self::job_status(id, job)));
+        }
+
+        match &self.store {
+            Some(store) => Ok(store.get_report_job(id).await?.map(JobStatus::from_record)),
+            None => Ok(None),
+        }
+    }
+
+    // Every job still retained, most recent first. Those in memory are more up to date than
+    // their saved record.
+    pub async fn list(&self) -> Result<Vec<JobStatus>> {
+        let mut jobs: Vec<JobStatus> = self
+            .jobs
+            .read()
+            .await
+            .iter()
+            .map(|(id, job)| Self::job_status(id, job))
+            .collect();
+        if let Some(store) = &self.store {
+            let records = store.list_report_jobs().await?;
+            let ids: HashSet<String> = jobs.iter().map(|job| job.id.clone()).collect();
+            jobs.extend(
+                records
+                    .into_iter()
+                    .filter(|record| !ids.contains(&record.id))
+                    .map(JobStatus::from_record),
+            );
+        }
+        jobs.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+
+        Ok(jobs)
+    }
+
+    // The output of a finished job, and the name to download it under.
+    pub async fn result(&self, id: &str) -> Result<Option<(String, Arc<Vec<u8>>)>> {
+        if let Some(job) = self.jobs.read().await.get(id) {
+            return Ok(job
+                .result
+                .clone()
+                .map(|result| (job.filename.clone(), result)));
+        }
+
+        let Some(store) = &self.store else {
+            return Ok(None);
+        };
+        let Some(job) = store.get_report_job(id).await? else {
+            return Ok(None);
+        };
+        Ok(store
+            .get_report_job_result(id)
+            .await?
+            .map(|result| (job.filename, Arc::new(result))))
+    }
+
+    fn job_status(id: &str, job: &Job) -> JobStatus {
+        JobStatus {
+            id: id.to_string(),
+            state: job.state,
+            error: job.error.clone(),
+            filename: job.filename.clone(),
+            created_at: job.created_at,
+            finished_at: job.finished_at,
+            progress: job.progress.snapshot(),
+        }
+    }
+}
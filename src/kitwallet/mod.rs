@@ -1,20 +1,49 @@
 mod models;
 
-use std::{collections::HashMap, num::NonZeroU32, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    env,
+    sync::Arc,
+    time::Instant,
+};
 
 use anyhow::bail;
-use governor::{Quota, RateLimiter};
-use tokio::sync::RwLock;
-use tracing::{error, info};
-use tta_rust::RateLim;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+use tta_rust::{metrics::metrics, rate_limiter::AdaptiveRateLimiter};
 
-use crate::kitwallet::models::FastNearFT;
+use crate::{
+    config::{
+        Config, LikelyTokensDb, TokenFilter, FASTNEAR_QUOTA, KITWALLET_QUOTA,
+        LIKELY_TOKENS_STALE_SECS, LIKELY_TOKENS_TTL_SECS, PIKESPEAK_QUOTA,
+    },
+    kitwallet::models::{FastNearFT, PikespeakBalance},
+    tta::sql::sql_queries::SqlClient,
+};
+
+const FASTNEAR_PROVIDER: &str = "api.fastnear.com";
+const KITWALLET_PROVIDER: &str = "api.kitwallet.app";
+const PIKESPEAK_PROVIDER: &str = "api.pikespeak.ai";
 
 #[derive(Clone)]
 pub struct KitWallet {
-    rate_limiter: Arc<RwLock<RateLim>>,
+    fastnear_rate_limiter: Arc<AdaptiveRateLimiter>,
+    kitwallet_rate_limiter: Arc<AdaptiveRateLimiter>,
+    pikespeak_rate_limiter: Arc<AdaptiveRateLimiter>,
+    // Pikespeak requires an API key, it is only queried when one is configured.
+    pikespeak_api_key: Option<String>,
     client: reqwest::Client,
     cache: Arc<RwLock<HashMap<String, (i64, Vec<String>)>>>,
+    // Entries younger than `cache_ttl` are served as is, entries younger than `cache_stale_ttl`
+    // are served while being refreshed in the background, older entries are evicted.
+    cache_ttl: i64,
+    cache_stale_ttl: i64,
+    refreshing: Arc<Mutex<HashSet<String>>>,
+    sql_client: Option<SqlClient>,
+    likely_tokens_db: LikelyTokensDb,
+    token_filter: Arc<TokenFilter>,
 }
 
 impl Default for KitWallet {
@@ -26,64 +55,289 @@ impl Default for KitWallet {
 impl KitWallet {
     pub fn new() -> Self {
         Self {
-            rate_limiter: Arc::new(RwLock::new(RateLimiter::direct(Quota::per_second(
-                NonZeroU32::new(4u32).unwrap(),
-            )))),
+            fastnear_rate_limiter: Arc::new(FASTNEAR_QUOTA.rate_limiter(FASTNEAR_PROVIDER)),
+            kitwallet_rate_limiter: Arc::new(KITWALLET_QUOTA.rate_limiter(KITWALLET_PROVIDER)),
+            pikespeak_rate_limiter: Arc::new(PIKESPEAK_QUOTA.rate_limiter(PIKESPEAK_PROVIDER)),
+            pikespeak_api_key: env::var("PIKESPEAK_API_KEY").ok(),
             client: reqwest::Client::builder()
                 .timeout(std::time::Duration::from_secs(60))
                 .build()
                 .unwrap(),
             cache: Arc::new(RwLock::new(HashMap::new())),
+            cache_ttl: LIKELY_TOKENS_TTL_SECS,
+            cache_stale_ttl: LIKELY_TOKENS_STALE_SECS,
+            refreshing: Arc::new(Mutex::new(HashSet::new())),
+            sql_client: None,
+            likely_tokens_db: LikelyTokensDb::Off,
+            token_filter: Arc::new(TokenFilter::default()),
         }
     }
 
-    // TODO(plg): expire the cache.
+    pub fn with_config(mut self, config: &Config) -> Self {
+        let quotas = &config.quotas;
+        self.fastnear_rate_limiter = Arc::new(quotas.fastnear.rate_limiter(FASTNEAR_PROVIDER));
+        self.kitwallet_rate_limiter = Arc::new(quotas.kitwallet.rate_limiter(KITWALLET_PROVIDER));
+        self.pikespeak_rate_limiter = Arc::new(quotas.pikespeak.rate_limiter(PIKESPEAK_PROVIDER));
+        self.cache_ttl = config.likely_tokens_ttl_secs;
+        self.cache_stale_ttl = config.likely_tokens_stale_secs.max(self.cache_ttl);
+        self.likely_tokens_db = config.likely_tokens_db;
+        self.token_filter = Arc::new(config.token_filter.clone());
+        self
+    }
+
+    pub fn with_sql_client(mut self, sql_client: SqlClient) -> Self {
+        self.sql_client = Some(sql_client);
+        self
+    }
+
+    pub fn rate_limiters(&self) -> Vec<Arc<AdaptiveRateLimiter>> {
+        vec![
+            self.fastnear_rate_limiter.clone(),
+            self.kitwallet_rate_limiter.clone(),
+            self.pikespeak_rate_limiter.clone(),
+        ]
+    }
+
     pub async fn get_likely_tokens(&self, account: String) -> anyhow::Result<Vec<String>> {
-        let cache_read = self.cache.read().await;
+        let cached = self.cache.read().await.get(&account).cloned();
 
-        if let Some(likely_tokens) = cache_read.get(&account) {
-            // Check if the cache is expired
-            if chrono::Utc::now().timestamp() - likely_tokens.0 < 60 {
-                return Ok(likely_tokens.1.clone());
+        if let Some((fetched_at, likely_tokens)) = cached {
+            let age = chrono::Utc::now().timestamp() - fetched_at;
+            if age < self.cache_ttl {
+                metrics().record_cache_lookup("likely_tokens", true);
+                return Ok(likely_tokens);
+            }
+            // Served stale, that still counts as a hit.
+            if age < self.cache_stale_ttl {
+                metrics().record_cache_lookup("likely_tokens", true);
+                self.spawn_refresh(account);
+                return Ok(likely_tokens);
             }
         }
-
-        drop(cache_read); // Release the read lock
-
-        // Now, only here do we apply the rate limiter
-        self.rate_limiter.read().await.until_ready().await;
+        metrics().record_cache_lookup("likely_tokens", false);
 
         info!(
             "Account {} likely tokens not cached, fetching from API",
             account
         );
-        // https://api.fastnear.com/v1/account/here.near/ft
-        let likely_tokens = self
-            .client
-            .get(format!(
-                "https://api.fastnear.com/v1/account/{}/ft",
-                account
-            ))
-            .send()
-            .await?
-            .json::<FastNearFT>()
-            .await?;
+        self.refresh_likely_tokens(&account).await
+    }
+
+    // Drops the cached likely tokens of the given accounts.
+    pub async fn evict(&self, accounts: &[String]) {
+        let mut cache_write = self.cache.write().await;
+        for account in accounts {
+            cache_write.remove(account);
+        }
+    }
+
+    fn spawn_refresh(&self, account: String) {
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            if !self_clone.refreshing.lock().await.insert(account.clone()) {
+                // Already being refreshed.
+                return;
+            }
+            if let Err(e) = self_clone.refresh_likely_tokens(&account).await {
+                warn!(
+                    "Background refresh of likely tokens for {} failed: {:?}",
+                    account, e
+                );
+            }
+            self_clone.refreshing.lock().await.remove(&account);
+        });
+    }
+
+    async fn refresh_likely_tokens(&self, account: &str) -> anyhow::Result<Vec<String>> {
+        let likely_tokens = match self.likely_tokens_db {
+            LikelyTokensDb::Primary => match self.get_db_likely_tokens(account).await {
+                Ok(likely_tokens) => likely_tokens,
+                Err(e) => {
+                    warn!("DB likely tokens failed for {}: {:?}", account, e);
+                    self.get_api_likely_tokens(account).await?
+                }
+            },
+            LikelyTokensDb::Fallback => match self.get_api_likely_tokens(account).await {
+                Ok(likely_tokens) => likely_tokens,
+                Err(e) => {
+                    warn!("{:?}, falling back to the DB", e);
+                    self.get_db_likely_tokens(account).await?
+                }
+            },
+            LikelyTokensDb::Off => self.get_api_likely_tokens(account).await?,
+        };
+        let likely_tokens: Vec<String> = likely_tokens
+            .into_iter()
+            .filter(|token_id| self.token_filter.is_allowed(token_id))
+            .collect();
 
-        // Insert the result into the cache
+        // Insert the result into the cache, evicting whatever is too old to be served.
+        let now = chrono::Utc::now().timestamp();
         let mut cache_write = self.cache.write().await;
-        cache_write.insert(
-            account.clone(),
-            (
-                chrono::Utc::now().timestamp(),
-                likely_tokens
-                    .tokens
-                    .iter()
-                    .map(|t| t.contract_id.clone())
-                    .collect(),
-            ),
+        cache_write.retain(|_, (fetched_at, _)| now - *fetched_at < self.cache_stale_ttl);
+        cache_write.insert(account.to_string(), (now, likely_tokens.clone()));
+
+        Ok(likely_tokens)
+    }
+
+    async fn get_db_likely_tokens(&self, account: &str) -> anyhow::Result<Vec<String>> {
+        match &self.sql_client {
+            Some(sql_client) => sql_client.get_likely_tokens(account).await,
+            None => bail!("No DB configured for likely tokens"),
+        }
+    }
+
+    // Likely tokens are aggregated over all providers, so an outage or a missing token on one of
+    // them doesn't drop assets from the balance reports. Only fails if every provider failed.
+    async fn get_api_likely_tokens(&self, account: &str) -> anyhow::Result<Vec<String>> {
+        let (fastnear, kitwallet, pikespeak) = tokio::join!(
+            self.get_fastnear_likely_tokens(account),
+            self.get_kitwallet_likely_tokens(account),
+            self.get_pikespeak_likely_tokens(account),
         );
 
-        Ok(cache_write.get(&account).unwrap().1.clone())
+        let mut likely_tokens = BTreeSet::new();
+        let mut succeeded = false;
+        for (provider, result) in [
+            (FASTNEAR_PROVIDER, fastnear),
+            (KITWALLET_PROVIDER, kitwallet),
+            (PIKESPEAK_PROVIDER, pikespeak),
+        ] {
+            match result {
+                Ok(Some(tokens)) => {
+                    succeeded = true;
+                    likely_tokens.extend(tokens);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("{} likely tokens failed for {}: {:?}", provider, account, e),
+            }
+        }
+
+        if !succeeded {
+            bail!("All likely tokens providers failed for {}", account);
+        }
+
+        Ok(likely_tokens.into_iter().collect())
+    }
+
+    // https://api.fastnear.com/v1/account/here.near/ft
+    async fn get_fastnear_likely_tokens(
+        &self,
+        account: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let likely_tokens: FastNearFT = self
+            .get_json(
+                &self.fastnear_rate_limiter,
+                "account_ft",
+                self.client.get(format!(
+                    "https://api.fastnear.com/v1/account/{}/ft",
+                    account
+                )),
+            )
+            .await?;
+
+        Ok(Some(
+            likely_tokens
+                .tokens
+                .into_iter()
+                .map(|t| t.contract_id)
+                .collect(),
+        ))
+    }
+
+    // https://api.kitwallet.app/account/here.near/likelyTokens
+    async fn get_kitwallet_likely_tokens(
+        &self,
+        account: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let likely_tokens: Vec<String> = self
+            .get_json(
+                &self.kitwallet_rate_limiter,
+                "likely_tokens",
+                self.client.get(format!(
+                    "https://api.kitwallet.app/account/{}/likelyTokens",
+                    account
+                )),
+            )
+            .await?;
+
+        Ok(Some(likely_tokens))
+    }
+
+    // https://api.pikespeak.ai/account/balance/here.near
+    async fn get_pikespeak_likely_tokens(
+        &self,
+        account: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let api_key = match &self.pikespeak_api_key {
+            Some(api_key) => api_key,
+            None => return Ok(None),
+        };
+
+        let balances: Vec<PikespeakBalance> = self
+            .get_json(
+                &self.pikespeak_rate_limiter,
+                "account_balance",
+                self.client
+                    .get(format!(
+                        "https://api.pikespeak.ai/account/balance/{}",
+                        account
+                    ))
+                    .header("x-api-key", api_key),
+            )
+            .await?;
+
+        Ok(Some(
+            balances
+                .into_iter()
+                .filter_map(|b| b.contract)
+                .filter(|contract| contract != "near")
+                .collect(),
+        ))
+    }
+
+    // Sends the request through the provider's rate limiter, feeding throttling back into it.
+    // `method` names the endpoint in the call metrics.
+    async fn get_json<T: DeserializeOwned>(
+        &self,
+        rate_limiter: &AdaptiveRateLimiter,
+        method: &str,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<T> {
+        rate_limiter.until_ready().await;
+
+        let started_at = Instant::now();
+        let result = Self::send_json(rate_limiter, request).await;
+        metrics().record_call(
+            rate_limiter.provider(),
+            method,
+            started_at.elapsed(),
+            result.is_ok(),
+        );
+        result
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        rate_limiter: &AdaptiveRateLimiter,
+        request: reqwest::RequestBuilder,
+    ) -> anyhow::Result<T> {
+        let response = match request.send().await {
+            Ok(response) => response,
+            Err(e) => {
+                if e.is_timeout() {
+                    rate_limiter.on_throttled();
+                }
+                bail!("{}: {}", rate_limiter.provider(), e);
+            }
+        };
+        if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error()
+        {
+            rate_limiter.on_throttled();
+            bail!("{} returned {}", rate_limiter.provider(), response.status());
+        }
+        rate_limiter.on_success();
+
+        Ok(response.error_for_status()?.json::<T>().await?)
     }
 
     // get all in parallel
@@ -17,3 +17,10 @@ pub struct Token {
     #[serde(rename = "last_update_block_height")]
     pub last_update_block_height: Value,
 }
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PikespeakBalance {
+    pub contract: Option<String>,
+    pub symbol: Option<String>,
+    pub amount: Option<Value>,
+}
@@ -1,11 +1,17 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, sync::OnceLock};
 
 use anyhow::Result;
+use chrono::{DateTime, Duration, Months, TimeZone, Utc};
 use governor::{clock, state, RateLimiter};
 use hyper::{Body, Response};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
+pub mod idempotency;
+pub mod metrics;
+pub mod rate_limiter;
+pub mod url_policy;
+
 pub type RateLim = RateLimiter<
     state::NotKeyed,
     state::InMemoryState,
@@ -13,13 +19,32 @@ pub type RateLim = RateLimiter<
     governor::middleware::NoOpMiddleware<clock::QuantaInstant>,
 >;
 
+// Protocol accounts, never reported on.
+pub const DEFAULT_EXCLUDED_ACCOUNTS: [&str; 2] = ["near", "system"];
+
+static EXCLUDED_ACCOUNTS: OnceLock<HashSet<String>> = OnceLock::new();
+
+// Set once on start, from the config. Until then only the defaults are excluded.
+pub fn set_excluded_accounts(accounts: HashSet<String>) {
+    let _ = EXCLUDED_ACCOUNTS.set(accounts);
+}
+
+// Accounts dropped from the accounts of every request. Only the requested accounts are
+// filtered, rows with an excluded counterparty are still reported, e.g. transfers from near.
+pub fn is_excluded_account(account: &str) -> bool {
+    match EXCLUDED_ACCOUNTS.get() {
+        Some(excluded) => excluded.contains(account),
+        None => DEFAULT_EXCLUDED_ACCOUNTS.contains(&account),
+    }
+}
+
 // Extract accounts,
 // returns: account, is lockup, master account
 pub fn get_accounts_and_lockups(accounts: &str) -> HashSet<(String, Option<String>)> {
     let mut accounts: HashSet<(String, Option<String>)> = accounts
         .split(',')
         .map(String::from)
-        .filter(|account| account != "near" && account != "system")
+        .filter(|account| !is_excluded_account(account))
         .map(|account| (account, None))
         .collect();
 
@@ -34,19 +59,263 @@ pub fn get_accounts_and_lockups(accounts: &str) -> HashSet<(String, Option<Strin
     accounts
 }
 
-// Consolidate results and return a Response
-pub fn results_to_response<T: Serialize>(results: Vec<T>) -> Result<Response<Body>, csv::Error> {
-    let mut wtr = csv::Writer::from_writer(Vec::new());
-    for row in results {
-        wtr.serialize(row)?;
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CsvDelimiter {
+    #[default]
+    Comma,
+    Semicolon,
+    Tab,
+}
+
+// Output options of CSV downloads. Excel set up for European locales expects semicolons, and only
+// detects UTF-8 with a BOM.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CsvDialect {
+    #[serde(default)]
+    pub delimiter: CsvDelimiter,
+    pub quote_all: Option<bool>,
+    pub crlf: Option<bool>,
+    pub bom: Option<bool>,
+    // Amounts with a comma as decimal separator, and grouped by thousands with `thousands`.
+    pub decimal_comma: Option<bool>,
+    pub thousands: Option<bool>,
+}
+
+impl CsvDialect {
+    pub fn writer(&self) -> csv::Writer<Vec<u8>> {
+        let delimiter = match self.delimiter {
+            CsvDelimiter::Comma => b',',
+            CsvDelimiter::Semicolon => b';',
+            CsvDelimiter::Tab => b'\t',
+        };
+        let quote_style = if self.quote_all.unwrap_or(false) {
+            csv::QuoteStyle::Always
+        } else {
+            csv::QuoteStyle::Necessary
+        };
+        let terminator = if self.crlf.unwrap_or(false) {
+            csv::Terminator::CRLF
+        } else {
+            csv::Terminator::Any(b'\n')
+        };
+        let buffer = if self.bom.unwrap_or(false) {
+            UTF8_BOM.to_vec()
+        } else {
+            Vec::new()
+        };
+
+        csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .quote_style(quote_style)
+            .terminator(terminator)
+            .from_writer(buffer)
     }
-    wtr.flush()?;
+
+    fn formats_numbers(&self) -> bool {
+        self.decimal_comma.unwrap_or(false) || self.thousands.unwrap_or(false)
+    }
+
+    // Decimal amounts of the `AMOUNT_COLUMNS` rendered with the requested separators. Other
+    // columns, text that looks like a number such as memos included, are left untouched.
+    pub fn format_field(&self, header: &str, field: &str) -> String {
+        if !self.formats_numbers()
+            || !AMOUNT_COLUMNS.contains(&header)
+            || field.parse::<f64>().is_err()
+        {
+            return field.to_string();
+        }
+        let Some((int_part, fraction)) = field.split_once('.') else {
+            return field.to_string();
+        };
+        let (decimal_separator, thousands_separator) = if self.decimal_comma.unwrap_or(false) {
+            (',', '.')
+        } else {
+            ('.', ',')
+        };
+
+        let (sign, digits) = match int_part.strip_prefix('-') {
+            Some(digits) => ("-", digits),
+            None => ("", int_part),
+        };
+        let mut grouped = String::new();
+        for (i, digit) in digits.chars().enumerate() {
+            if self.thousands.unwrap_or(false) && i > 0 && (digits.len() - i) % 3 == 0 {
+                grouped.push(thousands_separator);
+            }
+            grouped.push(digit);
+        }
+
+        format!("{sign}{grouped}{decimal_separator}{fraction}")
+    }
+
+    // Fields past the headers are left untouched.
+    pub fn format_record<I, S>(&self, headers: &[String], record: I) -> Vec<String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        record
+            .into_iter()
+            .enumerate()
+            .map(|(i, field)| match headers.get(i) {
+                Some(header) => self.format_field(header, field.as_ref()),
+                None => field.as_ref().to_string(),
+            })
+            .collect()
+    }
+}
+
+// Amount, balance and price columns of the CSV downloads, the decimal separators of a dialect
+// only apply to them.
+pub const AMOUNT_COLUMNS: &[&str] = &[
+    "amount",
+    "amount_fiat_in",
+    "amount_fiat_out",
+    "amount_staked",
+    "amount_transferred",
+    "amount_unstaked",
+    "amount_usd_in",
+    "amount_usd_out",
+    "balance",
+    "cost_basis_usd",
+    "delegation_earnings",
+    "delta",
+    "disposed",
+    "end_balance",
+    "end_locked",
+    "end_price_usd",
+    "end_storage_locked_near",
+    "fee_earnings",
+    "ft_amount_in",
+    "ft_amount_out",
+    "ft_usd",
+    "holding",
+    "holding_cost_usd",
+    "inflow",
+    "liquid_amount",
+    "locked",
+    "locked_amount",
+    "lockup_balance",
+    "lockup_locked",
+    "near",
+    "near_amount",
+    "near_burnt",
+    "near_price_usd",
+    "net",
+    "onchain_balance",
+    "outflow",
+    "owner_balance",
+    "owner_earnings",
+    "owner_share",
+    "principal",
+    "proceeds_usd",
+    "realized_gain_usd",
+    "reward_fee",
+    "rewards_to_date",
+    "staked",
+    "staked_balance",
+    "start_balance",
+    "start_locked",
+    "start_storage_locked_near",
+    "storage_locked_near",
+    "total_amount",
+    "total_in",
+    "total_near",
+    "total_out",
+    "total_usd",
+    "unknown_basis",
+    "unrealized_gain_usd",
+    "unstaked_balance",
+];
+
+pub const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// Consolidate results and return a Response
+pub fn results_to_response<T: Serialize>(
+    results: Vec<T>,
+    dialect: &CsvDialect,
+) -> Result<Response<Body>, csv::Error> {
     Ok(Response::builder()
         .header("Content-Type", "text/csv")
-        .body(Body::from(wtr.into_inner().unwrap()))
+        .body(Body::from(results_to_csv(results, dialect)?))
         .unwrap())
 }
 
+pub fn results_to_csv<T: Serialize>(
+    results: Vec<T>,
+    dialect: &CsvDialect,
+) -> Result<Vec<u8>, csv::Error> {
+    let mut wtr = dialect.writer();
+    if dialect.formats_numbers() {
+        // Serialized as usual first, the fields are only known as strings afterwards.
+        let mut plain = csv::Writer::from_writer(Vec::new());
+        for row in results {
+            plain.serialize(row)?;
+        }
+        let plain = plain.into_inner().map_err(|e| e.into_error())?;
+        let mut reader = csv::ReaderBuilder::new().from_reader(plain.as_slice());
+        // No headers without results.
+        let headers: Vec<String> = reader.headers()?.iter().map(String::from).collect();
+        if !headers.is_empty() {
+            wtr.write_record(&headers)?;
+        }
+        for record in reader.records() {
+            wtr.write_record(dialect.format_record(&headers, &record?))?;
+        }
+    } else {
+        for row in results {
+            wtr.serialize(row)?;
+        }
+    }
+    wtr.flush()?;
+    Ok(wtr.into_inner().unwrap())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Day,
+    Week,
+    Month,
+}
+
+// Sample points of a time series report, from start to end (inclusive) every interval.
+pub fn sample_dates(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    interval: Interval,
+) -> Vec<DateTime<Utc>> {
+    let mut dates = vec![];
+    for n in 0.. {
+        let date = match interval {
+            Interval::Day => Some(start + Duration::days(n)),
+            Interval::Week => Some(start + Duration::weeks(n)),
+            // Always offset from the start, so month ends don't drift (Jan 31, Feb 28, Mar 31).
+            Interval::Month => start.checked_add_months(Months::new(n as u32)),
+        };
+        match date {
+            Some(date) if date <= end => dates.push(date),
+            _ => break,
+        }
+    }
+    dates
+}
+
+// Unix timestamps in milliseconds or nanoseconds, told apart by their magnitude: since 1973 in
+// milliseconds is over 10^11 and in nanoseconds over 10^17, until 5138 and 2286 respectively.
+pub fn parse_unix_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value: i64 = value.parse().ok()?;
+    match value {
+        100_000_000_000..=99_999_999_999_999 => Utc.timestamp_millis_opt(value).single(),
+        100_000_000_000_000_000.. => Some(Utc.timestamp_nanos(value)),
+        _ => None,
+    }
+}
+
 pub fn get_associated_lockup(account_id: &str, master_account_id: &str) -> String {
     format!(
         "{}.lockup.{}",
@@ -60,3 +329,73 @@ fn sha256(value: &str) -> String {
     hasher.update(value.as_bytes());
     format!("{:x}", hasher.finalize())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_dates_by_interval() {
+        let start = DateTime::parse_from_rfc3339("2023-01-31T00:00:00Z")
+            .unwrap()
+            .into();
+        let end = DateTime::parse_from_rfc3339("2023-04-30T00:00:00Z")
+            .unwrap()
+            .into();
+
+        assert_eq!(sample_dates(start, end, Interval::Day).len(), 90);
+        assert_eq!(sample_dates(start, end, Interval::Week).len(), 13);
+
+        let months: Vec<String> = sample_dates(start, end, Interval::Month)
+            .iter()
+            .map(|d| d.format("%Y-%m-%d").to_string())
+            .collect();
+        assert_eq!(
+            months,
+            vec!["2023-01-31", "2023-02-28", "2023-03-31", "2023-04-30"]
+        );
+    }
+
+    #[test]
+    fn unix_timestamps() {
+        let date: DateTime<Utc> = DateTime::parse_from_rfc3339("2023-01-01T00:00:00Z")
+            .unwrap()
+            .into();
+        assert_eq!(parse_unix_timestamp("1672531200000"), Some(date));
+        assert_eq!(parse_unix_timestamp("1672531200000000000"), Some(date));
+        assert_eq!(parse_unix_timestamp("1672531200"), None);
+        assert_eq!(parse_unix_timestamp("2023-01-01T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn csv_dialect_for_excel() {
+        let dialect = CsvDialect {
+            delimiter: CsvDelimiter::Semicolon,
+            quote_all: Some(true),
+            crlf: Some(true),
+            bom: Some(true),
+            ..Default::default()
+        };
+        let mut wtr = dialect.writer();
+        wtr.write_record(["a", "b"]).unwrap();
+        assert_eq!(
+            wtr.into_inner().unwrap(),
+            b"\xEF\xBB\xBF\"a\";\"b\"\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn csv_dialect_number_format() {
+        let dialect = CsvDialect {
+            decimal_comma: Some(true),
+            thousands: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(dialect.format_field("amount", "1234.56789"), "1.234,56789");
+        assert_eq!(dialect.format_field("amount", "-1234567.5"), "-1.234.567,5");
+        assert_eq!(dialect.format_field("amount", "0.10000"), "0,10000");
+        assert_eq!(dialect.format_field("amount", "103498312"), "103498312");
+        assert_eq!(dialect.format_field("amount", "wrap.near"), "wrap.near");
+        assert_eq!(dialect.format_field("memo", "12.5"), "12.5");
+    }
+}
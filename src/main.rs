@@ -1,51 +1,111 @@
-use csv::Writer;
 use hyper::Body;
+use janitor::Janitor;
+use jobs::{JobStatus, JobStore};
 use kitwallet::KitWallet;
+use metadata_source::MetadataSource;
 use near_primitives::types::AccountId;
+use near_social::NearSocial;
+use pricing::{fx::FiatCurrency, PriceService};
+use spool::ReportSpool;
+use tokio_util::io::ReaderStream;
 use tower::ServiceBuilder;
 use tower_http::{
     cors::{Any, CorsLayer},
     trace::TraceLayer,
 };
 use tracing_loki::url::Url;
-use tta::models::ReportRow;
+use tta::{
+    activity::ActivityMaintainer,
+    aggregations::{by_counterparty, monthly, summarize},
+    income::IncomeClassifier,
+    models::{disambiguate_symbols, ReportRow, Watermark, DATE_FORMAT, TIME_FORMAT},
+    progress::ReportProgress,
+    snapshots::BalanceSnapshotter,
+};
+use watchlist::Watchlist;
+use webhooks::{alerts::BalanceAlerts, WebhookNotifier, WebhookSender};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
 
 use axum::{
     body,
-    extract::{Query, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    extract::{Path, Query, RawQuery, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::delete,
     routing::get,
     routing::post,
+    routing::put,
     Json, Router,
 };
 
-use chrono::DateTime;
+use anyhow::Context;
+use chrono::{DateTime, NaiveDate, NaiveTime, SecondsFormat, TimeZone};
+use chrono_tz::Tz;
 use dotenvy::dotenv;
 
-use futures_util::future::join_all;
+use futures_util::{future::join_all, stream, StreamExt};
 use near_jsonrpc_client::JsonRpcClient;
+use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
-use sqlx::postgres::PgPoolOptions;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     env,
+    io::{Cursor, Write},
     sync::{Arc, RwLock},
 };
-use tokio::{spawn, sync::Semaphore};
+use tokio::{io::AsyncReadExt, spawn, sync::Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
 use tracing::*;
 use tracing_subscriber::{prelude::__tracing_subscriber_SubscriberExt, EnvFilter, FmtSubscriber};
-use tta::tta_impl::TTA;
-use tta_rust::{get_accounts_and_lockups, results_to_response};
+use tta::tta_impl::{
+    pair_chunk_rows, ReportEstimate, ReportTooLarge, RpcBudgetExceeded, SharedReportError, TTA,
+};
+use tta_rust::{
+    get_accounts_and_lockups,
+    idempotency::{idempotent, IdempotencyCache},
+    is_excluded_account,
+    metrics::metrics,
+    parse_unix_timestamp, results_to_csv, results_to_response, sample_dates, set_excluded_accounts,
+    url_policy::UrlPolicy,
+    CsvDialect, Interval, UTF8_BOM,
+};
 
-use crate::tta::{ft_metadata::FtService, sql::sql_queries::SqlClient, tta_impl::safe_divide_u128};
+use crate::{
+    config::Config,
+    tta::{
+        ft_metadata::{
+            storage_locked, FtMetadataOverride, FtService, TokenBalance, ViewAccountError,
+        },
+        sql::{
+            models::{BalanceAlert, BalanceSnapshot, Watch},
+            sql_queries::{pool_options, PoolStats, SqlClient},
+        },
+        tta_impl::safe_divide_u128,
+    },
+};
 
+pub mod config;
+pub mod janitor;
+pub mod jobs;
 pub mod kitwallet;
 pub mod lockup;
+pub mod metadata_source;
+pub mod near_social;
+pub mod pricing;
+pub mod spool;
 pub mod tta;
+pub mod watchlist;
+pub mod webhooks;
 
-const POOL_SIZE: u32 = 500;
-const SEMAPHORE_SIZE: usize = 50;
+// Upper bound for resizing the DB pool at runtime.
+const MAX_POOL_SIZE: u32 = 1000;
+// Days of rows generated at once by spooled reports.
+const SPOOL_CHUNK_DAYS: i64 = 30;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -110,12 +170,17 @@ fn init_tracing() -> anyhow::Result<()> {
 }
 
 async fn router() -> anyhow::Result<Router> {
-    let pool = PgPoolOptions::new()
-        .max_connections(POOL_SIZE)
+    let config = Config::from_env();
+    set_excluded_accounts(config.excluded_accounts.clone());
+
+    let pool = pool_options()
+        .max_connections(config.pool_size)
         .connect(env!("DATABASE_URL"))
         .await?;
 
-    let sql_client = SqlClient::new(pool);
+    let sql_client = SqlClient::new(pool).with_config(&config);
+    sql_client.check_indexer_schema().await?;
+    sql_client.migrate().await?;
     // let archival_near_client = JsonRpcClient::connect("http://beta.rpc.mainnet.near.org");
     let client = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(60 * 5))
@@ -123,36 +188,280 @@ async fn router() -> anyhow::Result<Router> {
     let archival_near_client =
         JsonRpcClient::with(client).connect("http://beta.rpc.mainnet.near.org");
     // let near_client = JsonRpcClient::connect(NEAR_MAINNET_RPC_URL);
-    let ft_service = FtService::new(archival_near_client);
-    let kitwallet = KitWallet::new();
-    let semaphore = Arc::new(Semaphore::new(SEMAPHORE_SIZE));
-
-    let tta_service = TTA::new(sql_client.clone(), ft_service.clone(), semaphore);
+    let ft_service = FtService::new(archival_near_client)
+        .with_quota(&config.quotas.archival_rpc)
+        .with_concurrency(&config.concurrency)
+        .with_token_filter(&config.token_filter)
+        .with_spam_check_holders(config.spam_check_holders)
+        .with_metadata_store(sql_client.clone());
+    match ft_service.load_metadata_cache().await {
+        Ok(count) => info!("Loaded {} ft_metadata entries from the DB", count),
+        Err(e) => warn!("Failed to load ft_metadata cache: {:?}", e),
+    }
+    let kitwallet = KitWallet::new()
+        .with_config(&config)
+        .with_sql_client(sql_client.clone());
+
+    let near_social = NearSocial::new().with_config(&config);
+    let prices = PriceService::new(sql_client.clone()).with_config(&config);
+
+    metrics().register_rate_limiter(ft_service.archival_rate_limiter.clone());
+    metrics().register_rate_limiter(near_social.rate_limiter());
+    metrics().register_rate_limiter(prices.rate_limiter());
+    for rate_limiter in kitwallet.rate_limiters() {
+        metrics().register_rate_limiter(rate_limiter);
+    }
+    let semaphore = Arc::new(Semaphore::new(config.concurrency.semaphore_size));
+
+    let tta_service = TTA::new(sql_client.clone(), ft_service.clone(), semaphore)
+        .with_concurrency(&config.concurrency)
+        .with_max_report_rows(config.max_report_rows)
+        .with_max_report_rpc_calls(config.max_report_rpc_calls)
+        .with_spool_threshold_rows(config.spool_threshold_rows)
+        .with_price_service(prices.clone())
+        .with_gas_refund_rule(&config.gas_refund)
+        .with_metadata_source(MetadataSource::new().with_config(&config));
+    let snapshotter =
+        BalanceSnapshotter::new(sql_client.clone(), ft_service.clone(), kitwallet.clone())
+            .with_config(&config);
+    snapshotter.spawn();
+    let activity =
+        ActivityMaintainer::new(tta_service.clone(), sql_client.clone()).with_config(&config);
+    activity.spawn();
+    let watchlist = Watchlist::new(sql_client.clone()).with_config(&config);
+    let webhook_sender = WebhookSender::new().with_config(&config);
+    WebhookNotifier::new(
+        tta_service.clone(),
+        watchlist.clone(),
+        webhook_sender.clone(),
+    )
+    .with_config(&config)
+    .spawn();
+    let balance_alerts = BalanceAlerts::new(sql_client.clone(), ft_service.clone(), webhook_sender)
+        .with_config(&config);
+    balance_alerts.spawn();
+    let jobs = JobStore::new()
+        .with_config(&config)
+        .with_store(sql_client.clone());
+    if let Err(e) = jobs.init().await {
+        warn!("Failed to initialize the report jobs store: {:?}", e);
+    }
+    Janitor::new(sql_client.clone(), jobs.clone())
+        .with_config(&config)
+        .spawn();
+    let idempotency = IdempotencyCache::new(std::time::Duration::from_secs(
+        config.idempotency_window_secs,
+    ))
+    .with_max_body_bytes(config.idempotency_max_body_bytes);
+    let idempotency_layer = || middleware::from_fn_with_state(idempotency.clone(), idempotent);
 
     let trace = TraceLayer::new_for_http();
-    let cors = CorsLayer::new().allow_methods(Any).allow_origin(Any);
+    // The UI names downloads after Content-Disposition, which browsers hide unless exposed.
+    let cors = CorsLayer::new()
+        .allow_methods(Any)
+        .allow_origin(Any)
+        .expose_headers([
+            header::CONTENT_DISPOSITION,
+            header::HeaderName::from_static("x-watermark"),
+        ]);
     let middleware = ServiceBuilder::new().layer(trace).layer(cors);
+    let admin_routes = match config.admin_token.clone() {
+        Some(token) => Router::new()
+            .route("/admin/pool", put(resize_pool))
+            .route_layer(middleware::from_fn_with_state(
+                Arc::new(token),
+                require_admin_token,
+            ))
+            .with_state(sql_client.clone()),
+        None => Router::new(),
+    };
 
     Ok(Router::new()
-        .route("/tta", post(get_txns_report))
+        .route(
+            "/tta/jobs",
+            post(create_txns_job).layer(idempotency_layer()),
+        )
+        .route("/tta/jobs", get(list_txns_jobs))
+        .route("/tta/jobs/:id", get(get_txns_job))
+        .route("/tta/jobs/:id/result", get(get_txns_job_result))
+        .route("/tta/diff", post(get_txns_diff))
+        .with_state((tta_service.clone(), near_social.clone(), jobs))
+        .route("/tta", post(post_txns_report).layer(idempotency_layer()))
         .route("/tta", get(get_txns_report))
-        .with_state(tta_service)
+        .route("/tta/estimate", get(estimate_txns_report))
+        .route("/tta/summary", post(get_txns_summary))
+        .route("/tta/summary", get(get_txns_summary))
+        .route("/tta/monthly", post(get_txns_monthly))
+        .route("/tta/monthly", get(get_txns_monthly))
+        .route("/tta/gains", post(get_txns_gains))
+        .route("/tta/gains", get(get_txns_gains))
+        .route("/tta/airdrops", post(get_txns_airdrops))
+        .route("/tta/airdrops", get(get_txns_airdrops))
+        .route("/tta/counterparties", post(get_txns_by_counterparty))
+        .route("/tta/counterparties", get(get_txns_by_counterparty))
+        .route("/tta/changes", get(get_txns_changes))
+        .route("/tta/tail", get(tail_txns))
+        .route("/tta/tx/:hash", get(get_txn_rows))
+        .route("/tta/tx/:hash/explain", get(explain_txn))
+        .route("/receipts/raw", get(get_raw_receipts))
+        .with_state((tta_service, near_social))
         .route("/likelyBlockId", get(get_closest_block_id))
+        .route("/admin/pool", get(get_pool_stats))
+        .route("/gas", get(get_gas_report))
+        .route("/lifecycle", get(get_account_lifecycle))
+        .route("/deployments", get(get_contract_deployments))
         .with_state(sql_client.clone())
+        .route("/alerts", get(list_balance_alerts))
+        .route("/alerts", post(create_balance_alert))
+        .route("/alerts/:id", delete(delete_balance_alert))
+        .with_state((sql_client.clone(), config.webhook_url_policy.clone()))
+        .route("/watchlist", get(list_watches))
+        .route("/watchlist/:account", get(get_watch))
+        .route("/watchlist/:account", put(save_watch))
+        .route("/watchlist/:account", delete(remove_watch))
+        .with_state(watchlist)
+        .route("/likelyTokens", delete(evict_likely_tokens))
+        .with_state(kitwallet.clone())
+        .route("/admin/tokens", get(list_metadata_overrides))
+        .route("/admin/tokens/:token_id", put(set_metadata_override))
+        .route("/admin/tokens/:token_id", delete(remove_metadata_override))
+        .with_state(ft_service.clone())
         .route("/balances", get(get_balances))
         .route("/balances", post(get_balances))
         .with_state((sql_client.clone(), ft_service.clone(), kitwallet.clone()))
-        .route("/balancesfull", post(get_balances_full))
-        .with_state((sql_client.clone(), ft_service.clone(), kitwallet))
+        .route(
+            "/balancesfull",
+            post(get_balances_full).layer(idempotency_layer()),
+        )
+        .with_state((sql_client.clone(), ft_service.clone(), kitwallet.clone()))
         .route("/staking", get(get_staking_report))
         .route("/staking", post(get_staking_report))
+        .route("/staking/operator", get(get_operator_earnings))
+        .route("/staking/delegators", get(get_pool_delegators))
         .with_state((sql_client.clone(), ft_service.clone()))
         .route("/lockup", get(get_lockup_balances))
         .route("/lockup", post(get_lockup_balances))
-        .with_state((sql_client, ft_service))
+        .with_state((sql_client.clone(), ft_service.clone()))
+        .route("/portfolio", get(get_portfolio))
+        .with_state((sql_client, ft_service, kitwallet, prices))
+        .route("/metrics", get(get_metrics))
+        .merge(admin_routes)
         .layer(middleware))
 }
 
+async fn get_metrics() -> String {
+    metrics().render()
+}
+
+fn parse_tz(tz: Option<&str>) -> anyhow::Result<Tz> {
+    match tz {
+        Some(tz) => tz
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Unknown timezone: {}", tz)),
+        None => Ok(Tz::UTC),
+    }
+}
+
+// RFC 3339, or a Unix timestamp in milliseconds or nanoseconds.
+fn parse_date(date: &str) -> anyhow::Result<DateTime<chrono::Utc>> {
+    if let Some(date) = parse_unix_timestamp(date) {
+        return Ok(date);
+    }
+    Ok(DateTime::parse_from_rfc3339(date)
+        .with_context(|| format!("Invalid date: {}", date))?
+        .into())
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct DownloadParams {
+    // Overrides the generated download name.
+    pub filename: Option<String>,
+}
+
+// `<report>_<accounts>_<first date>_<last date>.csv`, e.g.
+// `tta_nf-payments.near_2023-01-01_2023-02-01.csv`. Past three accounts only the count is kept.
+fn report_filename(report: &str, accounts: &str, dates: &[DateTime<chrono::Utc>]) -> String {
+    let accounts: Vec<&str> = accounts
+        .split(',')
+        .map(str::trim)
+        .filter(|account| !account.is_empty())
+        .collect();
+    let mut parts = vec![report.to_string()];
+    if accounts.len() > 3 {
+        parts.push(format!("{}+{}-more", accounts[0], accounts.len() - 1));
+    } else if !accounts.is_empty() {
+        parts.push(accounts.join("+"));
+    }
+    let mut dates: Vec<String> = dates
+        .iter()
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .collect();
+    dates.dedup();
+    if let (Some(first), Some(last)) = (dates.first(), dates.last()) {
+        parts.push(first.clone());
+        if first != last {
+            parts.push(last.clone());
+        }
+    }
+
+    format!("{}.csv", parts.join("_"))
+}
+
+// Marks the response as a download, under the requested name or `default_filename`.
+fn as_attachment(
+    mut response: Response<Body>,
+    download: &DownloadParams,
+    default_filename: String,
+) -> Response<Body> {
+    let extension = default_filename
+        .rsplit_once('.')
+        .map_or("csv", |(_, extension)| extension)
+        .to_string();
+    let filename: String = download
+        .filename
+        .clone()
+        .unwrap_or(default_filename)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | '+') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let filename = if filename.ends_with(&format!(".{}", extension)) {
+        filename
+    } else {
+        format!("{}.{}", filename, extension)
+    };
+    response.headers_mut().insert(
+        "Content-Disposition",
+        format!("attachment; filename={}", filename)
+            .parse()
+            .unwrap(),
+    );
+    response
+}
+
+// Either the single `date`, or every `interval` from `start_date` to `end_date`.
+fn get_sample_dates(
+    date: Option<&str>,
+    start_date: Option<&str>,
+    end_date: Option<&str>,
+    interval: Option<Interval>,
+) -> anyhow::Result<Vec<DateTime<chrono::Utc>>> {
+    match (start_date, end_date, date) {
+        (Some(start_date), Some(end_date), _) => Ok(sample_dates(
+            parse_date(start_date)?,
+            parse_date(end_date)?,
+            interval.unwrap_or(Interval::Day),
+        )),
+        (_, _, Some(date)) => Ok(vec![parse_date(date)?]),
+        _ => anyhow::bail!("Either date or start_date and end_date are required"),
+    }
+}
+
 // HTTP layer
 type AccountID = String;
 type TransactionID = String;
@@ -164,231 +473,1821 @@ struct TxnsReportParams {
     pub end_date: String,
     pub accounts: String,
     pub include_balances: Option<bool>,
+    // Fill `counterparty_name` from near.social profiles.
+    pub resolve_names: Option<bool>,
+    // Fill `epoch_id` from the archival RPC.
+    pub include_epoch_id: Option<bool>,
+    // Fill `amount_usd_in` and `amount_usd_out` from daily CoinGecko prices.
+    pub include_usd: Option<bool>,
+    // Also convert the USD amounts to this currency, e.g. EUR. Implies `include_usd`.
+    pub fiat: Option<FiatCurrency>,
+    // IANA timezone for the `date` and `time` columns, UTC by default.
+    pub tz: Option<String>,
+    pub format: Option<ReportFormat>,
+    // Override the deployment's gas refund rule, see `GasRefundRule`.
+    pub gas_refund_max_near: Option<f64>,
+    pub gas_refund_system_only: Option<bool>,
+    // Report the gas refunds too, as rows with `refund` set.
+    pub include_refunds: Option<bool>,
+    // Off for the basic transaction fields only, fast, without token amounts or balances.
+    pub decode: Option<bool>,
+}
+
+// What the report of `params` would cost, to pick between downloading it and running a job.
+async fn estimate_txns_report(
+    Query(params): Query<TxnsReportParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+) -> Result<Json<ReportEstimate>, AppError> {
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    let estimate = report_service(&tta_service, &params)
+        .estimate_report(
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+            &report_accounts(&params),
+            params.include_balances.unwrap_or(false),
+            params.include_epoch_id.unwrap_or(false),
+        )
+        .await?;
+
+    Ok(Json(estimate))
+}
+
+// The service to run the report of `params` with, under their gas refund and decoding options.
+fn report_service(tta_service: &TTA, params: &TxnsReportParams) -> TTA {
+    let mut rule = tta_service.gas_refund_rule();
+    if let Some(max_near) = params.gas_refund_max_near {
+        rule.max_near = max_near;
+    }
+    if let Some(system_only) = params.gas_refund_system_only {
+        rule.system_only = system_only;
+    }
+    tta_service
+        .clone()
+        .with_report_gas_refund_rule(rule)
+        .with_report_include_refunds(params.include_refunds.unwrap_or(false))
+        .with_report_decode(params.decode.unwrap_or(true))
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ReportFormat {
+    #[default]
+    Csv,
+    // One CSV per account and a summary.csv, in a zip archive.
+    Zip,
+}
+
+// Metadata is passed inline, or by `metadata_url` when too large for a request body.
 #[derive(Debug, Deserialize, Default, Clone)]
 struct TxnsReportWithMetadata {
+    #[serde(default)]
     pub metadata: Metadata,
+    #[serde(default)]
+    pub metadata_url: Option<String>,
 }
 
-async fn get_txns_report(
-    Query(params): Query<TxnsReportParams>,
-    State(tta_service): State<TTA>,
-    metadata_body: Option<Json<TxnsReportWithMetadata>>,
-) -> Result<Response<Body>, AppError> {
-    let start_date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.start_date)
-        .unwrap()
-        .into();
-    let end_date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.end_date)
-        .unwrap()
-        .into();
+// POST /tta body. The report parameters set here take precedence over the query string, so that
+// long account lists don't have to fit in the URL.
+#[derive(Debug, Deserialize, Default)]
+struct TxnsReportBody {
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    pub accounts: Option<Vec<String>>,
+    pub include_balances: Option<bool>,
+    #[serde(flatten)]
+    pub metadata: TxnsReportWithMetadata,
+}
 
-    let accounts: HashSet<String> = params
-        .accounts
-        .split(',')
-        .map(|s| String::from(s.trim()))
-        .filter(|account| account != "near" && account != "system" && !account.is_empty())
-        .collect();
+impl TxnsReportBody {
+    // The query string with the parameters of the body, as `TxnsReportParams`.
+    fn report_params(&self, query: Option<&str>) -> anyhow::Result<TxnsReportParams> {
+        let mut overrides = vec![];
+        if let Some(start_date) = &self.start_date {
+            overrides.push(("start_date", start_date.clone()));
+        }
+        if let Some(end_date) = &self.end_date {
+            overrides.push(("end_date", end_date.clone()));
+        }
+        if let Some(accounts) = &self.accounts {
+            overrides.push(("accounts", accounts.join(",")));
+        }
+        if let Some(include_balances) = self.include_balances {
+            overrides.push(("include_balances", include_balances.to_string()));
+        }
 
-    let include_balances = params.include_balances.unwrap_or(false);
+        let mut pairs: Vec<(String, String)> = serde_urlencoded::from_str(query.unwrap_or(""))?;
+        pairs.retain(|(key, _)| !overrides.iter().any(|(k, _)| k == key));
+        pairs.extend(overrides.into_iter().map(|(k, v)| (k.to_string(), v)));
+        serde_urlencoded::from_str(&serde_urlencoded::to_string(&pairs)?)
+            .context("Invalid report parameters")
+    }
+}
+
+// The body's metadata, with the document at its `metadata_url` merged in.
+async fn report_metadata(
+    tta_service: &TTA,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> anyhow::Result<Arc<RwLock<TxnsReportWithMetadata>>> {
+    let mut metadata = metadata_body.unwrap_or_default().0;
+    if let Some(url) = metadata.metadata_url.take() {
+        for (account, fetched) in tta_service.metadata_source().fetch(&url).await? {
+            let txns = metadata.metadata.entry(account).or_default();
+            for (txn, value) in fetched {
+                // Inline entries win.
+                txns.entry(txn).or_insert(value);
+            }
+        }
+    }
 
-    let metadata = Arc::new(RwLock::new(metadata_body.unwrap_or_default().0));
+    Ok(Arc::new(RwLock::new(metadata)))
+}
 
-    let csv_data = tta_service
-        .get_txns_report(
-            start_date.timestamp_nanos() as u128,
-            end_date.timestamp_nanos() as u128,
-            accounts,
-            include_balances,
-            metadata,
+async fn get_txns_report(
+    Query(params): Query<TxnsReportParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, near_social)): State<(TTA, NearSocial)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Response<Body>, AppError> {
+    if params.format.unwrap_or_default() == ReportFormat::Csv
+        && spools_txns_report(&tta_service, &params).await?
+    {
+        let start_date = parse_date(&params.start_date)?;
+        let end_date = parse_date(&params.end_date)?;
+        tta_service
+            .check_report_size(
+                start_date.timestamp_nanos() as u128,
+                end_date.timestamp_nanos() as u128,
+                &report_accounts(&params),
+            )
+            .await?;
+        let (report_hash, file) = spool_txns_report(
+            &tta_service,
+            &near_social,
+            &params,
+            metadata_body,
+            Arc::new(ReportProgress::default()),
+            &dialect,
         )
         .await?;
 
-    // Create a Writer with a Vec<u8> as the underlying writer
-    let mut wtr = Writer::from_writer(Vec::new());
+        let response = Response::builder()
+            .header("Content-Type", "text/csv")
+            .header("X-Report-Hash", &report_hash)
+            .body(Body::wrap_stream(ReaderStream::new(file)))?;
+        return Ok(as_attachment(
+            response,
+            &download,
+            txns_report_filename("tta", &params)?,
+        ));
+    }
 
-    // Write the headers
-    wtr.write_record(&ReportRow::get_vec_headers())?;
+    let mut csv_data = run_txns_report(&tta_service, &params, metadata_body, None).await?;
+    enrich_txns_report(&tta_service, &near_social, &params, &mut csv_data).await;
+
+    if params.format.unwrap_or_default() == ReportFormat::Zip {
+        let mut files = vec![(
+            "summary.csv".to_string(),
+            results_to_csv(summarize(&csv_data), &dialect)?,
+        )];
+        let mut rows_by_account: BTreeMap<String, Vec<ReportRow>> = BTreeMap::new();
+        for row in csv_data {
+            rows_by_account
+                .entry(row.account_id.clone())
+                .or_default()
+                .push(row);
+        }
+        for (account_id, rows) in rows_by_account {
+            let (data, _) = report_csv(&rows, &dialect)?;
+            files.push((format!("{}.csv", account_id), data));
+        }
 
-    // Write each row
-    for row in csv_data {
-        let record: Vec<String> = row.to_vec();
-        wtr.write_record(&record)?;
+        let response = Response::builder()
+            .header("Content-Type", "application/zip")
+            .body(Body::from(zip_files(files)?))?;
+        let filename = txns_report_filename("tta", &params)?;
+        return Ok(as_attachment(
+            response,
+            &download,
+            format!("{}.zip", filename.trim_end_matches(".csv")),
+        ));
     }
 
-    // Get the CSV data
-    let csv_data = wtr.into_inner()?;
+    let (csv_data, report_hash) = report_csv(&csv_data, &dialect)?;
 
     // Create a response with the CSV data
     let response = Response::builder()
         .header("Content-Type", "text/csv")
-        .header("Content-Disposition", "attachment; filename=data.csv")
+        .header("X-Report-Hash", &report_hash)
         .body(Body::from(csv_data))?;
 
-    Ok(response)
+    Ok(as_attachment(
+        response,
+        &download,
+        txns_report_filename("tta", &params)?,
+    ))
+}
+
+// POST /tta, with the report parameters in the query string, the JSON body or both.
+async fn post_txns_report(
+    RawQuery(query): RawQuery,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    state: State<(TTA, NearSocial)>,
+    body: Option<Json<TxnsReportBody>>,
+) -> Result<Response<Body>, AppError> {
+    let body = body.map(|Json(body)| body).unwrap_or_default();
+    let params = body.report_params(query.as_deref())?;
+    get_txns_report(
+        Query(params),
+        Query(dialect),
+        Query(download),
+        state,
+        Some(Json(body.metadata)),
+    )
+    .await
 }
 
 #[derive(Debug, Deserialize)]
-struct ClosestBlockIdParams {
-    pub date: String,
+struct TxnsChangesParams {
+    pub accounts: String,
+    // `X-Watermark` of the previous export. The first export starts at `start_date` instead.
+    pub since: Option<String>,
+    pub start_date: Option<String>,
+    pub include_balances: Option<bool>,
+    pub tz: Option<String>,
 }
 
-async fn get_closest_block_id(
-    Query(params): Query<ClosestBlockIdParams>,
-    State(sql_client): State<SqlClient>,
+// Where a /tta/changes or /tta/tail export starts, None when neither is given.
+fn changes_start_date(
+    since: Option<&Watermark>,
+    start_date: Option<&str>,
+) -> anyhow::Result<Option<DateTime<chrono::Utc>>> {
+    match (since, start_date) {
+        (Some(since), _) => Ok(Some(
+            chrono::Utc.timestamp_nanos(since.block_timestamp as i64),
+        )),
+        (None, Some(start_date)) => Ok(Some(parse_date(start_date)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+// Rows of blocks older than this are taken to be indexed. A watermark past newer blocks would skip
+// the rows of those blocks indexed after it.
+const CHANGES_INDEXER_LAG_MINUTES: i64 = 10;
+
+// Up to where /tta/changes and /tta/tail export, see `CHANGES_INDEXER_LAG_MINUTES`.
+fn changes_end_date() -> DateTime<chrono::Utc> {
+    chrono::Utc::now() - chrono::Duration::minutes(CHANGES_INDEXER_LAG_MINUTES)
+}
+
+// The /tta rows from `start_date` up to `end_date`, past `since`.
+async fn txns_rows_since(
+    tta_service: &TTA,
+    changes: &TxnsChangesParams,
+    since: Option<&Watermark>,
+    start_date: DateTime<chrono::Utc>,
+    end_date: DateTime<chrono::Utc>,
+) -> anyhow::Result<(TxnsReportParams, Vec<ReportRow>)> {
+    let params = TxnsReportParams {
+        start_date: start_date.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        end_date: end_date.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        accounts: changes.accounts.clone(),
+        include_balances: changes.include_balances,
+        resolve_names: None,
+        include_epoch_id: None,
+        include_usd: None,
+        fiat: None,
+        tz: changes.tz.clone(),
+        format: None,
+        gas_refund_max_near: None,
+        gas_refund_system_only: None,
+        include_refunds: None,
+        decode: None,
+    };
+
+    if end_date <= start_date {
+        return Ok((params, vec![]));
+    }
+    let mut rows = run_txns_report(tta_service, &params, None, None).await?;
+    if let Some(since) = since {
+        rows.retain(|row| Watermark::of(row) > *since);
+    }
+
+    Ok((params, rows))
+}
+
+// The /tta rows added since the previous export, up to `CHANGES_INDEXER_LAG_MINUTES` ago, for
+// incremental syncs. The watermark to pass as `since` next time is returned in `X-Watermark`,
+// unchanged when there is nothing new.
+async fn get_txns_changes(
+    Query(changes): Query<TxnsChangesParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
 ) -> Result<Response<Body>, AppError> {
-    let date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.date).unwrap().into();
-    let nanos = date.timestamp_nanos() as u128;
-    let d = sql_client.get_closest_block_id(nanos).await?;
-    Ok(Response::new(Body::from(d.to_string())))
+    let since: Option<Watermark> = changes.since.as_deref().map(str::parse).transpose()?;
+    let Some(start_date) = changes_start_date(since.as_ref(), changes.start_date.as_deref())?
+    else {
+        return Ok(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Either since or start_date is required"))?);
+    };
+    let (params, rows) = txns_rows_since(
+        &tta_service,
+        &changes,
+        since.as_ref(),
+        start_date,
+        changes_end_date(),
+    )
+    .await?;
+    let watermark = rows.iter().map(Watermark::of).max().or(since);
+
+    let (csv_data, report_hash) = report_csv(&rows, &dialect)?;
+    let mut response = Response::builder()
+        .header("Content-Type", "text/csv")
+        .header("X-Report-Hash", &report_hash);
+    if let Some(watermark) = watermark {
+        response = response.header("X-Watermark", watermark.to_string());
+    }
+
+    Ok(as_attachment(
+        response.body(Body::from(csv_data))?,
+        &download,
+        txns_report_filename("tta-changes", &params)?,
+    ))
 }
 
-#[derive(Debug, Deserialize)]
-struct GetBalances {
-    pub start_date: String,
-    pub end_date: String,
-    pub accounts: Option<String>,
+// How often /tta/tail looks for new rows once caught up.
+const TAIL_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+struct TailState {
+    tta_service: TTA,
+    changes: TxnsChangesParams,
+    watermark: Option<Watermark>,
+    start_date: DateTime<chrono::Utc>,
+    pending: VecDeque<ReportRow>,
+    caught_up: bool,
 }
 
 #[derive(Debug, Deserialize)]
-struct GetBalancesBody {
-    pub accounts: Vec<String>,
+struct TxnRowsParams {
+    pub account: String,
+    pub include_balances: Option<bool>,
 }
 
-#[derive(Debug, Serialize, Clone)]
-struct GetBalancesResultRow {
+// The /tta rows of a single transaction for the account, as JSON, to look into a row that looks
+// wrong.
+async fn get_txn_rows(
+    Path(hash): Path<String>,
+    Query(params): Query<TxnRowsParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+) -> Result<Response, AppError> {
+    let rows = tta_service
+        .decode_transaction(
+            &hash,
+            params.account.trim(),
+            params.include_balances.unwrap_or(false),
+        )
+        .await?;
+    match rows {
+        Some(mut rows) => {
+            disambiguate_symbols(&mut rows);
+            Ok(Json(rows).into_response())
+        }
+        None => Ok((StatusCode::NOT_FOUND, "Transaction not found").into_response()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ExplainTxnParams {
     pub account: String,
+}
+
+// Which rule kept each receipt of the transaction out of the account's report, for the "missing
+// transaction" tickets.
+async fn explain_txn(
+    Path(hash): Path<String>,
+    Query(params): Query<ExplainTxnParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+) -> Result<Response, AppError> {
+    match tta_service
+        .explain_transaction(&hash, params.account.trim())
+        .await?
+    {
+        Some(explanations) => Ok(Json(explanations).into_response()),
+        None => Ok((StatusCode::NOT_FOUND, "Transaction not found").into_response()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RawReceiptsParams {
+    pub accounts: String,
     pub start_date: String,
     pub end_date: String,
-    pub start_block_id: u128,
-    pub end_block_id: u128,
-    pub token_id: String,
-    pub symbol: String,
-    pub lockup_of: Option<String>,
-    pub start_balance: Option<f64>,
-    pub end_balance: Option<f64>,
 }
 
-async fn get_balances(
-    Query(params): Query<GetBalances>,
-    State((sql_client, ft_service, kitwallet)): State<(SqlClient, FtService, KitWallet)>,
-    body: Option<Json<GetBalancesBody>>,
+// The indexer rows /tta decodes for the accounts and their lockups, as NDJSON, to look into rows
+// decoded differently than on an explorer. A response ending in an error is cut short.
+async fn get_raw_receipts(
+    Query(params): Query<RawReceiptsParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
 ) -> Result<Response<Body>, AppError> {
-    let start_date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.start_date)
-        .unwrap()
-        .into();
-    let end_date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.end_date)
-        .unwrap()
-        .into();
-    let start_nanos = start_date.timestamp_nanos() as u128;
-    let end_nanos = end_date.timestamp_nanos() as u128;
+    let start_date = parse_date(&params.start_date)?.timestamp_nanos() as u128;
+    let end_date = parse_date(&params.end_date)?.timestamp_nanos() as u128;
+    let accounts: HashSet<String> = params
+        .accounts
+        .split(',')
+        .map(|account| account.trim().to_string())
+        .filter(|account| !is_excluded_account(account) && !account.is_empty())
+        .collect();
 
-    let start_block_id = sql_client.get_closest_block_id(start_nanos).await?;
-    let end_block_id = sql_client.get_closest_block_id(end_nanos).await?;
-    let a = match body {
-        Some(body) => body.accounts.join(","),
-        None => params.accounts.unwrap_or("".to_string()),
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+    let export = spawn(async move {
+        tta_service
+            .raw_receipts(accounts, start_date, end_date, tx)
+            .await
+    });
+    let lines = ReceiverStream::new(rx)
+        .map(|receipt| {
+            let mut line = serde_json::to_vec(&receipt)?;
+            line.push(b'\n');
+            Ok::<_, anyhow::Error>(line)
+        })
+        .chain(
+            stream::once(async move {
+                let e = match export.await {
+                    Ok(Ok(())) => return None,
+                    Ok(Err(e)) => e,
+                    Err(e) => e.into(),
+                };
+                error!(?e, "Error exporting raw receipts");
+                Some(Err(e))
+            })
+            .filter_map(futures_util::future::ready),
+        );
+
+    Ok(Response::builder()
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::wrap_stream(lines))?)
+}
+
+// Server-sent events of the /tta rows of the accounts: the rows since `since` (or `start_date`)
+// first, then new rows once surely indexed, see `CHANGES_INDEXER_LAG_MINUTES`. Each `row` event
+// has its watermark as id, so a reconnecting client resumes after the last row it got through
+// `Last-Event-ID`.
+async fn tail_txns(
+    Query(changes): Query<TxnsChangesParams>,
+    headers: HeaderMap,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+) -> Result<Response, AppError> {
+    let since = headers
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .or(changes.since.as_deref());
+    let watermark: Option<Watermark> = since.map(str::parse).transpose()?;
+    let Some(start_date) = changes_start_date(watermark.as_ref(), changes.start_date.as_deref())?
+    else {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "Either since or start_date is required",
+        )
+            .into_response());
     };
 
-    let accounts = get_accounts_and_lockups(&a);
-    let mut f = vec![];
+    let state = TailState {
+        tta_service,
+        changes,
+        watermark,
+        start_date,
+        pending: VecDeque::new(),
+        caught_up: false,
+    };
+    let events = stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(row) = state.pending.pop_front() {
+                let watermark = Watermark::of(&row);
+                let event = Event::default()
+                    .event("row")
+                    .id(watermark.to_string())
+                    .json_data(&row);
+                state.watermark = Some(watermark);
+                return Some((event, state));
+            }
 
-    for (a, b) in accounts.clone() {
-        f.push(a.clone());
-        if let Some(b) = b {
-            f.push(b.clone())
-        };
+            if state.caught_up {
+                tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+            }
+            let start_date = match &state.watermark {
+                Some(watermark) => chrono::Utc.timestamp_nanos(watermark.block_timestamp as i64),
+                None => state.start_date,
+            };
+            match txns_rows_since(
+                &state.tta_service,
+                &state.changes,
+                state.watermark.as_ref(),
+                start_date,
+                changes_end_date(),
+            )
+            .await
+            {
+                Ok((_, mut rows)) => {
+                    rows.sort_by_cached_key(Watermark::of);
+                    state.pending.extend(rows);
+                    state.caught_up = true;
+                }
+                Err(e) => {
+                    error!(?e, "Error tailing transactions");
+                    state.caught_up = true;
+                    let event = Event::default().event("error").data(e.to_string());
+                    return Some((Ok(event), state));
+                }
+            }
+        }
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+// The /tta CSV and its hash.
+fn report_csv(rows: &[ReportRow], dialect: &CsvDialect) -> anyhow::Result<(Vec<u8>, String)> {
+    // Create a Writer with a Vec<u8> as the underlying writer
+    let mut wtr = dialect.writer();
+
+    // Write the headers
+    let headers = ReportRow::get_vec_headers();
+    wtr.write_record(&headers)?;
+
+    // Write each row
+    for row in rows {
+        let record: Vec<String> = dialect.format_record(&headers, row.to_vec());
+        wtr.write_record(&record)?;
     }
+    wtr.flush()?;
 
-    kitwallet.get_likely_tokens_for_accounts(f).await?;
+    // Fingerprint of the rows, so two downloads can be proven identical. It is also appended as
+    // a trailer row, which is not part of the hashed data.
+    let report_hash = format!("{:x}", Sha256::digest(wtr.get_ref()));
+    wtr.write_record(&report_hash_trailer(&report_hash))?;
 
-    let mut handles = vec![];
+    Ok((wtr.into_inner()?, report_hash))
+}
 
-    for (account, lockup_of) in accounts {
-        let ft_service = ft_service.clone();
-        let start_block_id = start_block_id;
-        let end_block_id = end_block_id;
-        let start_date = start_date;
-        let end_date = end_date;
-        let kitwallet = kitwallet.clone();
+fn zip_files(files: Vec<(String, Vec<u8>)>) -> anyhow::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    for (name, data) in files {
+        zip.start_file(name, options)?;
+        zip.write_all(&data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
 
-        let handle = spawn(async move {
-            info!(
-                "Getting balances for {}, dates: start {} end {}",
-                account, start_date, end_date
-            );
-            let mut rows: Vec<GetBalancesResultRow> = vec![];
+fn report_hash_trailer(report_hash: &str) -> Vec<String> {
+    let mut trailer = vec![String::new(); ReportRow::get_vec_headers().len()];
+    trailer[0] = "report_hash".to_string();
+    trailer[1] = report_hash.to_string();
+    trailer
+}
 
-            let likely_tokens = kitwallet.get_likely_tokens(account.clone()).await?;
-            let token_handles: Vec<_> = likely_tokens
-                .iter()
-                .map(|token| {
-                    let token = token.clone();
-                    let account = account.clone();
-                    let ft_service = ft_service.clone();
-                    let lockup_of = lockup_of.clone();
-                    async move {
-                        let metadata = match ft_service.assert_ft_metadata(&token).await {
-                            Ok(v) => v,
-                            Err(e) => {
-                                debug!("{}: {}", account, e);
-                                return Err(e);
-                            }
-                        };
-                        let start_balance = match ft_service
-                            .assert_ft_balance(&token, &account, start_block_id as u64)
-                            .await
-                        {
-                            Ok(v) => v,
-                            Err(e) => {
-                                debug!("{}: {}", account, e);
-                                0.0
-                            }
-                        };
-                        let end_balance = match ft_service
-                            .assert_ft_balance(&token, &account, end_block_id as u64)
-                            .await
-                        {
-                            Ok(v) => v,
-                            Err(e) => {
-                                debug!("{}: {}", account, e);
-                                0.0
-                            }
-                        };
-                        let record = GetBalancesResultRow {
-                            account: account.clone(),
-                            start_date: start_date.to_rfc3339(),
-                            end_date: end_date.to_rfc3339(),
-                            start_block_id,
-                            end_block_id,
-                            start_balance: Some(start_balance),
-                            end_balance: Some(end_balance),
-                            token_id: token.clone(),
-                            symbol: metadata.symbol,
-                            lockup_of,
-                        };
-                        Ok(record)
-                    }
-                })
-                .collect();
+fn txns_report_filename(report: &str, params: &TxnsReportParams) -> anyhow::Result<String> {
+    let dates = [
+        parse_date(&params.start_date)?,
+        parse_date(&params.end_date)?,
+    ];
+    Ok(report_filename(report, &params.accounts, &dates))
+}
+
+// Optional columns needing other services than the indexer.
+async fn enrich_txns_report(
+    tta_service: &TTA,
+    near_social: &NearSocial,
+    params: &TxnsReportParams,
+    rows: &mut [ReportRow],
+) {
+    if params.include_epoch_id.unwrap_or(false) {
+        tta_service.resolve_epoch_ids(rows).await;
+    }
+
+    if params.include_usd.unwrap_or(false) || params.fiat.is_some() {
+        tta_service.resolve_usd_amounts(rows).await;
+    }
+    if let Some(fiat) = &params.fiat {
+        tta_service.resolve_fiat_amounts(rows, fiat).await;
+    }
+
+    if params.resolve_names.unwrap_or(false) {
+        let counterparties: Vec<String> = rows
+            .iter()
+            .map(|row| row.counterparty().to_string())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let names = near_social.get_names(&counterparties).await;
+        for row in rows.iter_mut() {
+            row.counterparty_name = names.get(row.counterparty()).cloned();
+        }
+    }
+}
+
+// The report accounts and their lockups, transfers between them are not income.
+fn own_accounts(params: &TxnsReportParams) -> HashSet<String> {
+    get_accounts_and_lockups(&params.accounts)
+        .into_iter()
+        .map(|(account, _)| account.trim().to_string())
+        .collect()
+}
+
+fn report_accounts(params: &TxnsReportParams) -> HashSet<String> {
+    params
+        .accounts
+        .split(',')
+        .map(|s| String::from(s.trim()))
+        .filter(|account| !is_excluded_account(account) && !account.is_empty())
+        .collect()
+}
+
+// Jobs pass their progress, and are not subject to the report size check.
+async fn run_txns_report(
+    tta_service: &TTA,
+    params: &TxnsReportParams,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+    job_progress: Option<Arc<ReportProgress>>,
+) -> anyhow::Result<Vec<ReportRow>> {
+    let tta_service = &report_service(tta_service, params);
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    let tz = parse_tz(params.tz.as_deref())?;
+
+    let accounts = report_accounts(params);
+
+    let include_balances = params.include_balances.unwrap_or(false);
+
+    let metadata = report_metadata(tta_service, metadata_body).await?;
+
+    let progress = match job_progress {
+        Some(progress) => progress,
+        None => {
+            tta_service
+                .check_report_size(
+                    start_date.timestamp_nanos() as u128,
+                    end_date.timestamp_nanos() as u128,
+                    &accounts,
+                )
+                .await?;
+            Arc::new(ReportProgress::default())
+        }
+    };
+
+    let mut rows = tta_service
+        .get_txns_report(
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+            accounts,
+            include_balances,
+            metadata,
+            progress,
+        )
+        .await?;
+    if tz != Tz::UTC {
+        rows.iter_mut().for_each(|row| row.localize(tz));
+    }
+    disambiguate_symbols(&mut rows);
+    IncomeClassifier::new(own_accounts(params)).classify(&mut rows);
+
+    Ok(rows)
+}
+
+async fn spools_txns_report(tta_service: &TTA, params: &TxnsReportParams) -> anyhow::Result<bool> {
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    tta_service
+        .spools_report(
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+            &report_accounts(params),
+        )
+        .await
+}
+
+// `run_txns_report` then `report_csv`, for reports too large to be held in memory. The report is
+// generated one account and `SPOOL_CHUNK_DAYS` at a time, in the order of the full report, and
+// spooled to disk. Rows are paired across chunks and symbols disambiguated over the whole report,
+// as for an in-memory one.
+async fn spool_txns_report(
+    tta_service: &TTA,
+    near_social: &NearSocial,
+    params: &TxnsReportParams,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+    progress: Arc<ReportProgress>,
+    dialect: &CsvDialect,
+) -> anyhow::Result<(String, tokio::fs::File)> {
+    let tta_service = &report_service(tta_service, params);
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    let tz = parse_tz(params.tz.as_deref())?;
+    let include_balances = params.include_balances.unwrap_or(false);
+    let metadata = report_metadata(tta_service, metadata_body).await?;
+
+    let mut accounts: Vec<String> = report_accounts(params).into_iter().collect();
+    accounts.sort();
+
+    let mut classifier = IncomeClassifier::new(own_accounts(params));
+    let mut spool = ReportSpool::create(dialect).await?;
+    for account in accounts {
+        let mut held_back = vec![];
+        let mut chunk_start = start_date;
+        while chunk_start < end_date {
+            let chunk_end = (chunk_start + chrono::Duration::days(SPOOL_CHUNK_DAYS)).min(end_date);
+            let rows = tta_service
+                .get_txns_report(
+                    chunk_start.timestamp_nanos() as u128,
+                    chunk_end.timestamp_nanos() as u128,
+                    HashSet::from([account.clone()]),
+                    include_balances,
+                    metadata.clone(),
+                    progress.clone(),
+                )
+                .await?;
+            let next_chunk_start =
+                (chunk_end < end_date).then_some(chunk_end.timestamp_nanos() as u128);
+            let (mut rows, rest) = pair_chunk_rows(held_back, rows, next_chunk_start);
+            held_back = rest;
+            if tz != Tz::UTC {
+                rows.iter_mut().for_each(|row| row.localize(tz));
+            }
+            classifier.classify(&mut rows);
+            enrich_txns_report(tta_service, near_social, params, &mut rows).await;
+            spool.append(&rows).await?;
+            chunk_start = chunk_end;
+        }
+    }
+
+    spool.finish(report_hash_trailer).await
+}
+
+// Per account and token totals, for the same parameters as /tta.
+async fn get_txns_summary(
+    Query(params): Query<TxnsReportParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Response<Body>, AppError> {
+    let start_date = parse_date(&params.start_date)?.timestamp_nanos() as u128;
+    let end_date = parse_date(&params.end_date)?.timestamp_nanos() as u128;
+    let materialized = report_service(&tta_service, &params)
+        .materialized_summary(start_date, end_date, &report_accounts(&params))
+        .await
+        .unwrap_or_else(|e| {
+            warn!(?e, "Error reading the materialized account activity");
+            None
+        });
+    let summary = match materialized {
+        Some(summary) => summary,
+        None => summarize(&run_txns_report(&tta_service, &params, metadata_body, None).await?),
+    };
+    let r = results_to_response(summary, &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        txns_report_filename("tta-summary", &params)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct GainsParams {
+    // Where the cost basis starts, the start date by default. Tokens held before it have no
+    // known cost.
+    pub basis_start_date: Option<String>,
+}
+
+// Realized gains in the period and unrealized gains at the end date, per account and token, for
+// the same parameters as /tta.
+async fn get_txns_gains(
+    Query(params): Query<TxnsReportParams>,
+    Query(gains_params): Query<GainsParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Response<Body>, AppError> {
+    let filename = txns_report_filename("tta-gains", &params)?;
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    let basis_start_date = match &gains_params.basis_start_date {
+        Some(date) => parse_date(date)?.min(start_date),
+        None => start_date,
+    };
+
+    let report_params = TxnsReportParams {
+        start_date: basis_start_date.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        ..params
+    };
+    let rows = run_txns_report(&tta_service, &report_params, metadata_body, None).await?;
+    let gains = tta_service
+        .gains_report(
+            &rows,
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+        )
+        .await;
+    let r = results_to_response(gains, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
+}
+
+// Tokens received from contracts the accounts never called before, for the same parameters as
+// /tta.
+async fn get_txns_airdrops(
+    Query(params): Query<TxnsReportParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Response<Body>, AppError> {
+    let rows = run_txns_report(&tta_service, &params, metadata_body, None).await?;
+    let airdrops = tta_service.airdrop_report(&rows).await?;
+    let r = results_to_response(airdrops, &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        txns_report_filename("tta-airdrops", &params)?,
+    ))
+}
+
+// Inflow/outflow per calendar month and token, for the same parameters as /tta.
+async fn get_txns_monthly(
+    Query(params): Query<TxnsReportParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Response<Body>, AppError> {
+    let tz = parse_tz(params.tz.as_deref())?;
+    let rows = run_txns_report(&tta_service, &params, metadata_body, None).await?;
+    let r = results_to_response(monthly(&rows, tz), &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        txns_report_filename("tta-monthly", &params)?,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct CounterpartyParams {
+    // Keep only the N biggest counterparties per token.
+    pub top: Option<usize>,
+}
+
+// Flows grouped by counterparty and token, for the same parameters as /tta.
+async fn get_txns_by_counterparty(
+    Query(params): Query<TxnsReportParams>,
+    Query(counterparty_params): Query<CounterpartyParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _)): State<(TTA, NearSocial)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Response<Body>, AppError> {
+    let rows = run_txns_report(&tta_service, &params, metadata_body, None).await?;
+    let r = results_to_response(by_counterparty(&rows, counterparty_params.top), &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        txns_report_filename("tta-counterparties", &params)?,
+    ))
+}
+
+// One side of a diff: a report run for the same parameters as /tta, or the stored result of a
+// job, as `{"job_id": ...}`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum TxnsDiffSide {
+    Job { job_id: String },
+    Report(TxnsReportParams),
+}
+
+#[derive(Debug, Deserialize)]
+struct TxnsDiffBody {
+    pub left: TxnsDiffSide,
+    pub right: TxnsDiffSide,
+}
+
+// Rows present in only one of two runs, e.g. to check a decoder change didn't drop transactions.
+// Rows of job results are written as stored, in the dialect of the job, under the current
+// columns.
+async fn get_txns_diff(
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, _, jobs)): State<(TTA, NearSocial, JobStore)>,
+    Json(body): Json<TxnsDiffBody>,
+) -> Result<Response<Body>, AppError> {
+    let (left, right) = tokio::join!(
+        diff_records(&tta_service, &jobs, &body.left, &dialect),
+        diff_records(&tta_service, &jobs, &body.right, &dialect),
+    );
+    let (left, right) = (left?, right?);
+
+    // Counted, a row decoded twice on one side and once on the other is a difference too.
+    let key_counts = |records: &[((String, String, String), Vec<String>)]| {
+        let mut counts: HashMap<(String, String, String), usize> = HashMap::new();
+        for (key, _) in records {
+            *counts.entry(key.clone()).or_default() += 1;
+        }
+        counts
+    };
+    let (left_counts, right_counts) = (key_counts(&left), key_counts(&right));
+
+    let mut wtr = dialect.writer();
+    let mut headers = vec!["side".to_string()];
+    headers.extend(ReportRow::get_vec_headers());
+    wtr.write_record(&headers)?;
+
+    // The rows of a key past the count of the other side.
+    for (side, records, other_counts) in [
+        ("left", &left, &right_counts),
+        ("right", &right, &left_counts),
+    ] {
+        let mut seen: HashMap<&(String, String, String), usize> = HashMap::new();
+        for (key, fields) in records {
+            let seen = seen.entry(key).or_default();
+            *seen += 1;
+            if *seen <= other_counts.get(key).copied().unwrap_or(0) {
+                continue;
+            }
+            let mut record = vec![side.to_string()];
+            record.extend(fields.iter().cloned());
+            wtr.write_record(&record)?;
+        }
+    }
+
+    let response = Response::builder()
+        .header("Content-Type", "text/csv")
+        .body(Body::from(wtr.into_inner()?))?;
+
+    let filename = match &body.left {
+        TxnsDiffSide::Report(params) => txns_report_filename("tta-diff", params)?,
+        TxnsDiffSide::Job { job_id } => format!("tta-diff-{}.csv", job_id),
+    };
+    Ok(as_attachment(response, &download, filename))
+}
+
+// The rows of a diff side, by `ReportRow::diff_key`, with their CSV fields.
+async fn diff_records(
+    tta_service: &TTA,
+    jobs: &JobStore,
+    side: &TxnsDiffSide,
+    dialect: &CsvDialect,
+) -> anyhow::Result<Vec<((String, String, String), Vec<String>)>> {
+    let job_id = match side {
+        TxnsDiffSide::Report(params) => {
+            let rows = run_txns_report(tta_service, params, None, None).await?;
+            let headers = ReportRow::get_vec_headers();
+            return Ok(rows
+                .iter()
+                .map(|row| {
+                    (
+                        row.diff_key(),
+                        dialect.format_record(&headers, row.to_vec()),
+                    )
+                })
+                .collect());
+        }
+        TxnsDiffSide::Job { job_id } => job_id,
+    };
+
+    let (_, result) = jobs
+        .result(job_id)
+        .await?
+        .with_context(|| format!("Job {} not found or without a result", job_id))?;
+    let csv_data = result.strip_prefix(UTF8_BOM).unwrap_or(result.as_slice());
+    // The dialect of the job is not stored, the delimiter is the one of the headers.
+    let header_line = csv_data.split(|b| *b == b'\n').next().unwrap_or_default();
+    let delimiter = [b';', b'\t']
+        .into_iter()
+        .find(|delimiter| header_line.contains(delimiter))
+        .unwrap_or(b',');
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(csv_data);
+    let headers = rdr.headers()?.clone();
+    // Jobs stored by earlier releases may have other columns, they are mapped by name onto the
+    // current ones. Columns they miss are left empty.
+    let columns: Vec<Option<usize>> = ReportRow::get_vec_headers()
+        .iter()
+        .map(|name| headers.iter().position(|header| header == name))
+        .collect();
+
+    let mut records = vec![];
+    for record in rdr.records() {
+        let record = record?;
+        // The report hash trailer.
+        if record.get(0) == Some("report_hash") {
+            continue;
+        }
+        let key = ReportRow::record_diff_key(&headers, &record)
+            .with_context(|| format!("Job {} result is not a /tta report", job_id))?;
+        let fields = columns
+            .iter()
+            .map(|column| {
+                column
+                    .and_then(|i| record.get(i))
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect();
+        records.push((key, fields));
+    }
+
+    Ok(records)
+}
+
+// Runs the /tta report in the background, for reports too large or slow to wait for. The status
+// is polled from /tta/jobs/:id and the CSV downloaded from /tta/jobs/:id/result.
+async fn create_txns_job(
+    Query(params): Query<TxnsReportParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((tta_service, near_social, jobs)): State<(TTA, NearSocial, JobStore)>,
+    metadata_body: Option<Json<TxnsReportWithMetadata>>,
+) -> Result<Json<JobStatus>, AppError> {
+    let filename = match download.filename {
+        Some(filename) => filename,
+        None => txns_report_filename("tta", &params)?,
+    };
+    let (id, progress) = jobs.create(filename).await;
+
+    spawn({
+        let jobs = jobs.clone();
+        let id = id.clone();
+        async move {
+            let result: anyhow::Result<Vec<u8>> = async {
+                if spools_txns_report(&tta_service, &params).await? {
+                    let (_, mut file) = spool_txns_report(
+                        &tta_service,
+                        &near_social,
+                        &params,
+                        metadata_body,
+                        progress,
+                        &dialect,
+                    )
+                    .await?;
+                    let mut csv_data = vec![];
+                    file.read_to_end(&mut csv_data).await?;
+                    return Ok(csv_data);
+                }
+
+                let mut rows =
+                    run_txns_report(&tta_service, &params, metadata_body, Some(progress)).await?;
+                enrich_txns_report(&tta_service, &near_social, &params, &mut rows).await;
+                let (csv_data, _) = report_csv(&rows, &dialect)?;
+                Ok(csv_data)
+            }
+            .await;
+            if let Err(e) = &result {
+                error!(?e, %id, "Report job failed");
+            }
+            jobs.finish(&id, result).await;
+        }
+    });
+
+    let status = jobs.status(&id).await?.context("Job not found")?;
+    Ok(Json(status))
+}
+
+// Running and retained jobs, most recent first.
+async fn list_txns_jobs(
+    State((_, _, jobs)): State<(TTA, NearSocial, JobStore)>,
+) -> Result<Json<Vec<JobStatus>>, AppError> {
+    Ok(Json(jobs.list().await?))
+}
+
+async fn get_txns_job(
+    Path(id): Path<String>,
+    State((_, _, jobs)): State<(TTA, NearSocial, JobStore)>,
+) -> Result<Response, AppError> {
+    match jobs.status(&id).await? {
+        Some(status) => Ok(Json(status).into_response()),
+        None => Ok((StatusCode::NOT_FOUND, "Job not found").into_response()),
+    }
+}
+
+async fn get_txns_job_result(
+    Path(id): Path<String>,
+    State((_, _, jobs)): State<(TTA, NearSocial, JobStore)>,
+) -> Result<Response, AppError> {
+    let Some(status) = jobs.status(&id).await? else {
+        return Ok((StatusCode::NOT_FOUND, "Job not found").into_response());
+    };
+    let Some((filename, csv_data)) = jobs.result(&id).await? else {
+        return Ok((
+            StatusCode::CONFLICT,
+            format!("Job has no result, its state is {:?}", status.state),
+        )
+            .into_response());
+    };
+
+    let response = Response::builder()
+        .header("Content-Type", "text/csv")
+        .body(Body::from(csv_data.as_ref().clone()))?;
+    Ok(as_attachment(response, &DownloadParams::default(), filename).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct ClosestBlockIdParams {
+    pub date: String,
+}
+
+async fn get_closest_block_id(
+    Query(params): Query<ClosestBlockIdParams>,
+    State(sql_client): State<SqlClient>,
+) -> Result<Response<Body>, AppError> {
+    let date = parse_date(&params.date)?;
+    let nanos = date.timestamp_nanos() as u128;
+    let d = sql_client.get_closest_block_id(nanos).await?;
+    Ok(Response::new(Body::from(d.to_string())))
+}
+
+async fn get_pool_stats(State(sql_client): State<SqlClient>) -> Json<PoolStats> {
+    Json(sql_client.pool_stats())
+}
+
+#[derive(Debug, Deserialize)]
+struct ResizePoolParams {
+    pub max_connections: u32,
+}
+
+// Admin routes need `Authorization: Bearer <ADMIN_TOKEN>`.
+async fn require_admin_token<B>(
+    State(token): State<Arc<String>>,
+    request: axum::http::Request<B>,
+    next: middleware::Next<B>,
+) -> Response {
+    let authorized = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |given| {
+            // Compared by digest, so the time taken doesn't tell how much of the token matched.
+            Sha256::digest(given.as_bytes()) == Sha256::digest(token.as_bytes())
+        });
+    if !authorized {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn resize_pool(
+    State(sql_client): State<SqlClient>,
+    Json(params): Json<ResizePoolParams>,
+) -> Result<Response, AppError> {
+    if params.max_connections == 0 || params.max_connections > MAX_POOL_SIZE {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            format!("max_connections must be between 1 and {}", MAX_POOL_SIZE),
+        )
+            .into_response());
+    }
+
+    sql_client.resize_pool(params.max_connections).await?;
+    Ok(Json(sql_client.pool_stats()).into_response())
+}
+
+async fn list_watches(State(watchlist): State<Watchlist>) -> Result<Json<Vec<Watch>>, AppError> {
+    Ok(Json(watchlist.list().await?))
+}
+
+async fn get_watch(
+    Path(account): Path<String>,
+    State(watchlist): State<Watchlist>,
+) -> Result<Response, AppError> {
+    match watchlist.get(&account).await? {
+        Some(watch) => Ok(Json(watch).into_response()),
+        None => Ok((StatusCode::NOT_FOUND, "Account is not watched").into_response()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchParams {
+    pub min_amount: Option<f64>,
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    #[serde(default)]
+    pub webhook_urls: Vec<String>,
+}
+
+async fn save_watch(
+    Path(account): Path<String>,
+    State(watchlist): State<Watchlist>,
+    Json(params): Json<WatchParams>,
+) -> Result<Response, AppError> {
+    if account.parse::<AccountId>().is_err() {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid account id").into_response());
+    }
+    if params.min_amount.map_or(false, |amount| amount < 0.0) {
+        return Ok((StatusCode::BAD_REQUEST, "min_amount can't be negative").into_response());
+    }
+    if let Err(e) = watchlist.check_webhook_urls(&params.webhook_urls).await {
+        return Ok((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+    }
+
+    let watch = watchlist
+        .save(&Watch {
+            min_amount: params.min_amount,
+            tokens: params.tokens,
+            webhook_urls: params.webhook_urls,
+            ..Watch::new(account)
+        })
+        .await?;
+    Ok(Json(watch).into_response())
+}
+
+async fn remove_watch(
+    Path(account): Path<String>,
+    State(watchlist): State<Watchlist>,
+) -> Result<StatusCode, AppError> {
+    if watchlist.remove(&account).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AccountsPeriodParams {
+    pub start_date: String,
+    pub end_date: String,
+    pub accounts: String,
+}
+
+impl AccountsPeriodParams {
+    fn account_list(&self) -> Vec<String> {
+        self.accounts
+            .split(',')
+            .map(|account| account.trim().to_string())
+            .filter(|account| !is_excluded_account(account) && !account.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct GasReportRow {
+    pub account: String,
+    // Empty on the account's total.
+    pub receiver_account_id: Option<String>,
+    pub transactions: i64,
+    pub gas_burnt: u128,
+    pub near_burnt: f64,
+}
+
+// Gas and NEAR burnt by the transactions the accounts signed in the period, per receiver contract
+// followed by the account's total.
+async fn get_gas_report(
+    Query(params): Query<AccountsPeriodParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State(sql_client): State<SqlClient>,
+) -> Result<Response<Body>, AppError> {
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+
+    let usage = sql_client
+        .get_gas_usage(
+            &params.account_list(),
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+        )
+        .await?;
+
+    let mut by_account: BTreeMap<String, Vec<GasReportRow>> = BTreeMap::new();
+    for usage in usage {
+        by_account
+            .entry(usage.account_id.clone())
+            .or_default()
+            .push(GasReportRow {
+                account: usage.account_id,
+                receiver_account_id: Some(usage.receiver_account_id),
+                transactions: usage.transactions,
+                gas_burnt: usage.gas_burnt.to_u128().unwrap_or_default(),
+                near_burnt: safe_divide_u128(usage.tokens_burnt.to_u128().unwrap_or_default(), 24),
+            });
+    }
+    let mut rows = vec![];
+    for (account, receivers) in by_account {
+        let total = GasReportRow {
+            account,
+            receiver_account_id: None,
+            transactions: receivers.iter().map(|row| row.transactions).sum(),
+            gas_burnt: receivers.iter().map(|row| row.gas_burnt).sum(),
+            near_burnt: receivers.iter().map(|row| row.near_burnt).sum(),
+        };
+        rows.extend(receivers);
+        rows.push(total);
+    }
+
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        report_filename("gas", &params.accounts, &[start_date, end_date]),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct LifecycleRow {
+    pub date: String,
+    pub time: String,
+    pub account_id: String,
+    // CREATE_ACCOUNT or DELETE_ACCOUNT.
+    pub event: String,
+    // Creator, or who deleted the account.
+    pub predecessor_account_id: String,
+    pub beneficiary_id: Option<String>,
+    // Deposit attached to the creation, or balance sent to the beneficiary.
+    pub amount: Option<f64>,
+    pub block_height: u128,
+    pub block_timestamp: u128,
+    pub transaction_hash: String,
+    pub receipt_id: String,
+}
+
+// Creations and deletions of the accounts, and deletions whose balance went to them, which the
+// transfers of /tta do not show.
+async fn get_account_lifecycle(
+    Query(params): Query<AccountsPeriodParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State(sql_client): State<SqlClient>,
+) -> Result<Response<Body>, AppError> {
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+
+    let rows: Vec<LifecycleRow> = sql_client
+        .get_account_lifecycle(
+            &params.account_list(),
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+        )
+        .await?
+        .into_iter()
+        .map(|event| {
+            let block_timestamp = event.block_timestamp.to_u128().unwrap_or_default();
+            let datetime = chrono::Utc.timestamp_nanos(block_timestamp as i64);
+            LifecycleRow {
+                date: datetime.format(DATE_FORMAT).to_string(),
+                time: datetime.format(TIME_FORMAT).to_string(),
+                account_id: event.account_id,
+                event: event.action_kind,
+                predecessor_account_id: event.predecessor_account_id,
+                beneficiary_id: event.beneficiary_id,
+                amount: event
+                    .amount
+                    .and_then(|amount| amount.to_u128())
+                    .map(|amount| safe_divide_u128(amount, 24)),
+                block_height: event.block_height.to_u128().unwrap_or_default(),
+                block_timestamp,
+                transaction_hash: event.transaction_hash,
+                receipt_id: event.receipt_id,
+            }
+        })
+        .collect();
+
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        report_filename("lifecycle", &params.accounts, &[start_date, end_date]),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+struct DeploymentRow {
+    pub date: String,
+    pub time: String,
+    pub account_id: String,
+    pub code_sha256: Option<String>,
+    // Account the deployment receipt came from, and the signer of its transaction.
+    pub deployer: String,
+    pub signer_account_id: String,
+    pub block_height: u128,
+    pub block_timestamp: u128,
+    pub transaction_hash: String,
+    pub receipt_id: String,
+}
+
+// Contract code deployed to the accounts, e.g. upgrades of multisigs, lockups and DAOs.
+async fn get_contract_deployments(
+    Query(params): Query<AccountsPeriodParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State(sql_client): State<SqlClient>,
+) -> Result<Response<Body>, AppError> {
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+
+    let rows: Vec<DeploymentRow> = sql_client
+        .get_contract_deployments(
+            &params.account_list(),
+            start_date.timestamp_nanos() as u128,
+            end_date.timestamp_nanos() as u128,
+        )
+        .await?
+        .into_iter()
+        .map(|deployment| {
+            let block_timestamp = deployment.block_timestamp.to_u128().unwrap_or_default();
+            let datetime = chrono::Utc.timestamp_nanos(block_timestamp as i64);
+            DeploymentRow {
+                date: datetime.format(DATE_FORMAT).to_string(),
+                time: datetime.format(TIME_FORMAT).to_string(),
+                account_id: deployment.account_id,
+                code_sha256: deployment.code_sha256,
+                deployer: deployment.predecessor_account_id,
+                signer_account_id: deployment.signer_account_id,
+                block_height: deployment.block_height.to_u128().unwrap_or_default(),
+                block_timestamp,
+                transaction_hash: deployment.transaction_hash,
+                receipt_id: deployment.receipt_id,
+            }
+        })
+        .collect();
+
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(
+        r,
+        &download,
+        report_filename("deployments", &params.accounts, &[start_date, end_date]),
+    ))
+}
+
+async fn list_balance_alerts(
+    State((sql_client, _)): State<(SqlClient, UrlPolicy)>,
+) -> Result<Json<Vec<BalanceAlert>>, AppError> {
+    Ok(Json(sql_client.list_balance_alerts().await?))
+}
+
+#[derive(Debug, Deserialize)]
+struct BalanceAlertParams {
+    pub account_id: String,
+    // `near`, or the contract of a fungible token.
+    #[serde(default = "default_alert_token")]
+    pub token_id: String,
+    pub threshold: f64,
+    #[serde(default)]
+    pub webhook_urls: Vec<String>,
+}
+
+fn default_alert_token() -> String {
+    "near".to_string()
+}
+
+async fn create_balance_alert(
+    State((sql_client, webhook_url_policy)): State<(SqlClient, UrlPolicy)>,
+    Json(params): Json<BalanceAlertParams>,
+) -> Result<Response, AppError> {
+    if params.account_id.parse::<AccountId>().is_err() {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid account id").into_response());
+    }
+    if params.token_id != "near" && params.token_id.parse::<AccountId>().is_err() {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid token id").into_response());
+    }
+    for url in &params.webhook_urls {
+        if let Err(e) = webhook_url_policy.check(url).await {
+            return Ok((StatusCode::BAD_REQUEST, e.to_string()).into_response());
+        }
+    }
+
+    let alert = sql_client
+        .create_balance_alert(
+            &params.account_id,
+            &params.token_id,
+            params.threshold,
+            &params.webhook_urls,
+        )
+        .await?;
+    Ok((StatusCode::CREATED, Json(alert)).into_response())
+}
+
+async fn delete_balance_alert(
+    Path(id): Path<i64>,
+    State(sql_client): State<SqlClient>,
+) -> Result<StatusCode, AppError> {
+    if sql_client.delete_balance_alert(id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn list_metadata_overrides(
+    State(ft_service): State<FtService>,
+) -> Json<Vec<FtMetadataOverride>> {
+    Json(ft_service.metadata_overrides().await)
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataOverrideParams {
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    #[serde(default)]
+    pub zero_balance: bool,
+}
+
+async fn set_metadata_override(
+    Path(token_id): Path<String>,
+    State(ft_service): State<FtService>,
+    Json(params): Json<MetadataOverrideParams>,
+) -> Result<Response, AppError> {
+    if token_id.parse::<AccountId>().is_err() {
+        return Ok((StatusCode::BAD_REQUEST, "Invalid token id").into_response());
+    }
+    if params.symbol.is_none() && params.decimals.is_none() && !params.zero_balance {
+        return Ok((
+            StatusCode::BAD_REQUEST,
+            "Set symbol, decimals or zero_balance",
+        )
+            .into_response());
+    }
+
+    let metadata_override = FtMetadataOverride {
+        token_id,
+        symbol: params.symbol,
+        decimals: params.decimals,
+        zero_balance: params.zero_balance,
+    };
+    ft_service
+        .set_metadata_override(metadata_override.clone())
+        .await?;
+    Ok(Json(metadata_override).into_response())
+}
+
+async fn remove_metadata_override(
+    Path(token_id): Path<String>,
+    State(ft_service): State<FtService>,
+) -> Result<StatusCode, AppError> {
+    if ft_service.remove_metadata_override(&token_id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EvictLikelyTokensParams {
+    pub accounts: String,
+}
+
+async fn evict_likely_tokens(
+    Query(params): Query<EvictLikelyTokensParams>,
+    State(kitwallet): State<KitWallet>,
+) -> StatusCode {
+    let accounts: Vec<String> = params
+        .accounts
+        .split(',')
+        .map(|s| String::from(s.trim()))
+        .collect();
+    kitwallet.evict(&accounts).await;
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Debug, Deserialize)]
+struct GetBalances {
+    pub start_date: String,
+    pub end_date: String,
+    // Read the balances at these blocks instead of the closest ones to the dates, which then only
+    // label the report.
+    pub start_block: Option<u128>,
+    pub end_block: Option<u128>,
+    pub accounts: Option<String>,
+    // Likely spam tokens are skipped unless asked for, and then flagged in a `spam` column.
+    pub include_spam: Option<bool>,
+    // Skips the rows whose start and end balances are both zero or absent.
+    pub nonzero_only: Option<bool>,
+    // Also reports the lockups of the accounts, true by default.
+    pub include_lockups: Option<bool>,
+    // Fails the request when a balance can't be read, instead of reporting it as zero or absent.
+    pub strict: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GetBalancesBody {
+    pub accounts: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct GetBalancesResultRow {
+    pub account: String,
+    pub start_date: String,
+    pub end_date: String,
+    pub start_block_id: u128,
+    pub end_block_id: u128,
+    pub token_id: String,
+    pub symbol: String,
+    pub lockup_of: Option<String>,
+    pub start_balance: Option<f64>,
+    pub end_balance: Option<f64>,
+    // End minus start, an absent balance counting as zero.
+    pub delta: Option<f64>,
+    // NEAR locked by validator stake, not part of the balance, NEAR rows only.
+    pub start_locked: Option<f64>,
+    pub end_locked: Option<f64>,
+    // Bytes of state and the NEAR locked for them, NEAR rows only.
+    pub start_storage_usage: Option<u64>,
+    pub end_storage_usage: Option<u64>,
+    pub start_storage_locked_near: Option<f64>,
+    pub end_storage_locked_near: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spam: Option<bool>,
+    // Why a balance could not be read, set balances are then zero or absent.
+    pub error: Option<String>,
+}
+
+fn balance_delta(start_balance: Option<f64>, end_balance: Option<f64>) -> Option<f64> {
+    if start_balance.is_none() && end_balance.is_none() {
+        return None;
+    }
+    Some(end_balance.unwrap_or(0.0) - start_balance.unwrap_or(0.0))
+}
+
+impl GetBalancesResultRow {
+    fn is_zero(&self) -> bool {
+        self.start_balance.unwrap_or(0.0) == 0.0 && self.end_balance.unwrap_or(0.0) == 0.0
+    }
+}
+
+// The accounts and their derived lockups, see `get_accounts_and_lockups`, without the lockups
+// that were never created. Lockups are left out entirely unless `include_lockups`.
+async fn balance_accounts(
+    sql_client: &SqlClient,
+    accounts: &str,
+    include_lockups: bool,
+) -> HashSet<(String, Option<String>)> {
+    let accounts = get_accounts_and_lockups(accounts);
+    let lockups: Vec<String> = accounts
+        .iter()
+        .filter(|(_, lockup_of)| lockup_of.is_some())
+        .map(|(account, _)| account.clone())
+        .collect();
+    if lockups.is_empty() {
+        return accounts;
+    }
+    let existing = if include_lockups {
+        match sql_client.get_existing_accounts(&lockups).await {
+            Ok(existing) => existing,
+            Err(e) => {
+                warn!("Failed to check lockups: {:?}", e);
+                return accounts;
+            }
+        }
+    } else {
+        HashSet::new()
+    };
+
+    accounts
+        .into_iter()
+        .filter(|(account, lockup_of)| lockup_of.is_none() || existing.contains(account))
+        .collect()
+}
+
+async fn get_balances(
+    Query(params): Query<GetBalances>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((sql_client, ft_service, kitwallet)): State<(SqlClient, FtService, KitWallet)>,
+    body: Option<Json<GetBalancesBody>>,
+) -> Result<Response<Body>, AppError> {
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    let start_nanos = start_date.timestamp_nanos() as u128;
+    let end_nanos = end_date.timestamp_nanos() as u128;
+
+    let start_block_id = match params.start_block {
+        Some(block_id) => block_id,
+        None => sql_client.get_closest_block_id(start_nanos).await?,
+    };
+    let end_block_id = match params.end_block {
+        Some(block_id) => block_id,
+        None => sql_client.get_closest_block_id(end_nanos).await?,
+    };
+    let a = match body {
+        Some(body) => body.accounts.join(","),
+        None => params.accounts.unwrap_or("".to_string()),
+    };
+
+    let filename = report_filename("balances", &a, &[start_date, end_date]);
+    let include_spam = params.include_spam.unwrap_or(false);
+    let nonzero_only = params.nonzero_only.unwrap_or(false);
+    let strict = params.strict.unwrap_or(false);
+    let accounts = balance_accounts(&sql_client, &a, params.include_lockups.unwrap_or(true)).await;
+    let mut f = vec![];
+
+    for (a, b) in accounts.clone() {
+        f.push(a.clone());
+        if let Some(b) = b {
+            f.push(b.clone())
+        };
+    }
+
+    kitwallet.get_likely_tokens_for_accounts(f).await?;
 
-            let token_results: Vec<_> = join_all(token_handles).await;
-            for result in token_results {
-                match result {
-                    Ok(record) => rows.push(record),
+    let mut handles = vec![];
+
+    for (account, lockup_of) in accounts {
+        let ft_service = ft_service.clone();
+        let start_block_id = start_block_id;
+        let end_block_id = end_block_id;
+        let start_date = start_date;
+        let end_date = end_date;
+        let kitwallet = kitwallet.clone();
+
+        let handle = spawn(async move {
+            info!(
+                "Getting balances for {}, dates: start {} end {}",
+                account, start_date, end_date
+            );
+            let mut rows: Vec<GetBalancesResultRow> = vec![];
+
+            let likely_tokens = kitwallet.get_likely_tokens(account.clone()).await?;
+            let (start_balances, end_balances) = tokio::join!(
+                ft_service.assert_ft_balances(&likely_tokens, &account, start_block_id as u64),
+                ft_service.assert_ft_balances(&likely_tokens, &account, end_block_id as u64),
+            );
+
+            for ((token, start_balance), (_, end_balance)) in
+                start_balances.into_iter().zip(end_balances)
+            {
+                let metadata = match ft_service.assert_ft_metadata(&token).await {
+                    Ok(v) => v,
+                    Err(e) if strict => anyhow::bail!("{}: {} metadata: {}", account, token, e),
                     Err(e) => {
-                        debug!("Token fetch error: {:?}", e);
+                        debug!("Token fetch error: {}: {:?}", account, e);
+                        continue;
                     }
+                };
+                let spam = ft_service.is_likely_spam(&token).await;
+                if spam && !include_spam {
+                    continue;
                 }
+                let mut errors = vec![];
+                let start_balance = match start_balance {
+                    Ok(v) => v,
+                    Err(e) if strict => {
+                        anyhow::bail!("{}: {} at {}: {}", account, token, start_block_id, e)
+                    }
+                    Err(e) => {
+                        debug!("{}: {}", account, e);
+                        errors.push(format!("start: {}", e));
+                        0.0
+                    }
+                };
+                let end_balance = match end_balance {
+                    Ok(v) => v,
+                    Err(e) if strict => {
+                        anyhow::bail!("{}: {} at {}: {}", account, token, end_block_id, e)
+                    }
+                    Err(e) => {
+                        debug!("{}: {}", account, e);
+                        errors.push(format!("end: {}", e));
+                        0.0
+                    }
+                };
+                rows.push(GetBalancesResultRow {
+                    account: account.clone(),
+                    start_date: start_date.to_rfc3339(),
+                    end_date: end_date.to_rfc3339(),
+                    start_block_id,
+                    end_block_id,
+                    start_balance: Some(start_balance),
+                    end_balance: Some(end_balance),
+                    delta: Some(end_balance - start_balance),
+                    start_locked: None,
+                    end_locked: None,
+                    start_storage_usage: None,
+                    end_storage_usage: None,
+                    start_storage_locked_near: None,
+                    end_storage_locked_near: None,
+                    token_id: token.clone(),
+                    symbol: metadata.symbol,
+                    lockup_of: lockup_of.clone(),
+                    spam: include_spam.then_some(spam),
+                    error: (!errors.is_empty()).then(|| errors.join("; ")),
+                });
             }
 
+            // RPC errors are reported, unlike unknown accounts which have no balance.
+            let mut errors = vec![];
             let start_near_balance = match ft_service
                 .get_near_balance(&account, start_block_id as u64)
                 .await
             {
                 Ok(v) => v,
+                Err(e) if strict => return Err(e),
                 Err(e) => {
                     debug!("{}: {}", account, e);
+                    errors.push(format!("start: {}", e));
                     None
                 }
             };
@@ -397,23 +2296,36 @@ async fn get_balances(
                 .await
             {
                 Ok(v) => v,
+                Err(e) if strict => return Err(e),
                 Err(e) => {
                     debug!("{}: {}", account, e);
+                    errors.push(format!("end: {}", e));
                     None
                 }
             };
 
+            let start_balance = start_near_balance.map(|start| start.amount);
+            let end_balance = end_near_balance.map(|end| end.amount);
             let record = GetBalancesResultRow {
                 account: account.clone(),
                 start_date: start_date.to_rfc3339(),
                 end_date: end_date.to_rfc3339(),
                 start_block_id,
                 end_block_id,
-                start_balance: start_near_balance.map(|start| start.0),
-                end_balance: end_near_balance.map(|end: (f64, f64)| end.0),
+                start_balance,
+                end_balance,
+                delta: balance_delta(start_balance, end_balance),
+                start_locked: start_near_balance.map(|start| start.locked),
+                end_locked: end_near_balance.map(|end| end.locked),
+                start_storage_usage: start_near_balance.map(|start| start.storage_usage),
+                end_storage_usage: end_near_balance.map(|end| end.storage_usage),
+                start_storage_locked_near: start_near_balance.map(|start| start.storage_locked()),
+                end_storage_locked_near: end_near_balance.map(|end| end.storage_locked()),
                 token_id: "NEAR".to_string(),
                 symbol: "NEAR".to_string(),
                 lockup_of,
+                spam: include_spam.then_some(false),
+                error: (!errors.is_empty()).then(|| errors.join("; ")),
             };
             rows.push(record);
 
@@ -423,20 +2335,27 @@ async fn get_balances(
     }
 
     let mut rows = vec![];
-    join_all(handles).await.iter().for_each(|row| match row {
-        Ok(result) => match result {
-            Ok(res) => rows.extend(res.iter().cloned()),
+    for row in join_all(handles).await {
+        match row {
+            Ok(result) => match result {
+                Ok(res) => rows.extend(res),
+                Err(e) if strict => return Err(e.into()),
+                Err(e) => {
+                    println!("{:?}", e)
+                }
+            },
+            Err(e) if strict => return Err(e.into()),
             Err(e) => {
-                println!("{:?}", e)
+                warn!("{:?}", e)
             }
-        },
-        Err(e) => {
-            warn!("{:?}", e)
         }
-    });
+    }
+    if nonzero_only {
+        rows.retain(|row| !row.is_zero());
+    }
 
-    let r = results_to_response(rows)?;
-    Ok(r)
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
 }
 
 #[derive(Debug, Deserialize)]
@@ -444,6 +2363,9 @@ struct GetBalancesFull {
     pub start_date: String,
     pub end_date: String,
     pub accounts: Vec<String>,
+    pub include_spam: Option<bool>,
+    // Also reports the lockups of the accounts, true by default.
+    pub include_lockups: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -455,21 +2377,35 @@ struct GetBalancesFullResultRow {
     pub symbol: String,
     pub lockup_of: Option<String>,
     pub balance: Option<f64>,
+    // NEAR locked by validator stake, not part of the balance, NEAR rows only.
+    pub locked: Option<f64>,
+    // Bytes of state and the NEAR locked for them, NEAR rows only.
+    pub storage_usage: Option<u64>,
+    pub storage_locked_near: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spam: Option<bool>,
+    // Why `balance` could not be read, unknown for snapshotted balances.
+    pub error: Option<String>,
 }
 
 #[tracing::instrument(skip(sql_client, ft_service, kitwallet))]
 async fn get_balances_full(
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
     State((sql_client, ft_service, kitwallet)): State<(SqlClient, FtService, KitWallet)>,
     Json(params): Json<GetBalancesFull>,
 ) -> Result<Response<Body>, AppError> {
-    let start_date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.start_date)
-        .unwrap()
-        .into();
-    let end_date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.end_date)
-        .unwrap()
-        .into();
+    let start_date = parse_date(&params.start_date)?;
+    let end_date = parse_date(&params.end_date)?;
+    let include_spam = params.include_spam.unwrap_or(false);
     let accounts = params.accounts.join(",");
-    let accounts = get_accounts_and_lockups(accounts.as_str());
+    let filename = report_filename("balances-full", &accounts, &[start_date, end_date]);
+    let accounts = balance_accounts(
+        &sql_client,
+        &accounts,
+        params.include_lockups.unwrap_or(true),
+    )
+    .await;
     let mut f = vec![];
 
     for (a, b) in &accounts {
@@ -501,6 +2437,8 @@ async fn get_balances_full(
                 .collect(),
         )
         .await?;
+    let mut snapshots = balance_snapshots(&sql_client, &accounts, &all_dates).await;
+    let mut rows = vec![];
     let mut handles = vec![];
 
     for (idx, date) in all_dates.iter().enumerate() {
@@ -509,6 +2447,31 @@ async fn get_balances_full(
         let block_id = block_ids[idx];
 
         for (account, lockup_of) in &accounts {
+            if let Some(snapshot) = snapshots.remove(&(account.clone(), date.date_naive())) {
+                rows.extend(
+                    snapshot
+                        .into_iter()
+                        .filter(|balance| include_spam || !balance.spam)
+                        .map(|balance| GetBalancesFullResultRow {
+                            account: account.clone(),
+                            date: date.to_rfc3339(),
+                            token_id: balance.token_id,
+                            symbol: balance.symbol,
+                            lockup_of: lockup_of.clone(),
+                            block_id: balance.block_id as u128,
+                            balance: balance.balance,
+                            locked: balance.locked,
+                            storage_usage: balance.storage_usage.map(|v| v as u64),
+                            storage_locked_near: balance
+                                .storage_usage
+                                .map(|v| storage_locked(v as u64)),
+                            spam: include_spam.then_some(balance.spam),
+                            error: None,
+                        }),
+                );
+                continue;
+            }
+
             let ft_service = ft_service.clone();
             let likely_tokens = likely_tokens.get(account).unwrap().clone();
             let account = account.clone();
@@ -518,85 +2481,33 @@ async fn get_balances_full(
             tokio::time::sleep(std::time::Duration::from_millis(1)).await;
 
             let handle = spawn(async move {
-                let mut rows: Vec<GetBalancesFullResultRow> = vec![];
-
-                let token_handles: Vec<_> = likely_tokens
-                    .iter()
-                    .map(|token| {
-                        let token = token.clone();
-                        let account = account.clone();
-                        let ft_service = ft_service.clone();
-                        let lockup_of = lockup_of.clone();
-                        async move {
-                            let metadata = match ft_service.assert_ft_metadata(&token).await {
-                                Ok(v) => v,
-                                Err(e) => {
-                                    debug!("{}: {}", account, e);
-                                    return Err(e);
-                                }
-                            };
-                            let balance = match ft_service
-                                .assert_ft_balance(&token, &account, block_id as u64)
-                                .await
-                            {
-                                Ok(v) => Some(v),
-                                Err(e) => {
-                                    debug!("{}: {}", account, e);
-                                    None
-                                }
-                            };
-
-                            let record = GetBalancesFullResultRow {
-                                account: account.clone(),
-                                date: date.to_rfc3339(),
-                                token_id: token.clone(),
-                                symbol: metadata.symbol,
-                                lockup_of: lockup_of.clone(),
-                                block_id,
-                                balance,
-                            };
-                            Ok(record)
-                        }
+                let rows: Vec<GetBalancesFullResultRow> = ft_service
+                    .account_balances(&likely_tokens, &account, block_id as u64)
+                    .await
+                    .into_iter()
+                    .filter(|balance| include_spam || !balance.spam)
+                    .map(|balance| GetBalancesFullResultRow {
+                        account: account.clone(),
+                        date: date.to_rfc3339(),
+                        token_id: balance.token_id,
+                        symbol: balance.symbol,
+                        lockup_of: lockup_of.clone(),
+                        block_id,
+                        balance: balance.balance,
+                        locked: balance.locked,
+                        storage_usage: balance.storage_usage,
+                        storage_locked_near: balance.storage_usage.map(storage_locked),
+                        spam: include_spam.then_some(balance.spam),
+                        error: balance.error,
                     })
                     .collect();
 
-                let token_results: Vec<_> = join_all(token_handles).await;
-                for result in token_results {
-                    match result {
-                        Ok(record) => rows.push(record),
-                        Err(e) => {
-                            debug!("Token fetch error: {:?}", e);
-                        }
-                    }
-                }
-
-                let near_balance =
-                    match ft_service.get_near_balance(&account, block_id as u64).await {
-                        Ok(v) => v.map(|v| v.0),
-                        Err(e) => {
-                            error!("{}: {}", account, e);
-                            None
-                        }
-                    };
-
-                let record = GetBalancesFullResultRow {
-                    account: account.clone(),
-                    date: date.to_rfc3339(),
-                    block_id,
-                    balance: near_balance,
-                    token_id: "NEAR".to_string(),
-                    symbol: "NEAR".to_string(),
-                    lockup_of: lockup_of.clone(),
-                };
-                rows.push(record);
-
                 anyhow::Ok(rows)
             });
             handles.push(handle);
         }
     }
 
-    let mut rows = vec![];
     join_all(handles).await.iter().for_each(|row| match row {
         Ok(result) => match result {
             Ok(res) => rows.extend(res.iter().cloned()),
@@ -609,14 +2520,237 @@ async fn get_balances_full(
         }
     });
 
-    let r = results_to_response(rows)?;
-    Ok(r)
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
+}
+
+// Snapshotted balances by account and day, when every date is a UTC midnight. Accounts and days
+// that are not snapshotted are read from the archival RPC.
+async fn balance_snapshots(
+    sql_client: &SqlClient,
+    accounts: &HashSet<(String, Option<String>)>,
+    dates: &[DateTime<chrono::Utc>],
+) -> HashMap<(String, NaiveDate), Vec<BalanceSnapshot>> {
+    let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+    let (Some(first), Some(last)) = (dates.first(), dates.last()) else {
+        return HashMap::new();
+    };
+    if dates.iter().any(|date| date.time() != midnight) {
+        return HashMap::new();
+    }
+
+    let accounts: Vec<String> = accounts
+        .iter()
+        .map(|(account, _)| account.clone())
+        .collect();
+    let snapshots = sql_client
+        .get_balance_snapshots(&accounts, first.date_naive(), last.date_naive())
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to read balance snapshots: {:?}", e);
+            vec![]
+        });
+
+    let mut by_day: HashMap<(String, NaiveDate), Vec<BalanceSnapshot>> = HashMap::new();
+    for snapshot in snapshots {
+        by_day
+            .entry((snapshot.account_id.clone(), snapshot.day))
+            .or_default()
+            .push(snapshot);
+    }
+    by_day
+}
+
+#[derive(Debug, Deserialize)]
+struct OperatorEarningsParams {
+    pub start_date: String,
+    pub end_date: String,
+    pub interval: Option<Interval>,
+    // Staking pools, their owner is the operator.
+    pub pools: String,
+}
+
+#[derive(Debug, Serialize)]
+struct OperatorEarningsRow {
+    pub staking_pool: String,
+    pub owner_id: String,
+    pub start_date: String,
+    pub end_date: String,
+    pub start_block_id: u128,
+    pub end_block_id: u128,
+    pub reward_fee: f64,
+    // Growth of the owner's balance on the pool, net of its deposits and withdrawals, split
+    // between the pool fee and the rewards of the owner's own stake.
+    pub owner_earnings: f64,
+    pub fee_earnings: f64,
+    pub delegation_earnings: f64,
+}
+
+// Owner's balance, principal and share of the stake of a pool at a sample.
+struct OperatorSample {
+    owner_id: String,
+    reward_fee: f64,
+    owner_balance: f64,
+    principal: f64,
+    owner_share: f64,
+}
+
+async fn operator_sample(
+    sql_client: &SqlClient,
+    ft_service: &FtService,
+    pool_id: &str,
+    date: DateTime<chrono::Utc>,
+    block_id: u128,
+) -> anyhow::Result<OperatorSample> {
+    let (owner_id, reward_fee, total_staked) = ft_service
+        .get_pool_details(pool_id, block_id as u64)
+        .await?;
+    let (staking_details, principal) = tokio::join!(
+        ft_service.get_staking_details(pool_id, &owner_id, block_id as u64),
+        sql_client.get_staking_principal(&owner_id, pool_id, date.timestamp_nanos() as u128),
+    );
+    let (staked, unstaked, _) = staking_details?;
+    let total_staked = safe_divide_u128(total_staked, 24);
+
+    Ok(OperatorSample {
+        reward_fee,
+        owner_balance: staked + unstaked,
+        principal: principal? as f64 / 1e24,
+        owner_share: if total_staked > 0.0 {
+            staked / total_staked
+        } else {
+            0.0
+        },
+        owner_id,
+    })
+}
+
+// Earnings of the operators of staking pools between consecutive samples. The pool rewards R of an
+// interval are not read directly: the owner earns the fee f * R plus its share s of the rest, so
+// its earnings G give R = G / (f + s * (1 - f)), with the fee and share at the interval start.
+async fn get_operator_earnings(
+    Query(params): Query<OperatorEarningsParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((sql_client, ft_service)): State<(SqlClient, FtService)>,
+) -> Result<Response<Body>, AppError> {
+    let dates = get_sample_dates(
+        None,
+        Some(&params.start_date),
+        Some(&params.end_date),
+        params.interval,
+    )?;
+    let filename = report_filename("operator-earnings", &params.pools, &dates);
+    let block_ids = sql_client
+        .get_closest_block_ids(dates.iter().map(|d| d.timestamp_nanos() as u128).collect())
+        .await?;
+    let samples: Vec<(DateTime<chrono::Utc>, u128)> = dates.into_iter().zip(block_ids).collect();
+
+    let mut rows = vec![];
+    for pool_id in params.pools.split(',').map(str::trim) {
+        let pool_samples = join_all(samples.iter().map(|(date, block_id)| {
+            operator_sample(&sql_client, &ft_service, pool_id, *date, *block_id)
+        }))
+        .await;
+
+        for (window, results) in samples.windows(2).zip(pool_samples.windows(2)) {
+            let [(start_date, start_block_id), (end_date, end_block_id)] = window else {
+                continue;
+            };
+            let (start, end) = match results {
+                [Ok(start), Ok(end)] => (start, end),
+                _ => {
+                    warn!("{}: skipping {} to {}", pool_id, start_date, end_date);
+                    continue;
+                }
+            };
+            let owner_earnings =
+                (end.owner_balance - end.principal) - (start.owner_balance - start.principal);
+            let weight = start.reward_fee + start.owner_share * (1.0 - start.reward_fee);
+            let fee_earnings = if weight > 0.0 {
+                owner_earnings * start.reward_fee / weight
+            } else {
+                0.0
+            };
+            rows.push(OperatorEarningsRow {
+                staking_pool: pool_id.to_string(),
+                owner_id: end.owner_id.clone(),
+                start_date: start_date.to_rfc3339(),
+                end_date: end_date.to_rfc3339(),
+                start_block_id: *start_block_id,
+                end_block_id: *end_block_id,
+                reward_fee: start.reward_fee,
+                owner_earnings,
+                fee_earnings,
+                delegation_earnings: owner_earnings - fee_earnings,
+            });
+        }
+    }
+
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
 }
 
 #[derive(Debug, Deserialize)]
-struct DateAndAccounts {
+struct DelegatorsParams {
+    pub pool: String,
     pub date: String,
+}
+
+#[derive(Debug, Serialize)]
+struct DelegatorRow {
+    pub staking_pool: String,
+    pub date: String,
+    pub block_id: u128,
+    pub account_id: String,
+    pub staked_balance: f64,
+    pub unstaked_balance: f64,
+    pub can_withdraw: bool,
+}
+
+// Delegators of a staking pool at a date, biggest stake first.
+async fn get_pool_delegators(
+    Query(params): Query<DelegatorsParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((sql_client, ft_service)): State<(SqlClient, FtService)>,
+) -> Result<Response<Body>, AppError> {
+    let date = parse_date(&params.date)?;
+    let filename = report_filename("delegators", &params.pool, &[date]);
+    let block_id = sql_client
+        .get_closest_block_id(date.timestamp_nanos() as u128)
+        .await?;
+
+    let mut rows: Vec<DelegatorRow> = ft_service
+        .get_pool_accounts(&params.pool, block_id as u64)
+        .await?
+        .into_iter()
+        .map(|account| DelegatorRow {
+            staking_pool: params.pool.clone(),
+            date: date.to_rfc3339(),
+            block_id,
+            account_id: account.account_id,
+            staked_balance: safe_divide_u128(account.staked_balance.0, 24),
+            unstaked_balance: safe_divide_u128(account.unstaked_balance.0, 24),
+            can_withdraw: account.can_withdraw,
+        })
+        .collect();
+    rows.sort_by(|a, b| b.staked_balance.total_cmp(&a.staked_balance));
+
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
+}
+
+#[derive(Debug, Deserialize)]
+struct StakingParams {
+    // Either a single date, or a start_date/end_date range sampled every interval.
+    pub date: Option<String>,
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    pub interval: Option<Interval>,
     pub accounts: String,
+    // Probe every known staking pool instead of only the ones the account deposited to.
+    pub discover_pools: Option<bool>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -626,6 +2760,9 @@ struct StakingReportRow {
     pub amount_staked: f64,
     pub amount_unstaked: f64,
     pub ready_for_withdraw: bool,
+    // Net deposited principal, and staked + unstaked balance above it.
+    pub principal: Option<f64>,
+    pub rewards_to_date: Option<f64>,
     pub lockup_of: Option<String>,
     pub date: String,
     pub block_id: u128,
@@ -643,61 +2780,162 @@ struct Pool {
     pool_id: String,
 }
 
+// Staking pools the account deposited to, from the indexer DB with fastnear as fallback.
+async fn get_staking_pools(
+    sql_client: &SqlClient,
+    client: &reqwest::Client,
+    account: &str,
+) -> anyhow::Result<Vec<String>> {
+    match sql_client.get_staking_pools(account).await {
+        Ok(pools) => return Ok(pools),
+        Err(e) => warn!("Staking pools from DB failed for {}: {:?}", account, e),
+    }
+
+    let staking_deposits = client
+        .get(format!(
+            "https://api.fastnear.com/v1/account/{account}/staking"
+        ))
+        .send()
+        .await?
+        .json::<StakingData>()
+        .await?;
+
+    Ok(staking_deposits
+        .pools
+        .into_iter()
+        .map(|pool| pool.pool_id)
+        .collect())
+}
+
+// Pools where the account has a non zero balance at the block, out of every known pool.
+async fn discover_staking_pools(
+    ft_service: &FtService,
+    known_pools: &[String],
+    account: &str,
+    block_id: u64,
+) -> Vec<String> {
+    let probes = known_pools.iter().map(|pool_id| async move {
+        match ft_service
+            .get_total_staked_balance(pool_id, account, block_id)
+            .await
+        {
+            Ok(balance) if balance > 0 => Some(pool_id.clone()),
+            Ok(_) => None,
+            Err(e) => {
+                debug!("{}: {}", account, e);
+                None
+            }
+        }
+    });
+
+    join_all(probes).await.into_iter().flatten().collect()
+}
+
 async fn get_staking_report(
-    params: Option<Query<DateAndAccounts>>,
+    params: Option<Query<StakingParams>>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
     State((sql_client, ft_service)): State<(SqlClient, FtService)>,
-    body: Option<Json<DateAndAccounts>>,
+    body: Option<Json<StakingParams>>,
 ) -> Result<Response<Body>, AppError> {
     let params = match params {
         Some(params) => params.0,
         None => body.unwrap().0,
     };
 
-    let date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.date).unwrap().into();
-    let start_nanos = date.timestamp_nanos() as u128;
-
-    let block_id = sql_client.get_closest_block_id(start_nanos).await?;
+    let dates = get_sample_dates(
+        params.date.as_deref(),
+        params.start_date.as_deref(),
+        params.end_date.as_deref(),
+        params.interval,
+    )?;
+    let filename = report_filename("staking", &params.accounts, &dates);
+    let block_ids = sql_client
+        .get_closest_block_ids(dates.iter().map(|d| d.timestamp_nanos() as u128).collect())
+        .await?;
+    let samples: Arc<Vec<(DateTime<chrono::Utc>, u128)>> =
+        Arc::new(dates.into_iter().zip(block_ids).collect());
 
     let accounts = get_accounts_and_lockups(&params.accounts);
 
+    let known_pools = if params.discover_pools.unwrap_or(false) {
+        Arc::new(sql_client.get_known_staking_pools().await?)
+    } else {
+        Arc::new(vec![])
+    };
+
     let client = reqwest::Client::new();
     let mut handles = vec![];
 
     for (account, master_account) in accounts {
         let client = client.clone();
+        let sql_client = sql_client.clone();
         let ft_service = ft_service.clone();
-        let block_id = block_id;
+        let known_pools = known_pools.clone();
+        let samples = samples.clone();
 
         let handle = spawn(async move {
             info!("Getting staking for {}", account);
             let mut rows: Vec<StakingReportRow> = vec![];
 
-            let staking_deposits = client
-                .get(format!(
-                    "https://api.fastnear.com/v1/account/{account}/staking"
-                ))
-                .send()
-                .await?
-                .json::<StakingData>()
-                .await?;
-            info!(
-                "Account {} staking deposits: {:?}",
-                account, staking_deposits
-            );
+            let mut staking_pools = get_staking_pools(&sql_client, &client, &account).await?;
+            // Probed at every sample, the account may have left a pool before the last one.
+            // Pools already found are not probed again.
+            for (_, block_id) in samples.iter() {
+                let unseen: Vec<String> = known_pools
+                    .iter()
+                    .filter(|pool_id| !staking_pools.contains(pool_id))
+                    .cloned()
+                    .collect();
+                if unseen.is_empty() {
+                    break;
+                }
+                let block_id = *block_id as u64;
+                staking_pools
+                    .extend(discover_staking_pools(&ft_service, &unseen, &account, block_id).await);
+            }
+            // Lockups stake through their own methods, read the pool they selected instead.
+            if master_account.is_some() {
+                for (_, block_id) in samples.iter() {
+                    match ft_service
+                        .get_lockup_staking_pool(&account, *block_id as u64)
+                        .await
+                    {
+                        Ok(Some(pool_id)) => {
+                            if !staking_pools.contains(&pool_id) {
+                                staking_pools.push(pool_id);
+                            }
+                        }
+                        Ok(None) => {}
+                        Err(e) => debug!("{}: {}", account, e),
+                    }
+                }
+            }
+            info!("Account {} staking pools: {:?}", account, staking_pools);
 
-            let handles: Vec<_> = staking_deposits
-                .pools
+            let handles: Vec<_> = samples
                 .iter()
-                .map(|pool| {
-                    let pool_id = pool.pool_id.clone();
+                .flat_map(|(date, block_id)| {
+                    staking_pools
+                        .iter()
+                        .map(move |pool_id| (*date, *block_id, pool_id))
+                })
+                .map(|(date, block_id, pool_id)| {
+                    let pool_id = pool_id.clone();
                     let account = account.clone();
                     let ft_service = ft_service.clone();
+                    let sql_client = sql_client.clone();
                     let master_account = master_account.clone();
                     async move {
-                        let staking_details = match ft_service
-                            .get_staking_details(&pool_id, &account, block_id as u64)
-                            .await
-                        {
+                        let (staking_details, principal) = tokio::join!(
+                            ft_service.get_staking_details(&pool_id, &account, block_id as u64),
+                            sql_client.get_staking_principal(
+                                &account,
+                                &pool_id,
+                                date.timestamp_nanos() as u128
+                            )
+                        );
+                        let staking_details = match staking_details {
                             Ok(v) => v,
                             Err(e) => {
                                 debug!("{}: {}", account, e);
@@ -709,12 +2947,23 @@ async fn get_staking_report(
                             return Ok(None);
                         }
 
+                        let principal = match principal {
+                            Ok(principal) => Some(principal as f64 / 1e24),
+                            Err(e) => {
+                                warn!("{}: principal on {} failed: {:?}", account, pool_id, e);
+                                None
+                            }
+                        };
+
                         let record = StakingReportRow {
                             account,
                             staking_pool: pool_id.clone(),
                             amount_staked: staking_details.0,
                             amount_unstaked: staking_details.1,
                             ready_for_withdraw: staking_details.2,
+                            principal,
+                            rewards_to_date: principal
+                                .map(|p| staking_details.0 + staking_details.1 - p),
                             lockup_of: master_account,
                             date: date.to_rfc3339(),
                             block_id,
@@ -756,8 +3005,8 @@ async fn get_staking_report(
         }
     });
 
-    let r = results_to_response(rows)?;
-    Ok(r)
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -766,24 +3015,52 @@ struct LockupBalanceRow {
     pub lockup_balance: Option<f64>,
     pub locked_amount: Option<f64>,
     pub liquid_amount: Option<f64>,
+    pub owner_account_id: String,
+    // Durations and timestamps in nanoseconds, as stored in the lockup contract.
+    pub lockup_duration: u64,
+    pub release_duration: Option<u64>,
+    pub lockup_timestamp: Option<u64>,
+    pub staking_pool: Option<String>,
     pub lockup_of: Option<String>,
     pub date: String,
     pub block_id: u128,
 }
 
+#[derive(Debug, Deserialize)]
+struct LockupParams {
+    // Either a single date, or a start_date/end_date range sampled every interval.
+    pub date: Option<String>,
+    pub start_date: Option<String>,
+    pub end_date: Option<String>,
+    pub interval: Option<Interval>,
+    pub accounts: String,
+}
+
 async fn get_lockup_balances(
-    params: Option<Query<DateAndAccounts>>,
+    params: Option<Query<LockupParams>>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
     State((sql_client, ft_service)): State<(SqlClient, FtService)>,
-    body: Option<Json<DateAndAccounts>>,
+    body: Option<Json<LockupParams>>,
 ) -> Result<Response<Body>, AppError> {
     let params = match params {
         Some(params) => params.0,
         None => body.unwrap().0,
     };
 
-    let date: DateTime<chrono::Utc> = DateTime::parse_from_rfc3339(&params.date).unwrap().into();
-    let date_nanos = date.timestamp_nanos() as u128;
-    let block_id = sql_client.get_closest_block_id(date_nanos).await?;
+    let dates = get_sample_dates(
+        params.date.as_deref(),
+        params.start_date.as_deref(),
+        params.end_date.as_deref(),
+        params.interval,
+    )?;
+    let filename = report_filename("lockup", &params.accounts, &dates);
+    let block_ids = sql_client
+        .get_closest_block_ids(dates.iter().map(|d| d.timestamp_nanos() as u128).collect())
+        .await?;
+    let samples: Arc<Vec<(DateTime<chrono::Utc>, u128)>> =
+        Arc::new(dates.into_iter().zip(block_ids).collect());
+
     let accounts = get_accounts_and_lockups(&params.accounts);
     let mut handles = vec![];
 
@@ -794,39 +3071,64 @@ async fn get_lockup_balances(
 
         let ft_service = ft_service.clone();
         let account: AccountId = account.parse().unwrap();
-        let block_id = block_id as u64;
+        let samples = samples.clone();
 
         let handle = spawn(async move {
             info!("Getting lockup_balance for {}", account);
+            let mut rows = vec![];
+
+            for (date, block_id) in samples.iter() {
+                let block_id = *block_id as u64;
+                let lockup = match lockup::l::get_lockup_contract_state(
+                    &ft_service.near_client,
+                    &account,
+                    &block_id,
+                )
+                .await
+                {
+                    Ok(lockup) => lockup,
+                    Err(e) => {
+                        // The lockup may not exist yet at earlier sample points.
+                        debug!("{} at {}: {:?}", account, block_id, e);
+                        continue;
+                    }
+                };
+                let timestamp = date.timestamp_nanos();
+
+                // todo: address has_bug, get hash of contract
+                let locked_amount = lockup.get_locked_amount(timestamp as u64, false);
+                // let unlocked = lockup.get_unvested_amount(timestamp as u64, false);
+                let locked_amount = safe_divide_u128(locked_amount.0, 24);
+                let near_balance = match ft_service.get_near_balance(&account, block_id).await {
+                    Ok(near_balance) => near_balance,
+                    Err(e) => {
+                        warn!("{} at {}: {:?}", account, block_id, e);
+                        continue;
+                    }
+                };
 
-            let account = account.clone();
-            let ft_service = ft_service.clone();
-            let master_account = master_account.clone();
-
-            let lockup =
-                lockup::l::get_lockup_contract_state(&ft_service.near_client, &account, &block_id)
-                    .await?;
-            let timestamp = date.timestamp_nanos();
-
-            // todo: address has_bug, get hash of contract
-            let locked_amount = lockup.get_locked_amount(timestamp as u64, false);
-            // let unlocked = lockup.get_unvested_amount(timestamp as u64, false);
-            let locked_amount = safe_divide_u128(locked_amount.0, 24);
-            let near_balance = ft_service.get_near_balance(&account, block_id).await?;
-
-            info!("Account {} lockup balance: {:?}", account, near_balance);
-
-            let record = LockupBalanceRow {
-                account: account.to_string(),
-                lockup_of: master_account,
-                lockup_balance: near_balance.map(|v| v.0),
-                locked_amount: Some(locked_amount),
-                liquid_amount: near_balance.map(|v| v.0 - locked_amount),
-                date: date.to_rfc3339(),
-                block_id: block_id as u128,
-            };
+                info!("Account {} lockup balance: {:?}", account, near_balance);
+
+                rows.push(LockupBalanceRow {
+                    account: account.to_string(),
+                    lockup_of: master_account.clone(),
+                    lockup_balance: near_balance.map(|v| v.amount),
+                    locked_amount: Some(locked_amount),
+                    liquid_amount: near_balance.map(|v| v.amount - locked_amount),
+                    owner_account_id: lockup.owner_account_id.to_string(),
+                    lockup_duration: lockup.lockup_information.lockup_duration,
+                    release_duration: lockup.lockup_information.release_duration,
+                    lockup_timestamp: lockup.lockup_information.lockup_timestamp,
+                    staking_pool: lockup
+                        .staking_information
+                        .as_ref()
+                        .map(|s| s.staking_pool_account_id.to_string()),
+                    date: date.to_rfc3339(),
+                    block_id: block_id as u128,
+                });
+            }
 
-            anyhow::Ok(record)
+            anyhow::Ok(rows)
         });
         handles.push(handle);
     }
@@ -834,7 +3136,7 @@ async fn get_lockup_balances(
     let mut rows = vec![];
     join_all(handles).await.iter().for_each(|row| match row {
         Ok(result) => match result {
-            Ok(res) => rows.push(res.clone()),
+            Ok(res) => rows.extend(res.iter().cloned()),
             Err(e) => {
                 println!("{:?}", e)
             }
@@ -844,14 +3146,266 @@ async fn get_lockup_balances(
         }
     });
 
-    let r = results_to_response(rows)?;
-    Ok(r)
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
+}
+
+#[derive(Debug, Deserialize)]
+struct PortfolioParams {
+    pub date: String,
+    pub accounts: String,
+    pub include_spam: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+struct PortfolioRow {
+    // Empty on the total of all the accounts.
+    pub account: Option<String>,
+    pub lockup_of: Option<String>,
+    pub date: String,
+    pub block_id: u128,
+    pub near: f64,
+    // Staked and unstaked balance in the pools the account deposited to.
+    pub staked: f64,
+    // Part of `near` and `staked` still locked in the lockup, already in the totals.
+    pub lockup_locked: f64,
+    pub ft_usd: f64,
+    // Tokens held without a USD price, left out of the totals.
+    pub unpriced_tokens: usize,
+    pub near_price_usd: Option<f64>,
+    pub total_near: Option<f64>,
+    pub total_usd: Option<f64>,
+}
+
+impl PortfolioRow {
+    fn add(&mut self, other: &PortfolioRow) {
+        self.near += other.near;
+        self.staked += other.staked;
+        self.lockup_locked += other.lockup_locked;
+        self.ft_usd += other.ft_usd;
+        self.unpriced_tokens += other.unpriced_tokens;
+    }
+
+    fn with_totals(mut self, near_price_usd: Option<f64>) -> Self {
+        let near = self.near + self.staked;
+        self.near_price_usd = near_price_usd;
+        self.total_usd = near_price_usd.map(|price| near * price + self.ft_usd);
+        self.total_near = near_price_usd
+            .filter(|price| *price > 0.0)
+            .map(|price| near + self.ft_usd / price);
+        self
+    }
+}
+
+// NEAR, staked and lockup-locked holdings of the account at the block, and its FT balances. None
+// when the account does not exist at the block.
+async fn portfolio_holdings(
+    sql_client: &SqlClient,
+    ft_service: &FtService,
+    client: &reqwest::Client,
+    account: &str,
+    lockup_of: Option<String>,
+    likely_tokens: &[String],
+    block_id: u64,
+) -> anyhow::Result<Option<(PortfolioRow, Vec<TokenBalance>)>> {
+    let mut near = None;
+    let mut ft_balances = vec![];
+    for balance in ft_service
+        .account_balances(likely_tokens, &account.to_string(), block_id)
+        .await
+    {
+        if balance.token_id == "NEAR" {
+            near = balance.balance;
+        } else {
+            ft_balances.push(balance);
+        }
+    }
+    let Some(near) = near else {
+        debug!("{}: no NEAR balance at {}", account, block_id);
+        return Ok(None);
+    };
+
+    let mut staking_pools = match get_staking_pools(sql_client, client, account).await {
+        Ok(pools) => pools,
+        Err(e) => {
+            warn!("{}: staking pools failed: {:?}", account, e);
+            vec![]
+        }
+    };
+    let mut lockup_locked = 0.0;
+    if lockup_of.is_some() {
+        if let Ok(Some(pool_id)) = ft_service.get_lockup_staking_pool(account, block_id).await {
+            if !staking_pools.contains(&pool_id) {
+                staking_pools.push(pool_id);
+            }
+        }
+        match ft_service.get_locked_amount(account, block_id).await {
+            Ok(locked) => lockup_locked = safe_divide_u128(locked, 24),
+            Err(e) => debug!("{}: {}", account, e),
+        }
+    }
+    let staked: f64 = join_all(
+        staking_pools
+            .iter()
+            .map(|pool_id| ft_service.get_staking_details(pool_id, account, block_id)),
+    )
+    .await
+    .into_iter()
+    .filter_map(|details| match details {
+        Ok((staked, unstaked, _)) => Some(staked + unstaked),
+        Err(e) => {
+            debug!("{}: {}", account, e);
+            None
+        }
+    })
+    .sum();
+
+    let row = PortfolioRow {
+        account: Some(account.to_string()),
+        lockup_of,
+        near,
+        staked,
+        lockup_locked,
+        ..Default::default()
+    };
+    Ok(Some((row, ft_balances)))
+}
+
+// Value of the NEAR, FT, staked and lockup-locked holdings of each account at the date, followed
+// by the total of all the accounts. FTs are valued at their USD price of the UTC day.
+async fn get_portfolio(
+    Query(params): Query<PortfolioParams>,
+    Query(dialect): Query<CsvDialect>,
+    Query(download): Query<DownloadParams>,
+    State((sql_client, ft_service, kitwallet, prices)): State<(
+        SqlClient,
+        FtService,
+        KitWallet,
+        PriceService,
+    )>,
+) -> Result<Response<Body>, AppError> {
+    let date = parse_date(&params.date)?;
+    let filename = report_filename("portfolio", &params.accounts, &[date]);
+    let include_spam = params.include_spam.unwrap_or(false);
+    let block_id = sql_client
+        .get_closest_block_id(date.timestamp_nanos() as u128)
+        .await?;
+
+    let mut accounts: Vec<(String, Option<String>)> = get_accounts_and_lockups(&params.accounts)
+        .into_iter()
+        .collect();
+    accounts.sort();
+    let likely_tokens = kitwallet
+        .get_likely_tokens_for_accounts(accounts.iter().map(|(a, _)| a.clone()).collect())
+        .await?;
+
+    let client = reqwest::Client::new();
+    let mut handles = vec![];
+    for (account, lockup_of) in accounts {
+        let sql_client = sql_client.clone();
+        let ft_service = ft_service.clone();
+        let client = client.clone();
+        let likely_tokens = likely_tokens.get(&account).cloned().unwrap_or_default();
+
+        handles.push(spawn(async move {
+            portfolio_holdings(
+                &sql_client,
+                &ft_service,
+                &client,
+                &account,
+                lockup_of,
+                &likely_tokens,
+                block_id as u64,
+            )
+            .await
+        }));
+    }
+
+    let mut holdings = vec![];
+    for result in join_all(handles).await {
+        match result {
+            Ok(Ok(Some((row, ft_balances)))) => {
+                let ft_balances: Vec<(String, f64)> = ft_balances
+                    .into_iter()
+                    .filter(|balance| include_spam || !balance.spam)
+                    .filter_map(|balance| Some((balance.token_id, balance.balance?)))
+                    .filter(|(_, amount)| *amount > 0.0)
+                    .collect();
+                holdings.push((row, ft_balances));
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => error!("{:?}", e),
+            Err(e) => warn!("{:?}", e),
+        }
+    }
+
+    let day = date.date_naive();
+    let pairs: HashSet<(String, NaiveDate)> = holdings
+        .iter()
+        .flat_map(|(_, ft_balances)| ft_balances.iter().map(|(token_id, _)| token_id.clone()))
+        .chain(std::iter::once("NEAR".to_string()))
+        .map(|token_id| (token_id, day))
+        .collect();
+    let token_prices = prices.get_prices(&pairs).await;
+    let near_price_usd = token_prices.get(&("NEAR".to_string(), day)).cloned();
+
+    let mut total = PortfolioRow {
+        date: date.to_rfc3339(),
+        block_id,
+        ..Default::default()
+    };
+    let mut rows = vec![];
+    for (mut row, ft_balances) in holdings {
+        for (token_id, amount) in ft_balances {
+            match token_prices.get(&(token_id, day)) {
+                Some(price) => row.ft_usd += amount * price,
+                None => row.unpriced_tokens += 1,
+            }
+        }
+        row.date = date.to_rfc3339();
+        row.block_id = block_id;
+        total.add(&row);
+        rows.push(row.with_totals(near_price_usd));
+    }
+    rows.push(total.with_totals(near_price_usd));
+
+    let r = results_to_response(rows, &dialect)?;
+    Ok(as_attachment(r, &download, filename))
 }
 
 struct AppError(anyhow::Error);
 
+impl AppError {
+    // Errors of reports computed once for identical requests come wrapped in a
+    // `SharedReportError`.
+    fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: std::fmt::Display + std::fmt::Debug + Send + Sync + 'static,
+    {
+        self.0.downcast_ref::<E>().or_else(|| {
+            self.0
+                .downcast_ref::<SharedReportError>()?
+                .0
+                .downcast_ref::<E>()
+        })
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
+        if let Some(e) = self.downcast_ref::<ReportTooLarge>() {
+            return (StatusCode::PAYLOAD_TOO_LARGE, e.to_string()).into_response();
+        }
+        // The request is fine, the balances it asks for are over the budget.
+        if let Some(e) = self.downcast_ref::<RpcBudgetExceeded>() {
+            return (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response();
+        }
+        if let Some(e) = self.downcast_ref::<ViewAccountError>() {
+            if e.retryable {
+                return (StatusCode::SERVICE_UNAVAILABLE, e.to_string()).into_response();
+            }
+        }
+
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Something went wrong: {}", self.0),
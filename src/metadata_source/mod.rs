@@ -0,0 +1,113 @@
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use lru::LruCache;
+use tokio::sync::Mutex;
+use tracing::info;
+use tta_rust::url_policy::UrlPolicy;
+
+use crate::{
+    config::{Config, METADATA_URL_CACHE_SECS, METADATA_URL_MAX_BYTES},
+    Metadata,
+};
+
+// Distinct documents kept, reports of a deployment usually share one or a few.
+const CACHED_DOCUMENTS: usize = 16;
+
+// Fetches the report metadata passed by `metadata_url` instead of in the body, a JSON document
+// shaped like the body's `metadata`. Only https URLs on the `METADATA_URL_HOSTS` are fetched, and
+// redirects are not followed. Documents are cached per url for `ttl`.
+#[derive(Clone)]
+pub struct MetadataSource {
+    client: reqwest::Client,
+    url_policy: UrlPolicy,
+    max_bytes: u64,
+    ttl: Duration,
+    cache: Arc<Mutex<LruCache<String, (Instant, Metadata)>>>,
+}
+
+impl Default for MetadataSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetadataSource {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(60))
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+            url_policy: UrlPolicy {
+                hosts: Some(HashSet::new()),
+                ..UrlPolicy::default()
+            },
+            max_bytes: METADATA_URL_MAX_BYTES,
+            ttl: Duration::from_secs(METADATA_URL_CACHE_SECS),
+            cache: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(CACHED_DOCUMENTS).unwrap(),
+            ))),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.max_bytes = config.metadata_url_max_bytes;
+        self.ttl = Duration::from_secs(config.metadata_url_cache_secs);
+        self.url_policy.hosts = Some(config.metadata_url_hosts.clone());
+        self
+    }
+
+    pub async fn fetch(&self, url: &str) -> Result<Metadata> {
+        self.url_policy
+            .check(url)
+            .await
+            .context("metadata_url is not allowed")?;
+        if let Some((fetched_at, metadata)) = self.cache.lock().await.get(url) {
+            if fetched_at.elapsed() < self.ttl {
+                return Ok(metadata.clone());
+            }
+        }
+
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to fetch the metadata at {}", url))?
+            .error_for_status()?;
+        if response
+            .content_length()
+            .map_or(false, |len| len > self.max_bytes)
+        {
+            bail!("The metadata at {} is over {} bytes", url, self.max_bytes);
+        }
+        // The length is not always announced, the body is checked as it comes too.
+        let mut body = vec![];
+        while let Some(chunk) = response.chunk().await? {
+            if (body.len() + chunk.len()) as u64 > self.max_bytes {
+                bail!("The metadata at {} is over {} bytes", url, self.max_bytes);
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let metadata: Metadata = serde_json::from_slice(&body)
+            .with_context(|| format!("Invalid metadata at {}", url))?;
+        info!(
+            "Fetched the metadata at {}, {} accounts",
+            url,
+            metadata.len()
+        );
+
+        self.cache
+            .lock()
+            .await
+            .put(url.to_string(), (Instant::now(), metadata.clone()));
+        Ok(metadata)
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex, OnceLock},
+    time::Duration,
+};
+
+use crate::rate_limiter::AdaptiveRateLimiter;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+// Process wide metrics registry, rendered in the Prometheus text format on `/metrics`.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rate_limiters: Mutex<Vec<Arc<AdaptiveRateLimiter>>>,
+    // By (service, method).
+    calls: Mutex<BTreeMap<(String, String), CallStats>>,
+    // By cache, hits and misses.
+    cache_lookups: Mutex<BTreeMap<String, (u64, u64)>>,
+    // By query.
+    sql_queries: Mutex<BTreeMap<String, SqlQueryStats>>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Cumulative, one per `LATENCY_BUCKETS` bound.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum_secs: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum_secs += secs;
+    }
+}
+
+#[derive(Debug, Default)]
+struct CallStats {
+    ok: u64,
+    errors: u64,
+    latency: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct SqlQueryStats {
+    rows: u64,
+    row_errors: u64,
+    duration: Histogram,
+}
+
+impl Metrics {
+    pub fn register_rate_limiter(&self, rate_limiter: Arc<AdaptiveRateLimiter>) {
+        self.rate_limiters.lock().unwrap().push(rate_limiter);
+    }
+
+    // A call to an external service (RPC, indexer APIs), with its latency once let through by
+    // the rate limiter.
+    pub fn record_call(&self, service: &str, method: &str, elapsed: Duration, ok: bool) {
+        let mut calls = self.calls.lock().unwrap();
+        let stats = calls
+            .entry((service.to_string(), method.to_string()))
+            .or_default();
+        if ok {
+            stats.ok += 1;
+        } else {
+            stats.errors += 1;
+        }
+        stats.latency.observe(elapsed);
+    }
+
+    // A streamed indexer query, timed until its last row, with the rows it returned.
+    pub fn record_sql_query(&self, query: &str, elapsed: Duration, rows: u64, row_errors: u64) {
+        let mut sql_queries = self.sql_queries.lock().unwrap();
+        let stats = sql_queries.entry(query.to_string()).or_default();
+        stats.rows += rows;
+        stats.row_errors += row_errors;
+        stats.duration.observe(elapsed);
+    }
+
+    pub fn record_cache_lookup(&self, cache: &str, hit: bool) {
+        let mut cache_lookups = self.cache_lookups.lock().unwrap();
+        let (hits, misses) = cache_lookups.entry(cache.to_string()).or_default();
+        if hit {
+            *hits += 1;
+        } else {
+            *misses += 1;
+        }
+    }
+
+    // Share of the lookups of the cache that were hits, None before any lookup.
+    pub fn cache_hit_ratio(&self, cache: &str) -> Option<f64> {
+        let cache_lookups = self.cache_lookups.lock().unwrap();
+        match cache_lookups.get(cache) {
+            Some((hits, misses)) if hits + misses > 0 => {
+                Some(*hits as f64 / (hits + misses) as f64)
+            }
+            _ => None,
+        }
+    }
+
+    // Mean latency of the calls to any method of the service so far.
+    pub fn mean_call_secs(&self, service: &str) -> Option<f64> {
+        let calls = self.calls.lock().unwrap();
+        let (count, sum_secs) = calls.iter().filter(|((s, _), _)| s == service).fold(
+            (0, 0.0),
+            |(count, sum_secs), (_, stats)| {
+                (
+                    count + stats.latency.count,
+                    sum_secs + stats.latency.sum_secs,
+                )
+            },
+        );
+        (count > 0).then(|| sum_secs / count as f64)
+    }
+
+    // Time the queries took per row they streamed so far.
+    pub fn secs_per_row(&self, queries: &[&str]) -> Option<f64> {
+        let sql_queries = self.sql_queries.lock().unwrap();
+        let (rows, sum_secs) = queries
+            .iter()
+            .filter_map(|query| sql_queries.get(*query))
+            .fold((0, 0.0), |(rows, sum_secs), stats| {
+                (rows + stats.rows, sum_secs + stats.duration.sum_secs)
+            });
+        (rows > 0).then(|| sum_secs / rows as f64)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let rate_limiters = self.rate_limiters.lock().unwrap();
+        write_rate_limiter_family(
+            &mut out,
+            "tta_rate_limit_current_rps",
+            "gauge",
+            &rate_limiters,
+            |l| l.current_rps() as u64,
+        );
+        write_rate_limiter_family(
+            &mut out,
+            "tta_rate_limit_max_rps",
+            "gauge",
+            &rate_limiters,
+            |l| l.max_rps() as u64,
+        );
+        write_rate_limiter_family(
+            &mut out,
+            "tta_rate_limit_acquired_total",
+            "counter",
+            &rate_limiters,
+            |l| l.acquired(),
+        );
+        write_rate_limiter_family(
+            &mut out,
+            "tta_rate_limit_throttled_total",
+            "counter",
+            &rate_limiters,
+            |l| l.throttled(),
+        );
+        let _ = writeln!(out, "# TYPE tta_rate_limit_wait_seconds_total counter");
+        for limiter in rate_limiters.iter() {
+            let _ = writeln!(
+                out,
+                "tta_rate_limit_wait_seconds_total{} {}",
+                format_labels(&[("provider", limiter.provider())]),
+                limiter.waited().as_secs_f64()
+            );
+        }
+        drop(rate_limiters);
+
+        let calls = self.calls.lock().unwrap();
+        let _ = writeln!(out, "# TYPE tta_external_calls_total counter");
+        for ((service, method), stats) in calls.iter() {
+            for (outcome, count) in [("ok", stats.ok), ("error", stats.errors)] {
+                let _ = writeln!(
+                    out,
+                    "tta_external_calls_total{} {}",
+                    format_labels(&[
+                        ("service", service.as_str()),
+                        ("method", method.as_str()),
+                        ("outcome", outcome)
+                    ]),
+                    count
+                );
+            }
+        }
+        let _ = writeln!(out, "# TYPE tta_external_call_duration_seconds histogram");
+        for ((service, method), stats) in calls.iter() {
+            write_histogram(
+                &mut out,
+                "tta_external_call_duration_seconds",
+                &[("service", service.as_str()), ("method", method.as_str())],
+                &stats.latency,
+            );
+        }
+        drop(calls);
+
+        let cache_lookups = self.cache_lookups.lock().unwrap();
+        let _ = writeln!(out, "# TYPE tta_cache_lookups_total counter");
+        for (cache, (hits, misses)) in cache_lookups.iter() {
+            for (result, count) in [("hit", hits), ("miss", misses)] {
+                let _ = writeln!(
+                    out,
+                    "tta_cache_lookups_total{} {}",
+                    format_labels(&[("cache", cache.as_str()), ("result", result)]),
+                    count
+                );
+            }
+        }
+        drop(cache_lookups);
+
+        let sql_queries = self.sql_queries.lock().unwrap();
+        let _ = writeln!(out, "# TYPE tta_sql_query_duration_seconds histogram");
+        for (query, stats) in sql_queries.iter() {
+            write_histogram(
+                &mut out,
+                "tta_sql_query_duration_seconds",
+                &[("query", query.as_str())],
+                &stats.duration,
+            );
+        }
+        let counters: [(&str, fn(&SqlQueryStats) -> u64); 2] = [
+            ("tta_sql_rows_streamed_total", |s| s.rows),
+            ("tta_sql_row_errors_total", |s| s.row_errors),
+        ];
+        for (name, value) in counters {
+            let _ = writeln!(out, "# TYPE {name} counter");
+            for (query, stats) in sql_queries.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{} {}",
+                    name,
+                    format_labels(&[("query", query.as_str())]),
+                    value(stats)
+                );
+            }
+        }
+
+        out
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+    let bounds = LATENCY_BUCKETS.map(|bound| bound.to_string());
+    let mut bucket_labels = labels.to_vec();
+    for (bucket, bound) in histogram.buckets.iter().zip(&bounds) {
+        bucket_labels.push(("le", bound));
+        let _ = writeln!(
+            out,
+            "{}_bucket{} {}",
+            name,
+            format_labels(&bucket_labels),
+            bucket
+        );
+        bucket_labels.pop();
+    }
+    bucket_labels.push(("le", "+Inf"));
+    let _ = writeln!(
+        out,
+        "{}_bucket{} {}",
+        name,
+        format_labels(&bucket_labels),
+        histogram.count
+    );
+    let _ = writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        format_labels(labels),
+        histogram.sum_secs
+    );
+    let _ = writeln!(
+        out,
+        "{}_count{} {}",
+        name,
+        format_labels(labels),
+        histogram.count
+    );
+}
+
+fn write_rate_limiter_family<F: Fn(&AdaptiveRateLimiter) -> u64>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    rate_limiters: &[Arc<AdaptiveRateLimiter>],
+    value: F,
+) {
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    for limiter in rate_limiters {
+        let _ = writeln!(
+            out,
+            "{}{} {}",
+            name,
+            format_labels(&[("provider", limiter.provider())]),
+            value(limiter)
+        );
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
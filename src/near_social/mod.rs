@@ -0,0 +1,121 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::bail;
+use reqwest::StatusCode;
+use serde_json::{json, Value};
+use tokio::sync::RwLock;
+use tracing::warn;
+use tta_rust::rate_limiter::AdaptiveRateLimiter;
+
+use crate::config::{Config, NEAR_SOCIAL_QUOTA};
+
+const NEAR_SOCIAL_PROVIDER: &str = "api.near.social";
+// Accounts per `get` request.
+const BATCH_SIZE: usize = 100;
+
+// Resolves account ids to their near.social profile name. Names rarely change, lookups (including
+// accounts without a profile) are cached for the lifetime of the process.
+#[derive(Clone)]
+pub struct NearSocial {
+    rate_limiter: Arc<AdaptiveRateLimiter>,
+    client: reqwest::Client,
+    cache: Arc<RwLock<HashMap<String, Option<String>>>>,
+}
+
+impl Default for NearSocial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NearSocial {
+    pub fn new() -> Self {
+        Self {
+            rate_limiter: Arc::new(NEAR_SOCIAL_QUOTA.rate_limiter(NEAR_SOCIAL_PROVIDER)),
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(60))
+                .build()
+                .unwrap(),
+            cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.rate_limiter = Arc::new(config.quotas.near_social.rate_limiter(NEAR_SOCIAL_PROVIDER));
+        self
+    }
+
+    pub fn rate_limiter(&self) -> Arc<AdaptiveRateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    // Profile names of the given accounts, accounts without one are left out.
+    pub async fn get_names(&self, accounts: &[String]) -> HashMap<String, String> {
+        let missing: Vec<String> = {
+            let cache = self.cache.read().await;
+            accounts
+                .iter()
+                .filter(|account| !cache.contains_key(*account))
+                .cloned()
+                .collect()
+        };
+
+        for batch in missing.chunks(BATCH_SIZE) {
+            match self.fetch_names(batch).await {
+                Ok(names) => {
+                    let mut cache = self.cache.write().await;
+                    for account in batch {
+                        cache.insert(account.clone(), names.get(account).cloned());
+                    }
+                }
+                Err(e) => warn!("Failed to resolve near.social names: {:?}", e),
+            }
+        }
+
+        let cache = self.cache.read().await;
+        accounts
+            .iter()
+            .filter_map(|account| {
+                cache
+                    .get(account)
+                    .cloned()
+                    .flatten()
+                    .map(|name| (account.clone(), name))
+            })
+            .collect()
+    }
+
+    // https://api.near.social/get {"keys": ["here.near/profile/name"]}
+    async fn fetch_names(&self, accounts: &[String]) -> anyhow::Result<HashMap<String, String>> {
+        let keys: Vec<String> = accounts
+            .iter()
+            .map(|account| format!("{}/profile/name", account))
+            .collect();
+
+        self.rate_limiter.until_ready().await;
+        let response = self
+            .client
+            .post("https://api.near.social/get")
+            .json(&json!({ "keys": keys }))
+            .send()
+            .await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error()
+        {
+            self.rate_limiter.on_throttled();
+            bail!("{} returned {}", NEAR_SOCIAL_PROVIDER, response.status());
+        }
+        self.rate_limiter.on_success();
+
+        let data: HashMap<String, Value> = response.error_for_status()?.json().await?;
+        Ok(data
+            .into_iter()
+            .filter_map(|(account, profile)| {
+                profile["profile"]["name"]
+                    .as_str()
+                    .map(|name| name.trim().to_string())
+                    .filter(|name| !name.is_empty())
+                    .map(|name| (account, name))
+            })
+            .collect())
+    }
+}
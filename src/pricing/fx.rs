@@ -0,0 +1,162 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use chrono::{Duration, NaiveDate, Utc};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::warn;
+
+use crate::config::{Config, FX_API_URL};
+
+// ECB publishes no rates on weekends and holidays, those days use the last published rate.
+const MAX_DAYS_WITHOUT_RATE: i64 = 7;
+
+// ISO 4217 code of the currency reports are valued in, uppercased.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct FiatCurrency(String);
+
+impl FiatCurrency {
+    pub fn is_usd(&self) -> bool {
+        self.0 == "USD"
+    }
+}
+
+impl TryFrom<String> for FiatCurrency {
+    type Error = anyhow::Error;
+
+    fn try_from(code: String) -> Result<Self> {
+        if code.len() != 3 || !code.chars().all(|c| c.is_ascii_alphabetic()) {
+            bail!("Invalid currency: {}", code);
+        }
+        Ok(Self(code.to_ascii_uppercase()))
+    }
+}
+
+impl std::fmt::Display for FiatCurrency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Deserialize)]
+struct TimeSeries {
+    rates: BTreeMap<NaiveDate, HashMap<String, f64>>,
+}
+
+// Daily USD exchange rates from the ECB reference rates, through the Frankfurter API. Rates of
+// past days are cached for the lifetime of the process.
+#[derive(Clone)]
+pub struct FxRates {
+    client: reqwest::Client,
+    api_url: String,
+    rates: Arc<RwLock<HashMap<(FiatCurrency, NaiveDate), Option<f64>>>>,
+}
+
+impl Default for FxRates {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FxRates {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(60))
+                .build()
+                .unwrap(),
+            api_url: FX_API_URL.to_string(),
+            rates: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.api_url = config.fx_api_url.clone();
+        self
+    }
+
+    // Units of `currency` per USD on the given days, days without a rate are left out.
+    pub async fn get_rates(
+        &self,
+        currency: &FiatCurrency,
+        days: &HashSet<NaiveDate>,
+    ) -> HashMap<NaiveDate, f64> {
+        if currency.is_usd() {
+            return days.iter().map(|day| (*day, 1.0)).collect();
+        }
+
+        let missing: Vec<NaiveDate> = {
+            let rates = self.rates.read().await;
+            days.iter()
+                .filter(|day| !rates.contains_key(&(currency.clone(), **day)))
+                .cloned()
+                .collect()
+        };
+        let mut fetched = HashMap::new();
+        if let (Some(start_day), Some(end_day)) = (missing.iter().min(), missing.iter().max()) {
+            match self.fetch_rates(currency, *start_day, *end_day).await {
+                Ok(series) => {
+                    let today = Utc::now().date_naive();
+                    let mut rates = self.rates.write().await;
+                    for day in missing {
+                        let rate = series
+                            .range(day - Duration::days(MAX_DAYS_WITHOUT_RATE)..=day)
+                            .next_back()
+                            .map(|(_, rate)| *rate);
+                        // Today's rate may not be published yet.
+                        if day < today {
+                            rates.insert((currency.clone(), day), rate);
+                        }
+                        fetched.insert(day, rate);
+                    }
+                }
+                Err(e) => warn!(%currency, "Failed to get exchange rates: {:?}", e),
+            }
+        }
+
+        let rates = self.rates.read().await;
+        days.iter()
+            .filter_map(|day| {
+                let rate = rates
+                    .get(&(currency.clone(), *day))
+                    .or_else(|| fetched.get(day))
+                    .cloned();
+                rate.flatten().map(|rate| (*day, rate))
+            })
+            .collect()
+    }
+
+    // https://api.frankfurter.app/2023-12-01..2023-12-31?from=USD&to=EUR
+    async fn fetch_rates(
+        &self,
+        currency: &FiatCurrency,
+        start_day: NaiveDate,
+        end_day: NaiveDate,
+    ) -> Result<BTreeMap<NaiveDate, f64>> {
+        let url = format!(
+            "{}/{}..{}?from=USD&to={}",
+            self.api_url,
+            start_day - Duration::days(MAX_DAYS_WITHOUT_RATE),
+            end_day,
+            currency
+        );
+        let series: TimeSeries = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(series
+            .rates
+            .into_iter()
+            .filter_map(|(day, rates)| Some((day, *rates.get(&currency.0)?)))
+            .collect())
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::{bail, Result};
+use chrono::{NaiveDate, Utc};
+use reqwest::StatusCode;
+use serde_json::Value;
+use tokio::sync::RwLock;
+use tracing::warn;
+use tta_rust::rate_limiter::AdaptiveRateLimiter;
+
+use crate::{
+    config::{Config, PRICING_API_URL, PRICING_QUOTA},
+    tta::sql::sql_queries::SqlClient,
+};
+
+pub mod fx;
+
+use fx::{FiatCurrency, FxRates};
+
+const PRICING_PROVIDER: &str = "coingecko";
+
+// Daily USD prices of NEAR and NEP-141 tokens from CoinGecko, by token contract. A day's price is
+// CoinGecko's price at 00:00 UTC. Prices of past days never change, they are cached in memory and
+// in the DB, tokens CoinGecko does not list included.
+#[derive(Clone)]
+pub struct PriceService {
+    sql_client: SqlClient,
+    rate_limiter: Arc<AdaptiveRateLimiter>,
+    client: reqwest::Client,
+    api_url: String,
+    api_key: Option<String>,
+    prices: Arc<RwLock<HashMap<(String, NaiveDate), Option<f64>>>>,
+    // CoinGecko coin id of each token contract.
+    coin_ids: Arc<RwLock<HashMap<String, Option<String>>>>,
+    fx_rates: FxRates,
+}
+
+impl PriceService {
+    pub fn new(sql_client: SqlClient) -> Self {
+        Self {
+            sql_client,
+            rate_limiter: Arc::new(PRICING_QUOTA.rate_limiter(PRICING_PROVIDER)),
+            client: reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(60))
+                .build()
+                .unwrap(),
+            api_url: PRICING_API_URL.to_string(),
+            api_key: None,
+            prices: Arc::new(RwLock::new(HashMap::new())),
+            coin_ids: Arc::new(RwLock::new(HashMap::new())),
+            fx_rates: FxRates::new(),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.rate_limiter = Arc::new(config.quotas.pricing.rate_limiter(PRICING_PROVIDER));
+        self.api_url = config.pricing_api_url.clone();
+        self.api_key = config.pricing_api_key.clone();
+        self.fx_rates = self.fx_rates.with_config(config);
+        self
+    }
+
+    pub fn rate_limiter(&self) -> Arc<AdaptiveRateLimiter> {
+        self.rate_limiter.clone()
+    }
+
+    // USD prices of the given (token, day) pairs, pairs without a price are left out. NEAR is
+    // `NEAR`, tokens are their contract.
+    pub async fn get_prices(
+        &self,
+        pairs: &HashSet<(String, NaiveDate)>,
+    ) -> HashMap<(String, NaiveDate), f64> {
+        if let Err(e) = self.load_prices(pairs).await {
+            warn!("Failed to read token prices: {:?}", e);
+        }
+
+        let missing: Vec<(String, NaiveDate)> = {
+            let prices = self.prices.read().await;
+            pairs
+                .iter()
+                .filter(|pair| !prices.contains_key(*pair))
+                .cloned()
+                .collect()
+        };
+        let today = Utc::now().date_naive();
+        let mut fetched = HashMap::new();
+        for (token_id, day) in missing {
+            let price = match self.fetch_price(&token_id, day).await {
+                Ok(price) => price,
+                Err(e) => {
+                    warn!(token_id, %day, "Failed to get price: {:?}", e);
+                    continue;
+                }
+            };
+            // Today's price is still moving, it is not cached.
+            if day < today {
+                if let Err(e) = self
+                    .sql_client
+                    .save_token_price(&token_id, day, price)
+                    .await
+                {
+                    warn!(token_id, %day, "Failed to save price: {:?}", e);
+                }
+                self.prices
+                    .write()
+                    .await
+                    .insert((token_id.clone(), day), price);
+            }
+            fetched.insert((token_id, day), price);
+        }
+
+        let prices = self.prices.read().await;
+        pairs
+            .iter()
+            .filter_map(|pair| {
+                let price = prices.get(pair).or_else(|| fetched.get(pair)).cloned();
+                price.flatten().map(|price| (pair.clone(), price))
+            })
+            .collect()
+    }
+
+    // Units of `currency` per USD on the given days, days without a rate are left out.
+    pub async fn get_fx_rates(
+        &self,
+        currency: &FiatCurrency,
+        days: &HashSet<NaiveDate>,
+    ) -> HashMap<NaiveDate, f64> {
+        self.fx_rates.get_rates(currency, days).await
+    }
+
+    // Reads the pairs not in memory from the DB.
+    async fn load_prices(&self, pairs: &HashSet<(String, NaiveDate)>) -> Result<()> {
+        let missing: Vec<&(String, NaiveDate)> = {
+            let prices = self.prices.read().await;
+            pairs
+                .iter()
+                .filter(|pair| !prices.contains_key(*pair))
+                .collect()
+        };
+        let (Some(start_day), Some(end_day)) = (
+            missing.iter().map(|(_, day)| *day).min(),
+            missing.iter().map(|(_, day)| *day).max(),
+        ) else {
+            return Ok(());
+        };
+        let token_ids: Vec<String> = missing
+            .iter()
+            .map(|(token_id, _)| token_id.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        let stored = self
+            .sql_client
+            .get_token_prices(&token_ids, start_day, end_day)
+            .await?;
+        let mut prices = self.prices.write().await;
+        for (token_id, day, price) in stored {
+            prices.insert((token_id, day), price);
+        }
+
+        Ok(())
+    }
+
+    async fn fetch_price(&self, token_id: &str, day: NaiveDate) -> Result<Option<f64>> {
+        let Some(coin_id) = self.coin_id(token_id).await? else {
+            return Ok(None);
+        };
+        // https://api.coingecko.com/api/v3/coins/near/history?date=30-12-2023
+        let data = self
+            .get(&format!(
+                "/coins/{}/history?date={}&localization=false",
+                coin_id,
+                day.format("%d-%m-%Y")
+            ))
+            .await?;
+        Ok(data.and_then(|data| data["market_data"]["current_price"]["usd"].as_f64()))
+    }
+
+    async fn coin_id(&self, token_id: &str) -> Result<Option<String>> {
+        if token_id == "NEAR" || token_id == "wrap.near" {
+            return Ok(Some("near".to_string()));
+        }
+        if let Some(coin_id) = self.coin_ids.read().await.get(token_id) {
+            return Ok(coin_id.clone());
+        }
+
+        // https://api.coingecko.com/api/v3/coins/near-protocol/contract/usdt.tether-token.near
+        let coin_id = self
+            .get(&format!("/coins/near-protocol/contract/{}", token_id))
+            .await?
+            .and_then(|data| data["id"].as_str().map(String::from));
+        self.coin_ids
+            .write()
+            .await
+            .insert(token_id.to_string(), coin_id.clone());
+        Ok(coin_id)
+    }
+
+    // The JSON response to `path`, or None when CoinGecko does not know it.
+    async fn get(&self, path: &str) -> Result<Option<Value>> {
+        self.rate_limiter.until_ready().await;
+        let mut request = self.client.get(format!("{}{}", self.api_url, path));
+        if let Some(api_key) = &self.api_key {
+            request = request.header("x-cg-pro-api-key", api_key);
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS || response.status().is_server_error()
+        {
+            self.rate_limiter.on_throttled();
+            bail!("{} returned {}", PRICING_PROVIDER, response.status());
+        }
+        self.rate_limiter.on_success();
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+}
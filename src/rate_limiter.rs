@@ -0,0 +1,153 @@
+use std::{
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use governor::{Quota, RateLimiter};
+use tracing::{debug, warn};
+
+use crate::RateLim;
+
+// Rate limiter for a single external provider that adapts to the feedback it gets.
+// The allowed rate is halved whenever the provider signals it is overloaded (429s, timeouts)
+// and grows back slowly while calls keep succeeding.
+#[derive(Debug)]
+pub struct AdaptiveRateLimiter {
+    provider: String,
+    min_rps: u32,
+    max_rps: u32,
+    state: Mutex<AdaptiveState>,
+    acquired: AtomicU64,
+    throttled: AtomicU64,
+    waited_micros: AtomicU64,
+}
+
+#[derive(Debug)]
+struct AdaptiveState {
+    rps: u32,
+    successes: u32,
+    limiter: Arc<RateLim>,
+}
+
+impl AdaptiveState {
+    fn new(rps: u32) -> Self {
+        Self {
+            rps,
+            successes: 0,
+            limiter: Arc::new(RateLimiter::direct(Quota::per_second(
+                NonZeroU32::new(rps).unwrap(),
+            ))),
+        }
+    }
+}
+
+impl AdaptiveRateLimiter {
+    pub fn new(provider: &str, min_rps: u32, initial_rps: u32, max_rps: u32) -> Self {
+        let min_rps = min_rps.max(1);
+        let max_rps = max_rps.max(min_rps);
+        Self {
+            provider: provider.to_string(),
+            min_rps,
+            max_rps,
+            state: Mutex::new(AdaptiveState::new(initial_rps.clamp(min_rps, max_rps))),
+            acquired: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+            waited_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn provider(&self) -> &str {
+        &self.provider
+    }
+
+    pub fn current_rps(&self) -> u32 {
+        self.state.lock().unwrap().rps
+    }
+
+    pub fn max_rps(&self) -> u32 {
+        self.max_rps
+    }
+
+    // Total number of calls let through since start.
+    pub fn acquired(&self) -> u64 {
+        self.acquired.load(Ordering::Relaxed)
+    }
+
+    // Total number of throttling signals received from the provider.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Total time calls spent waiting for the limiter since start.
+    pub fn waited(&self) -> Duration {
+        Duration::from_micros(self.waited_micros.load(Ordering::Relaxed))
+    }
+
+    pub async fn until_ready(&self) {
+        let started_at = Instant::now();
+        let limiter = self.state.lock().unwrap().limiter.clone();
+        limiter.until_ready().await;
+        self.acquired.fetch_add(1, Ordering::Relaxed);
+        self.waited_micros
+            .fetch_add(started_at.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn on_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.rps >= self.max_rps {
+            return;
+        }
+
+        state.successes += 1;
+        // Ramp up once a full second worth of calls went through without complaints.
+        if state.successes >= state.rps {
+            let rps = (state.rps + state.rps / 10 + 1).min(self.max_rps);
+            debug!("{}: raising rate limit to {} rps", self.provider, rps);
+            *state = AdaptiveState::new(rps);
+        }
+    }
+
+    pub fn on_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        let rps = (state.rps / 2).max(self.min_rps);
+        if rps == state.rps {
+            state.successes = 0;
+            return;
+        }
+
+        warn!(
+            "{}: provider is throttling, lowering rate limit from {} to {} rps",
+            self.provider, state.rps, rps
+        );
+        *state = AdaptiveState::new(rps);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_and_ramps_up() {
+        let limiter = AdaptiveRateLimiter::new("test", 1, 10, 20);
+
+        limiter.on_throttled();
+        assert_eq!(limiter.current_rps(), 5);
+        limiter.on_throttled();
+        limiter.on_throttled();
+        limiter.on_throttled();
+        assert_eq!(limiter.current_rps(), 1);
+
+        limiter.on_success();
+        assert_eq!(limiter.current_rps(), 2);
+        for _ in 0..1000 {
+            limiter.on_success();
+        }
+        assert_eq!(limiter.current_rps(), 20);
+    }
+}
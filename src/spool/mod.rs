@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+};
+use tracing::warn;
+use tta_rust::CsvDialect;
+
+use crate::tta::models::{ReportRow, SymbolContracts};
+
+// A CSV report written to temp files as its rows are generated, so only the rows of the current
+// chunk are held in memory. Rows are first spooled as they come, then written out as CSV once
+// they all went by, with the symbols disambiguated over the whole report. The report hash is
+// computed along the way, over the same bytes as for an in-memory report.
+pub struct ReportSpool {
+    rows_path: PathBuf,
+    rows_file: BufWriter<File>,
+    csv_path: PathBuf,
+    symbols: SymbolContracts,
+    dialect: CsvDialect,
+}
+
+impl ReportSpool {
+    pub async fn create(dialect: &CsvDialect) -> Result<Self> {
+        let spool_id = uuid::Uuid::new_v4();
+        let rows_path = std::env::temp_dir().join(format!("tta-{}.jsonl", spool_id));
+        Ok(Self {
+            rows_file: BufWriter::new(File::create(&rows_path).await?),
+            rows_path,
+            csv_path: std::env::temp_dir().join(format!("tta-{}.csv", spool_id)),
+            symbols: SymbolContracts::default(),
+            dialect: dialect.clone(),
+        })
+    }
+
+    pub async fn append(&mut self, rows: &[ReportRow]) -> Result<()> {
+        self.symbols.observe(rows);
+        for row in rows {
+            let mut line = serde_json::to_vec(row)?;
+            line.push(b'\n');
+            self.rows_file.write_all(&line).await?;
+        }
+        Ok(())
+    }
+
+    // Writes the report with its headers, the BOM if asked for and the trailer, which is not
+    // hashed. Returns the report hash with the file opened for reading. The files are unlinked
+    // right away, the report is gone once the handle is dropped.
+    pub async fn finish<F>(mut self, trailer: F) -> Result<(String, File)>
+    where
+        F: FnOnce(&str) -> Vec<String>,
+    {
+        self.rows_file.flush().await?;
+        let mut rows = BufReader::new(File::open(&self.rows_path).await?).lines();
+        let mut csv_file = BufWriter::new(File::create(&self.csv_path).await?);
+        let mut hasher = Sha256::new();
+
+        let headers = ReportRow::get_vec_headers();
+        let mut wtr = self.dialect.writer();
+        wtr.write_record(&headers)?;
+        let data = wtr.into_inner()?;
+        hasher.update(&data);
+        csv_file.write_all(&data).await?;
+
+        // Only the headers start with a BOM.
+        let dialect = CsvDialect {
+            bom: Some(false),
+            ..self.dialect.clone()
+        };
+        while let Some(line) = rows.next_line().await? {
+            let mut row: ReportRow = serde_json::from_str(&line)?;
+            self.symbols.disambiguate(&mut row);
+            let mut wtr = dialect.writer();
+            wtr.write_record(&dialect.format_record(&headers, row.to_vec()))?;
+            let data = wtr.into_inner()?;
+            hasher.update(&data);
+            csv_file.write_all(&data).await?;
+        }
+
+        let report_hash = format!("{:x}", hasher.finalize());
+        let mut wtr = dialect.writer();
+        wtr.write_record(&trailer(&report_hash))?;
+        csv_file.write_all(&wtr.into_inner()?).await?;
+        csv_file.flush().await?;
+
+        let file = File::open(&self.csv_path).await?;
+        fs::remove_file(&self.csv_path).await?;
+
+        Ok((report_hash, file))
+    }
+}
+
+impl Drop for ReportSpool {
+    // The rows, and the CSV of unfinished reports, on errors. Drop can't await, the removal is
+    // left to the blocking pool.
+    fn drop(&mut self) {
+        let paths = [self.rows_path.clone(), self.csv_path.clone()];
+        tokio::task::spawn_blocking(move || {
+            for path in paths {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    if e.kind() != std::io::ErrorKind::NotFound {
+                        warn!(?e, ?path, "Error removing a report spool");
+                    }
+                }
+            }
+        });
+    }
+}
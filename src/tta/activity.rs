@@ -0,0 +1,252 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use tracing::{error, info};
+
+use crate::{
+    config::{Config, ACTIVITY_BACKFILL_DAYS, ACTIVITY_REFRESH_SECS},
+    TxnsReportWithMetadata,
+};
+
+use super::{
+    aggregations::{disambiguate_summary, summarize, SummaryRow},
+    models::ReportRow,
+    progress::ReportProgress,
+    sql::sql_queries::SqlClient,
+    tta_impl::TTA,
+    utils::block_datetime,
+};
+
+// Days reported on at once when catching up.
+const CHUNK_DAYS: i64 = 7;
+// A day is materialized once the indexer is this far past its end.
+const INDEXER_LAG_MINUTES: i64 = 60;
+const NANOS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+// Stored rows are only read back when materialized with this version. Bump it when the decoding
+// or pairing of report rows, or what is stored of them, changes, the days are then materialized
+// again.
+pub const DECODER_VERSION: i32 = 2;
+
+// Per account and day rows and totals of the /tta report, for the accounts in
+// `ACTIVITY_ACCOUNTS`. They are materialized in the background once each day is over, so /tta
+// only processes the blocks since, and /tta/summary and the report size check can read them
+// instead of scanning the indexer tables.
+#[derive(Clone)]
+pub struct ActivityMaintainer {
+    tta: TTA,
+    sql_client: SqlClient,
+    accounts: Vec<String>,
+    backfill_days: i64,
+    refresh_interval: std::time::Duration,
+}
+
+impl ActivityMaintainer {
+    pub fn new(tta: TTA, sql_client: SqlClient) -> Self {
+        Self {
+            tta,
+            sql_client,
+            accounts: vec![],
+            backfill_days: ACTIVITY_BACKFILL_DAYS,
+            refresh_interval: std::time::Duration::from_secs(ACTIVITY_REFRESH_SECS),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.accounts = config.activity_accounts.iter().cloned().collect();
+        self.accounts.sort();
+        self.backfill_days = config.activity_backfill_days;
+        self.refresh_interval = std::time::Duration::from_secs(config.activity_refresh_secs);
+        self
+    }
+
+    // Keeps the tables up to date until the process exits. Does nothing without accounts.
+    pub fn spawn(self) {
+        if self.accounts.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                self.refresh().await;
+                tokio::time::sleep(self.refresh_interval).await;
+            }
+        });
+    }
+
+    async fn refresh(&self) {
+        let end_day = (Utc::now() - Duration::minutes(INDEXER_LAG_MINUTES)).date_naive();
+        for account in &self.accounts {
+            if let Err(e) = self.catch_up(account, end_day).await {
+                error!(?e, account, "Error materializing account activity");
+            }
+        }
+    }
+
+    // Materializes every day of the account from the last one stored up to `end_day`, excluded.
+    // Days stored by another decoder version are materialized again, from the first of them.
+    async fn catch_up(&self, account: &str, end_day: NaiveDate) -> Result<()> {
+        let stale_day = self
+            .sql_client
+            .first_stale_account_activity_day(account, DECODER_VERSION)
+            .await?;
+        let mut day = match (
+            stale_day,
+            self.sql_client.last_account_activity_day(account).await?,
+        ) {
+            (Some(stale_day), _) => stale_day,
+            (None, Some(last_day)) => last_day + Duration::days(1),
+            (None, None) => end_day - Duration::days(self.backfill_days),
+        };
+        while day < end_day {
+            let chunk_end = (day + Duration::days(CHUNK_DAYS)).min(end_day);
+            self.materialize(account, day, chunk_end).await?;
+            day = chunk_end;
+        }
+
+        Ok(())
+    }
+
+    async fn materialize(
+        &self,
+        account: &str,
+        start_day: NaiveDate,
+        end_day: NaiveDate,
+    ) -> Result<()> {
+        let rows = self
+            .tta
+            .get_txns_report(
+                day_start(start_day),
+                day_start(end_day),
+                HashSet::from([account.to_string()]),
+                // Stored with balances so the rows can serve any report, see
+                // `TTA::incremental_txns_report`.
+                true,
+                Arc::new(RwLock::new(TxnsReportWithMetadata::default())),
+                Arc::new(ReportProgress::default()),
+            )
+            .await?;
+
+        let mut rows_by_day: BTreeMap<NaiveDate, Vec<&ReportRow>> = BTreeMap::new();
+        for row in &rows {
+            let day = block_datetime(row.block_timestamp, Tz::UTC).date_naive();
+            rows_by_day.entry(day).or_default().push(row);
+        }
+
+        // Days are stored in order, the last one stored is where the next run resumes.
+        let mut day = start_day;
+        while day < end_day {
+            let rows = rows_by_day.remove(&day).unwrap_or_default();
+            self.sql_client
+                .save_account_activity(
+                    account,
+                    day,
+                    &rows,
+                    &summarize(rows.iter().copied()),
+                    DECODER_VERSION,
+                )
+                .await?;
+            day += Duration::days(1);
+        }
+        info!(account, %start_day, %end_day, "Materialized account activity");
+
+        Ok(())
+    }
+}
+
+pub(super) fn day_start(day: NaiveDate) -> u128 {
+    day.and_hms_opt(0, 0, 0)
+        .expect("Midnight is a valid time")
+        .timestamp_nanos() as u128
+}
+
+// The UTC days covered by [start_date, end_date), when both are midnights.
+fn whole_days(start_date: u128, end_date: u128) -> Option<(NaiveDate, NaiveDate)> {
+    let day = |nanos: u128| {
+        if nanos % NANOS_PER_DAY != 0 {
+            return None;
+        }
+        NaiveDateTime::from_timestamp_opt((nanos / 1_000_000_000) as i64, 0).map(|d| d.date())
+    };
+    let (start_day, end_day) = (day(start_date)?, day(end_date)?);
+    (start_day < end_day).then_some((start_day, end_day))
+}
+
+// [start, end) of the stored report rows of the account.
+pub async fn stored_rows_range(
+    sql_client: &SqlClient,
+    account: &str,
+) -> Result<Option<(u128, u128)>> {
+    Ok(sql_client
+        .get_account_activity_days(account, DECODER_VERSION)
+        .await?
+        .map(|(first_day, last_day)| {
+            (
+                day_start(first_day),
+                day_start(last_day + Duration::days(1)),
+            )
+        }))
+}
+
+// The report rows of the range, if every day of it is materialized for every account.
+async fn materialized_rows(
+    sql_client: &SqlClient,
+    accounts: &[String],
+    start_day: NaiveDate,
+    end_day: NaiveDate,
+) -> Result<Option<u64>> {
+    if accounts.is_empty() {
+        return Ok(None);
+    }
+    let (days, row_count) = sql_client
+        .count_account_activity_days(accounts, start_day, end_day, DECODER_VERSION)
+        .await?;
+    let expected_days = (end_day - start_day).num_days() as u64 * accounts.len() as u64;
+
+    Ok((days == expected_days).then_some(row_count))
+}
+
+// Report rows over the range, when it is materialized for all the accounts.
+pub async fn materialized_row_count(
+    sql_client: &SqlClient,
+    start_date: u128,
+    end_date: u128,
+    accounts: &HashSet<String>,
+) -> Result<Option<u64>> {
+    let Some((start_day, end_day)) = whole_days(start_date, end_date) else {
+        return Ok(None);
+    };
+    let accounts: Vec<String> = accounts.iter().cloned().collect();
+
+    materialized_rows(sql_client, &accounts, start_day, end_day).await
+}
+
+// The /tta/summary of the range, when it is materialized for all the accounts.
+pub async fn materialized_summary(
+    sql_client: &SqlClient,
+    start_date: u128,
+    end_date: u128,
+    accounts: &HashSet<String>,
+) -> Result<Option<Vec<SummaryRow>>> {
+    let Some((start_day, end_day)) = whole_days(start_date, end_date) else {
+        return Ok(None);
+    };
+    let accounts: Vec<String> = accounts.iter().cloned().collect();
+    if materialized_rows(sql_client, &accounts, start_day, end_day)
+        .await?
+        .is_none()
+    {
+        return Ok(None);
+    }
+
+    let mut summary = sql_client
+        .get_account_activity_summary(&accounts, start_day, end_day)
+        .await?;
+    // Stored with their symbols as is, collisions are only known over the whole summary.
+    disambiguate_summary(&mut summary);
+
+    Ok(Some(summary))
+}
@@ -0,0 +1,219 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use super::{models::ReportRow, utils::block_datetime};
+
+// Every token movement of a row as (token, signed amount), NEAR included.
+fn row_flows(row: &ReportRow) -> Vec<(String, f64)> {
+    contract_flows(row)
+        .into_iter()
+        .map(|(_, token, amount)| (token, amount))
+        .collect()
+}
+
+// `row_flows` with the token contract of each movement, empty for NEAR.
+fn contract_flows(row: &ReportRow) -> Vec<(String, String, f64)> {
+    let mut flows = vec![];
+    if row.amount_transferred != 0.0 {
+        flows.push((
+            String::new(),
+            row.currency_transferred.clone(),
+            row.amount_transferred,
+        ));
+    }
+    if let (Some(amount), Some(token)) = (row.ft_amount_in, &row.ft_currency_in) {
+        let contract = row.ft_token_contract_in.clone().unwrap_or_default();
+        flows.push((contract, token.clone(), amount));
+    }
+    if let (Some(amount), Some(token)) = (row.ft_amount_out, &row.ft_currency_out) {
+        let contract = row.ft_token_contract_out.clone().unwrap_or_default();
+        flows.push((contract, token.clone(), -amount));
+    }
+    flows
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SummaryRow {
+    pub account_id: String,
+    pub token: String,
+    // Empty for NEAR.
+    pub token_contract: String,
+    pub total_in: f64,
+    pub total_out: f64,
+    pub net: f64,
+    pub tx_count: usize,
+}
+
+#[derive(Default)]
+struct Totals {
+    total_in: f64,
+    total_out: f64,
+    transactions: HashSet<String>,
+}
+
+impl Totals {
+    fn add(&mut self, amount: f64, transaction_hash: &str) {
+        if amount >= 0.0 {
+            self.total_in += amount;
+        } else {
+            self.total_out += -amount;
+        }
+        self.transactions.insert(transaction_hash.to_string());
+    }
+}
+
+// Per account and token totals over the report rows. Tokens are told apart by contract, symbols
+// can be shared.
+pub fn summarize<'a>(rows: impl IntoIterator<Item = &'a ReportRow>) -> Vec<SummaryRow> {
+    let mut totals: BTreeMap<(String, String, String), Totals> = BTreeMap::new();
+    for row in rows {
+        for (token_contract, token, amount) in contract_flows(row) {
+            totals
+                .entry((row.account_id.clone(), token, token_contract))
+                .or_default()
+                .add(amount, &row.transaction_hash);
+        }
+    }
+
+    totals
+        .into_iter()
+        .map(|((account_id, token, token_contract), totals)| SummaryRow {
+            account_id,
+            token,
+            token_contract,
+            total_in: totals.total_in,
+            total_out: totals.total_out,
+            net: totals.total_in - totals.total_out,
+            tx_count: totals.transactions.len(),
+        })
+        .collect()
+}
+
+// `disambiguate_symbols` for totals summarized from rows that weren't, e.g. the materialized ones.
+pub fn disambiguate_summary(rows: &mut [SummaryRow]) {
+    let mut contracts: HashMap<String, HashSet<String>> = HashMap::new();
+    for row in rows.iter().filter(|row| !row.token_contract.is_empty()) {
+        contracts
+            .entry(row.token.clone())
+            .or_default()
+            .insert(row.token_contract.clone());
+    }
+
+    for row in rows.iter_mut() {
+        if !row.token_contract.is_empty()
+            && contracts.get(&row.token).map_or(false, |c| c.len() > 1)
+        {
+            row.token = format!("{} ({})", row.token, row.token_contract);
+        }
+    }
+    rows.sort_by(|a, b| {
+        (&a.account_id, &a.token, &a.token_contract).cmp(&(
+            &b.account_id,
+            &b.token,
+            &b.token_contract,
+        ))
+    });
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct MonthlyRow {
+    pub month: String,
+    pub token: String,
+    pub inflow: f64,
+    pub outflow: f64,
+    pub net: f64,
+}
+
+// "2023-01" for a block timestamp in nanoseconds, in `tz`.
+fn month_of(block_timestamp: u128, tz: Tz) -> String {
+    block_datetime(block_timestamp, tz)
+        .format("%Y-%m")
+        .to_string()
+}
+
+// Inflow, outflow and net per calendar month of `tz` and token, over all accounts of the report.
+pub fn monthly<'a>(rows: impl IntoIterator<Item = &'a ReportRow>, tz: Tz) -> Vec<MonthlyRow> {
+    let mut totals: BTreeMap<(String, String), Totals> = BTreeMap::new();
+    for row in rows {
+        let month = month_of(row.block_timestamp, tz);
+        for (token, amount) in row_flows(row) {
+            totals
+                .entry((month.clone(), token))
+                .or_default()
+                .add(amount, &row.transaction_hash);
+        }
+    }
+
+    totals
+        .into_iter()
+        .map(|((month, token), totals)| MonthlyRow {
+            month,
+            token,
+            inflow: totals.total_in,
+            outflow: totals.total_out,
+            net: totals.total_in - totals.total_out,
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct CounterpartyRow {
+    pub counterparty: String,
+    pub token: String,
+    pub total_in: f64,
+    pub total_out: f64,
+    pub net: f64,
+    pub tx_count: usize,
+}
+
+// Totals per counterparty and token, sorted by volume. With `top`, only the biggest
+// counterparties of each token are kept.
+pub fn by_counterparty<'a>(
+    rows: impl IntoIterator<Item = &'a ReportRow>,
+    top: Option<usize>,
+) -> Vec<CounterpartyRow> {
+    let mut totals: BTreeMap<(String, String), Totals> = BTreeMap::new();
+    for row in rows {
+        for (token, amount) in row_flows(row) {
+            totals
+                .entry((token, row.counterparty().to_string()))
+                .or_default()
+                .add(amount, &row.transaction_hash);
+        }
+    }
+
+    let mut rows: Vec<CounterpartyRow> = totals
+        .into_iter()
+        .map(|((token, counterparty), totals)| CounterpartyRow {
+            counterparty,
+            token,
+            total_in: totals.total_in,
+            total_out: totals.total_out,
+            net: totals.total_in - totals.total_out,
+            tx_count: totals.transactions.len(),
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        a.token.cmp(&b.token).then(
+            (b.total_in + b.total_out)
+                .partial_cmp(&(a.total_in + a.total_out))
+                .unwrap_or(std::cmp::Ordering::Equal),
+        )
+    });
+
+    match top {
+        Some(top) => {
+            let mut kept: BTreeMap<String, usize> = BTreeMap::new();
+            rows.into_iter()
+                .filter(|row| {
+                    let count = kept.entry(row.token.clone()).or_default();
+                    *count += 1;
+                    *count <= top
+                })
+                .collect()
+        }
+        None => rows,
+    }
+}
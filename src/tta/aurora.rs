@@ -0,0 +1,105 @@
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::Value;
+
+use super::sql::models::TaArgs;
+
+pub const AURORA: &str = "aurora";
+
+// Address on the Aurora side of a call, `0x` prefixed: the recipient of tokens sent to the engine,
+// or the target of an EVM transaction submitted to it.
+pub fn aurora_evm_address(receiver: &str, method_name: &str, txn_args: &TaArgs) -> Option<String> {
+    let args = general_purpose::STANDARD
+        .decode(txn_args.args_base64.as_ref()?)
+        .ok()?;
+    match method_name {
+        "ft_transfer_call" => {
+            let args: Value = serde_json::from_slice(&args).ok()?;
+            if args["receiver_id"].as_str()? != AURORA {
+                return None;
+            }
+            msg_evm_address(args["msg"].as_str()?)
+        }
+        "submit" if receiver == AURORA => submitted_tx_to(&args),
+        _ => None,
+    }
+}
+
+// The `msg` of deposits to Aurora is the recipient address, hex. Deposits of ETH prefix it with
+// `<relayer>:` and the fee, 32 bytes.
+pub fn msg_evm_address(msg: &str) -> Option<String> {
+    let hex = msg.rsplit(':').next()?.trim_start_matches("0x");
+    let address = match hex.len() {
+        40 => hex,
+        104 => &hex[64..],
+        _ => return None,
+    };
+    address
+        .chars()
+        .all(|c| c.is_ascii_hexdigit())
+        .then(|| format!("0x{}", address.to_lowercase()))
+}
+
+// `to` of an RLP encoded transaction, legacy or typed (EIP-2930 and EIP-1559). Contract creations
+// have none.
+fn submitted_tx_to(tx: &[u8]) -> Option<String> {
+    let (to_index, tx) = match tx.first()? {
+        0x01 => (4, &tx[1..]),
+        0x02 => (5, &tx[1..]),
+        _ => (3, tx),
+    };
+    let (mut fields, _) = rlp_item(tx)?;
+    for _ in 0..to_index {
+        fields = rlp_item(fields)?.1;
+    }
+    let (to, _) = rlp_item(fields)?;
+    (to.len() == 20).then(|| {
+        let hex: String = to.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("0x{}", hex)
+    })
+}
+
+// The payload of the first RLP item, a string or a list, and what follows it.
+fn rlp_item(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let prefix = *data.first()?;
+    let (start, len) = match prefix {
+        0x00..=0x7f => (0, 1),
+        0x80..=0xb7 => (1, (prefix - 0x80) as usize),
+        0xc0..=0xf7 => (1, (prefix - 0xc0) as usize),
+        0xb8..=0xbf | 0xf8..=0xff => {
+            let len_of_len = (prefix - if prefix >= 0xf8 { 0xf7 } else { 0xb7 }) as usize;
+            let len = data
+                .get(1..1 + len_of_len)?
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            (1 + len_of_len, len)
+        }
+    };
+    let end = start.checked_add(len)?;
+    Some((data.get(start..end)?, data.get(end..)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_evm_addresses() {
+        let address = "0x1111111111111111111111111111111111111111";
+        assert_eq!(msg_evm_address(&address[2..]).as_deref(), Some(address));
+        let eth_deposit = format!("relayer.near:{}{}", "0".repeat(64), &address[2..]);
+        assert_eq!(msg_evm_address(&eth_deposit).as_deref(), Some(address));
+        assert!(msg_evm_address("alice.near").is_none());
+
+        // Legacy transaction, every field empty but `to`.
+        let mut tx = vec![0xdd, 0x80, 0x80, 0x80, 0x94];
+        tx.extend([0x11; 20]);
+        tx.extend([0x80; 5]);
+        assert_eq!(submitted_tx_to(&tx).as_deref(), Some(address));
+
+        let mut typed = vec![0x02];
+        typed.extend([0xdf, 0x80, 0x80, 0x80, 0x80, 0x80, 0x94]);
+        typed.extend([0x11; 20]);
+        typed.extend([0x80; 5]);
+        assert_eq!(submitted_tx_to(&typed).as_deref(), Some(address));
+    }
+}
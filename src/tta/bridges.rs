@@ -0,0 +1,237 @@
+use serde_json::Value;
+
+use super::aurora::{msg_evm_address, AURORA};
+
+// Cross-chain movements recognized by the bridge decoders below. Amounts are raw, in the
+// smallest unit of `token_id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeTransfer {
+    pub bridge: &'static str,
+    pub direction: BridgeDirection,
+    pub token_id: String,
+    pub amount: u128,
+    // Account on the other chain when the call tells us, the bridge contract otherwise.
+    pub counterparty: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BridgeDirection {
+    // Tokens leaving NEAR.
+    Out,
+    // Tokens arriving on NEAR.
+    In,
+}
+
+// A function call as seen by the decoders.
+pub struct BridgeCall<'a> {
+    pub predecessor: &'a str,
+    pub receiver: &'a str,
+    pub method_name: &'a str,
+    pub args: &'a Value,
+}
+
+pub trait BridgeDecoder: Sync {
+    fn decode(&self, call: &BridgeCall) -> Option<BridgeTransfer>;
+}
+
+static BRIDGE_DECODERS: &[&dyn BridgeDecoder] = &[&RainbowBridge, &Aurora, &OmniBridge, &Wormhole];
+
+// First decoder recognizing the call wins.
+pub fn decode_bridge_transfer(call: &BridgeCall) -> Option<BridgeTransfer> {
+    BRIDGE_DECODERS
+        .iter()
+        .find_map(|decoder| decoder.decode(call))
+}
+
+fn amount(args: &Value) -> Option<u128> {
+    match &args["amount"] {
+        Value::String(s) => s.parse().ok(),
+        Value::Number(n) => n.as_u64().map(u128::from),
+        _ => None,
+    }
+}
+
+fn str_arg<'a>(args: &'a Value, key: &str) -> Option<&'a str> {
+    args[key].as_str()
+}
+
+// Tokens sent with `ft_transfer_call` to one of `bridges`.
+fn ft_transfer_call_to(call: &BridgeCall, bridges: &[&str]) -> Option<(String, u128)> {
+    if call.method_name != "ft_transfer_call" {
+        return None;
+    }
+    let receiver_id = str_arg(call.args, "receiver_id")?;
+    if !bridges.contains(&receiver_id) {
+        return None;
+    }
+    Some((receiver_id.to_string(), amount(call.args)?))
+}
+
+// Rainbow bridge: bridged tokens live under factory.bridge.near, `mint` brings them in and
+// `withdraw` burns them to the Ethereum recipient, reported as `eth:<address>`.
+struct RainbowBridge;
+
+const RAINBOW_FACTORY_SUFFIX: &str = ".factory.bridge.near";
+
+impl BridgeDecoder for RainbowBridge {
+    fn decode(&self, call: &BridgeCall) -> Option<BridgeTransfer> {
+        if !call.receiver.ends_with(RAINBOW_FACTORY_SUFFIX) {
+            return None;
+        }
+        let (direction, counterparty) = match call.method_name {
+            "mint" => (BridgeDirection::In, call.predecessor.to_string()),
+            // The recipient is the Ethereum address, hex without the 0x prefix.
+            "withdraw" => (
+                BridgeDirection::Out,
+                str_arg(call.args, "recipient")
+                    .map(|recipient| format!("eth:{}", recipient.trim_start_matches("0x")))
+                    .unwrap_or_else(|| call.receiver.to_string()),
+            ),
+            _ => return None,
+        };
+
+        Some(BridgeTransfer {
+            bridge: "rainbow",
+            direction,
+            token_id: call.receiver.to_string(),
+            amount: amount(call.args)?,
+            counterparty,
+        })
+    }
+}
+
+// Aurora: tokens sent to the `aurora` engine with the Ethereum address as `msg`.
+struct Aurora;
+
+impl BridgeDecoder for Aurora {
+    fn decode(&self, call: &BridgeCall) -> Option<BridgeTransfer> {
+        let (_, amount) = ft_transfer_call_to(call, &[AURORA])?;
+        let counterparty = str_arg(call.args, "msg")
+            .and_then(msg_evm_address)
+            .unwrap_or_else(|| AURORA.to_string());
+
+        Some(BridgeTransfer {
+            bridge: "aurora",
+            direction: BridgeDirection::Out,
+            token_id: call.receiver.to_string(),
+            amount,
+            counterparty,
+        })
+    }
+}
+
+// Omni bridge: tokens locked with `ft_transfer_call`, the recipient is in the JSON `msg`.
+struct OmniBridge;
+
+const OMNI_BRIDGES: &[&str] = &["omni.bridge.near", "omni-locker.near"];
+
+impl BridgeDecoder for OmniBridge {
+    fn decode(&self, call: &BridgeCall) -> Option<BridgeTransfer> {
+        if OMNI_BRIDGES.contains(&call.predecessor) && call.method_name == "ft_transfer" {
+            return Some(BridgeTransfer {
+                bridge: "omni",
+                direction: BridgeDirection::In,
+                token_id: call.receiver.to_string(),
+                amount: amount(call.args)?,
+                counterparty: call.predecessor.to_string(),
+            });
+        }
+
+        let (bridge, amount) = ft_transfer_call_to(call, OMNI_BRIDGES)?;
+        let counterparty = str_arg(call.args, "msg")
+            .and_then(|msg| serde_json::from_str::<Value>(msg).ok())
+            .and_then(|msg| msg["recipient"].as_str().map(String::from))
+            .unwrap_or(bridge);
+
+        Some(BridgeTransfer {
+            bridge: "omni",
+            direction: BridgeDirection::Out,
+            token_id: call.receiver.to_string(),
+            amount,
+            counterparty,
+        })
+    }
+}
+
+// Wormhole (Portal): wrapped assets are deployed under contract.portalbridge.near and minted by
+// it, native tokens are sent to it with `ft_transfer_call`.
+struct Wormhole;
+
+const WORMHOLE_TOKEN_BRIDGE: &str = "contract.portalbridge.near";
+
+impl BridgeDecoder for Wormhole {
+    fn decode(&self, call: &BridgeCall) -> Option<BridgeTransfer> {
+        if call.predecessor == WORMHOLE_TOKEN_BRIDGE
+            && matches!(call.method_name, "ft_transfer" | "vaa_transfer" | "mint")
+        {
+            return Some(BridgeTransfer {
+                bridge: "wormhole",
+                direction: BridgeDirection::In,
+                token_id: call.receiver.to_string(),
+                amount: amount(call.args)?,
+                counterparty: WORMHOLE_TOKEN_BRIDGE.to_string(),
+            });
+        }
+
+        let (bridge, amount) = ft_transfer_call_to(call, &[WORMHOLE_TOKEN_BRIDGE])?;
+
+        Some(BridgeTransfer {
+            bridge: "wormhole",
+            direction: BridgeDirection::Out,
+            token_id: call.receiver.to_string(),
+            amount,
+            counterparty: bridge,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn decodes_bridge_calls() {
+        let args = json!({ "amount": "1000", "recipient": "a1b2c3" });
+        let transfer = decode_bridge_transfer(&BridgeCall {
+            predecessor: "alice.near",
+            receiver: "a0b86991c6218b36c1d19d4a2e9eb0ce3606eb48.factory.bridge.near",
+            method_name: "withdraw",
+            args: &args,
+        })
+        .unwrap();
+        assert_eq!(transfer.bridge, "rainbow");
+        assert_eq!(transfer.direction, BridgeDirection::Out);
+        assert_eq!(transfer.amount, 1000);
+        assert_eq!(transfer.counterparty, "eth:a1b2c3");
+
+        let args = json!({
+            "receiver_id": "aurora",
+            "amount": "5",
+            "msg": "0x1111111111111111111111111111111111111111",
+        });
+        let transfer = decode_bridge_transfer(&BridgeCall {
+            predecessor: "alice.near",
+            receiver: "wrap.near",
+            method_name: "ft_transfer_call",
+            args: &args,
+        })
+        .unwrap();
+        assert_eq!(transfer.bridge, "aurora");
+        assert_eq!(transfer.token_id, "wrap.near");
+        assert_eq!(
+            transfer.counterparty,
+            "0x1111111111111111111111111111111111111111"
+        );
+
+        let args = json!({ "receiver_id": "bob.near", "amount": "5" });
+        assert!(decode_bridge_transfer(&BridgeCall {
+            predecessor: "alice.near",
+            receiver: "wrap.near",
+            method_name: "ft_transfer_call",
+            args: &args,
+        })
+        .is_none());
+    }
+}
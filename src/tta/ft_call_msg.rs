@@ -0,0 +1,92 @@
+use near_primitives::types::AccountId;
+use serde_json::Value;
+
+// Tokens sent with `ft_transfer_call` land on the receiver contract, but for deposit flows the
+// account they are credited to is in `msg`. The decoders below tell it for the contracts we know.
+pub trait FtCallMsgDecoder: Sync {
+    // `None` when the call is not for this contract, or the msg doesn't name anyone.
+    fn destination(&self, receiver_id: &str, sender_id: &str, msg: &str) -> Option<String>;
+}
+
+static FT_CALL_MSG_DECODERS: &[&dyn FtCallMsgDecoder] = &[&Intents, &RefFinance];
+
+// First decoder recognizing the call wins. Bridges are decoded beforehand, see `bridges`.
+pub fn ft_call_destination(receiver_id: &str, sender_id: &str, msg: &str) -> Option<String> {
+    FT_CALL_MSG_DECODERS
+        .iter()
+        .find_map(|decoder| decoder.destination(receiver_id, sender_id, msg))
+}
+
+fn account_id(value: &str) -> Option<String> {
+    value
+        .parse::<AccountId>()
+        .ok()
+        .map(|account_id| account_id.to_string())
+}
+
+// NEAR Intents: the msg is the account to credit, or JSON with it as `receiver_id`. Deposits
+// without one are credited to the sender.
+struct Intents;
+
+const INTENTS: &str = "intents.near";
+
+impl FtCallMsgDecoder for Intents {
+    fn destination(&self, receiver_id: &str, _sender_id: &str, msg: &str) -> Option<String> {
+        if receiver_id != INTENTS {
+            return None;
+        }
+        let beneficiary = match serde_json::from_str::<Value>(msg) {
+            Ok(msg) => msg["receiver_id"].as_str().and_then(account_id),
+            Err(_) => account_id(msg),
+        };
+        beneficiary.map(|account| format!("{}:{}", INTENTS, account))
+    }
+}
+
+// Ref Finance: an empty msg deposits the tokens to the sender's Ref account, swaps (a JSON msg
+// with `actions`) send the output back to the sender and are left alone.
+struct RefFinance;
+
+const REF_FINANCE: &str = "v2.ref-finance.near";
+
+impl FtCallMsgDecoder for RefFinance {
+    fn destination(&self, receiver_id: &str, sender_id: &str, msg: &str) -> Option<String> {
+        if receiver_id != REF_FINANCE || !msg.trim().is_empty() {
+            return None;
+        }
+        Some(format!("{}:{}", REF_FINANCE, sender_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_ft_call_destinations() {
+        assert_eq!(
+            ft_call_destination("intents.near", "alice.near", "bob.near").as_deref(),
+            Some("intents.near:bob.near")
+        );
+        assert_eq!(
+            ft_call_destination(
+                "intents.near",
+                "alice.near",
+                r#"{"receiver_id":"bob.near"}"#
+            )
+            .as_deref(),
+            Some("intents.near:bob.near")
+        );
+        assert_eq!(
+            ft_call_destination("v2.ref-finance.near", "alice.near", "").as_deref(),
+            Some("v2.ref-finance.near:alice.near")
+        );
+        assert!(ft_call_destination(
+            "v2.ref-finance.near",
+            "alice.near",
+            r#"{"force":0,"actions":[]}"#
+        )
+        .is_none());
+        assert!(ft_call_destination("bob.near", "alice.near", "bob.near").is_none());
+    }
+}
@@ -1,7 +1,14 @@
 use anyhow::{bail, Result};
-use governor::{Quota, RateLimiter};
+use futures_util::{stream, StreamExt};
 use lru::LruCache;
-use near_jsonrpc_client::JsonRpcClient;
+use near_jsonrpc_client::{
+    errors::{
+        JsonRpcError, JsonRpcServerError, JsonRpcServerResponseStatusError,
+        JsonRpcTransportSendError, RpcTransportError,
+    },
+    methods::block::RpcBlockRequest,
+    JsonRpcClient,
+};
 use near_jsonrpc_primitives::types::query::{
     QueryResponseKind, RpcQueryError, RpcQueryRequest, RpcQueryResponse,
 };
@@ -14,20 +21,27 @@ use near_primitives::{
     },
     views::{CallResult, QueryRequest},
 };
-use serde::{Deserialize, Serialize};
-use serde_json::json;
+use near_sdk::json_types::U128;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
 use std::{
     collections::HashMap,
-    num::{NonZeroU32, NonZeroUsize},
+    num::NonZeroUsize,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{join, sync::RwLock};
 use tracing::{debug, error};
-use tta_rust::RateLim;
+use tta_rust::{metrics::metrics, rate_limiter::AdaptiveRateLimiter};
 
 use std::hash::{Hash, Hasher};
 
-use crate::tta::tta_impl::safe_divide_u128;
+use crate::{
+    config::{
+        ConcurrencyConfig, ProviderQuota, TokenFilter, ARCHIVAL_RPC_QUOTA, RPC_PIPELINE_SIZE,
+    },
+    tta::{sql::sql_queries::SqlClient, tta_impl::safe_divide_u128},
+};
 
 #[derive(Debug, Clone)]
 pub struct CompositeKey {
@@ -54,53 +68,353 @@ impl Hash for CompositeKey {
     }
 }
 
+// Balance of a token, or NEAR, held by an account at a block. `balance` is unset when it could
+// not be read.
+#[derive(Debug, Clone)]
+pub struct TokenBalance {
+    pub token_id: String,
+    pub symbol: String,
+    pub balance: Option<f64>,
+    pub spam: bool,
+    // NEAR locked by validator stake, and bytes of state the account pays storage for, NEAR only.
+    pub locked: Option<f64>,
+    pub storage_usage: Option<u64>,
+    // Why `balance` could not be read.
+    pub error: Option<String>,
+}
+
+// yoctoNEAR locked per byte of state.
+const STORAGE_PRICE_PER_BYTE: u128 = 10_000_000_000_000_000_000;
+
+// NEAR locked to pay for `storage_usage` bytes of state.
+pub fn storage_locked(storage_usage: u64) -> f64 {
+    safe_divide_u128(storage_usage as u128 * STORAGE_PRICE_PER_BYTE, 24)
+}
+
+const VIEW_ACCOUNT_ATTEMPTS: u32 = 3;
+// Doubled after each failed attempt, so throttled calls don't spend the rate limit right after it
+// was lowered.
+const VIEW_ACCOUNT_BACKOFF: Duration = Duration::from_millis(500);
+
+// A `view_account` that failed for another reason than the account not existing at the block.
+// Retryable failures are the provider's, e.g. a timeout or a node still syncing, the others the
+// query's, e.g. a block the node does not have.
+#[derive(Debug)]
+pub struct ViewAccountError {
+    pub account_id: String,
+    pub block_id: u64,
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl std::fmt::Display for ViewAccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Error calling ViewAccount for {} at {}: {}",
+            self.account_id, self.block_id, self.message
+        )
+    }
+}
+
+impl std::error::Error for ViewAccountError {}
+
+// Account state as returned by `view_account`, amounts in NEAR.
+#[derive(Debug, Clone, Copy)]
+pub struct NearBalance {
+    pub amount: f64,
+    pub locked: f64,
+    pub storage_usage: u64,
+}
+
+impl NearBalance {
+    pub fn storage_locked(&self) -> f64 {
+        storage_locked(self.storage_usage)
+    }
+}
+
+// Balances of a delegator as returned by the pool's `get_accounts`, in yoctoNEAR.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PoolAccount {
+    pub account_id: String,
+    pub unstaked_balance: U128,
+    pub staked_balance: U128,
+    pub can_withdraw: bool,
+}
+
+const POOL_ACCOUNTS_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+struct RewardFeeFraction {
+    numerator: u32,
+    denominator: u32,
+}
+
+// Operator provided metadata, for tokens whose on-chain metadata is wrong or whose contract no
+// longer responds. Stored in `tta_ft_metadata_overrides` and applied over the on-chain metadata;
+// a token with both fields set is not looked up at all, one with its decimals set is still
+// reported when the lookup fails.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FtMetadataOverride {
+    pub token_id: String,
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    // Skip the balance call entirely and report zero, for contracts that misbehave.
+    #[serde(default)]
+    pub zero_balance: bool,
+}
+
+impl FtMetadataOverride {
+    fn apply(&self, mut metadata: FtMetadata) -> FtMetadata {
+        if let Some(symbol) = &self.symbol {
+            metadata.symbol = symbol.clone();
+        }
+        if let Some(decimals) = self.decimals {
+            metadata.decimals = decimals;
+        }
+        metadata
+    }
+
+    fn metadata(&self) -> Option<FtMetadata> {
+        self.symbol.as_ref()?;
+        self.fallback_metadata()
+    }
+
+    // Without a symbol the token is named after its contract.
+    fn fallback_metadata(&self) -> Option<FtMetadata> {
+        let symbol = self.symbol.clone().unwrap_or_else(|| self.token_id.clone());
+        Some(FtMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: symbol.clone(),
+            symbol,
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: self.decimals?,
+        })
+    }
+}
+
+// Deserialization is lenient: plenty of live tokens omit fields or return decimals as strings.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct FtMetadata {
+    #[serde(default)]
     pub spec: String,
+    #[serde(default)]
     pub name: String,
+    #[serde(default)]
     pub symbol: String,
     pub icon: Option<String>,
     pub reference: Option<String>,
     pub reference_hash: Option<String>,
+    #[serde(deserialize_with = "deserialize_lenient_u8")]
     pub decimals: u8,
 }
 
+fn deserialize_lenient_u8<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<u8, D::Error> {
+    match Value::deserialize(d)? {
+        Value::Number(n) => n.as_u64().and_then(|n| u8::try_from(n).ok()),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+    .ok_or_else(|| serde::de::Error::custom("invalid decimals"))
+}
+
+// `ft_balance_of` is supposed to return a string, some tokens return a plain number instead.
+fn parse_lenient_u128(result: &[u8]) -> Result<u128> {
+    match serde_json::from_slice::<Value>(result)? {
+        Value::String(s) => Ok(s.trim().parse::<u128>()?),
+        Value::Number(n) => match (n.as_u64(), n.as_f64()) {
+            (Some(n), _) => Ok(n as u128),
+            (None, Some(n)) if n >= 0.0 => Ok(n as u128),
+            _ => bail!("Invalid amount: {}", n),
+        },
+        v => bail!("Invalid amount: {}", v),
+    }
+}
+
+// Real tokens use at most 24 decimals (NEAR itself).
+const MAX_SANE_DECIMALS: u8 = 32;
+
+fn contains_url(s: &str) -> bool {
+    let s = s.to_lowercase();
+    [
+        "http", "www.", ".com", ".io", ".xyz", ".org", ".net", "t.me/",
+    ]
+    .iter()
+    .any(|pattern| s.contains(pattern))
+}
+
+// Where a balance was read from, to verify it again against the same node and block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceSource {
+    pub rpc_endpoint: String,
+    pub block_height: u64,
+    // Served from `ft_balances_cache`, read from `rpc_endpoint` by an earlier lookup.
+    pub cached: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct FtService {
     pub ft_metadata_cache: Arc<RwLock<HashMap<String, FtMetadata>>>,
     pub ft_balances_cache: Arc<RwLock<LruCache<CompositeKey, f64>>>,
     pub near_client: JsonRpcClient,
-    pub archival_rate_limiter: Arc<RwLock<RateLim>>,
+    pub archival_rate_limiter: Arc<AdaptiveRateLimiter>,
     pub likely_tokens: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    metadata_store: Option<SqlClient>,
+    metadata_overrides: Arc<RwLock<HashMap<String, FtMetadataOverride>>>,
+    token_filter: Arc<TokenFilter>,
+    spam_cache: Arc<RwLock<HashMap<String, bool>>>,
+    spam_check_holders: bool,
+    epoch_ids_cache: Arc<RwLock<LruCache<u64, String>>>,
+    rpc_pipeline_size: usize,
 }
 
 impl FtService {
     pub fn new(near_client: JsonRpcClient) -> Self {
+        let archival_rate_limiter =
+            Arc::new(ARCHIVAL_RPC_QUOTA.rate_limiter(near_client.server_addr()));
         FtService {
             ft_metadata_cache: Arc::new(RwLock::new(HashMap::new())),
             ft_balances_cache: Arc::new(RwLock::new(LruCache::new(
                 NonZeroUsize::new(1_000_000).unwrap(),
             ))),
             near_client,
-            archival_rate_limiter: Arc::new(RwLock::new(RateLimiter::direct(Quota::per_second(
-                NonZeroU32::new(5_000_000u32).unwrap(),
-            )))),
+            archival_rate_limiter,
             likely_tokens: Arc::new(RwLock::new(HashMap::new())),
+            metadata_store: None,
+            metadata_overrides: Arc::new(RwLock::new(HashMap::new())),
+            token_filter: Arc::new(TokenFilter::default()),
+            spam_cache: Arc::new(RwLock::new(HashMap::new())),
+            spam_check_holders: false,
+            epoch_ids_cache: Arc::new(RwLock::new(LruCache::new(
+                NonZeroUsize::new(100_000).unwrap(),
+            ))),
+            rpc_pipeline_size: RPC_PIPELINE_SIZE,
+        }
+    }
+
+    pub fn with_concurrency(mut self, concurrency: &ConcurrencyConfig) -> Self {
+        self.rpc_pipeline_size = concurrency.rpc_pipeline_size;
+        self
+    }
+
+    pub fn with_quota(mut self, quota: &ProviderQuota) -> Self {
+        self.archival_rate_limiter = Arc::new(quota.rate_limiter(self.near_client.server_addr()));
+        self
+    }
+
+    pub fn with_token_filter(mut self, token_filter: &TokenFilter) -> Self {
+        self.token_filter = Arc::new(token_filter.clone());
+        self
+    }
+
+    pub fn with_spam_check_holders(mut self, spam_check_holders: bool) -> Self {
+        self.spam_check_holders = spam_check_holders;
+        self
+    }
+
+    // Token metadata is effectively immutable, so it is persisted to the DB and reloaded on
+    // start instead of being re-fetched from the archival RPC after every deploy.
+    pub fn with_metadata_store(mut self, sql_client: SqlClient) -> Self {
+        self.metadata_store = Some(sql_client);
+        self
+    }
+
+    pub async fn load_metadata_cache(&self) -> Result<usize> {
+        let store = match &self.metadata_store {
+            Some(store) => store,
+            None => return Ok(0),
+        };
+        let cached = store.get_ft_metadata_cache().await?;
+        let count = cached.len();
+
+        let mut w = self.ft_metadata_cache.write().await;
+        w.extend(cached);
+
+        let overrides = store.get_ft_metadata_overrides().await?;
+        self.metadata_overrides
+            .write()
+            .await
+            .extend(overrides.into_iter().map(|o| (o.token_id.clone(), o)));
+
+        Ok(count)
+    }
+
+    pub async fn metadata_overrides(&self) -> Vec<FtMetadataOverride> {
+        let mut overrides: Vec<FtMetadataOverride> = self
+            .metadata_overrides
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        overrides.sort_by(|a, b| a.token_id.cmp(&b.token_id));
+        overrides
+    }
+
+    pub async fn set_metadata_override(&self, metadata_override: FtMetadataOverride) -> Result<()> {
+        if let Some(store) = &self.metadata_store {
+            store.save_ft_metadata_override(&metadata_override).await?;
+        }
+        let token_id = metadata_override.token_id.clone();
+        self.metadata_overrides
+            .write()
+            .await
+            .insert(token_id.clone(), metadata_override);
+        self.evict_token(&token_id).await;
+
+        Ok(())
+    }
+
+    // Whether the token had an override.
+    pub async fn remove_metadata_override(&self, token_id: &str) -> Result<bool> {
+        if let Some(store) = &self.metadata_store {
+            store.delete_ft_metadata_override(token_id).await?;
+        }
+        let removed = self.metadata_overrides.write().await.remove(token_id);
+        self.evict_token(token_id).await;
+
+        Ok(removed.is_some())
+    }
+
+    // Balances and the spam verdict depend on the metadata.
+    async fn evict_token(&self, token_id: &str) {
+        self.spam_cache.write().await.remove(token_id);
+        let mut balances = self.ft_balances_cache.write().await;
+        let keys: Vec<CompositeKey> = balances
+            .iter()
+            .filter(|(key, _)| key.token_id == token_id)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            balances.pop(&key);
         }
     }
 
     pub async fn assert_ft_metadata(&self, ft_token_id: &str) -> Result<FtMetadata> {
-        if !self
+        let metadata_override = self
+            .metadata_overrides
+            .read()
+            .await
+            .get(ft_token_id)
+            .cloned();
+        if let Some(metadata) = metadata_override.as_ref().and_then(|o| o.metadata()) {
+            return Ok(metadata);
+        }
+
+        let cached = self
             .ft_metadata_cache
             .clone()
             .read()
             .await
-            .contains_key(ft_token_id)
-        {
-            // self.archival_rate_limiter.write().await.until_ready().await;
+            .contains_key(ft_token_id);
+        metrics().record_cache_lookup("ft_metadata", cached);
+        if !cached {
             let args = json!({}).to_string().into_bytes();
             let result = match view_function_call(
                 &self.near_client,
+                &self.archival_rate_limiter,
                 QueryRequest::CallFunction {
                     account_id: ft_token_id.parse().unwrap(),
                     method_name: "ft_metadata".to_string(),
@@ -112,6 +426,13 @@ impl FtService {
             {
                 Ok(v) => v,
                 Err(e) => {
+                    if let Some(metadata) = metadata_override
+                        .as_ref()
+                        .and_then(|o| o.fallback_metadata())
+                    {
+                        debug!(?e, "No ft_metadata for {}, using its override", ft_token_id);
+                        return Ok(metadata);
+                    }
                     bail!(
                         "Error getting ft_metadata for ft_token_id: {}, error: {:?}",
                         ft_token_id,
@@ -120,29 +441,104 @@ impl FtService {
                 }
             };
 
-            let v = serde_json::from_slice(&result)?;
+            let v: FtMetadata = serde_json::from_slice(&result)?;
+            if let Some(store) = &self.metadata_store {
+                if let Err(e) = store.save_ft_metadata(ft_token_id, &v).await {
+                    error!("Error persisting ft_metadata for {}: {:?}", ft_token_id, e);
+                }
+            }
             let e = self.ft_metadata_cache.clone();
             let mut w = e.write().await;
             w.insert(ft_token_id.to_string(), v);
         }
 
         match self.ft_metadata_cache.read().await.get(ft_token_id) {
-            Some(v) => Ok(v.clone()),
+            Some(v) => Ok(match &metadata_override {
+                Some(o) => o.apply(v.clone()),
+                None => v.clone(),
+            }),
             None => bail!("ft_metadata not found"),
         }
     }
 
-    #[tracing::instrument(skip(self))]
+    // Heuristic spam detection: absurd decimals, URLs in the name or symbol, or with
+    // `spam_check_holders` a token nobody ever held according to the DB. A token whose metadata
+    // can't be fetched is not flagged, nor is the verdict cached, the RPC may just be failing.
+    pub async fn is_likely_spam(&self, token_id: &str) -> bool {
+        let cached = self.spam_cache.read().await.get(token_id).copied();
+        metrics().record_cache_lookup("spam", cached.is_some());
+        if let Some(spam) = cached {
+            return spam;
+        }
+
+        let metadata = match self.assert_ft_metadata(token_id).await {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                debug!(?e, "No metadata to tell if {} is spam", token_id);
+                return false;
+            }
+        };
+        let spam = metadata.decimals > MAX_SANE_DECIMALS
+            || contains_url(&metadata.name)
+            || contains_url(&metadata.symbol)
+            || match &self.metadata_store {
+                Some(store) if self.spam_check_holders => {
+                    !store.has_ft_holders(token_id).await.unwrap_or(true)
+                }
+                _ => false,
+            };
+        if spam {
+            debug!("Token {} looks like spam", token_id);
+        }
+
+        self.spam_cache
+            .write()
+            .await
+            .insert(token_id.to_string(), spam);
+        spam
+    }
+
+    pub fn balance_source(&self, block_height: u64, cached: bool) -> BalanceSource {
+        BalanceSource {
+            rpc_endpoint: self.near_client.server_addr().to_string(),
+            block_height,
+            cached,
+        }
+    }
+
     pub async fn assert_ft_balance(
         &self,
         token_id: &String,
         account_id: &String,
         block_id: u64,
     ) -> Result<f64> {
-        if token_id == "kusama-airdrop.near" {
-            return Ok(0.0);
+        Ok(self
+            .sourced_ft_balance(token_id, account_id, block_id)
+            .await?
+            .0)
+    }
+
+    // The balance along with where it was read from, None for the tokens overridden to zero.
+    #[tracing::instrument(skip(self))]
+    pub async fn sourced_ft_balance(
+        &self,
+        token_id: &String,
+        account_id: &String,
+        block_id: u64,
+    ) -> Result<(f64, Option<BalanceSource>)> {
+        let zero_balance = self
+            .metadata_overrides
+            .read()
+            .await
+            .get(token_id.as_str())
+            .map_or(false, |o| o.zero_balance);
+        if zero_balance {
+            return Ok((0.0, None));
+        }
+        if !self.token_filter.is_allowed(token_id) {
+            bail!("Token {} is filtered out", token_id);
         }
-        if self
+        let cached = self
             .ft_balances_cache
             .clone()
             .read()
@@ -151,24 +547,26 @@ impl FtService {
                 block_id,
                 account_id: account_id.clone(),
                 token_id: token_id.clone(),
-            })
-        {
+            });
+        metrics().record_cache_lookup("ft_balances", cached);
+        if cached {
             debug!("Found ft_balance in cache");
             let mut w = self.ft_balances_cache.write().await;
-            return Ok(*w
+            let amount = *w
                 .get(&CompositeKey {
                     block_id,
                     account_id: account_id.clone(),
                     token_id: token_id.clone(),
                 })
-                .unwrap());
+                .unwrap();
+            return Ok((amount, Some(self.balance_source(block_id, true))));
         }
-        let metadata = self.assert_ft_metadata(token_id).await.unwrap();
+        let metadata = self.assert_ft_metadata(token_id).await?;
 
-        // self.archival_rate_limiter.write().await.until_ready().await;
         let args = json!({ "account_id": account_id }).to_string().into_bytes();
         let result = match view_function_call(
             &self.near_client,
+            &self.archival_rate_limiter,
             QueryRequest::CallFunction {
                 account_id: token_id.parse().unwrap(),
                 method_name: "ft_balance_of".to_string(),
@@ -192,8 +590,7 @@ impl FtService {
             }
         };
 
-        let amount: String = serde_json::from_slice(&result)?;
-        let amount = amount.parse::<u128>()?;
+        let amount = parse_lenient_u128(&result)?;
         let amount = safe_divide_u128(amount, metadata.decimals as u32);
 
         debug!("Got ft_balance amount: {}", amount);
@@ -207,17 +604,122 @@ impl FtService {
             amount,
         );
 
-        Ok(amount)
+        Ok((amount, Some(self.balance_source(block_id, false))))
+    }
+
+    // Balances of several tokens for the same account and block. The view calls are pipelined
+    // instead of being awaited one after the other, so a snapshot of 30 tokens costs a couple of
+    // round trips. Results are returned in the same order as `token_ids`, filtered out tokens are
+    // skipped.
+    #[tracing::instrument(skip(self, token_ids))]
+    pub async fn assert_ft_balances(
+        &self,
+        token_ids: &[String],
+        account_id: &String,
+        block_id: u64,
+    ) -> Vec<(String, Result<f64>)> {
+        let calls = token_ids
+            .iter()
+            .filter(|token_id| self.token_filter.is_allowed(token_id))
+            .map(|token_id| async move {
+                let balance = self.assert_ft_balance(token_id, account_id, block_id).await;
+                (token_id.clone(), balance)
+            });
+
+        stream::iter(calls)
+            .buffered(self.rpc_pipeline_size)
+            .collect()
+            .await
+    }
+
+    // Balances of the tokens with metadata then of NEAR, as listed by /balancesfull.
+    #[tracing::instrument(skip(self, token_ids))]
+    pub async fn account_balances(
+        &self,
+        token_ids: &[String],
+        account_id: &String,
+        block_id: u64,
+    ) -> Vec<TokenBalance> {
+        let mut balances = vec![];
+        for (token_id, balance) in self
+            .assert_ft_balances(token_ids, account_id, block_id)
+            .await
+        {
+            let metadata = match self.assert_ft_metadata(&token_id).await {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("Token fetch error: {}: {:?}", account_id, e);
+                    continue;
+                }
+            };
+            let spam = self.is_likely_spam(&token_id).await;
+            let (balance, error) = match balance {
+                Ok(v) => (Some(v), None),
+                Err(e) => {
+                    debug!("{}: {}", account_id, e);
+                    (None, Some(e.to_string()))
+                }
+            };
+            balances.push(TokenBalance {
+                token_id,
+                symbol: metadata.symbol,
+                balance,
+                spam,
+                locked: None,
+                storage_usage: None,
+                error,
+            });
+        }
+
+        let (near_balance, error) = match self.get_near_balance(account_id, block_id).await {
+            Ok(v) => (v, None),
+            Err(e) => {
+                error!("{}: {}", account_id, e);
+                (None, Some(e.to_string()))
+            }
+        };
+        balances.push(TokenBalance {
+            token_id: "NEAR".to_string(),
+            symbol: "NEAR".to_string(),
+            balance: near_balance.map(|v| v.amount),
+            spam: false,
+            locked: near_balance.map(|v| v.locked),
+            storage_usage: near_balance.map(|v| v.storage_usage),
+            error,
+        });
+
+        balances
     }
 
+    // The account's balance at the block, None when the account does not exist at the block.
+    // Retryable failures are retried a few times before being returned as `ViewAccountError`.
     #[tracing::instrument(skip(self))]
     pub async fn get_near_balance(
         &self,
         account_id: &str,
         block_id: u64,
-    ) -> Result<Option<(f64, f64)>> {
-        // self.archival_rate_limiter.write().await.until_ready().await;
-        let RpcQueryResponse { kind, .. } = match self
+    ) -> Result<Option<NearBalance>> {
+        let mut attempt = 1;
+        loop {
+            match self.view_account(account_id, block_id).await {
+                Err(e) if e.retryable && attempt < VIEW_ACCOUNT_ATTEMPTS => {
+                    debug!("{}, attempt {}", e, attempt);
+                    tokio::time::sleep(VIEW_ACCOUNT_BACKOFF * 2u32.pow(attempt - 1)).await;
+                    attempt += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    async fn view_account(
+        &self,
+        account_id: &str,
+        block_id: u64,
+    ) -> Result<Option<NearBalance>, ViewAccountError> {
+        self.archival_rate_limiter.until_ready().await;
+        let started_at = Instant::now();
+        let response = self
             .near_client
             .call(RpcQueryRequest {
                 request: QueryRequest::ViewAccount {
@@ -225,39 +727,87 @@ impl FtService {
                 },
                 block_reference: BlockReference::BlockId(Height(block_id)),
             })
-            .await
-        {
+            .await;
+        report_rpc_outcome(
+            &self.archival_rate_limiter,
+            "view_account",
+            started_at,
+            &response,
+        );
+        let error = |retryable: bool, message: String| ViewAccountError {
+            account_id: account_id.to_string(),
+            block_id,
+            retryable,
+            message,
+        };
+
+        let RpcQueryResponse { kind, .. } = match response {
             Ok(v) => v,
             Err(e) => {
-                if let Some(w) = e.handler_error() {
-                    match w {
-                        RpcQueryError::UnknownAccount { .. } => {
-                            if !account_id.ends_with("lockup.near") {
-                                error!("Unknown Account: {:?}", e); // Here's the debug print for UnknownAccount
-                            }
-                        }
-                        _ => {
-                            error!("Error calling ViewAccount: {:?}, block_id: {}", e, block_id);
+                let retryable = match e.handler_error() {
+                    Some(RpcQueryError::UnknownAccount { .. }) => {
+                        if !account_id.ends_with("lockup.near") {
+                            debug!("Unknown Account: {:?}", e);
                         }
+                        return Ok(None);
                     }
-                } else {
-                    error!("Error calling ViewAccount: {:?}", e);
-                }
-                return Ok(None);
+                    Some(
+                        RpcQueryError::NoSyncedBlocks
+                        | RpcQueryError::UnavailableShard { .. }
+                        | RpcQueryError::InternalError { .. },
+                    ) => true,
+                    Some(_) => false,
+                    None => true,
+                };
+                error!("Error calling ViewAccount: {:?}, block_id: {}", e, block_id);
+                return Err(error(retryable, format!("{:?}", e)));
             }
         };
         let view = match kind {
             QueryResponseKind::ViewAccount(view) => view,
             _ => {
                 error!("Received unexpected kind: {:?}", kind);
-                bail!("Received unexpected kind: {:?}", kind);
+                return Err(error(
+                    false,
+                    format!("Received unexpected kind: {:?}", kind),
+                ));
             }
         };
 
-        let amount = safe_divide_u128(view.amount, 24);
-        let locked = safe_divide_u128(view.locked, 24);
+        Ok(Some(NearBalance {
+            amount: safe_divide_u128(view.amount, 24),
+            locked: safe_divide_u128(view.locked, 24),
+            storage_usage: view.storage_usage,
+        }))
+    }
+
+    pub async fn get_epoch_id(&self, block_id: u64) -> Result<String> {
+        let cached = self.epoch_ids_cache.write().await.get(&block_id).cloned();
+        metrics().record_cache_lookup("epoch_ids", cached.is_some());
+        if let Some(epoch_id) = cached {
+            return Ok(epoch_id);
+        }
+
+        self.archival_rate_limiter.until_ready().await;
+        let started_at = Instant::now();
+        let response = self
+            .near_client
+            .call(RpcBlockRequest {
+                block_reference: BlockReference::BlockId(Height(block_id)),
+            })
+            .await;
+        report_rpc_outcome(&self.archival_rate_limiter, "block", started_at, &response);
 
-        Ok(Some((amount, locked)))
+        let epoch_id = match response {
+            Ok(block) => block.header.epoch_id.to_string(),
+            Err(e) => bail!("Error getting block {}: {:?}", block_id, e),
+        };
+        self.epoch_ids_cache
+            .write()
+            .await
+            .put(block_id, epoch_id.clone());
+
+        Ok(epoch_id)
     }
 
     pub async fn get_staking_details(
@@ -286,15 +836,48 @@ impl FtService {
         ))
     }
 
+    // Single call probe, used to find out whether the account has anything on a pool before
+    // asking for the detailed balances.
+    pub async fn get_total_staked_balance(
+        &self,
+        staking_pool: &str,
+        account_id: &str,
+        block_id: u64,
+    ) -> Result<u128> {
+        let args = json!({ "account_id": account_id }).to_string().into_bytes();
+        let result = view_function_call(
+            &self.near_client,
+            &self.archival_rate_limiter,
+            QueryRequest::CallFunction {
+                account_id: staking_pool.parse()?,
+                method_name: "get_account_total_balance".to_string(),
+                args: FunctionArgs::from(args),
+            },
+            BlockReference::BlockId(Height(block_id)),
+        )
+        .await;
+
+        match result {
+            Ok(v) => Ok(serde_json::from_slice::<String>(&v)?.parse::<u128>()?),
+            Err(e) => {
+                bail!(
+                    "Error getting total balance for staking pool: {}, error: {:?}",
+                    staking_pool,
+                    e
+                );
+            }
+        }
+    }
+
     async fn get_unstaked_balance(
         &self,
         staking_pool: &str,
         args: &[u8],
         block_id: u64,
     ) -> Result<u128> {
-        self.archival_rate_limiter.write().await.until_ready().await;
         let result = view_function_call(
             &self.near_client,
+            &self.archival_rate_limiter,
             QueryRequest::CallFunction {
                 account_id: staking_pool.parse()?,
                 method_name: "get_account_unstaked_balance".to_string(),
@@ -322,9 +905,9 @@ impl FtService {
         args: &[u8],
         block_id: u64,
     ) -> Result<u128> {
-        self.archival_rate_limiter.write().await.until_ready().await;
         let result = view_function_call(
             &self.near_client,
+            &self.archival_rate_limiter,
             QueryRequest::CallFunction {
                 account_id: staking_pool.parse()?,
                 method_name: "get_account_staked_balance".to_string(),
@@ -352,9 +935,9 @@ impl FtService {
         args: &[u8],
         block_id: u64,
     ) -> Result<bool> {
-        self.archival_rate_limiter.write().await.until_ready().await;
         let result = view_function_call(
             &self.near_client,
+            &self.archival_rate_limiter,
             QueryRequest::CallFunction {
                 account_id: staking_pool.parse()?,
                 method_name: "is_account_unstaked_balance_available".to_string(),
@@ -376,11 +959,122 @@ impl FtService {
         }
     }
 
+    // Owner, reward fee fraction and total staked balance of a staking pool.
+    pub async fn get_pool_details(
+        &self,
+        staking_pool: &str,
+        block_id: u64,
+    ) -> Result<(String, f64, u128)> {
+        let (owner_id, fee, total_staked) = join!(
+            self.view_pool(staking_pool, "get_owner_id", json!({}), block_id),
+            self.view_pool(staking_pool, "get_reward_fee_fraction", json!({}), block_id),
+            self.view_pool(
+                staking_pool,
+                "get_total_staked_balance",
+                json!({}),
+                block_id
+            ),
+        );
+        let owner_id: String = serde_json::from_value(owner_id?)?;
+        let fee: RewardFeeFraction = serde_json::from_value(fee?)?;
+        let total_staked: String = serde_json::from_value(total_staked?)?;
+
+        Ok((
+            owner_id,
+            fee.numerator as f64 / fee.denominator.max(1) as f64,
+            total_staked.parse()?,
+        ))
+    }
+
+    // Every account with a balance on the pool, a page at a time.
+    pub async fn get_pool_accounts(
+        &self,
+        staking_pool: &str,
+        block_id: u64,
+    ) -> Result<Vec<PoolAccount>> {
+        let mut accounts = vec![];
+        loop {
+            let args = json!({ "from_index": accounts.len(), "limit": POOL_ACCOUNTS_PAGE_SIZE });
+            let page: Vec<PoolAccount> = serde_json::from_value(
+                self.view_pool(staking_pool, "get_accounts", args, block_id)
+                    .await?,
+            )?;
+            let last_page = page.len() < POOL_ACCOUNTS_PAGE_SIZE;
+            accounts.extend(page);
+            if last_page {
+                return Ok(accounts);
+            }
+        }
+    }
+
+    async fn view_pool(
+        &self,
+        staking_pool: &str,
+        method_name: &str,
+        args: Value,
+        block_id: u64,
+    ) -> Result<Value> {
+        let result = view_function_call(
+            &self.near_client,
+            &self.archival_rate_limiter,
+            QueryRequest::CallFunction {
+                account_id: staking_pool.parse()?,
+                method_name: method_name.to_string(),
+                args: FunctionArgs::from(args.to_string().into_bytes()),
+            },
+            BlockReference::BlockId(Height(block_id)),
+        )
+        .await;
+
+        match result {
+            Ok(v) => Ok(serde_json::from_slice(&v)?),
+            Err(e) => {
+                bail!(
+                    "Error calling {} on staking pool: {}, error: {:?}",
+                    method_name,
+                    staking_pool,
+                    e
+                );
+            }
+        }
+    }
+
+    // The pool a lockup contract delegates to through `select_staking_pool`, if any.
+    pub async fn get_lockup_staking_pool(
+        &self,
+        lockup: &str,
+        block_id: u64,
+    ) -> Result<Option<String>> {
+        let args = json!({}).to_string().into_bytes();
+        let result = view_function_call(
+            &self.near_client,
+            &self.archival_rate_limiter,
+            QueryRequest::CallFunction {
+                account_id: lockup.parse()?,
+                method_name: "get_staking_pool_account_id".to_string(),
+                args: FunctionArgs::from(args.to_vec()),
+            },
+            BlockReference::BlockId(Height(block_id)),
+        )
+        .await;
+
+        match result {
+            Ok(v) => Ok(serde_json::from_slice::<Option<String>>(&v)?),
+            Err(e) => {
+                bail!(
+                    "Error getting staking pool for lockup: {}, error: {:?}",
+                    lockup,
+                    e
+                );
+            }
+        }
+    }
+
     pub async fn get_locked_amount(&self, lockup: &str, block_id: u64) -> Result<u128> {
-        self.archival_rate_limiter.write().await.until_ready().await;
         let args = json!({}).to_string().into_bytes();
         let result = view_function_call(
             &self.near_client,
+            &self.archival_rate_limiter,
             QueryRequest::CallFunction {
                 account_id: lockup.parse()?,
                 method_name: "get_locked_amount".to_string(),
@@ -403,10 +1097,10 @@ impl FtService {
     }
 
     pub async fn get_liquid_owners_balance(&self, lockup: &str, block_id: u64) -> Result<u128> {
-        self.archival_rate_limiter.write().await.until_ready().await;
         let args = json!({}).to_string().into_bytes();
         let result = view_function_call(
             &self.near_client,
+            &self.archival_rate_limiter,
             QueryRequest::CallFunction {
                 account_id: lockup.parse()?,
                 method_name: "get_liquid_owners_balance".to_string(),
@@ -429,19 +1123,60 @@ impl FtService {
     }
 }
 
-#[tracing::instrument(skip(client))]
+// Feeds the outcome of an RPC call back into the provider's adaptive rate limiter, and records
+// it in the call metrics.
+fn report_rpc_outcome<T, E>(
+    rate_limiter: &AdaptiveRateLimiter,
+    method: &str,
+    started_at: Instant,
+    result: &Result<T, JsonRpcError<E>>,
+) {
+    metrics().record_call("archival_rpc", method, started_at.elapsed(), result.is_ok());
+    match result {
+        Err(e) if is_throttling_error(e) => rate_limiter.on_throttled(),
+        _ => rate_limiter.on_success(),
+    }
+}
+
+// Errors that mean the provider is overloaded, as opposed to errors about the query itself.
+fn is_throttling_error<E>(e: &JsonRpcError<E>) -> bool {
+    match e {
+        JsonRpcError::ServerError(JsonRpcServerError::ResponseStatusError(status_error)) => {
+            match status_error {
+                JsonRpcServerResponseStatusError::TooManyRequests => true,
+                JsonRpcServerResponseStatusError::Unexpected { status } => status.is_server_error(),
+                _ => false,
+            }
+        }
+        JsonRpcError::TransportError(RpcTransportError::SendError(
+            JsonRpcTransportSendError::PayloadSendError(e),
+        )) => e.is_timeout(),
+        _ => false,
+    }
+}
+
+#[tracing::instrument(skip(client, rate_limiter))]
 pub async fn view_function_call(
     client: &JsonRpcClient,
+    rate_limiter: &AdaptiveRateLimiter,
     request: QueryRequest,
     block_reference: BlockReference,
 ) -> anyhow::Result<Vec<u8>> {
-    let RpcQueryResponse { kind, .. } = match client
+    let method = match &request {
+        QueryRequest::CallFunction { method_name, .. } => method_name.clone(),
+        _ => "query".to_string(),
+    };
+    rate_limiter.until_ready().await;
+    let started_at = Instant::now();
+    let response = client
         .call(RpcQueryRequest {
             block_reference: block_reference.clone(),
             request,
         })
-        .await
-    {
+        .await;
+    report_rpc_outcome(rate_limiter, &method, started_at, &response);
+
+    let RpcQueryResponse { kind, .. } = match response {
         Ok(v) => v,
         Err(e) => {
             if let Some(w) = e.handler_error() {
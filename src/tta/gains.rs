@@ -0,0 +1,200 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use serde::Serialize;
+
+use super::{income::INTERNAL_TRANSFER, models::ReportRow, utils::block_datetime};
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct GainsRow {
+    pub account_id: String,
+    pub token_id: String,
+    pub symbol: String,
+    // Disposals of the period, and their gains over the cost of the lots they consumed.
+    pub disposed: f64,
+    pub proceeds_usd: f64,
+    pub cost_basis_usd: f64,
+    pub realized_gain_usd: f64,
+    // Amount disposed in the period without a priced lot to match, left out of the gains.
+    pub unknown_basis: f64,
+    // Lots still held at the end date.
+    pub holding: f64,
+    pub holding_cost_usd: f64,
+    pub end_price_usd: Option<f64>,
+    pub unrealized_gain_usd: Option<f64>,
+}
+
+// Part of an acquisition not disposed yet. Acquisitions without a price have no cost.
+struct Lot {
+    amount: f64,
+    unit_cost: Option<f64>,
+}
+
+#[derive(Default)]
+struct Position {
+    symbol: String,
+    lots: VecDeque<Lot>,
+    gains: GainsRow,
+}
+
+impl Position {
+    fn acquire(&mut self, amount: f64, unit_cost: Option<f64>) {
+        self.lots.push_back(Lot { amount, unit_cost });
+    }
+
+    // Takes `amount` off the oldest lots first. What the lots don't cover comes back as a lot
+    // without cost.
+    fn take(&mut self, mut amount: f64) -> Vec<Lot> {
+        let mut taken = vec![];
+        while amount > 0.0 {
+            let Some(lot) = self.lots.front_mut() else {
+                taken.push(Lot {
+                    amount,
+                    unit_cost: None,
+                });
+                break;
+            };
+            let matched = lot.amount.min(amount);
+            taken.push(Lot {
+                amount: matched,
+                unit_cost: lot.unit_cost,
+            });
+            lot.amount -= matched;
+            amount -= matched;
+            if lot.amount <= 0.0 {
+                self.lots.pop_front();
+            }
+        }
+        taken
+    }
+
+    // Only disposals in the period are accounted for, earlier ones just consume lots.
+    fn dispose(&mut self, amount: f64, unit_price: Option<f64>, in_period: bool) {
+        let taken = self.take(amount);
+        if !in_period {
+            return;
+        }
+        self.gains.disposed += amount;
+        for lot in taken {
+            match (lot.unit_cost, unit_price) {
+                (Some(unit_cost), Some(unit_price)) => {
+                    self.gains.proceeds_usd += lot.amount * unit_price;
+                    self.gains.cost_basis_usd += lot.amount * unit_cost;
+                }
+                _ => self.gains.unknown_basis += lot.amount,
+            }
+        }
+    }
+}
+
+// Lots moving between the report's own accounts, by transaction hash, token, sender and receiver.
+type LotMoves = HashMap<(String, String, String, String), Vec<Lot>>;
+
+// FIFO realized gains of the disposals from `start_date`, and unrealized gains of the lots held
+// at the end, per account and token. Lots are built from all the rows, which should start early
+// enough to cover the acquisitions of the tokens held. `prices` are the USD prices by token and
+// UTC day, `end_prices` those at the end date. Transfers between the own accounts, tagged
+// `internal_transfer`, are no disposals: the lots move to the receiving account with their cost.
+pub fn gains<'a>(
+    rows: impl IntoIterator<Item = &'a ReportRow>,
+    start_date: u128,
+    prices: &HashMap<(String, NaiveDate), f64>,
+    end_prices: &HashMap<String, f64>,
+) -> Vec<GainsRow> {
+    let mut rows: Vec<&ReportRow> = rows.into_iter().collect();
+    // The sending side of a transfer goes first, so the lots it moves are there for the receiving
+    // side.
+    rows.sort_by_key(|row| (row.block_timestamp, row.movements().1.is_empty()));
+
+    let mut positions: BTreeMap<(String, String), Position> = BTreeMap::new();
+    let mut moves = LotMoves::new();
+    for row in rows {
+        let day = block_datetime(row.block_timestamp, Tz::UTC).date_naive();
+        let in_period = row.block_timestamp >= start_date;
+        let internal = row.income_type.as_deref() == Some(INTERNAL_TRANSFER);
+        let (incoming, outgoing) = row.movements();
+        for (token_id, amount) in incoming {
+            let key = (
+                row.transaction_hash.clone(),
+                token_id.clone(),
+                row.counterparty().to_string(),
+                row.account_id.clone(),
+            );
+            let position = position(&mut positions, row, &token_id);
+            // Transfers from an own account the rows don't cover are acquisitions.
+            match internal.then(|| moves.remove(&key)).flatten() {
+                Some(lots) => position.lots.extend(lots),
+                None => {
+                    let unit_price = prices.get(&(token_id.clone(), day)).cloned();
+                    position.acquire(amount, unit_price);
+                }
+            }
+        }
+        for (token_id, amount) in outgoing {
+            let position = position(&mut positions, row, &token_id);
+            if internal {
+                let key = (
+                    row.transaction_hash.clone(),
+                    token_id.clone(),
+                    row.account_id.clone(),
+                    row.counterparty().to_string(),
+                );
+                moves.entry(key).or_default().extend(position.take(amount));
+            } else {
+                let unit_price = prices.get(&(token_id.clone(), day)).cloned();
+                position.dispose(amount, unit_price, in_period);
+            }
+        }
+    }
+
+    positions
+        .into_iter()
+        .map(|((account_id, token_id), position)| {
+            let end_price_usd = end_prices.get(&token_id).cloned();
+            let holding: f64 = position.lots.iter().map(|lot| lot.amount).sum();
+            let holding_cost_usd: f64 = position
+                .lots
+                .iter()
+                .filter_map(|lot| Some(lot.amount * lot.unit_cost?))
+                .sum();
+            let priced = position.lots.iter().all(|lot| lot.unit_cost.is_some());
+            GainsRow {
+                account_id,
+                symbol: position.symbol,
+                realized_gain_usd: position.gains.proceeds_usd - position.gains.cost_basis_usd,
+                holding,
+                holding_cost_usd,
+                end_price_usd,
+                unrealized_gain_usd: end_price_usd
+                    .filter(|_| priced)
+                    .map(|price| holding * price - holding_cost_usd),
+                token_id,
+                ..position.gains
+            }
+        })
+        .collect()
+}
+
+fn position<'a>(
+    positions: &'a mut BTreeMap<(String, String), Position>,
+    row: &ReportRow,
+    token_id: &str,
+) -> &'a mut Position {
+    positions
+        .entry((row.account_id.clone(), token_id.to_string()))
+        .or_insert_with(|| Position {
+            symbol: symbol_of(row, token_id),
+            ..Default::default()
+        })
+}
+
+fn symbol_of(row: &ReportRow, token_id: &str) -> String {
+    if row.ft_token_contract_in.as_deref() == Some(token_id) {
+        row.ft_currency_in.clone().unwrap_or_default()
+    } else if row.ft_token_contract_out.as_deref() == Some(token_id) {
+        row.ft_currency_out.clone().unwrap_or_default()
+    } else {
+        token_id.to_string()
+    }
+}
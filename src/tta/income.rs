@@ -0,0 +1,81 @@
+use std::collections::{HashMap, HashSet};
+
+use super::models::{MethodName, ReportRow};
+
+pub const INTERNAL_TRANSFER: &str = "internal_transfer";
+pub const STAKING_REWARD: &str = "staking_reward";
+pub const AIRDROP: &str = "airdrop";
+
+fn is_staking_pool(account: &str) -> bool {
+    account.ends_with(".poolv1.near") || account.ends_with(".pool.near")
+}
+
+// Tags the rows that are income, or transfers between the report's own accounts, in
+// `income_type`. Rows are classified in order, and the state carries over between calls so a
+// report generated in chunks is classified like a whole one. Only the rows seen are known: NEAR
+// staked before the first row is counted as reward when withdrawn.
+pub struct IncomeClassifier {
+    own_accounts: HashSet<String>,
+    // NEAR staked and not withdrawn yet, by account and pool.
+    principal: HashMap<(String, String), f64>,
+    // Accounts each account interacted with so far.
+    known: HashMap<String, HashSet<String>>,
+}
+
+impl IncomeClassifier {
+    pub fn new(own_accounts: HashSet<String>) -> Self {
+        Self {
+            own_accounts,
+            principal: HashMap::new(),
+            known: HashMap::new(),
+        }
+    }
+
+    pub fn classify(&mut self, rows: &mut [ReportRow]) {
+        let mut order: Vec<usize> = (0..rows.len()).collect();
+        order.sort_by_key(|&i| rows[i].block_timestamp);
+        for i in order {
+            let row = &mut rows[i];
+            row.income_type = self.income_type(row).map(String::from);
+        }
+    }
+
+    fn income_type(&mut self, row: &ReportRow) -> Option<&'static str> {
+        let counterparty = row.counterparty().to_string();
+        let first_seen = self
+            .known
+            .entry(row.account_id.clone())
+            .or_default()
+            .insert(counterparty.clone());
+
+        if counterparty != row.account_id && self.own_accounts.contains(&counterparty) {
+            return Some(INTERNAL_TRANSFER);
+        }
+
+        if is_staking_pool(&counterparty) && row.amount_transferred != 0.0 {
+            let principal = self
+                .principal
+                .entry((row.account_id.clone(), counterparty))
+                .or_default();
+            if row.amount_transferred < 0.0 {
+                *principal -= row.amount_transferred;
+                return None;
+            }
+            let withdrawn_principal = (*principal).min(row.amount_transferred);
+            *principal -= withdrawn_principal;
+            return (row.amount_transferred > withdrawn_principal).then_some(STAKING_REWARD);
+        }
+
+        // Tokens sent or minted to the account, by a contract or sender it never dealt with.
+        let unsolicited = row.signer_account_id != row.account_id
+            && matches!(
+                MethodName::from(row.method_name.as_str()),
+                MethodName::FtTransfer | MethodName::FtTransferCall | MethodName::Mint
+            );
+        if row.ft_amount_in.is_some() && unsolicited && first_seen {
+            return Some(AIRDROP);
+        }
+
+        None
+    }
+}
@@ -1,6 +1,16 @@
+pub mod activity;
+pub mod aggregations;
+pub mod aurora;
+pub mod gains;
+pub mod income;
 pub mod models;
 pub mod sql;
 pub mod tta_impl;
 
+pub mod bridges;
+pub mod ft_call_msg;
 pub mod ft_metadata;
+pub mod progress;
+pub mod snapshots;
+pub mod sources;
 mod utils;
@@ -1,30 +1,128 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Context;
 use near_primitives::types::AccountId;
 use near_sdk::json_types::U128;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+use chrono_tz::Tz;
+
+use super::{sql::models::MultiSigRequest, utils::block_datetime};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReportRow {
+    // Date and time of the block, in UTC unless the report is localized.
     pub date: String,
+    pub time: String,
     pub account_id: String,
     pub method_name: String,
+    pub category: Option<String>,
+    // Income, or transfers between the report's own accounts, see `income`.
+    #[serde(default)]
+    pub income_type: Option<String>,
+    // Gas refunds, only reported with `include_refunds`.
+    #[serde(default)]
+    pub refund: bool,
     pub block_timestamp: u128,
     pub from_account: String,
     pub block_height: u128,
+    pub block_hash: String,
+    pub epoch_id: Option<String>,
     pub args: String,
     pub transaction_hash: String,
+    pub receipt_id: String,
     pub amount_transferred: f64,
     pub currency_transferred: String,
     pub ft_amount_out: Option<f64>,
     pub ft_currency_out: Option<String>,
+    // Contracts of the tokens moved, symbols alone are ambiguous. Rows stored before they were
+    // added have none.
+    #[serde(default)]
+    pub ft_token_contract_out: Option<String>,
     pub ft_amount_in: Option<f64>,
     pub ft_currency_in: Option<String>,
+    #[serde(default)]
+    pub ft_token_contract_in: Option<String>,
+    // Memo of `ft_transfer` and `ft_transfer_call`, exchanges put deposit references there.
+    #[serde(default)]
+    pub memo: Option<String>,
+    // USD value of everything moved in and out, NEAR included, at the day's price.
+    #[serde(default)]
+    pub amount_usd_in: Option<f64>,
+    #[serde(default)]
+    pub amount_usd_out: Option<f64>,
+    // The USD values converted to the requested fiat at the day's ECB rate.
+    #[serde(default)]
+    pub fiat_currency: Option<String>,
+    #[serde(default)]
+    pub amount_fiat_in: Option<f64>,
+    #[serde(default)]
+    pub amount_fiat_out: Option<f64>,
     pub to_account: String,
+    // Who signed the originating transaction, and where the value ended up at the end of the
+    // receipt chain, past routers and multicall contracts.
+    pub signer_account_id: String,
+    pub final_recipient: Option<String>,
+    // Address on the Aurora side of transfers to the engine and EVM transactions submitted to it.
+    #[serde(default)]
+    pub evm_address: Option<String>,
+    // near.social profile name of the other side of the row, when asked for.
+    pub counterparty_name: Option<String>,
     pub amount_staked: f64,
     pub onchain_balance: Option<f64>,
     pub onchain_balance_token: Option<String>,
+    // Node and block the balance was read at, and whether it was served from the cache.
+    #[serde(default)]
+    pub balance_rpc_endpoint: Option<String>,
+    #[serde(default)]
+    pub balance_block_height: Option<u64>,
+    #[serde(default)]
+    pub balance_cached: Option<bool>,
     pub metadata: Option<String>,
 }
 
+pub const DATE_FORMAT: &str = "%B %d, %Y";
+pub const TIME_FORMAT: &str = "%H:%M:%S %Z";
+
+// Position of the last row of an incremental export, as `<block_timestamp>:<receipt_id>`. The
+// next export returns the rows after it in (block_timestamp, receipt_id) order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Watermark {
+    pub block_timestamp: u128,
+    pub receipt_id: String,
+}
+
+impl Watermark {
+    pub fn of(row: &ReportRow) -> Self {
+        Self {
+            block_timestamp: row.block_timestamp,
+            receipt_id: row.receipt_id.clone(),
+        }
+    }
+}
+
+impl std::str::FromStr for Watermark {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block_timestamp, receipt_id) = s
+            .split_once(':')
+            .with_context(|| format!("Invalid watermark: {}", s))?;
+        Ok(Self {
+            block_timestamp: block_timestamp
+                .parse()
+                .with_context(|| format!("Invalid watermark: {}", s))?,
+            receipt_id: receipt_id.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for Watermark {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.block_timestamp, self.receipt_id)
+    }
+}
+
 // Define the extension trait
 pub trait FloatExt {
     fn to_5dp_string(&self) -> String;
@@ -38,26 +136,117 @@ impl FloatExt for f64 {
 }
 
 impl ReportRow {
+    // Identifies the row across report runs: the same receipt seen from the same account, moving
+    // the same token.
+    pub fn diff_key(&self) -> (String, String, String) {
+        let token = self
+            .ft_token_contract_out
+            .clone()
+            .or_else(|| self.ft_token_contract_in.clone())
+            .or_else(|| self.ft_currency_out.clone())
+            .or_else(|| self.ft_currency_in.clone())
+            .unwrap_or_else(|| self.currency_transferred.clone());
+        (self.account_id.clone(), self.receipt_id.clone(), token)
+    }
+
+    // `diff_key` of a row read back from a report CSV. None when the headers are not those of a
+    // report.
+    pub fn record_diff_key(
+        headers: &csv::StringRecord,
+        record: &csv::StringRecord,
+    ) -> Option<(String, String, String)> {
+        let field = |name: &str| {
+            let i = headers.iter().position(|header| header == name)?;
+            Some(record.get(i).unwrap_or_default().to_string())
+        };
+        let token = [
+            "ft_token_contract_out",
+            "ft_token_contract_in",
+            "ft_currency_out",
+            "ft_currency_in",
+        ]
+        .into_iter()
+        .filter_map(|name| field(name).filter(|value| !value.is_empty()))
+        .next()
+        .or_else(|| field("currency_transferred"))?;
+        Some((field("account_id")?, field("receipt_id")?, token))
+    }
+
+    // Renders `date` and `time` in `tz`.
+    pub fn localize(&mut self, tz: Tz) {
+        let datetime = block_datetime(self.block_timestamp, tz);
+        self.date = datetime.format(DATE_FORMAT).to_string();
+        self.time = datetime.format(TIME_FORMAT).to_string();
+    }
+
+    // Tokens moved into and out of the account as (token contract or NEAR, amount).
+    pub fn movements(&self) -> (Vec<(String, f64)>, Vec<(String, f64)>) {
+        let (mut incoming, mut outgoing) = (vec![], vec![]);
+        if self.amount_transferred > 0.0 {
+            incoming.push(("NEAR".to_string(), self.amount_transferred));
+        } else if self.amount_transferred < 0.0 {
+            outgoing.push(("NEAR".to_string(), -self.amount_transferred));
+        }
+        if let (Some(amount), Some(contract)) = (self.ft_amount_in, &self.ft_token_contract_in) {
+            incoming.push((contract.clone(), amount.abs()));
+        }
+        if let (Some(amount), Some(contract)) = (self.ft_amount_out, &self.ft_token_contract_out) {
+            outgoing.push((contract.clone(), amount.abs()));
+        }
+        (incoming, outgoing)
+    }
+
+    // The account on the other side of the row.
+    pub fn counterparty(&self) -> &str {
+        if self.from_account == self.account_id {
+            &self.to_account
+        } else {
+            &self.from_account
+        }
+    }
+
     pub fn get_vec_headers() -> Vec<String> {
         vec![
             "date".to_string(),
+            "time".to_string(),
             "account_id".to_string(),
             "method_name".to_string(),
+            "category".to_string(),
+            "income_type".to_string(),
+            "refund".to_string(),
             "block_timestamp".to_string(),
             "from_account".to_string(),
             "block_height".to_string(),
+            "block_hash".to_string(),
+            "epoch_id".to_string(),
             "args".to_string(),
             "transaction_hash".to_string(),
+            "receipt_id".to_string(),
             "amount_transferred".to_string(),
             "currency_transferred".to_string(),
             "ft_amount_out".to_string(),
             "ft_currency_out".to_string(),
+            "ft_token_contract_out".to_string(),
             "ft_amount_in".to_string(),
             "ft_currency_in".to_string(),
+            "ft_token_contract_in".to_string(),
+            "memo".to_string(),
+            "amount_usd_in".to_string(),
+            "amount_usd_out".to_string(),
+            "fiat_currency".to_string(),
+            "amount_fiat_in".to_string(),
+            "amount_fiat_out".to_string(),
             "to_account".to_string(),
+            "signer_account_id".to_string(),
+            "final_recipient".to_string(),
+            "evm_address".to_string(),
+            "counterparty_name".to_string(),
             "amount_staked".to_string(),
             "onchain_balance".to_string(),
             "onchain_balance_token".to_string(),
+            "balance_rpc_endpoint".to_string(),
+            "balance_block_height".to_string(),
+            "balance_cached".to_string(),
             "metadata".to_string(),
         ]
     }
@@ -65,31 +254,126 @@ impl ReportRow {
     pub fn to_vec(&self) -> Vec<String> {
         vec![
             self.date.clone(),
+            self.time.clone(),
             self.account_id.clone(),
             self.method_name.clone(),
+            self.category.clone().unwrap_or_default(),
+            self.income_type.clone().unwrap_or_default(),
+            self.refund.to_string(),
             self.block_timestamp.to_string(),
             self.from_account.clone(),
             self.block_height.to_string(),
+            self.block_hash.clone(),
+            self.epoch_id.clone().unwrap_or_default(),
             self.args.clone(),
             self.transaction_hash.clone(),
+            self.receipt_id.clone(),
             self.amount_transferred.to_5dp_string(),
             self.currency_transferred.clone(),
             self.ft_amount_out
                 .map_or(String::new(), |v| v.to_5dp_string()),
             self.ft_currency_out.clone().unwrap_or_default(),
+            self.ft_token_contract_out.clone().unwrap_or_default(),
             self.ft_amount_in
                 .map_or(String::new(), |v| v.to_5dp_string()),
             self.ft_currency_in.clone().unwrap_or_default(),
+            self.ft_token_contract_in.clone().unwrap_or_default(),
+            self.memo.clone().unwrap_or_default(),
+            self.amount_usd_in
+                .map_or(String::new(), |v| v.to_5dp_string()),
+            self.amount_usd_out
+                .map_or(String::new(), |v| v.to_5dp_string()),
+            self.fiat_currency.clone().unwrap_or_default(),
+            self.amount_fiat_in
+                .map_or(String::new(), |v| v.to_5dp_string()),
+            self.amount_fiat_out
+                .map_or(String::new(), |v| v.to_5dp_string()),
             self.to_account.clone(),
+            self.signer_account_id.clone(),
+            self.final_recipient.clone().unwrap_or_default(),
+            self.evm_address.clone().unwrap_or_default(),
+            self.counterparty_name.clone().unwrap_or_default(),
             self.amount_staked.to_5dp_string(),
             self.onchain_balance
                 .map_or(String::new(), |v| v.to_5dp_string()),
             self.onchain_balance_token.clone().unwrap_or_default(),
+            self.balance_rpc_endpoint.clone().unwrap_or_default(),
+            self.balance_block_height
+                .map_or(String::new(), |v| v.to_string()),
+            self.balance_cached.map_or(String::new(), |v| v.to_string()),
             self.metadata.clone().unwrap_or_default(),
         ]
     }
 }
 
+// Symbols shared by several tokens in the same rows, e.g. bridged and native USDT, get their
+// contract appended: `USDT (usdt.tether-token.near)`.
+pub fn disambiguate_symbols(rows: &mut [ReportRow]) {
+    let mut contracts = SymbolContracts::default();
+    contracts.observe(rows);
+    rows.iter_mut().for_each(|row| contracts.disambiguate(row));
+}
+
+// The token contracts seen behind each symbol, for reports disambiguated after all their rows
+// went by, see `disambiguate_symbols`.
+#[derive(Debug, Default)]
+pub struct SymbolContracts(HashMap<String, HashSet<String>>);
+
+impl SymbolContracts {
+    pub fn observe(&mut self, rows: &[ReportRow]) {
+        for row in rows {
+            for (symbol, contract) in [
+                (&row.ft_currency_in, &row.ft_token_contract_in),
+                (&row.ft_currency_out, &row.ft_token_contract_out),
+            ] {
+                if let (Some(symbol), Some(contract)) = (symbol, contract) {
+                    self.0
+                        .entry(symbol.clone())
+                        .or_default()
+                        .insert(contract.clone());
+                }
+            }
+        }
+    }
+
+    pub fn disambiguate(&self, row: &mut ReportRow) {
+        let is_ambiguous = |symbol: &str| self.0.get(symbol).map_or(false, |c| c.len() > 1);
+        let balance_contract = row
+            .ft_token_contract_in
+            .clone()
+            .or_else(|| row.ft_token_contract_out.clone());
+        for (symbol, contract) in [
+            (&mut row.ft_currency_in, row.ft_token_contract_in.clone()),
+            (&mut row.ft_currency_out, row.ft_token_contract_out.clone()),
+            (&mut row.onchain_balance_token, balance_contract),
+        ] {
+            if let (Some(symbol), Some(contract)) = (symbol, contract) {
+                if is_ambiguous(symbol) {
+                    *symbol = format!("{} ({})", symbol, contract);
+                }
+            }
+        }
+    }
+}
+
+// A token received from a contract the account never called before, with its first transfer of
+// the period.
+#[derive(Debug, Serialize, Clone)]
+pub struct AirdropRow {
+    pub account_id: String,
+    pub token_id: String,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+    pub spam: bool,
+    pub first_seen_date: String,
+    pub first_seen_block_timestamp: u128,
+    pub first_transaction_hash: String,
+    pub from_account: String,
+    pub transfers: usize,
+    pub total_amount: f64,
+}
+
 #[derive(Debug, Clone)]
 pub struct FtAmounts {
     pub ft_amount_out: Option<f64>,
@@ -98,6 +382,12 @@ pub struct FtAmounts {
     pub ft_currency_in: Option<String>,
     pub from_account: String,
     pub to_account: String,
+    pub category: Option<String>,
+    pub memo: Option<String>,
+    // NEAR moved by the call itself rather than by its attached deposit, e.g. multisig requests.
+    pub near_amount: Option<f64>,
+    // Contract of the token moved.
+    pub token_id: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -108,9 +398,23 @@ pub enum MethodName {
     NearDeposit,
     NearWithdraw,
     Mint,
+    MultisigRequest,
+    StorageDeposit,
+    StorageWithdraw,
     Unsupported,
 }
 
+impl MethodName {
+    // Coarse grouping of the calls, for the `category` column.
+    pub fn category(&self) -> Option<&'static str> {
+        match self {
+            MethodName::StorageDeposit | MethodName::StorageWithdraw => Some("storage"),
+            MethodName::MultisigRequest => Some("multisig"),
+            _ => None,
+        }
+    }
+}
+
 impl From<&str> for MethodName {
     fn from(s: &str) -> Self {
         match s {
@@ -120,6 +424,9 @@ impl From<&str> for MethodName {
             "near_deposit" => MethodName::NearDeposit,
             "near_withdraw" => MethodName::NearWithdraw,
             "mint" => MethodName::Mint,
+            "add_request" | "add_request_and_confirm" => MethodName::MultisigRequest,
+            "storage_deposit" => MethodName::StorageDeposit,
+            "storage_withdraw" | "storage_unregister" => MethodName::StorageWithdraw,
             _ => MethodName::Unsupported,
         }
     }
@@ -152,6 +459,17 @@ pub struct WithdrawFromBridge {
     pub amount: U128,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MultisigAddRequest {
+    pub request: MultiSigRequest,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LockupTransfer {
+    pub amount: U128,
+    pub receiver_id: AccountId,
+}
+
 #[derive(Clone, Serialize, Deserialize)]
 pub struct RainbowBridgeMint {
     pub account_id: AccountId,
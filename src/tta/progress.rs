@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+// Progress of a running /tta report, updated by `TTA::get_txns_report` as its parts complete.
+#[derive(Debug, Default)]
+pub struct ReportProgress {
+    accounts_total: AtomicUsize,
+    accounts_done: AtomicUsize,
+    sql_streams_total: AtomicUsize,
+    sql_streams_done: AtomicUsize,
+    rows_processed: AtomicUsize,
+    balance_lookups_done: AtomicUsize,
+    balance_lookups_started: AtomicUsize,
+    // Per account, the transaction streams not processed yet.
+    pending_streams: Mutex<HashMap<String, usize>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ProgressSnapshot {
+    pub accounts_total: usize,
+    pub accounts_done: usize,
+    pub sql_streams_total: usize,
+    pub sql_streams_done: usize,
+    pub rows_processed: usize,
+    pub balance_lookups_done: usize,
+}
+
+impl ReportProgress {
+    pub fn start_account(&self, account: &str, streams: usize) {
+        self.accounts_total.fetch_add(1, Ordering::Relaxed);
+        self.sql_streams_total.fetch_add(streams, Ordering::Relaxed);
+        self.pending_streams
+            .lock()
+            .unwrap()
+            .insert(account.to_string(), streams);
+    }
+
+    pub fn sql_stream_done(&self) {
+        self.sql_streams_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The rows of one of the account's streams are all processed.
+    pub fn stream_processed(&self, account: &str) {
+        let mut pending_streams = self.pending_streams.lock().unwrap();
+        if let Some(pending) = pending_streams.get_mut(account) {
+            *pending = pending.saturating_sub(1);
+            if *pending == 0 {
+                pending_streams.remove(account);
+                self.accounts_done.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn row_processed(&self) {
+        self.rows_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The balance lookups started so far, this one included.
+    pub fn start_balance_lookup(&self) -> usize {
+        self.balance_lookups_started.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn balance_lookup_done(&self) {
+        self.balance_lookups_done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            accounts_total: self.accounts_total.load(Ordering::Relaxed),
+            accounts_done: self.accounts_done.load(Ordering::Relaxed),
+            sql_streams_total: self.sql_streams_total.load(Ordering::Relaxed),
+            sql_streams_done: self.sql_streams_done.load(Ordering::Relaxed),
+            rows_processed: self.rows_processed.load(Ordering::Relaxed),
+            balance_lookups_done: self.balance_lookups_done.load(Ordering::Relaxed),
+        }
+    }
+}
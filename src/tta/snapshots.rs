@@ -0,0 +1,124 @@
+use anyhow::Result;
+use chrono::{Duration, NaiveDate, Utc};
+use tracing::{error, info};
+use tta_rust::get_accounts_and_lockups;
+
+use crate::{
+    config::{Config, SNAPSHOT_BACKFILL_DAYS},
+    kitwallet::KitWallet,
+};
+
+use super::{
+    activity::day_start,
+    ft_metadata::FtService,
+    sql::{models::BalanceSnapshot, sql_queries::SqlClient},
+};
+
+// A day is snapshotted once the indexer is this far past its start.
+const INDEXER_LAG_MINUTES: i64 = 10;
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+// End of day balances of the `SNAPSHOT_ACCOUNTS` and their lockups, NEAR and likely tokens,
+// snapshotted in the background so /balancesfull can read them instead of calling the archival
+// RPC for every day. Past days are snapshotted with the current likely tokens.
+#[derive(Clone)]
+pub struct BalanceSnapshotter {
+    sql_client: SqlClient,
+    ft_service: FtService,
+    kitwallet: KitWallet,
+    accounts: Vec<String>,
+    backfill_days: i64,
+}
+
+impl BalanceSnapshotter {
+    pub fn new(sql_client: SqlClient, ft_service: FtService, kitwallet: KitWallet) -> Self {
+        Self {
+            sql_client,
+            ft_service,
+            kitwallet,
+            accounts: vec![],
+            backfill_days: SNAPSHOT_BACKFILL_DAYS,
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        let accounts: Vec<String> = config.snapshot_accounts.iter().cloned().collect();
+        self.accounts = get_accounts_and_lockups(&accounts.join(","))
+            .into_iter()
+            .map(|(account, _)| account)
+            .collect();
+        self.accounts.sort();
+        self.backfill_days = config.snapshot_backfill_days;
+        self
+    }
+
+    // Keeps the snapshots up to date until the process exits. Does nothing without accounts.
+    pub fn spawn(self) {
+        if self.accounts.is_empty() {
+            return;
+        }
+        tokio::spawn(async move {
+            loop {
+                self.refresh().await;
+                tokio::time::sleep(REFRESH_INTERVAL).await;
+            }
+        });
+    }
+
+    async fn refresh(&self) {
+        let last_day = (Utc::now() - Duration::minutes(INDEXER_LAG_MINUTES)).date_naive();
+        for account in &self.accounts {
+            if let Err(e) = self.catch_up(account, last_day).await {
+                error!(?e, account, "Error snapshotting balances");
+            }
+        }
+    }
+
+    // Snapshots every day of the account from the last one stored up to `last_day`, included.
+    async fn catch_up(&self, account: &str, last_day: NaiveDate) -> Result<()> {
+        let mut day = match self.sql_client.last_balance_snapshot_day(account).await? {
+            Some(day) => day + Duration::days(1),
+            None => last_day - Duration::days(self.backfill_days),
+        };
+        while day <= last_day {
+            self.snapshot(account, day).await?;
+            day += Duration::days(1);
+        }
+
+        Ok(())
+    }
+
+    async fn snapshot(&self, account: &str, day: NaiveDate) -> Result<()> {
+        let block_id = self.sql_client.get_closest_block_id(day_start(day)).await? as u64;
+        let likely_tokens = self
+            .kitwallet
+            .get_likely_tokens_for_accounts(vec![account.to_string()])
+            .await?
+            .remove(account)
+            .unwrap_or_default();
+
+        let snapshots: Vec<BalanceSnapshot> = self
+            .ft_service
+            .account_balances(&likely_tokens, &account.to_string(), block_id)
+            .await
+            .into_iter()
+            .map(|balance| BalanceSnapshot {
+                account_id: account.to_string(),
+                day,
+                token_id: balance.token_id,
+                symbol: balance.symbol,
+                block_id: block_id as i64,
+                balance: balance.balance,
+                spam: balance.spam,
+                locked: balance.locked,
+                storage_usage: balance.storage_usage.map(|v| v as i64),
+            })
+            .collect();
+        self.sql_client
+            .save_balance_snapshots(account, day, &snapshots)
+            .await?;
+        info!(account, %day, "Snapshotted balances");
+
+        Ok(())
+    }
+}
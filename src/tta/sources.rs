@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use num_traits::ToPrimitive;
+use tokio::sync::mpsc::Sender;
+
+use super::{
+    ft_metadata::{BalanceSource, FtMetadata, FtService, NearBalance},
+    sql::{models::Transaction, sql_queries::SqlClient},
+};
+
+// The indexer queries the decoding of a report reads, `SqlClient` in production.
+#[async_trait]
+pub trait TxnSource: Clone + Send + Sync + 'static {
+    async fn get_outgoing_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()>;
+
+    async fn get_incoming_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()>;
+
+    async fn get_ft_incoming_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()>;
+
+    async fn get_ft_net_transferred(
+        &self,
+        transaction_hash: &str,
+        token_id: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<u128>;
+
+    async fn has_receipts_between(
+        &self,
+        transaction_hash: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<bool>;
+
+    async fn get_final_recipients(
+        &self,
+        transaction_hashes: &[String],
+    ) -> Result<HashMap<String, String>>;
+}
+
+// The chain state the decoding of a report reads, `FtService` over the archival RPC in
+// production.
+#[async_trait]
+pub trait ChainView: Clone + Send + Sync + 'static {
+    async fn assert_ft_metadata(&self, ft_token_id: &str) -> Result<FtMetadata>;
+
+    async fn sourced_ft_balance(
+        &self,
+        token_id: &String,
+        account_id: &String,
+        block_id: u64,
+    ) -> Result<(f64, Option<BalanceSource>)>;
+
+    async fn get_near_balance(
+        &self,
+        account_id: &str,
+        block_id: u64,
+    ) -> Result<Option<NearBalance>>;
+
+    fn balance_source(&self, block_height: u64, cached: bool) -> BalanceSource;
+}
+
+#[async_trait]
+impl TxnSource for SqlClient {
+    async fn get_outgoing_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        SqlClient::get_outgoing_txns(self, accounts, start_date, end_date, sender_txn).await
+    }
+
+    async fn get_incoming_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        SqlClient::get_incoming_txns(self, accounts, start_date, end_date, sender_txn).await
+    }
+
+    async fn get_ft_incoming_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        SqlClient::get_ft_incoming_txns(self, accounts, start_date, end_date, sender_txn).await
+    }
+
+    async fn get_ft_net_transferred(
+        &self,
+        transaction_hash: &str,
+        token_id: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<u128> {
+        SqlClient::get_ft_net_transferred(self, transaction_hash, token_id, from, to).await
+    }
+
+    async fn has_receipts_between(
+        &self,
+        transaction_hash: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<bool> {
+        SqlClient::has_receipts_between(self, transaction_hash, from, to).await
+    }
+
+    async fn get_final_recipients(
+        &self,
+        transaction_hashes: &[String],
+    ) -> Result<HashMap<String, String>> {
+        SqlClient::get_final_recipients(self, transaction_hashes).await
+    }
+}
+
+#[async_trait]
+impl ChainView for FtService {
+    async fn assert_ft_metadata(&self, ft_token_id: &str) -> Result<FtMetadata> {
+        FtService::assert_ft_metadata(self, ft_token_id).await
+    }
+
+    async fn sourced_ft_balance(
+        &self,
+        token_id: &String,
+        account_id: &String,
+        block_id: u64,
+    ) -> Result<(f64, Option<BalanceSource>)> {
+        FtService::sourced_ft_balance(self, token_id, account_id, block_id).await
+    }
+
+    async fn get_near_balance(
+        &self,
+        account_id: &str,
+        block_id: u64,
+    ) -> Result<Option<NearBalance>> {
+        FtService::get_near_balance(self, account_id, block_id).await
+    }
+
+    fn balance_source(&self, block_height: u64, cached: bool) -> BalanceSource {
+        FtService::balance_source(self, block_height, cached)
+    }
+}
+
+// Indexer rows held in memory, for decoding tests without an indexer DB. The rows are picked
+// like the queries pick them: by account, block timestamp and execution status.
+#[derive(Debug, Clone, Default)]
+pub struct FakeTxnSource {
+    pub txns: Vec<Transaction>,
+    // By transaction hash, token, from and to.
+    pub ft_net_transferred: HashMap<(String, String, String, String), u128>,
+    // Transactions with receipts from one account to the other, as (hash, from, to).
+    pub receipts_between: HashSet<(String, String, String)>,
+    // By transaction hash.
+    pub final_recipients: HashMap<String, String>,
+}
+
+impl FakeTxnSource {
+    pub fn with_txn(mut self, txn: Transaction) -> Self {
+        self.txns.push(txn);
+        self
+    }
+
+    async fn send_txns(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        // Outgoing and FT incoming rows are dropped when any receipt of their transaction failed.
+        skip_failed_txns: bool,
+        picks: impl Fn(&Transaction) -> bool,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        let failed_txns: HashSet<&str> = self
+            .txns
+            .iter()
+            .filter(|txn| txn.eo_status == "FAILURE")
+            .map(|txn| txn.t_transaction_hash.as_str())
+            .collect();
+        for txn in &self.txns {
+            let block_timestamp = txn.b_block_timestamp.to_u128().unwrap_or_default();
+            if block_timestamp < start_date
+                || block_timestamp >= end_date
+                || !matches!(
+                    txn.eo_status.as_str(),
+                    "SUCCESS_RECEIPT_ID" | "SUCCESS_VALUE"
+                )
+                || (skip_failed_txns && failed_txns.contains(txn.t_transaction_hash.as_str()))
+                || !picks(txn)
+            {
+                continue;
+            }
+            sender_txn.send(txn.clone()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TxnSource for FakeTxnSource {
+    async fn get_outgoing_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        let picks = |txn: &Transaction| accounts.contains(&txn.ara_receipt_predecessor_account_id);
+        self.send_txns(start_date, end_date, true, picks, sender_txn)
+            .await
+    }
+
+    async fn get_incoming_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        let picks = |txn: &Transaction| accounts.contains(&txn.ara_receipt_receiver_account_id);
+        self.send_txns(start_date, end_date, false, picks, sender_txn)
+            .await
+    }
+
+    async fn get_ft_incoming_txns(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        sender_txn: Sender<Transaction>,
+    ) -> Result<()> {
+        let picks = |txn: &Transaction| {
+            txn.ara_action_kind == "FUNCTION_CALL"
+                && ["receiver_id", "account_id"].iter().any(|key| {
+                    txn.ara_args["args_json"][key]
+                        .as_str()
+                        .map_or(false, |account| accounts.contains(account))
+                })
+        };
+        self.send_txns(start_date, end_date, true, picks, sender_txn)
+            .await
+    }
+
+    async fn get_ft_net_transferred(
+        &self,
+        transaction_hash: &str,
+        token_id: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<u128> {
+        let key = (
+            transaction_hash.to_string(),
+            token_id.to_string(),
+            from.to_string(),
+            to.to_string(),
+        );
+        Ok(self.ft_net_transferred.get(&key).copied().unwrap_or(0))
+    }
+
+    async fn has_receipts_between(
+        &self,
+        transaction_hash: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<bool> {
+        Ok(self.receipts_between.contains(&(
+            transaction_hash.to_string(),
+            from.to_string(),
+            to.to_string(),
+        )))
+    }
+
+    async fn get_final_recipients(
+        &self,
+        transaction_hashes: &[String],
+    ) -> Result<HashMap<String, String>> {
+        Ok(transaction_hashes
+            .iter()
+            .filter_map(|hash| {
+                self.final_recipients
+                    .get(hash)
+                    .map(|recipient| (hash.clone(), recipient.clone()))
+            })
+            .collect())
+    }
+}
+
+// Token metadata and balances held in memory, for decoding tests without the archival RPC.
+// Lookups of anything not set fail like an RPC error would.
+#[derive(Debug, Clone, Default)]
+pub struct FakeChainView {
+    pub ft_metadata: HashMap<String, FtMetadata>,
+    // By token, account and block height.
+    pub ft_balances: HashMap<(String, String, u64), f64>,
+    // By account and block height, accounts missing at the block are left out.
+    pub near_balances: HashMap<(String, u64), NearBalance>,
+}
+
+const FAKE_RPC_ENDPOINT: &str = "fake://chain-view";
+
+impl FakeChainView {
+    pub fn with_token(mut self, token_id: &str, symbol: &str, decimals: u8) -> Self {
+        self.ft_metadata.insert(
+            token_id.to_string(),
+            FtMetadata {
+                spec: "ft-1.0.0".to_string(),
+                name: symbol.to_string(),
+                symbol: symbol.to_string(),
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                decimals,
+            },
+        );
+        self
+    }
+}
+
+#[async_trait]
+impl ChainView for FakeChainView {
+    async fn assert_ft_metadata(&self, ft_token_id: &str) -> Result<FtMetadata> {
+        match self.ft_metadata.get(ft_token_id) {
+            Some(metadata) => Ok(metadata.clone()),
+            None => bail!("No ft_metadata for {}", ft_token_id),
+        }
+    }
+
+    async fn sourced_ft_balance(
+        &self,
+        token_id: &String,
+        account_id: &String,
+        block_id: u64,
+    ) -> Result<(f64, Option<BalanceSource>)> {
+        match self
+            .ft_balances
+            .get(&(token_id.clone(), account_id.clone(), block_id))
+        {
+            Some(balance) => Ok((*balance, Some(self.balance_source(block_id, false)))),
+            None => bail!(
+                "No ft_balance of {} for {} at {}",
+                token_id,
+                account_id,
+                block_id
+            ),
+        }
+    }
+
+    async fn get_near_balance(
+        &self,
+        account_id: &str,
+        block_id: u64,
+    ) -> Result<Option<NearBalance>> {
+        Ok(self
+            .near_balances
+            .get(&(account_id.to_string(), block_id))
+            .copied())
+    }
+
+    fn balance_source(&self, block_height: u64, cached: bool) -> BalanceSource {
+        BalanceSource {
+            rpc_endpoint: FAKE_RPC_ENDPOINT.to_string(),
+            block_height,
+            cached,
+        }
+    }
+}
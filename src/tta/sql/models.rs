@@ -1,6 +1,12 @@
+use chrono::{DateTime, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{types::Decimal, Type};
+use sqlx::{
+    types::{Decimal, Json},
+    Type,
+};
+
+use crate::tta::progress::ProgressSnapshot;
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase", default)]
@@ -539,3 +545,112 @@ pub struct BlockId {
     #[serde(rename = "block_ud", default)]
     pub block_height: Decimal,
 }
+
+// A finished /tta job, as persisted in `tta_report_jobs`. The CSV itself is only loaded when
+// downloaded.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct ReportJobRecord {
+    pub id: String,
+    pub state: String,
+    pub error: Option<String>,
+    pub filename: String,
+    pub progress: Json<ProgressSnapshot>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+// A watched account, as persisted in `tta_watchlist`.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Watch {
+    pub account_id: String,
+    // Rows moving less than this are not notified, `WEBHOOK_MIN_AMOUNT` when unset.
+    pub min_amount: Option<f64>,
+    // Currencies of interest, as in the report's currency columns. Any when empty.
+    pub tokens: Vec<String>,
+    // Notified in addition to `WEBHOOK_URLS`.
+    pub webhook_urls: Vec<String>,
+    // Unset for the `WATCHLIST` accounts that were never saved.
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl Watch {
+    pub fn new(account_id: String) -> Self {
+        Self {
+            account_id,
+            min_amount: None,
+            tokens: vec![],
+            webhook_urls: vec![],
+            updated_at: None,
+        }
+    }
+}
+
+// A rule notifying when the balance of an account drops below `threshold`, as persisted in
+// `tta_balance_alerts`.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct BalanceAlert {
+    pub id: i64,
+    pub account_id: String,
+    // `near`, or the contract of a fungible token.
+    pub token_id: String,
+    pub threshold: f64,
+    // Notified in addition to `WEBHOOK_URLS`.
+    pub webhook_urls: Vec<String>,
+    // Set once notified, until the balance is back above the threshold.
+    pub triggered_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+// A balance read at 00:00 UTC of `day`, i.e. at the end of the day before, as persisted in
+// `tta_balance_snapshots`.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct BalanceSnapshot {
+    pub account_id: String,
+    pub day: NaiveDate,
+    pub token_id: String,
+    pub symbol: String,
+    pub block_id: i64,
+    pub balance: Option<f64>,
+    pub spam: bool,
+    pub locked: Option<f64>,
+    pub storage_usage: Option<i64>,
+}
+
+// Gas burnt by the transactions an account signed, and the receipts they spawned, per receiver
+// of the transaction. Burnt tokens are in yoctoNEAR.
+#[derive(Debug, Clone)]
+pub struct GasUsage {
+    pub account_id: String,
+    pub receiver_account_id: String,
+    pub transactions: i64,
+    pub gas_burnt: Decimal,
+    pub tokens_burnt: Decimal,
+}
+
+// A `CREATE_ACCOUNT` or `DELETE_ACCOUNT` action. Amounts are in yoctoNEAR: the deposit attached
+// to the creation, or the balance sent to the beneficiary of the deletion.
+#[derive(Debug, Clone)]
+pub struct AccountLifecycleEvent {
+    pub account_id: String,
+    pub action_kind: String,
+    pub predecessor_account_id: String,
+    pub beneficiary_id: Option<String>,
+    pub amount: Option<Decimal>,
+    pub transaction_hash: String,
+    pub receipt_id: String,
+    pub block_height: Decimal,
+    pub block_timestamp: Decimal,
+}
+
+// A `DEPLOY_CONTRACT` action, `code_sha256` is the base64 hash the indexer stores.
+#[derive(Debug, Clone)]
+pub struct ContractDeployment {
+    pub account_id: String,
+    pub code_sha256: Option<String>,
+    pub predecessor_account_id: String,
+    pub signer_account_id: String,
+    pub transaction_hash: String,
+    pub receipt_id: String,
+    pub block_height: Decimal,
+    pub block_timestamp: Decimal,
+}
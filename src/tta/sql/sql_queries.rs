@@ -1,24 +1,294 @@
-use std::collections::{self};
+use std::{
+    collections::{self, BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use chrono::{DateTime, NaiveDate, Utc};
 use num_traits::cast::ToPrimitive;
-use sqlx::{types::Decimal, Pool, Postgres};
+use serde::Serialize;
+use sqlx::{
+    pool::PoolConnection,
+    postgres::PgPoolOptions,
+    types::{Decimal, Json},
+    Executor, Pool, Postgres,
+};
 use tokio::sync::mpsc::Sender;
 use tokio_stream::StreamExt;
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
+use tta_rust::metrics::metrics;
 
-use crate::tta::sql::models::BlockId;
+use crate::{
+    config::Config,
+    tta::{
+        aggregations::SummaryRow,
+        ft_metadata::{FtMetadata, FtMetadataOverride},
+        models::ReportRow,
+        sql::models::{
+            AccountLifecycleEvent, BalanceAlert, BalanceSnapshot, BlockId, ContractDeployment,
+            GasUsage, ReportJobRecord, Watch,
+        },
+    },
+};
 
 use super::models::Transaction;
 
+// Each query has its plan logged at most this often in diagnostics mode.
+const EXPLAIN_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// Schema of the service owned tables, the indexer one is left untouched.
+pub const SERVICE_SCHEMA: &str = "tta";
+
+// Options of every pool of the service. Service tables are looked up after the indexer ones.
+pub fn pool_options() -> PgPoolOptions {
+    PgPoolOptions::new().after_connect(|conn, _meta| {
+        Box::pin(async move {
+            conn.execute(format!("SET search_path TO public, {};", SERVICE_SCHEMA).as_str())
+                .await?;
+            Ok(())
+        })
+    })
+}
+
+const OUTGOING_TXNS_SQL: &str = include_str!("queries/outgoing_txns.sql");
+const INCOMING_TXNS_SQL: &str = include_str!("queries/incoming_txns.sql");
+const FT_INCOMING_TXNS_SQL: &str = include_str!("queries/ft_incoming_txns.sql");
+
+// Indexer columns the reports read, checked on start.
+const INDEXER_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "transactions",
+        &[
+            "transaction_hash",
+            "included_in_block_hash",
+            "included_in_chunk_hash",
+            "index_in_chunk",
+            "block_timestamp",
+            "signer_account_id",
+            "signer_public_key",
+            "nonce",
+            "receiver_account_id",
+            "signature",
+            "status",
+            "converted_into_receipt_id",
+            "receipt_conversion_gas_burnt",
+            "receipt_conversion_tokens_burnt",
+        ],
+    ),
+    (
+        "receipts",
+        &[
+            "receipt_id",
+            "included_in_block_hash",
+            "included_in_chunk_hash",
+            "index_in_chunk",
+            "included_in_block_timestamp",
+            "predecessor_account_id",
+            "receiver_account_id",
+            "receipt_kind",
+            "originated_from_transaction_hash",
+        ],
+    ),
+    (
+        "action_receipt_actions",
+        &[
+            "receipt_id",
+            "index_in_action_receipt",
+            "action_kind",
+            "args",
+            "receipt_predecessor_account_id",
+            "receipt_receiver_account_id",
+            "receipt_included_in_block_timestamp",
+        ],
+    ),
+    (
+        "blocks",
+        &[
+            "block_height",
+            "block_hash",
+            "prev_block_hash",
+            "block_timestamp",
+            "gas_price",
+            "author_account_id",
+        ],
+    ),
+    (
+        "execution_outcomes",
+        &[
+            "receipt_id",
+            "executed_in_block_hash",
+            "executed_in_block_timestamp",
+            "index_in_chunk",
+            "gas_burnt",
+            "tokens_burnt",
+            "executor_account_id",
+            "shard_id",
+            "status",
+        ],
+    ),
+];
+
+// Enum typed indexer columns, and the labels the queries compare them to.
+const INDEXER_ENUMS: &[(&str, &str, &str, &[&str])] = &[
+    (
+        "action_receipt_actions",
+        "action_kind",
+        "action_kind",
+        &[
+            "CREATE_ACCOUNT",
+            "DEPLOY_CONTRACT",
+            "FUNCTION_CALL",
+            "TRANSFER",
+            "DELETE_ACCOUNT",
+        ],
+    ),
+    (
+        "execution_outcomes",
+        "status",
+        "execution_outcome_status",
+        &["SUCCESS_RECEIPT_ID", "SUCCESS_VALUE", "FAILURE"],
+    ),
+];
+
 #[derive(Debug, Clone)]
 pub struct SqlClient {
-    pool: Pool<Postgres>,
+    // Swapped for a new pool on resize.
+    pool: Arc<RwLock<Pool<Postgres>>>,
+    // Queries waiting for a connection.
+    waiters: Arc<AtomicUsize>,
+    // Diagnostics mode, transaction queries slower than this get their plan explained.
+    slow_query_threshold: Option<Duration>,
+    // When each query last had its plan explained.
+    explained_at: Arc<Mutex<HashMap<&'static str, Instant>>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub max_connections: u32,
+    pub size: u32,
+    pub idle: usize,
+    pub waiters: usize,
 }
 
 impl SqlClient {
     pub fn new(pool: Pool<Postgres>) -> Self {
-        Self { pool }
+        Self {
+            pool: Arc::new(RwLock::new(pool)),
+            waiters: Arc::new(AtomicUsize::new(0)),
+            slow_query_threshold: None,
+            explained_at: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn pool(&self) -> Pool<Postgres> {
+        self.pool.read().unwrap().clone()
+    }
+
+    async fn acquire(&self) -> Result<PoolConnection<Postgres>> {
+        let pool = self.pool();
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        let conn = pool.acquire().await;
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        Ok(conn?)
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        let pool = self.pool();
+        PoolStats {
+            max_connections: pool.options().get_max_connections(),
+            size: pool.size(),
+            idle: pool.num_idle(),
+            waiters: self.waiters.load(Ordering::Relaxed),
+        }
+    }
+
+    // Creates or upgrades the service owned tables with the embedded `migrations`, in their own
+    // schema. The connection is not returned to the pool, its search_path is only for these.
+    pub async fn migrate(&self) -> Result<()> {
+        let mut conn = self.acquire().await?.detach();
+        conn.execute(format!("CREATE SCHEMA IF NOT EXISTS {};", SERVICE_SCHEMA).as_str())
+            .await?;
+        conn.execute(format!("SET search_path TO {}, public;", SERVICE_SCHEMA).as_str())
+            .await?;
+        sqlx::migrate!().run(&mut conn).await?;
+        info!("Service tables migrated in the {} schema", SERVICE_SCHEMA);
+
+        Ok(())
+    }
+
+    // Replaces the pool with one of `max_connections`. Queries already running keep their
+    // connection, the old pool is closed once they are all returned.
+    pub async fn resize_pool(&self, max_connections: u32) -> Result<()> {
+        let pool = pool_options()
+            .max_connections(max_connections)
+            .connect(env!("DATABASE_URL"))
+            .await?;
+        let old_pool = std::mem::replace(&mut *self.pool.write().unwrap(), pool);
+        info!("Resized the DB pool to {} connections", max_connections);
+        tokio::spawn(async move { old_pool.close().await });
+
+        Ok(())
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.slow_query_threshold =
+            (config.slow_query_ms > 0).then(|| Duration::from_millis(config.slow_query_ms));
+        self
+    }
+
+    // Explains a slow transaction query in the background, and logs its plan along with the
+    // parameters it was run with. Plain EXPLAIN, the query is not run again, and only once per
+    // `EXPLAIN_INTERVAL` for each query.
+    fn explain_if_slow(
+        &self,
+        query: &'static str,
+        sql: &'static str,
+        accs: &[String],
+        start_date: Decimal,
+        end_date: Decimal,
+        elapsed: Duration,
+    ) {
+        match self.slow_query_threshold {
+            Some(threshold) if elapsed >= threshold => {}
+            _ => return,
+        }
+        {
+            let mut explained_at = self.explained_at.lock().unwrap();
+            if let Some(at) = explained_at.get(query) {
+                if at.elapsed() < EXPLAIN_INTERVAL {
+                    return;
+                }
+            }
+            explained_at.insert(query, Instant::now());
+        }
+
+        let pool = self.pool();
+        let accs = accs.to_vec();
+        tokio::spawn(async move {
+            let explain = format!("EXPLAIN {}", sql.trim().trim_end_matches(';'));
+            let plan: Result<Vec<String>, _> = sqlx::query_scalar(&explain)
+                .bind(&accs)
+                .bind(start_date)
+                .bind(end_date)
+                .fetch_all(&pool)
+                .await;
+            match plan {
+                Ok(plan) => warn!(
+                    query,
+                    ?elapsed,
+                    accounts = ?accs,
+                    %start_date,
+                    %end_date,
+                    "Slow query plan:\n{}",
+                    plan.join("\n")
+                ),
+                Err(e) => error!(?e, query, "Error explaining slow query"),
+            }
+        });
     }
 
     #[instrument(skip(self, sender_txn))]
@@ -33,98 +303,43 @@ impl SqlClient {
         let start_date_decimal = Decimal::from(start_date);
         let end_date_decimal = Decimal::from(end_date);
 
-        let mut stream_txs = sqlx::query_as!(
+        let mut conn = self.acquire().await?;
+        let mut stream_txs = sqlx::query_file_as!(
             Transaction,
-            r##"SELECT
-                T.TRANSACTION_HASH as T_TRANSACTION_HASH,
-                T.INCLUDED_IN_BLOCK_HASH as T_INCLUDED_IN_BLOCK_HASH,
-                T.INCLUDED_IN_CHUNK_HASH as T_INCLUDED_IN_CHUNK_HASH,
-                T.INDEX_IN_CHUNK as T_INDEX_IN_CHUNK,
-                T.BLOCK_TIMESTAMP as T_BLOCK_TIMESTAMP,
-                T.SIGNER_ACCOUNT_ID as T_SIGNER_ACCOUNT_ID,
-                T.SIGNER_PUBLIC_KEY as T_SIGNER_PUBLIC_KEY,
-                T.NONCE as T_NONCE,
-                T.RECEIVER_ACCOUNT_ID as T_RECEIVER_ACCOUNT_ID,
-                T.SIGNATURE as T_SIGNATURE,
-                T.STATUS as "t_status: String",
-                T.CONVERTED_INTO_RECEIPT_ID as T_CONVERTED_INTO_RECEIPT_ID,
-                T.RECEIPT_CONVERSION_GAS_BURNT as T_RECEIPT_CONVERSION_GAS_BURNT,
-                T.RECEIPT_CONVERSION_TOKENS_BURNT as T_RECEIPT_CONVERSION_TOKENS_BURNT,
-                R.RECEIPT_ID as R_RECEIPT_ID,
-                R.INCLUDED_IN_BLOCK_HASH as R_INCLUDED_IN_BLOCK_HASH,
-                R.INCLUDED_IN_CHUNK_HASH as R_INCLUDED_IN_CHUNK_HASH,
-                R.INDEX_IN_CHUNK as R_INDEX_IN_CHUNK,
-                R.INCLUDED_IN_BLOCK_TIMESTAMP as R_INCLUDED_IN_BLOCK_TIMESTAMP,
-                R.PREDECESSOR_ACCOUNT_ID as R_PREDECESSOR_ACCOUNT_ID,
-                R.RECEIVER_ACCOUNT_ID as R_RECEIVER_ACCOUNT_ID,
-                R.RECEIPT_KIND as "r_receipt_kind: String",
-                R.ORIGINATED_FROM_TRANSACTION_HASH as R_ORIGINATED_FROM_TRANSACTION_HASH,
-                ARA.RECEIPT_ID as ARA_RECEIPT_ID,
-                ARA.INDEX_IN_ACTION_RECEIPT as ARA_INDEX_IN_ACTION_RECEIPT,
-                ARA.ARGS as ARA_ARGS,
-                ARA.RECEIPT_PREDECESSOR_ACCOUNT_ID as ARA_RECEIPT_PREDECESSOR_ACCOUNT_ID,
-                ARA.RECEIPT_RECEIVER_ACCOUNT_ID as ARA_RECEIPT_RECEIVER_ACCOUNT_ID,
-                ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP as ARA_RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP,
-                ARA.ACTION_KIND as "ara_action_kind: String",
-                B.BLOCK_HEIGHT as B_BLOCK_HEIGHT,
-                B.BLOCK_HASH as B_BLOCK_HASH,
-                B.PREV_BLOCK_HASH as B_PREV_BLOCK_HASH,
-                B.BLOCK_TIMESTAMP as B_BLOCK_TIMESTAMP,
-                B.GAS_PRICE as B_GAS_PRICE,
-                B.AUTHOR_ACCOUNT_ID as B_AUTHOR_ACCOUNT_ID,
-                EO.RECEIPT_ID as EO_RECEIPT_ID,
-                EO.EXECUTED_IN_BLOCK_HASH  as EO_EXECUTED_IN_BLOCK_HASH ,
-                EO.EXECUTED_IN_BLOCK_TIMESTAMP as EO_EXECUTED_IN_BLOCK_TIMESTAMP,
-                EO.INDEX_IN_CHUNK as EO_INDEX_IN_CHUNK,
-                EO.GAS_BURNT as EO_GAS_BURNT,
-                EO.TOKENS_BURNT as EO_TOKENS_BURNT,
-                EO.EXECUTOR_ACCOUNT_ID as EO_EXECUTOR_ACCOUNT_ID,
-                EO.SHARD_ID as EO_SHARD_ID,
-                EO.STATUS as "eo_status: String"
-            FROM
-                TRANSACTIONS T
-                LEFT JOIN RECEIPTS R ON (T.CONVERTED_INTO_RECEIPT_ID = R.RECEIPT_ID
-                        OR t.TRANSACTION_HASH = R.ORIGINATED_FROM_TRANSACTION_HASH)
-                LEFT JOIN ACTION_RECEIPT_ACTIONS ARA ON ARA.RECEIPT_ID = R.RECEIPT_ID
-                LEFT JOIN BLOCKS B ON B.BLOCK_HASH = R.INCLUDED_IN_BLOCK_HASH
-                LEFT JOIN EXECUTION_OUTCOMES EO ON EO.RECEIPT_ID = R.RECEIPT_ID
-            WHERE
-                receipt_predecessor_account_id = ANY($1)
-                AND EO.STATUS IN ('SUCCESS_RECEIPT_ID', 'SUCCESS_VALUE')
-                and B.BLOCK_TIMESTAMP >= $2
-                and B.BLOCK_TIMESTAMP < $3  
-                AND NOT EXISTS (
-                    SELECT 1
-                    FROM RECEIPTS R2
-                    JOIN EXECUTION_OUTCOMES EO2 ON EO2.RECEIPT_ID = R2.RECEIPT_ID
-                    WHERE (T.CONVERTED_INTO_RECEIPT_ID = R2.RECEIPT_ID OR T.TRANSACTION_HASH = R2.ORIGINATED_FROM_TRANSACTION_HASH)
-                    AND EO2.STATUS = 'FAILURE'
-                );
-            "##,
+            "src/tta/sql/queries/outgoing_txns.sql",
             &accs,
             &start_date_decimal,
             &end_date_decimal,
         )
-        .fetch(&self.pool);
+        .fetch(&mut *conn);
 
-        let start = chrono::Utc::now();
+        let started_at = Instant::now();
+        let (mut rows, mut row_errors) = (0, 0);
 
         while let Some(txn) = stream_txs.next().await {
             match txn {
                 Ok(txn) => {
+                    rows += 1;
                     if let Err(e) = sender_txn.send(txn).await {
                         error!("Error sending transaction: {}", e);
                     };
                 }
-                Err(e) => error!("Error getting transaction: {}", e),
+                Err(e) => {
+                    row_errors += 1;
+                    error!("Error getting transaction: {}", e)
+                }
             }
         }
 
-        let end = chrono::Utc::now();
-        info!(
-            "Time taken to get outgoing transactions: {:?} for {:?}",
-            end - start,
-            accs
+        let elapsed = started_at.elapsed();
+        metrics().record_sql_query("outgoing_txns", elapsed, rows, row_errors);
+        self.explain_if_slow(
+            "outgoing_txns",
+            OUTGOING_TXNS_SQL,
+            &accs,
+            start_date_decimal,
+            end_date_decimal,
+            elapsed,
         );
 
         Ok(())
@@ -142,92 +357,43 @@ impl SqlClient {
         let start_date_decimal = Decimal::from(start_date);
         let end_date_decimal = Decimal::from(end_date);
 
-        let mut stream_txs = sqlx::query_as!(
+        let mut conn = self.acquire().await?;
+        let mut stream_txs = sqlx::query_file_as!(
             Transaction,
-            r##"
-            SELECT
-                T.TRANSACTION_HASH as T_TRANSACTION_HASH,
-                T.INCLUDED_IN_BLOCK_HASH as T_INCLUDED_IN_BLOCK_HASH,
-                T.INCLUDED_IN_CHUNK_HASH as T_INCLUDED_IN_CHUNK_HASH,
-                T.INDEX_IN_CHUNK as T_INDEX_IN_CHUNK,
-                T.BLOCK_TIMESTAMP as T_BLOCK_TIMESTAMP,
-                T.SIGNER_ACCOUNT_ID as T_SIGNER_ACCOUNT_ID,
-                T.SIGNER_PUBLIC_KEY as T_SIGNER_PUBLIC_KEY,
-                T.NONCE as T_NONCE,
-                T.RECEIVER_ACCOUNT_ID as T_RECEIVER_ACCOUNT_ID,
-                T.SIGNATURE as T_SIGNATURE,
-                T.STATUS as "t_status: String",
-                T.CONVERTED_INTO_RECEIPT_ID as T_CONVERTED_INTO_RECEIPT_ID,
-                T.RECEIPT_CONVERSION_GAS_BURNT as T_RECEIPT_CONVERSION_GAS_BURNT,
-                T.RECEIPT_CONVERSION_TOKENS_BURNT as T_RECEIPT_CONVERSION_TOKENS_BURNT,
-                R.RECEIPT_ID as R_RECEIPT_ID,
-                R.INCLUDED_IN_BLOCK_HASH as R_INCLUDED_IN_BLOCK_HASH,
-                R.INCLUDED_IN_CHUNK_HASH as R_INCLUDED_IN_CHUNK_HASH,
-                R.INDEX_IN_CHUNK as R_INDEX_IN_CHUNK,
-                R.INCLUDED_IN_BLOCK_TIMESTAMP as R_INCLUDED_IN_BLOCK_TIMESTAMP,
-                R.PREDECESSOR_ACCOUNT_ID as R_PREDECESSOR_ACCOUNT_ID,
-                R.RECEIVER_ACCOUNT_ID as R_RECEIVER_ACCOUNT_ID,
-                R.RECEIPT_KIND as "r_receipt_kind: String",
-                R.ORIGINATED_FROM_TRANSACTION_HASH as R_ORIGINATED_FROM_TRANSACTION_HASH,
-                ARA.RECEIPT_ID as ARA_RECEIPT_ID,
-                ARA.INDEX_IN_ACTION_RECEIPT as ARA_INDEX_IN_ACTION_RECEIPT,
-                ARA.ARGS as ARA_ARGS,
-                ARA.RECEIPT_PREDECESSOR_ACCOUNT_ID as ARA_RECEIPT_PREDECESSOR_ACCOUNT_ID,
-                ARA.RECEIPT_RECEIVER_ACCOUNT_ID as ARA_RECEIPT_RECEIVER_ACCOUNT_ID,
-                ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP as ARA_RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP,
-                ARA.ACTION_KIND as "ara_action_kind: String",
-                B.BLOCK_HEIGHT as B_BLOCK_HEIGHT,
-                B.BLOCK_HASH as B_BLOCK_HASH,
-                B.PREV_BLOCK_HASH as B_PREV_BLOCK_HASH,
-                B.BLOCK_TIMESTAMP as B_BLOCK_TIMESTAMP,
-                B.GAS_PRICE as B_GAS_PRICE,
-                B.AUTHOR_ACCOUNT_ID as B_AUTHOR_ACCOUNT_ID,
-                EO.RECEIPT_ID as EO_RECEIPT_ID,
-                EO.EXECUTED_IN_BLOCK_HASH  as EO_EXECUTED_IN_BLOCK_HASH ,
-                EO.EXECUTED_IN_BLOCK_TIMESTAMP as EO_EXECUTED_IN_BLOCK_TIMESTAMP,
-                EO.INDEX_IN_CHUNK as EO_INDEX_IN_CHUNK,
-                EO.GAS_BURNT as EO_GAS_BURNT,
-                EO.TOKENS_BURNT as EO_TOKENS_BURNT,
-                EO.EXECUTOR_ACCOUNT_ID as EO_EXECUTOR_ACCOUNT_ID,
-                EO.SHARD_ID as EO_SHARD_ID,
-                EO.STATUS as "eo_status: String"
-            FROM
-                TRANSACTIONS T
-                LEFT JOIN RECEIPTS R ON (T.CONVERTED_INTO_RECEIPT_ID = R.RECEIPT_ID
-                        OR T.TRANSACTION_HASH = R.ORIGINATED_FROM_TRANSACTION_HASH)
-                LEFT JOIN ACTION_RECEIPT_ACTIONS ARA ON ARA.RECEIPT_ID = R.RECEIPT_ID
-                LEFT JOIN BLOCKS B ON B.BLOCK_HASH = R.INCLUDED_IN_BLOCK_HASH
-                LEFT JOIN EXECUTION_OUTCOMES EO ON EO.RECEIPT_ID = R.RECEIPT_ID
-            WHERE
-                RECEIPT_RECEIVER_ACCOUNT_ID = ANY ($1)
-                AND EO.STATUS IN ('SUCCESS_RECEIPT_ID', 'SUCCESS_VALUE')
-                AND B.BLOCK_TIMESTAMP >= $2
-                AND B.BLOCK_TIMESTAMP < $3;
-            "##,
+            "src/tta/sql/queries/incoming_txns.sql",
             &accs,
             &start_date_decimal,
             &end_date_decimal,
         )
-        .fetch(&self.pool);
+        .fetch(&mut *conn);
 
-        let start = chrono::Utc::now();
+        let started_at = Instant::now();
+        let (mut rows, mut row_errors) = (0, 0);
 
         while let Some(txn) = stream_txs.next().await {
             match txn {
                 Ok(txn) => {
+                    rows += 1;
                     if let Err(e) = sender_txn.send(txn).await {
                         error!("Error sending transaction: {}", e);
                     };
                 }
-                Err(e) => error!("Error getting transaction: {}", e),
+                Err(e) => {
+                    row_errors += 1;
+                    error!("Error getting transaction: {}", e)
+                }
             }
         }
 
-        let end = chrono::Utc::now();
-        info!(
-            "Time taken to get incoming transactions: {:?} for {:?}",
-            end - start,
-            accs
+        let elapsed = started_at.elapsed();
+        metrics().record_sql_query("incoming_txns", elapsed, rows, row_errors);
+        self.explain_if_slow(
+            "incoming_txns",
+            INCOMING_TXNS_SQL,
+            &accs,
+            start_date_decimal,
+            end_date_decimal,
+            elapsed,
         );
 
         Ok(())
@@ -245,103 +411,115 @@ impl SqlClient {
         let start_date_decimal = Decimal::from(start_date);
         let end_date_decimal = Decimal::from(end_date);
 
-        let mut stream_txs = sqlx::query_as!(
+        let mut conn = self.acquire().await?;
+        let mut stream_txs = sqlx::query_file_as!(
             Transaction,
-            r##"
-            SELECT
-                T.TRANSACTION_HASH as T_TRANSACTION_HASH,
-                T.INCLUDED_IN_BLOCK_HASH as T_INCLUDED_IN_BLOCK_HASH,
-                T.INCLUDED_IN_CHUNK_HASH as T_INCLUDED_IN_CHUNK_HASH,
-                T.INDEX_IN_CHUNK as T_INDEX_IN_CHUNK,
-                T.BLOCK_TIMESTAMP as T_BLOCK_TIMESTAMP,
-                T.SIGNER_ACCOUNT_ID as T_SIGNER_ACCOUNT_ID,
-                T.SIGNER_PUBLIC_KEY as T_SIGNER_PUBLIC_KEY,
-                T.NONCE as T_NONCE,
-                T.RECEIVER_ACCOUNT_ID as T_RECEIVER_ACCOUNT_ID,
-                T.SIGNATURE as T_SIGNATURE,
-                T.STATUS as "t_status: String",
-                T.CONVERTED_INTO_RECEIPT_ID as T_CONVERTED_INTO_RECEIPT_ID,
-                T.RECEIPT_CONVERSION_GAS_BURNT as T_RECEIPT_CONVERSION_GAS_BURNT,
-                T.RECEIPT_CONVERSION_TOKENS_BURNT as T_RECEIPT_CONVERSION_TOKENS_BURNT,
-                R.RECEIPT_ID as R_RECEIPT_ID,
-                R.INCLUDED_IN_BLOCK_HASH as R_INCLUDED_IN_BLOCK_HASH,
-                R.INCLUDED_IN_CHUNK_HASH as R_INCLUDED_IN_CHUNK_HASH,
-                R.INDEX_IN_CHUNK as R_INDEX_IN_CHUNK,
-                R.INCLUDED_IN_BLOCK_TIMESTAMP as R_INCLUDED_IN_BLOCK_TIMESTAMP,
-                R.PREDECESSOR_ACCOUNT_ID as R_PREDECESSOR_ACCOUNT_ID,
-                R.RECEIVER_ACCOUNT_ID as R_RECEIVER_ACCOUNT_ID,
-                R.RECEIPT_KIND as "r_receipt_kind: String",
-                R.ORIGINATED_FROM_TRANSACTION_HASH as R_ORIGINATED_FROM_TRANSACTION_HASH,
-                ARA.RECEIPT_ID as ARA_RECEIPT_ID,
-                ARA.INDEX_IN_ACTION_RECEIPT as ARA_INDEX_IN_ACTION_RECEIPT,
-                ARA.ARGS as ARA_ARGS,
-                ARA.RECEIPT_PREDECESSOR_ACCOUNT_ID as ARA_RECEIPT_PREDECESSOR_ACCOUNT_ID,
-                ARA.RECEIPT_RECEIVER_ACCOUNT_ID as ARA_RECEIPT_RECEIVER_ACCOUNT_ID,
-                ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP as ARA_RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP,
-                ARA.ACTION_KIND as "ara_action_kind: String",
-                B.BLOCK_HEIGHT as B_BLOCK_HEIGHT,
-                B.BLOCK_HASH as B_BLOCK_HASH,
-                B.PREV_BLOCK_HASH as B_PREV_BLOCK_HASH,
-                B.BLOCK_TIMESTAMP as B_BLOCK_TIMESTAMP,
-                B.GAS_PRICE as B_GAS_PRICE,
-                B.AUTHOR_ACCOUNT_ID as B_AUTHOR_ACCOUNT_ID,
-                EO.RECEIPT_ID as EO_RECEIPT_ID,
-                EO.EXECUTED_IN_BLOCK_HASH  as EO_EXECUTED_IN_BLOCK_HASH ,
-                EO.EXECUTED_IN_BLOCK_TIMESTAMP as EO_EXECUTED_IN_BLOCK_TIMESTAMP,
-                EO.INDEX_IN_CHUNK as EO_INDEX_IN_CHUNK,
-                EO.GAS_BURNT as EO_GAS_BURNT,
-                EO.TOKENS_BURNT as EO_TOKENS_BURNT,
-                EO.EXECUTOR_ACCOUNT_ID as EO_EXECUTOR_ACCOUNT_ID,
-                EO.SHARD_ID as EO_SHARD_ID,
-                EO.STATUS as "eo_status: String"
-            FROM TRANSACTIONS t
-                    LEFT JOIN RECEIPTS R ON (T.CONVERTED_INTO_RECEIPT_ID = R.RECEIPT_ID OR
-                                                t.TRANSACTION_HASH = R.ORIGINATED_FROM_TRANSACTION_HASH)
-                    LEFT JOIN ACTION_RECEIPT_ACTIONS ARA ON ARA.RECEIPT_ID = R.RECEIPT_ID
-                    LEFT JOIN BLOCKS B ON B.BLOCK_HASH = R.INCLUDED_IN_BLOCK_HASH
-                    LEFT JOIN EXECUTION_OUTCOMES EO ON EO.RECEIPT_ID = R.RECEIPT_ID
-            WHERE eo.status IN ('SUCCESS_RECEIPT_ID', 'SUCCESS_VALUE')
-                AND ARA.action_kind = 'FUNCTION_CALL'
-                AND (ARA.args -> 'args_json' ->> 'receiver_id' = ANY($1) OR ARA.args -> 'args_json' ->> 'account_id' = ANY($1))
-                AND B.BLOCK_TIMESTAMP >= $2
-                AND B.BLOCK_TIMESTAMP < $3
-                AND NOT EXISTS (
-                    SELECT 1
-                    FROM RECEIPTS R2
-                    JOIN EXECUTION_OUTCOMES EO2 ON EO2.RECEIPT_ID = R2.RECEIPT_ID
-                    WHERE (T.CONVERTED_INTO_RECEIPT_ID = R2.RECEIPT_ID OR T.TRANSACTION_HASH = R2.ORIGINATED_FROM_TRANSACTION_HASH)
-                    AND EO2.STATUS = 'FAILURE'
-            );
-            "##,
+            "src/tta/sql/queries/ft_incoming_txns.sql",
             &accs,
             &start_date_decimal,
             &end_date_decimal,
         )
-        .fetch(&self.pool);
+        .fetch(&mut *conn);
 
-        let start = chrono::Utc::now();
+        let started_at = Instant::now();
+        let (mut rows, mut row_errors) = (0, 0);
 
         while let Some(txn) = stream_txs.next().await {
             match txn {
                 Ok(txn) => {
+                    rows += 1;
                     if let Err(e) = sender_txn.send(txn).await {
                         error!("Error sending transaction: {}", e);
                     };
                 }
-                Err(e) => error!("Error getting transaction: {}", e),
+                Err(e) => {
+                    row_errors += 1;
+                    error!("Error getting transaction: {}", e)
+                }
             }
         }
 
-        let end = chrono::Utc::now();
-        info!(
-            "Time taken to get incoming FT transactions: {:?} for {:?}",
-            end - start,
-            accs
+        let elapsed = started_at.elapsed();
+        metrics().record_sql_query("ft_incoming_txns", elapsed, rows, row_errors);
+        self.explain_if_slow(
+            "ft_incoming_txns",
+            FT_INCOMING_TXNS_SQL,
+            &accs,
+            start_date_decimal,
+            end_date_decimal,
+            elapsed,
         );
 
         Ok(())
     }
 
+    // Fails with every missing table, column and enum label when the indexer DB does not have
+    // the schema the reports are written against.
+    pub async fn check_indexer_schema(&self) -> Result<()> {
+        let tables: Vec<String> = INDEXER_COLUMNS
+            .iter()
+            .map(|(table, _)| table.to_string())
+            .collect();
+        let columns = sqlx::query_as::<_, (String, String, String)>(
+            r##"
+            SELECT table_name::text, column_name::text, udt_name::text
+            FROM information_schema.columns
+            WHERE table_schema = current_schema() AND table_name = ANY($1);
+            "##,
+        )
+        .bind(&tables)
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+        let column_types: HashMap<(String, String), String> = columns
+            .into_iter()
+            .map(|(table, column, udt_name)| ((table, column), udt_name))
+            .collect();
+
+        let types: Vec<String> = INDEXER_ENUMS
+            .iter()
+            .map(|(_, _, enum_type, _)| enum_type.to_string())
+            .collect();
+        let labels = sqlx::query_as::<_, (String, String)>(
+            r##"
+            SELECT t.typname::text, e.enumlabel::text
+            FROM pg_enum e
+                JOIN pg_type t ON t.oid = e.enumtypid
+            WHERE t.typname = ANY($1);
+            "##,
+        )
+        .bind(&types)
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        let mut problems = vec![];
+        for (table, columns) in INDEXER_COLUMNS {
+            for column in *columns {
+                if !column_types.contains_key(&(table.to_string(), column.to_string())) {
+                    problems.push(format!("missing column {}.{}", table, column));
+                }
+            }
+        }
+        for (table, column, enum_type, expected_labels) in INDEXER_ENUMS {
+            match column_types.get(&(table.to_string(), column.to_string())) {
+                Some(udt_name) if udt_name != enum_type => problems.push(format!(
+                    "{}.{} is a {}, expected {}",
+                    table, column, udt_name, enum_type
+                )),
+                _ => {}
+            }
+            for label in *expected_labels {
+                if !labels.contains(&(enum_type.to_string(), label.to_string())) {
+                    problems.push(format!("enum {} has no {}", enum_type, label));
+                }
+            }
+        }
+        if !problems.is_empty() {
+            bail!("Unexpected indexer DB schema: {}", problems.join(", "));
+        }
+
+        Ok(())
+    }
+
     #[instrument(skip(self))]
     pub async fn get_closest_block_id(&self, date: u128) -> Result<u128> {
         debug!("calling DB");
@@ -358,12 +536,45 @@ impl SqlClient {
             "##,
             &date_decimal,
         )
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *self.acquire().await?)
         .await?;
 
         Ok(block.block_height.to_u128().unwrap())
     }
 
+    // Block timestamp of a transaction, None when the indexer doesn't have it.
+    #[instrument(skip(self))]
+    pub async fn get_transaction_timestamp(&self, transaction_hash: &str) -> Result<Option<u128>> {
+        let row = sqlx::query!(
+            r##"
+            SELECT block_timestamp AS "block_timestamp!"
+            FROM transactions
+            WHERE transaction_hash = $1;
+            "##,
+            transaction_hash,
+        )
+        .fetch_optional(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.and_then(|row| row.block_timestamp.to_u128()))
+    }
+
+    // Every receipt action of a transaction, whatever its status, in execution order.
+    #[instrument(skip(self))]
+    pub async fn get_transaction_receipts(
+        &self,
+        transaction_hash: &str,
+    ) -> Result<Vec<Transaction>> {
+        Ok(sqlx::query_file_as!(
+            Transaction,
+            "src/tta/sql/queries/transaction_receipts.sql",
+            transaction_hash,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?)
+    }
+
+    // Block ids are returned in ascending date order, callers pass sorted dates.
     #[instrument(skip(self, dates))]
     pub async fn get_closest_block_ids(&self, dates: Vec<u128>) -> Result<Vec<u128>> {
         debug!("calling DB");
@@ -387,10 +598,11 @@ impl SqlClient {
                 ) AS "block_height!"
             FROM timestamps_cte ts
             WHERE ts.date = ANY($1::numeric[])
+            ORDER BY ts.date ASC
             "##,
             &dates_decimal
         )
-        .fetch_all(&self.pool)
+        .fetch_all(&mut *self.acquire().await?)
         .await?;
 
         // Extract block_height from result and return
@@ -401,6 +613,1282 @@ impl SqlClient {
 
         Ok(block_ids)
     }
+
+    // Tokens the account ever sent or received according to the NEP-141 events, used as a
+    // likely tokens source that doesn't depend on external APIs.
+    #[instrument(skip(self))]
+    pub async fn get_likely_tokens(&self, account: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT DISTINCT emitted_by_contract_account_id AS "token_id!"
+            FROM assets__fungible_token_events
+            WHERE token_new_owner_account_id = $1
+                OR token_old_owner_account_id = $1;
+            "##,
+            account,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.token_id).collect())
+    }
+
+    // Staking pools the account ever staked with, derived from its staking calls.
+    #[instrument(skip(self))]
+    pub async fn get_staking_pools(&self, account: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT DISTINCT receipt_receiver_account_id AS "pool_id!"
+            FROM action_receipt_actions
+            WHERE receipt_predecessor_account_id = $1
+                AND action_kind = 'FUNCTION_CALL'
+                AND args ->> 'method_name' IN ('deposit_and_stake', 'stake');
+            "##,
+            account,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.pool_id).collect())
+    }
+
+    // Rough size of a /tta report: the actions the outgoing, incoming and FT incoming queries
+    // would pick up, without their joins and failure checks. Each of them is counted on its own,
+    // so the indexes on the accounts apply, and only up to `limit`, so large accounts don't scan
+    // their whole history.
+    #[instrument(skip(self, accounts))]
+    pub async fn estimate_txns_count(
+        &self,
+        accounts: &collections::HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        limit: u64,
+    ) -> Result<u64> {
+        let accs: Vec<String> = accounts.iter().cloned().collect();
+        let row = sqlx::query!(
+            r##"
+            SELECT
+                (SELECT COUNT(*) FROM (
+                    SELECT 1
+                    FROM ACTION_RECEIPT_ACTIONS ARA
+                    WHERE ARA.RECEIPT_PREDECESSOR_ACCOUNT_ID = ANY($1)
+                        AND ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP >= $2
+                        AND ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP < $3
+                    LIMIT $4
+                ) OUTGOING)
+                + (SELECT COUNT(*) FROM (
+                    SELECT 1
+                    FROM ACTION_RECEIPT_ACTIONS ARA
+                    WHERE ARA.RECEIPT_RECEIVER_ACCOUNT_ID = ANY($1)
+                        AND ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP >= $2
+                        AND ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP < $3
+                    LIMIT $4
+                ) INCOMING)
+                + (SELECT COUNT(*) FROM (
+                    SELECT 1
+                    FROM ACTION_RECEIPT_ACTIONS ARA
+                    WHERE ARA.ACTION_KIND = 'FUNCTION_CALL'
+                        AND (ARA.ARGS -> 'args_json' ->> 'receiver_id' = ANY($1)
+                            OR ARA.ARGS -> 'args_json' ->> 'account_id' = ANY($1))
+                        AND ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP >= $2
+                        AND ARA.RECEIPT_INCLUDED_IN_BLOCK_TIMESTAMP < $3
+                    LIMIT $4
+                ) FT_INCOMING) AS "count!"
+            "##,
+            &accs,
+            Decimal::from(start_date),
+            Decimal::from(end_date),
+            limit as i64,
+        )
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.count as u64)
+    }
+
+    // Tokens `from` -> `to` minus refunds `to` -> `from` within a transaction, from the NEP-141
+    // events the token emitted while executing it. Used to tell what an `ft_transfer_call`
+    // receiver actually kept.
+    #[instrument(skip(self))]
+    pub async fn get_ft_net_transferred(
+        &self,
+        transaction_hash: &str,
+        token_id: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<u128> {
+        let row = sqlx::query!(
+            r##"
+            SELECT
+                COALESCE(SUM(
+                    CASE WHEN FTE.token_old_owner_account_id = $3
+                        THEN FTE.amount
+                        ELSE -FTE.amount
+                    END
+                ), 0) AS "amount!"
+            FROM assets__fungible_token_events FTE
+                JOIN receipts R ON R.receipt_id = FTE.emitted_for_receipt_id
+            WHERE R.originated_from_transaction_hash = $1
+                AND FTE.emitted_by_contract_account_id = $2
+                AND (
+                    (FTE.token_old_owner_account_id = $3 AND FTE.token_new_owner_account_id = $4)
+                    OR (FTE.token_old_owner_account_id = $4 AND FTE.token_new_owner_account_id = $3)
+                );
+            "##,
+            transaction_hash,
+            token_id,
+            from,
+            to,
+        )
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.amount.to_u128().unwrap_or_default())
+    }
+
+    // Whether `from` sent any receipt to `to` as part of the transaction.
+    #[instrument(skip(self))]
+    pub async fn has_receipts_between(
+        &self,
+        transaction_hash: &str,
+        from: &str,
+        to: &str,
+    ) -> Result<bool> {
+        let row = sqlx::query!(
+            r##"
+            SELECT EXISTS (
+                SELECT 1
+                FROM receipts
+                WHERE originated_from_transaction_hash = $1
+                    AND predecessor_account_id = $2
+                    AND receiver_account_id = $3
+            ) AS "exists!";
+            "##,
+            transaction_hash,
+            from,
+            to,
+        )
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.exists)
+    }
+
+    // Gas paid by the accounts for the transactions they signed in the period: converting the
+    // transaction into a receipt, then executing every receipt it spawned.
+    #[instrument(skip(self))]
+    pub async fn get_gas_usage(
+        &self,
+        accounts: &[String],
+        start_date: u128,
+        end_date: u128,
+    ) -> Result<Vec<GasUsage>> {
+        let rows = sqlx::query!(
+            r##"
+            WITH txns AS (
+                SELECT transaction_hash, signer_account_id, receiver_account_id,
+                    receipt_conversion_gas_burnt, receipt_conversion_tokens_burnt
+                FROM transactions
+                WHERE signer_account_id = ANY($1)
+                    AND block_timestamp >= $2
+                    AND block_timestamp < $3
+            ),
+            receipts_burnt AS (
+                SELECT R.originated_from_transaction_hash AS transaction_hash,
+                    SUM(EO.gas_burnt) AS gas_burnt,
+                    SUM(EO.tokens_burnt) AS tokens_burnt
+                FROM receipts R
+                    JOIN execution_outcomes EO ON EO.receipt_id = R.receipt_id
+                WHERE R.originated_from_transaction_hash IN (SELECT transaction_hash FROM txns)
+                GROUP BY R.originated_from_transaction_hash
+            )
+            SELECT
+                T.signer_account_id AS "account_id!",
+                T.receiver_account_id AS "receiver_account_id!",
+                COUNT(*) AS "transactions!",
+                SUM(T.receipt_conversion_gas_burnt + COALESCE(RB.gas_burnt, 0)) AS "gas_burnt!",
+                SUM(T.receipt_conversion_tokens_burnt + COALESCE(RB.tokens_burnt, 0))
+                    AS "tokens_burnt!"
+            FROM txns T
+                LEFT JOIN receipts_burnt RB ON RB.transaction_hash = T.transaction_hash
+            GROUP BY T.signer_account_id, T.receiver_account_id
+            ORDER BY T.signer_account_id, T.receiver_account_id;
+            "##,
+            accounts,
+            Decimal::from(start_date),
+            Decimal::from(end_date),
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| GasUsage {
+                account_id: row.account_id,
+                receiver_account_id: row.receiver_account_id,
+                transactions: row.transactions,
+                gas_burnt: row.gas_burnt,
+                tokens_burnt: row.tokens_burnt,
+            })
+            .collect())
+    }
+
+    // Creation and deletion of the accounts, and deletions they are the beneficiary of. The
+    // deleted balance is sent to the beneficiary by `system`, next to the gas refund when the
+    // beneficiary signed the deletion, the largest transfer is the balance.
+    #[instrument(skip(self))]
+    pub async fn get_account_lifecycle(
+        &self,
+        accounts: &[String],
+        start_date: u128,
+        end_date: u128,
+    ) -> Result<Vec<AccountLifecycleEvent>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT
+                ARA.receipt_receiver_account_id AS "account_id!",
+                ARA.action_kind::text AS "action_kind!",
+                ARA.receipt_predecessor_account_id AS "predecessor_account_id!",
+                ARA.args ->> 'beneficiary_id' AS beneficiary_id,
+                CASE WHEN ARA.action_kind = 'CREATE_ACCOUNT' THEN (
+                    SELECT SUM((T.args ->> 'deposit')::numeric)
+                    FROM action_receipt_actions T
+                    WHERE T.receipt_id = ARA.receipt_id AND T.action_kind = 'TRANSFER'
+                ) ELSE (
+                    SELECT MAX((T.args ->> 'deposit')::numeric)
+                    FROM execution_outcome_receipts EOR
+                        JOIN action_receipt_actions T ON T.receipt_id = EOR.produced_receipt_id
+                    WHERE EOR.executed_receipt_id = ARA.receipt_id
+                        AND T.action_kind = 'TRANSFER'
+                        AND T.receipt_predecessor_account_id = 'system'
+                        AND T.receipt_receiver_account_id = ARA.args ->> 'beneficiary_id'
+                ) END AS amount,
+                R.originated_from_transaction_hash AS "transaction_hash!",
+                ARA.receipt_id AS "receipt_id!",
+                B.block_height AS "block_height!",
+                B.block_timestamp AS "block_timestamp!"
+            FROM action_receipt_actions ARA
+                JOIN receipts R ON R.receipt_id = ARA.receipt_id
+                JOIN blocks B ON B.block_hash = R.included_in_block_hash
+                JOIN execution_outcomes EO ON EO.receipt_id = ARA.receipt_id
+            WHERE ARA.action_kind IN ('CREATE_ACCOUNT', 'DELETE_ACCOUNT')
+                AND (ARA.receipt_receiver_account_id = ANY($1)
+                    OR ARA.args ->> 'beneficiary_id' = ANY($1))
+                AND EO.status IN ('SUCCESS_RECEIPT_ID', 'SUCCESS_VALUE')
+                AND ARA.receipt_included_in_block_timestamp >= $2
+                AND ARA.receipt_included_in_block_timestamp < $3
+            ORDER BY B.block_timestamp;
+            "##,
+            accounts,
+            Decimal::from(start_date),
+            Decimal::from(end_date),
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| AccountLifecycleEvent {
+                account_id: row.account_id,
+                action_kind: row.action_kind,
+                predecessor_account_id: row.predecessor_account_id,
+                beneficiary_id: row.beneficiary_id,
+                amount: row.amount,
+                transaction_hash: row.transaction_hash,
+                receipt_id: row.receipt_id,
+                block_height: row.block_height,
+                block_timestamp: row.block_timestamp,
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_contract_deployments(
+        &self,
+        accounts: &[String],
+        start_date: u128,
+        end_date: u128,
+    ) -> Result<Vec<ContractDeployment>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT
+                ARA.receipt_receiver_account_id AS "account_id!",
+                ARA.args ->> 'code_sha256' AS code_sha256,
+                ARA.receipt_predecessor_account_id AS "predecessor_account_id!",
+                T.signer_account_id AS "signer_account_id!",
+                T.transaction_hash AS "transaction_hash!",
+                ARA.receipt_id AS "receipt_id!",
+                B.block_height AS "block_height!",
+                B.block_timestamp AS "block_timestamp!"
+            FROM action_receipt_actions ARA
+                JOIN receipts R ON R.receipt_id = ARA.receipt_id
+                JOIN transactions T ON T.transaction_hash = R.originated_from_transaction_hash
+                JOIN blocks B ON B.block_hash = R.included_in_block_hash
+                JOIN execution_outcomes EO ON EO.receipt_id = ARA.receipt_id
+            WHERE ARA.action_kind = 'DEPLOY_CONTRACT'
+                AND ARA.receipt_receiver_account_id = ANY($1)
+                AND EO.status IN ('SUCCESS_RECEIPT_ID', 'SUCCESS_VALUE')
+                AND ARA.receipt_included_in_block_timestamp >= $2
+                AND ARA.receipt_included_in_block_timestamp < $3
+            ORDER BY B.block_timestamp;
+            "##,
+            accounts,
+            Decimal::from(start_date),
+            Decimal::from(end_date),
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ContractDeployment {
+                account_id: row.account_id,
+                code_sha256: row.code_sha256,
+                predecessor_account_id: row.predecessor_account_id,
+                signer_account_id: row.signer_account_id,
+                transaction_hash: row.transaction_hash,
+                receipt_id: row.receipt_id,
+                block_height: row.block_height,
+                block_timestamp: row.block_timestamp,
+            })
+            .collect())
+    }
+
+    // Whether `account` sent any receipt to `contract` before `before`, e.g. registering with a
+    // token or swapping into it.
+    #[instrument(skip(self))]
+    pub async fn has_called_before(
+        &self,
+        account: &str,
+        contract: &str,
+        before: u128,
+    ) -> Result<bool> {
+        let before = Decimal::from(before);
+        let row = sqlx::query!(
+            r##"
+            SELECT EXISTS (
+                SELECT 1
+                FROM receipts
+                WHERE predecessor_account_id = $1
+                    AND receiver_account_id = $2
+                    AND included_in_block_timestamp < $3
+            ) AS "exists!";
+            "##,
+            account,
+            contract,
+            &before,
+        )
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.exists)
+    }
+
+    // The given accounts that ever received a receipt, i.e. were created at some point.
+    #[instrument(skip(self, accounts))]
+    pub async fn get_existing_accounts(
+        &self,
+        accounts: &[String],
+    ) -> Result<collections::HashSet<String>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT a.account_id AS "account_id!"
+            FROM unnest($1::text[]) AS a(account_id)
+            WHERE EXISTS (
+                SELECT 1 FROM receipts WHERE receiver_account_id = a.account_id
+            );
+            "##,
+            accounts,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows.into_iter().map(|row| row.account_id).collect())
+    }
+
+    // Last account receiving NEAR or tokens in each transaction, walking every receipt that
+    // originated from it.
+    #[instrument(skip(self, transaction_hashes))]
+    pub async fn get_final_recipients(
+        &self,
+        transaction_hashes: &[String],
+    ) -> Result<HashMap<String, String>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT DISTINCT ON (moves.transaction_hash)
+                moves.transaction_hash AS "transaction_hash!",
+                moves.recipient AS "recipient!"
+            FROM (
+                SELECT
+                    R.originated_from_transaction_hash AS transaction_hash,
+                    R.receiver_account_id AS recipient,
+                    R.included_in_block_timestamp AS ts
+                FROM receipts R
+                    JOIN action_receipt_actions ARA ON ARA.receipt_id = R.receipt_id
+                WHERE R.originated_from_transaction_hash = ANY($1)
+                    AND ARA.action_kind = 'TRANSFER'
+                    AND R.predecessor_account_id <> 'system'
+                UNION ALL
+                SELECT
+                    R.originated_from_transaction_hash AS transaction_hash,
+                    FTE.token_new_owner_account_id AS recipient,
+                    FTE.emitted_at_block_timestamp AS ts
+                FROM assets__fungible_token_events FTE
+                    JOIN receipts R ON R.receipt_id = FTE.emitted_for_receipt_id
+                WHERE R.originated_from_transaction_hash = ANY($1)
+                    AND FTE.token_new_owner_account_id <> ''
+            ) moves
+            ORDER BY moves.transaction_hash, moves.ts DESC;
+            "##,
+            transaction_hashes,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|r| (r.transaction_hash, r.recipient))
+            .collect())
+    }
+
+    // Whether any account ever received the token, according to the indexer FT events.
+    #[instrument(skip(self))]
+    pub async fn has_ft_holders(&self, token_id: &str) -> Result<bool> {
+        let row = sqlx::query!(
+            r##"
+            SELECT EXISTS (
+                SELECT 1
+                FROM assets__fungible_token_events
+                WHERE emitted_by_contract_account_id = $1
+                    AND token_new_owner_account_id <> ''
+            ) AS "exists!";
+            "##,
+            token_id,
+        )
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.exists)
+    }
+
+    // Net principal the account put into the pool up to `date`: deposits minus whatever the pool
+    // transferred back on withdrawals, in yoctoNEAR.
+    #[instrument(skip(self))]
+    pub async fn get_staking_principal(
+        &self,
+        account: &str,
+        pool_id: &str,
+        date: u128,
+    ) -> Result<i128> {
+        let date_decimal = Decimal::from(date);
+
+        let row = sqlx::query!(
+            r##"
+            SELECT
+                COALESCE(SUM(
+                    CASE WHEN ARA.receipt_predecessor_account_id = $1
+                        THEN (ARA.args ->> 'deposit')::numeric
+                        ELSE -(ARA.args ->> 'deposit')::numeric
+                    END
+                ), 0) AS "principal!"
+            FROM action_receipt_actions ARA
+                JOIN execution_outcomes EO ON EO.receipt_id = ARA.receipt_id
+            WHERE EO.status IN ('SUCCESS_RECEIPT_ID', 'SUCCESS_VALUE')
+                AND ARA.receipt_included_in_block_timestamp <= $3
+                AND (
+                    (ARA.receipt_predecessor_account_id = $1
+                        AND ARA.receipt_receiver_account_id = $2
+                        AND ARA.action_kind = 'FUNCTION_CALL'
+                        AND ARA.args ->> 'method_name' IN ('deposit', 'deposit_and_stake'))
+                    OR (ARA.receipt_predecessor_account_id = $2
+                        AND ARA.receipt_receiver_account_id = $1
+                        AND ARA.action_kind = 'TRANSFER')
+                );
+            "##,
+            account,
+            pool_id,
+            &date_decimal,
+        )
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(row.principal.to_i128().unwrap_or_default())
+    }
+
+    // Every staking pool we know of: accounts created under the staking pool factories plus the
+    // pools ever added to the lockup staking pool whitelist.
+    #[instrument(skip(self))]
+    pub async fn get_known_staking_pools(&self) -> Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r##"
+            SELECT account_id AS "pool_id!"
+            FROM accounts
+            WHERE (account_id LIKE '%.poolv1.near' OR account_id LIKE '%.pool.near')
+                AND deleted_by_receipt_id IS NULL
+            UNION
+            SELECT args -> 'args_json' ->> 'staking_pool_account_id' AS "pool_id!"
+            FROM action_receipt_actions
+            WHERE receipt_receiver_account_id = 'lockup-whitelist.near'
+                AND action_kind = 'FUNCTION_CALL'
+                AND args ->> 'method_name' = 'add_staking_pool';
+            "##,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows.into_iter().map(|r| r.pool_id).collect())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_ft_metadata_cache(&self) -> Result<Vec<(String, FtMetadata)>> {
+        let rows = sqlx::query_as::<_, (String, Json<FtMetadata>)>(
+            r##"
+            SELECT token_id, metadata
+            FROM tta_ft_metadata;
+            "##,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|(token_id, metadata)| (token_id, metadata.0))
+            .collect())
+    }
+
+    #[instrument(skip(self, metadata))]
+    pub async fn save_ft_metadata(&self, token_id: &str, metadata: &FtMetadata) -> Result<()> {
+        sqlx::query(
+            r##"
+            INSERT INTO tta_ft_metadata (token_id, metadata)
+            VALUES ($1, $2)
+            ON CONFLICT (token_id) DO UPDATE
+            SET metadata = EXCLUDED.metadata, updated_at = NOW();
+            "##,
+        )
+        .bind(token_id)
+        .bind(Json(metadata))
+        .execute(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_ft_metadata_overrides(&self) -> Result<Vec<FtMetadataOverride>> {
+        let rows = sqlx::query_as::<_, (String, Option<String>, Option<i16>, bool)>(
+            r##"
+            SELECT token_id, symbol, decimals, zero_balance
+            FROM tta_ft_metadata_overrides;
+            "##,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(token_id, symbol, decimals, zero_balance)| FtMetadataOverride {
+                    token_id,
+                    symbol,
+                    decimals: decimals.map(|d| d as u8),
+                    zero_balance,
+                },
+            )
+            .collect())
+    }
+
+    #[instrument(skip(self, metadata_override), fields(token_id = %metadata_override.token_id))]
+    pub async fn save_ft_metadata_override(
+        &self,
+        metadata_override: &FtMetadataOverride,
+    ) -> Result<()> {
+        sqlx::query(
+            r##"
+            INSERT INTO tta_ft_metadata_overrides (token_id, symbol, decimals, zero_balance)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (token_id) DO UPDATE
+            SET symbol = EXCLUDED.symbol,
+                decimals = EXCLUDED.decimals,
+                zero_balance = EXCLUDED.zero_balance,
+                updated_at = NOW();
+            "##,
+        )
+        .bind(&metadata_override.token_id)
+        .bind(&metadata_override.symbol)
+        .bind(metadata_override.decimals.map(|d| d as i16))
+        .bind(metadata_override.zero_balance)
+        .execute(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_ft_metadata_override(&self, token_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM tta_ft_metadata_overrides WHERE token_id = $1;")
+            .bind(token_id)
+            .execute(&mut *self.acquire().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    // The report rows of a day, kept per receipt so later reports can reuse them, and their
+    // totals.
+    #[instrument(skip(self, rows, totals))]
+    pub async fn save_account_activity(
+        &self,
+        account: &str,
+        day: NaiveDate,
+        rows: &[&ReportRow],
+        totals: &[SummaryRow],
+        decoder_version: i32,
+    ) -> Result<()> {
+        let day_start = |day: NaiveDate| {
+            Decimal::from(day.and_hms_opt(0, 0, 0).unwrap().timestamp_nanos() as u128)
+        };
+        let mut rows_by_receipt: BTreeMap<&str, Vec<&ReportRow>> = BTreeMap::new();
+        for row in rows {
+            rows_by_receipt
+                .entry(&row.receipt_id)
+                .or_default()
+                .push(row);
+        }
+
+        let mut tx = self.pool().begin().await?;
+        sqlx::query("DELETE FROM tta_account_activity WHERE account_id = $1 AND day = $2;")
+            .bind(account)
+            .bind(day)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query(
+            r##"
+            DELETE FROM tta_report_rows
+            WHERE account_id = $1
+                AND block_timestamp >= $2
+                AND block_timestamp < $3;
+            "##,
+        )
+        .bind(account)
+        .bind(day_start(day))
+        .bind(day_start(day + chrono::Duration::days(1)))
+        .execute(&mut tx)
+        .await?;
+        for (receipt_id, rows) in rows_by_receipt {
+            sqlx::query(
+                r##"
+                INSERT INTO tta_report_rows
+                    (account_id, receipt_id, block_timestamp, rows, decoder_version)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (account_id, receipt_id) DO UPDATE
+                SET rows = EXCLUDED.rows, decoder_version = EXCLUDED.decoder_version;
+                "##,
+            )
+            .bind(account)
+            .bind(receipt_id)
+            .bind(Decimal::from(rows[0].block_timestamp))
+            .bind(Json(&rows))
+            .bind(decoder_version)
+            .execute(&mut tx)
+            .await?;
+        }
+        for total in totals {
+            sqlx::query(
+                r##"
+                INSERT INTO tta_account_activity
+                    (account_id, day, token, token_contract, total_in, total_out, tx_count)
+                VALUES ($1, $2, $3, $4, $5, $6, $7);
+                "##,
+            )
+            .bind(account)
+            .bind(day)
+            .bind(&total.token)
+            .bind(&total.token_contract)
+            .bind(total.total_in)
+            .bind(total.total_out)
+            .bind(total.tx_count as i64)
+            .execute(&mut tx)
+            .await?;
+        }
+        sqlx::query(
+            r##"
+            INSERT INTO tta_account_activity_days (account_id, day, row_count, decoder_version)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (account_id, day) DO UPDATE
+            SET row_count = EXCLUDED.row_count, decoder_version = EXCLUDED.decoder_version;
+            "##,
+        )
+        .bind(account)
+        .bind(day)
+        .bind(rows.len() as i64)
+        .bind(decoder_version)
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // First and last day of the account materialized with the decoder version.
+    #[instrument(skip(self))]
+    pub async fn get_account_activity_days(
+        &self,
+        account: &str,
+        decoder_version: i32,
+    ) -> Result<Option<(NaiveDate, NaiveDate)>> {
+        let days = sqlx::query_as::<_, (Option<NaiveDate>, Option<NaiveDate>)>(
+            r##"
+            SELECT MIN(day), MAX(day)
+            FROM tta_account_activity_days
+            WHERE account_id = $1
+                AND decoder_version = $2;
+            "##,
+        )
+        .bind(account)
+        .bind(decoder_version)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(match days {
+            (Some(first_day), Some(last_day)) => Some((first_day, last_day)),
+            _ => None,
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_report_rows(
+        &self,
+        account: &str,
+        start_date: u128,
+        end_date: u128,
+        decoder_version: i32,
+    ) -> Result<Vec<ReportRow>> {
+        let rows = sqlx::query_as::<_, (Json<Vec<ReportRow>>,)>(
+            r##"
+            SELECT rows
+            FROM tta_report_rows
+            WHERE account_id = $1
+                AND block_timestamp >= $2
+                AND block_timestamp < $3
+                AND decoder_version = $4;
+            "##,
+        )
+        .bind(account)
+        .bind(Decimal::from(start_date))
+        .bind(Decimal::from(end_date))
+        .bind(decoder_version)
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows.into_iter().flat_map(|(rows,)| rows.0).collect())
+    }
+
+    // Materialized days before `day` of every account, with their report rows. Returns the
+    // number of days deleted.
+    #[instrument(skip(self))]
+    pub async fn delete_account_activity_before(&self, day: NaiveDate) -> Result<u64> {
+        let day_start = Decimal::from(day.and_hms_opt(0, 0, 0).unwrap().timestamp_nanos() as u128);
+
+        let mut tx = self.pool().begin().await?;
+        let deleted = sqlx::query("DELETE FROM tta_account_activity_days WHERE day < $1;")
+            .bind(day)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM tta_account_activity WHERE day < $1;")
+            .bind(day)
+            .execute(&mut tx)
+            .await?;
+        sqlx::query("DELETE FROM tta_report_rows WHERE block_timestamp < $1;")
+            .bind(day_start)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(deleted.rows_affected())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn last_account_activity_day(&self, account: &str) -> Result<Option<NaiveDate>> {
+        let (day,) = sqlx::query_as::<_, (Option<NaiveDate>,)>(
+            r##"
+            SELECT MAX(day)
+            FROM tta_account_activity_days
+            WHERE account_id = $1;
+            "##,
+        )
+        .bind(account)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(day)
+    }
+
+    // First day of the account materialized with another decoder version.
+    #[instrument(skip(self))]
+    pub async fn first_stale_account_activity_day(
+        &self,
+        account: &str,
+        decoder_version: i32,
+    ) -> Result<Option<NaiveDate>> {
+        let (day,) = sqlx::query_as::<_, (Option<NaiveDate>,)>(
+            r##"
+            SELECT MIN(day)
+            FROM tta_account_activity_days
+            WHERE account_id = $1
+                AND decoder_version <> $2;
+            "##,
+        )
+        .bind(account)
+        .bind(decoder_version)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(day)
+    }
+
+    // Days in [start_day, end_day) materialized with the decoder version for any of the accounts,
+    // and their report rows.
+    #[instrument(skip(self))]
+    pub async fn count_account_activity_days(
+        &self,
+        accounts: &[String],
+        start_day: NaiveDate,
+        end_day: NaiveDate,
+        decoder_version: i32,
+    ) -> Result<(u64, u64)> {
+        let (days, row_count) = sqlx::query_as::<_, (i64, i64)>(
+            r##"
+            SELECT COUNT(*), COALESCE(SUM(row_count), 0)::BIGINT
+            FROM tta_account_activity_days
+            WHERE account_id = ANY($1)
+                AND day >= $2
+                AND day < $3
+                AND decoder_version = $4;
+            "##,
+        )
+        .bind(accounts)
+        .bind(start_day)
+        .bind(end_day)
+        .bind(decoder_version)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok((days as u64, row_count as u64))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_account_activity_summary(
+        &self,
+        accounts: &[String],
+        start_day: NaiveDate,
+        end_day: NaiveDate,
+    ) -> Result<Vec<SummaryRow>> {
+        let rows = sqlx::query_as::<_, (String, String, String, f64, f64, i64)>(
+            r##"
+            SELECT account_id, token, token_contract,
+                SUM(total_in), SUM(total_out), SUM(tx_count)::BIGINT
+            FROM tta_account_activity
+            WHERE account_id = ANY($1)
+                AND day >= $2
+                AND day < $3
+            GROUP BY account_id, token, token_contract
+            ORDER BY account_id, token, token_contract;
+            "##,
+        )
+        .bind(accounts)
+        .bind(start_day)
+        .bind(end_day)
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(account_id, token, token_contract, total_in, total_out, tx_count)| SummaryRow {
+                    account_id,
+                    token,
+                    token_contract,
+                    total_in,
+                    total_out,
+                    net: total_in - total_out,
+                    tx_count: tx_count as usize,
+                },
+            )
+            .collect())
+    }
+
+    #[instrument(skip(self, job, result), fields(id = %job.id))]
+    pub async fn save_report_job(
+        &self,
+        job: &ReportJobRecord,
+        result: Option<&[u8]>,
+    ) -> Result<()> {
+        sqlx::query(
+            r##"
+            INSERT INTO tta_report_jobs
+                (id, state, error, filename, progress, created_at, finished_at, result)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (id) DO UPDATE SET
+                state = EXCLUDED.state,
+                error = EXCLUDED.error,
+                progress = EXCLUDED.progress,
+                finished_at = EXCLUDED.finished_at,
+                result = EXCLUDED.result;
+            "##,
+        )
+        .bind(&job.id)
+        .bind(&job.state)
+        .bind(&job.error)
+        .bind(&job.filename)
+        .bind(&job.progress)
+        .bind(job.created_at)
+        .bind(job.finished_at)
+        .bind(result)
+        .execute(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_report_job(&self, id: &str) -> Result<Option<ReportJobRecord>> {
+        let job = sqlx::query_as::<_, ReportJobRecord>(
+            r##"
+            SELECT id, state, error, filename, progress, created_at, finished_at
+            FROM tta_report_jobs
+            WHERE id = $1;
+            "##,
+        )
+        .bind(id)
+        .fetch_optional(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(job)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_report_jobs(&self) -> Result<Vec<ReportJobRecord>> {
+        let jobs = sqlx::query_as::<_, ReportJobRecord>(
+            r##"
+            SELECT id, state, error, filename, progress, created_at, finished_at
+            FROM tta_report_jobs
+            ORDER BY created_at DESC;
+            "##,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_report_job_result(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        let result = sqlx::query_as::<_, (Option<Vec<u8>>,)>(
+            r##"
+            SELECT result
+            FROM tta_report_jobs
+            WHERE id = $1;
+            "##,
+        )
+        .bind(id)
+        .fetch_optional(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(result.and_then(|(result,)| result))
+    }
+
+    // Jobs left running by an earlier process, which took them down with it.
+    #[instrument(skip(self))]
+    pub async fn fail_running_report_jobs(&self, error: &str) -> Result<u64> {
+        let failed = sqlx::query(
+            r##"
+            UPDATE tta_report_jobs
+            SET state = 'failed', error = $1, finished_at = NOW()
+            WHERE state = 'running';
+            "##,
+        )
+        .bind(error)
+        .execute(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(failed.rows_affected())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_report_jobs_before(&self, finished_before: DateTime<Utc>) -> Result<u64> {
+        let deleted = sqlx::query(
+            r##"
+            DELETE FROM tta_report_jobs
+            WHERE finished_at < $1;
+            "##,
+        )
+        .bind(finished_before)
+        .execute(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(deleted.rows_affected())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_watches(&self) -> Result<Vec<Watch>> {
+        let watches = sqlx::query_as::<_, Watch>(
+            r##"
+            SELECT account_id, min_amount, tokens, webhook_urls, updated_at
+            FROM tta_watchlist
+            ORDER BY account_id;
+            "##,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(watches)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_watch(&self, account: &str) -> Result<Option<Watch>> {
+        let watch = sqlx::query_as::<_, Watch>(
+            r##"
+            SELECT account_id, min_amount, tokens, webhook_urls, updated_at
+            FROM tta_watchlist
+            WHERE account_id = $1;
+            "##,
+        )
+        .bind(account)
+        .fetch_optional(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(watch)
+    }
+
+    #[instrument(skip(self, watch), fields(account = %watch.account_id))]
+    pub async fn save_watch(&self, watch: &Watch) -> Result<Watch> {
+        let watch = sqlx::query_as::<_, Watch>(
+            r##"
+            INSERT INTO tta_watchlist (account_id, min_amount, tokens, webhook_urls, updated_at)
+            VALUES ($1, $2, $3, $4, now())
+            ON CONFLICT (account_id) DO UPDATE SET
+                min_amount = EXCLUDED.min_amount,
+                tokens = EXCLUDED.tokens,
+                webhook_urls = EXCLUDED.webhook_urls,
+                updated_at = EXCLUDED.updated_at
+            RETURNING account_id, min_amount, tokens, webhook_urls, updated_at;
+            "##,
+        )
+        .bind(&watch.account_id)
+        .bind(watch.min_amount)
+        .bind(&watch.tokens)
+        .bind(&watch.webhook_urls)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(watch)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_watch(&self, account: &str) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM tta_watchlist WHERE account_id = $1;")
+            .bind(account)
+            .execute(&mut *self.acquire().await?)
+            .await?;
+
+        Ok(deleted.rows_affected() > 0)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn list_balance_alerts(&self) -> Result<Vec<BalanceAlert>> {
+        let alerts = sqlx::query_as::<_, BalanceAlert>(
+            r##"
+            SELECT id, account_id, token_id, threshold, webhook_urls, triggered_at, created_at
+            FROM tta_balance_alerts
+            ORDER BY id;
+            "##,
+        )
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(alerts)
+    }
+
+    #[instrument(skip(self, webhook_urls))]
+    pub async fn create_balance_alert(
+        &self,
+        account: &str,
+        token_id: &str,
+        threshold: f64,
+        webhook_urls: &[String],
+    ) -> Result<BalanceAlert> {
+        let alert = sqlx::query_as::<_, BalanceAlert>(
+            r##"
+            INSERT INTO tta_balance_alerts (account_id, token_id, threshold, webhook_urls, created_at)
+            VALUES ($1, $2, $3, $4, now())
+            RETURNING id, account_id, token_id, threshold, webhook_urls, triggered_at, created_at;
+            "##,
+        )
+        .bind(account)
+        .bind(token_id)
+        .bind(threshold)
+        .bind(webhook_urls)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(alert)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn set_balance_alert_triggered(
+        &self,
+        id: i64,
+        triggered_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        sqlx::query("UPDATE tta_balance_alerts SET triggered_at = $2 WHERE id = $1;")
+            .bind(id)
+            .bind(triggered_at)
+            .execute(&mut *self.acquire().await?)
+            .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_balance_alert(&self, id: i64) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM tta_balance_alerts WHERE id = $1;")
+            .bind(id)
+            .execute(&mut *self.acquire().await?)
+            .await?;
+
+        Ok(deleted.rows_affected() > 0)
+    }
+
+    // Replaces the snapshot of the account on the day.
+    #[instrument(skip(self, snapshots))]
+    pub async fn save_balance_snapshots(
+        &self,
+        account: &str,
+        day: NaiveDate,
+        snapshots: &[BalanceSnapshot],
+    ) -> Result<()> {
+        let mut tx = self.pool().begin().await?;
+        sqlx::query("DELETE FROM tta_balance_snapshots WHERE account_id = $1 AND day = $2;")
+            .bind(account)
+            .bind(day)
+            .execute(&mut tx)
+            .await?;
+        for snapshot in snapshots {
+            sqlx::query(
+                r##"
+                INSERT INTO tta_balance_snapshots
+                    (account_id, day, token_id, symbol, block_id, balance, spam, locked, storage_usage)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9);
+                "##,
+            )
+            .bind(&snapshot.account_id)
+            .bind(snapshot.day)
+            .bind(&snapshot.token_id)
+            .bind(&snapshot.symbol)
+            .bind(snapshot.block_id)
+            .bind(snapshot.balance)
+            .bind(snapshot.spam)
+            .bind(snapshot.locked)
+            .bind(snapshot.storage_usage)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_balance_snapshots_before(&self, day: NaiveDate) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM tta_balance_snapshots WHERE day < $1;")
+            .bind(day)
+            .execute(&mut *self.acquire().await?)
+            .await?;
+
+        Ok(deleted.rows_affected())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn last_balance_snapshot_day(&self, account: &str) -> Result<Option<NaiveDate>> {
+        let (day,) = sqlx::query_as::<_, (Option<NaiveDate>,)>(
+            "SELECT max(day) FROM tta_balance_snapshots WHERE account_id = $1;",
+        )
+        .bind(account)
+        .fetch_one(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(day)
+    }
+
+    #[instrument(skip(self, accounts))]
+    pub async fn get_balance_snapshots(
+        &self,
+        accounts: &[String],
+        start_day: NaiveDate,
+        end_day: NaiveDate,
+    ) -> Result<Vec<BalanceSnapshot>> {
+        let snapshots = sqlx::query_as::<_, BalanceSnapshot>(
+            r##"
+            SELECT account_id, day, token_id, symbol, block_id, balance, spam, locked, storage_usage
+            FROM tta_balance_snapshots
+            WHERE account_id = ANY($1) AND day >= $2 AND day <= $3;
+            "##,
+        )
+        .bind(accounts)
+        .bind(start_day)
+        .bind(end_day)
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(snapshots)
+    }
+
+    #[instrument(skip(self, token_ids))]
+    pub async fn get_token_prices(
+        &self,
+        token_ids: &[String],
+        start_day: NaiveDate,
+        end_day: NaiveDate,
+    ) -> Result<Vec<(String, NaiveDate, Option<f64>)>> {
+        let prices = sqlx::query_as::<_, (String, NaiveDate, Option<f64>)>(
+            r##"
+            SELECT token_id, day, usd_price
+            FROM tta_token_prices
+            WHERE token_id = ANY($1) AND day >= $2 AND day <= $3;
+            "##,
+        )
+        .bind(token_ids)
+        .bind(start_day)
+        .bind(end_day)
+        .fetch_all(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(prices)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn save_token_price(
+        &self,
+        token_id: &str,
+        day: NaiveDate,
+        usd_price: Option<f64>,
+    ) -> Result<()> {
+        sqlx::query(
+            r##"
+            INSERT INTO tta_token_prices (token_id, day, usd_price)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (token_id, day) DO UPDATE SET usd_price = EXCLUDED.usd_price;
+            "##,
+        )
+        .bind(token_id)
+        .bind(day)
+        .bind(usd_price)
+        .execute(&mut *self.acquire().await?)
+        .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, sqlx::FromRow)]
@@ -1,36 +1,56 @@
 use std::{
-    collections::HashSet,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
     vec,
 };
 
 use anyhow::{bail, Context, Result};
 
-use futures_util::future::join_all;
+use futures_util::{future::join_all, stream, StreamExt};
 use near_sdk::ONE_NEAR;
 
-use crate::{tta::utils::get_associated_lockup, TxnsReportWithMetadata};
+use crate::{
+    config::{ConcurrencyConfig, GasRefundRule},
+    metadata_source::MetadataSource,
+    pricing::{fx::FiatCurrency, PriceService},
+    tta::utils::get_associated_lockup,
+    TxnsReportWithMetadata,
+};
 use base64::{engine::general_purpose, Engine as _};
-use chrono::{NaiveDateTime, Utc};
+use chrono::{NaiveDate, Utc};
+use chrono_tz::Tz;
 
 use num_traits::cast::ToPrimitive;
+use serde::Serialize;
+use serde_json::Value;
 use tokio::sync::{
     mpsc::{channel, Sender},
-    Semaphore,
+    OnceCell, Semaphore,
 };
+use tta_rust::metrics::metrics;
 
 use tracing::{debug, error, info, instrument};
 
 use super::{
+    activity::{materialized_row_count, materialized_summary, stored_rows_range, DECODER_VERSION},
+    aggregations::SummaryRow,
+    aurora::aurora_evm_address,
+    bridges::{decode_bridge_transfer, BridgeCall, BridgeDirection},
+    ft_call_msg::ft_call_destination,
     ft_metadata::{FtMetadata, FtService},
+    gains::{gains, GainsRow},
     models::{
-        FtAmounts, FtTransfer, FtTransferCall, MethodName, RainbowBridgeMint, ReportRow,
-        WithdrawFromBridge,
+        AirdropRow, FtAmounts, FtTransfer, FtTransferCall, LockupTransfer, MethodName,
+        MultisigAddRequest, RainbowBridgeMint, ReportRow, WithdrawFromBridge, DATE_FORMAT,
+        TIME_FORMAT,
     },
+    progress::ReportProgress,
+    sources::{ChainView, TxnSource},
     sql::{
         models::{TaArgs, Transaction},
         sql_queries::SqlClient,
     },
+    utils::block_datetime,
 };
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -41,9 +61,17 @@ pub enum TransactionType {
 }
 
 impl TransactionType {
-    async fn get_transaction(
+    fn as_str(self) -> &'static str {
+        match self {
+            TransactionType::Incoming => "incoming",
+            TransactionType::FtIncoming => "ft_incoming",
+            TransactionType::Outgoing => "outgoing",
+        }
+    }
+
+    async fn get_transaction<S: TxnSource>(
         self,
-        client: &SqlClient,
+        client: &S,
         accounts: HashSet<String>,
         start_date: u128,
         end_date: u128,
@@ -69,23 +97,578 @@ impl TransactionType {
     }
 }
 
+// The receipts of a decoded transaction are looked for this long after it, they usually execute
+// within a few blocks.
+const TXN_RECEIPTS_WINDOW_NANOS: u128 = 60 * 60 * 1_000_000_000;
+
+// An indexer row behind a report, as queried, with the query it came from.
+#[derive(Debug, Serialize)]
+pub struct RawReceipt {
+    pub direction: &'static str,
+    #[serde(flatten)]
+    pub txn: Transaction,
+}
+
+// Why a receipt of a transaction is, or isn't, in the report of an account.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptOutcome {
+    Reported,
+    // The receipt, or for outgoing and ft incoming rows any receipt of the transaction, failed.
+    Failure,
+    // Neither a function call nor a transfer.
+    UnsupportedAction,
+    GasRefund,
+    // A call the report doesn't decode, attaching no NEAR.
+    UnsupportedMethod,
+    // Dropped by `assert_moves_token`.
+    NoTokensMoved,
+    InvalidArgs,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReceiptExplanation {
+    pub receipt_id: String,
+    pub direction: &'static str,
+    pub action_kind: String,
+    pub method_name: Option<String>,
+    pub predecessor_account_id: String,
+    pub receiver_account_id: String,
+    pub status: String,
+    pub outcome: ReceiptOutcome,
+}
+
+// Generic over where the indexer rows and the chain state come from, see `sources`. Everything
+// past the decoding of the rows is only there for the production sources.
 #[derive(Debug, Clone)]
-pub struct TTA {
-    sql_client: SqlClient,
-    ft_service: FtService,
+pub struct TTA<S = SqlClient, C = FtService> {
+    sql_client: S,
+    ft_service: C,
     semaphore: Arc<Semaphore>,
+    max_report_rows: u64,
+    spool_threshold_rows: u64,
+    max_report_rpc_calls: u64,
+    concurrency: ConcurrencyConfig,
+    // Set by `new`, reports of other sources have no USD amounts.
+    prices: Option<PriceService>,
+    metadata_source: MetadataSource,
+    gas_refund_rule: GasRefundRule,
+    // The rule of the deployment, the materialized activity was computed with it.
+    materialized_gas_refund_rule: GasRefundRule,
+    include_refunds: bool,
+    // Off for the basic transaction fields only, without token amounts or balances.
+    decode: bool,
+    inflight_reports: Arc<Mutex<HashMap<ReportKey, Arc<OnceCell<SharedReport>>>>>,
+}
+
+// Identifies identical report requests, to compute them once when they run concurrently.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct ReportKey {
+    start_date: u128,
+    end_date: u128,
+    accounts: Vec<String>,
+    include_balances: bool,
+}
+
+// The error is shared by every request of the computation, typed so they still tell e.g.
+// `RpcBudgetExceeded` apart.
+type SharedReport = std::result::Result<Vec<ReportRow>, Arc<anyhow::Error>>;
+
+// Returned by the requests sharing a computation that failed, see `get_txns_report`.
+#[derive(Debug, Clone)]
+pub struct SharedReportError(pub Arc<anyhow::Error>);
+
+impl std::fmt::Display for SharedReportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedReportError {}
+
+// Used by the estimates until the process has its own query and RPC stats.
+const DEFAULT_SECS_PER_ROW: f64 = 0.002;
+const DEFAULT_RPC_CALL_SECS: f64 = 0.3;
+// Reports estimated to take longer are better run as jobs, clients and proxies tend to give up
+// on a request after a minute.
+const SYNC_REPORT_MAX_SECS: f64 = 60.0;
+// Counted rows per query of the indexer estimate, past it the estimate is a lower bound.
+const MAX_ESTIMATED_ROWS: u64 = 1_000_000;
+
+// What a report would cost, from the count queries and the stats of the earlier reports.
+#[derive(Debug, Serialize)]
+pub struct ReportEstimate {
+    pub rows: u64,
+    // The row count is exact when read from the materialized account activity.
+    pub exact_rows: bool,
+    pub rpc_calls: u64,
+    pub duration_secs: f64,
+    // Over `max_report_rows`, the report would be rejected.
+    pub too_large: bool,
+    // Over `max_report_rpc_calls`, the report would fail once it runs out of them.
+    pub over_rpc_budget: bool,
+    pub spooled: bool,
+    // Better run as a /tta/jobs job than downloaded directly.
+    pub recommend_job: bool,
+}
+
+// Returned when a report is estimated to exceed `max_report_rows`.
+#[derive(Debug)]
+pub struct ReportTooLarge {
+    pub estimated_rows: u64,
+    pub max_report_rows: u64,
+}
+
+impl std::fmt::Display for ReportTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The report would have about {} rows, over the limit of {}. Narrow the date range, \
+             request fewer accounts at a time, or run it as a job with POST /tta/jobs.",
+            self.estimated_rows, self.max_report_rows
+        )
+    }
 }
 
+impl std::error::Error for ReportTooLarge {}
+
+// Returned when a report looks up more balances than `max_report_rpc_calls`.
+#[derive(Debug)]
+pub struct RpcBudgetExceeded {
+    pub max_report_rpc_calls: u64,
+}
+
+impl std::fmt::Display for RpcBudgetExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The report needs more than {} archival RPC calls for its balances. Request it \
+             without include_balances, narrow the date range, or request fewer accounts at a time.",
+            self.max_report_rpc_calls
+        )
+    }
+}
+
+impl std::error::Error for RpcBudgetExceeded {}
+
 impl TTA {
     pub fn new(sql_client: SqlClient, ft_service: FtService, semaphore: Arc<Semaphore>) -> Self {
+        let prices = PriceService::new(sql_client.clone());
+        Self::from_sources(sql_client, ft_service, semaphore).with_price_service(prices)
+    }
+}
+
+impl<S: TxnSource, C: ChainView> TTA<S, C> {
+    // Decodes the rows of `sql_client` against the chain state of `ft_service`, the in memory
+    // fakes of `sources` in tests.
+    pub fn from_sources(sql_client: S, ft_service: C, semaphore: Arc<Semaphore>) -> Self {
         Self {
+            prices: None,
             sql_client,
             ft_service,
             semaphore,
+            max_report_rows: 0,
+            spool_threshold_rows: 0,
+            max_report_rpc_calls: 0,
+            concurrency: ConcurrencyConfig::default(),
+            metadata_source: MetadataSource::new(),
+            gas_refund_rule: GasRefundRule::default(),
+            materialized_gas_refund_rule: GasRefundRule::default(),
+            include_refunds: false,
+            decode: true,
+            inflight_reports: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
-    #[instrument(skip(self, start_date, end_date, accounts))]
+    // 0 disables the check.
+    pub fn with_max_report_rows(mut self, max_report_rows: u64) -> Self {
+        self.max_report_rows = max_report_rows;
+        self
+    }
+
+    // The semaphore is sized by the caller, `semaphore_size` is not used here.
+    pub fn with_concurrency(mut self, concurrency: &ConcurrencyConfig) -> Self {
+        self.concurrency = *concurrency;
+        self
+    }
+
+    // 0 disables the cap.
+    pub fn with_max_report_rpc_calls(mut self, max_report_rpc_calls: u64) -> Self {
+        self.max_report_rpc_calls = max_report_rpc_calls;
+        self
+    }
+
+    pub fn with_price_service(mut self, prices: PriceService) -> Self {
+        self.prices = Some(prices);
+        self
+    }
+
+    pub fn with_metadata_source(mut self, metadata_source: MetadataSource) -> Self {
+        self.metadata_source = metadata_source;
+        self
+    }
+
+    pub fn metadata_source(&self) -> &MetadataSource {
+        &self.metadata_source
+    }
+
+    pub fn with_gas_refund_rule(mut self, gas_refund_rule: &GasRefundRule) -> Self {
+        self.gas_refund_rule = *gas_refund_rule;
+        self.materialized_gas_refund_rule = *gas_refund_rule;
+        self
+    }
+
+    pub fn gas_refund_rule(&self) -> GasRefundRule {
+        self.gas_refund_rule
+    }
+
+    // For the reports of a single request. The materialized activity is not used unless the rule
+    // is the deployment one.
+    pub fn with_report_gas_refund_rule(mut self, gas_refund_rule: GasRefundRule) -> Self {
+        self.gas_refund_rule = gas_refund_rule;
+        self
+    }
+
+    // Reports the gas refunds as rows flagged `refund`, for a single request too.
+    pub fn with_report_include_refunds(mut self, include_refunds: bool) -> Self {
+        self.include_refunds = include_refunds;
+        self
+    }
+
+    // Skips the token amounts and the balances, for a quick look at the activity of a period.
+    pub fn with_report_decode(mut self, decode: bool) -> Self {
+        self.decode = decode;
+        self
+    }
+
+    fn uses_materialized_activity(&self) -> bool {
+        self.gas_refund_rule == self.materialized_gas_refund_rule
+            && !self.include_refunds
+            && self.decode
+    }
+
+    // 0 disables spooling.
+    pub fn with_spool_threshold_rows(mut self, spool_threshold_rows: u64) -> Self {
+        self.spool_threshold_rows = spool_threshold_rows;
+        self
+    }
+}
+
+impl TTA {
+    // Rejects reports estimated to go over `max_report_rows` before running them.
+    pub async fn check_report_size(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: &HashSet<String>,
+    ) -> Result<()> {
+        if self.max_report_rows == 0 {
+            return Ok(());
+        }
+
+        let (estimated_rows, _) = self
+            .estimate_report_rows(start_date, end_date, accounts)
+            .await?;
+        if estimated_rows > self.max_report_rows {
+            return Err(ReportTooLarge {
+                estimated_rows,
+                max_report_rows: self.max_report_rows,
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    // The rows of one transaction for the account, decoded like in reports. None when the indexer
+    // doesn't have the transaction.
+    pub async fn decode_transaction(
+        &self,
+        transaction_hash: &str,
+        account: &str,
+        include_balances: bool,
+    ) -> Result<Option<Vec<ReportRow>>> {
+        let Some(block_timestamp) = self
+            .sql_client
+            .get_transaction_timestamp(transaction_hash)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let mut rows = self
+            .compute_txns_report(
+                block_timestamp,
+                block_timestamp + TXN_RECEIPTS_WINDOW_NANOS,
+                HashSet::from([account.to_string()]),
+                include_balances,
+                Arc::new(RwLock::new(TxnsReportWithMetadata::default())),
+                Arc::new(ReportProgress::default()),
+            )
+            .await?;
+        rows.retain(|row| row.transaction_hash == transaction_hash);
+
+        Ok(Some(rows))
+    }
+
+    // Which rule kept each receipt of the transaction touching the account, or its lockup, out of
+    // its report. None when the indexer doesn't have the transaction.
+    pub async fn explain_transaction(
+        &self,
+        transaction_hash: &str,
+        account: &str,
+    ) -> Result<Option<Vec<ReceiptExplanation>>> {
+        let Some(rows) = self
+            .decode_transaction(transaction_hash, account, false)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let reported: HashSet<String> = rows.into_iter().map(|row| row.receipt_id).collect();
+
+        let receipts = self
+            .sql_client
+            .get_transaction_receipts(transaction_hash)
+            .await?;
+        let failed_txn = receipts.iter().any(|txn| txn.eo_status == "FAILURE");
+        let wallets = HashSet::from([account.to_string(), get_associated_lockup(account, "near")]);
+
+        let mut explanations = vec![];
+        for txn in receipts {
+            let ft_receiver = ["/args_json/receiver_id", "/args_json/account_id"]
+                .iter()
+                .filter_map(|pointer| txn.ara_args.pointer(pointer).and_then(Value::as_str))
+                .any(|receiver| wallets.contains(receiver));
+            let directions = [
+                (
+                    TransactionType::Outgoing,
+                    wallets.contains(&txn.ara_receipt_predecessor_account_id),
+                ),
+                (
+                    TransactionType::Incoming,
+                    wallets.contains(&txn.ara_receipt_receiver_account_id),
+                ),
+                (
+                    TransactionType::FtIncoming,
+                    txn.ara_action_kind == "FUNCTION_CALL" && ft_receiver,
+                ),
+            ];
+
+            for (txn_type, _) in directions.into_iter().filter(|(_, matches)| *matches) {
+                let failed = txn.eo_status == "FAILURE"
+                    || (txn_type != TransactionType::Incoming && failed_txn);
+                let txn_args = decode_args(&txn).ok();
+                let outcome = if failed {
+                    ReceiptOutcome::Failure
+                } else if reported.contains(&txn.r_receipt_id) {
+                    ReceiptOutcome::Reported
+                } else if txn.ara_action_kind != "FUNCTION_CALL"
+                    && txn.ara_action_kind != "TRANSFER"
+                {
+                    ReceiptOutcome::UnsupportedAction
+                } else {
+                    match &txn_args {
+                        None => ReceiptOutcome::InvalidArgs,
+                        Some(txn_args)
+                            if self.gas_refund_rule.is_refund(
+                                get_near_transferred(txn_args),
+                                &txn.ara_receipt_predecessor_account_id,
+                            ) && !self.include_refunds =>
+                        {
+                            ReceiptOutcome::GasRefund
+                        }
+                        Some(txn_args)
+                            if txn_args.method_name.as_deref().map(MethodName::from)
+                                == Some(MethodName::Unsupported)
+                                && get_near_transferred(txn_args) == 0.0 =>
+                        {
+                            ReceiptOutcome::UnsupportedMethod
+                        }
+                        Some(_) => ReceiptOutcome::NoTokensMoved,
+                    }
+                };
+
+                explanations.push(ReceiptExplanation {
+                    receipt_id: txn.r_receipt_id.clone(),
+                    direction: txn_type.as_str(),
+                    action_kind: txn.ara_action_kind.clone(),
+                    method_name: txn_args.and_then(|txn_args| txn_args.method_name),
+                    predecessor_account_id: txn.ara_receipt_predecessor_account_id.clone(),
+                    receiver_account_id: txn.ara_receipt_receiver_account_id.clone(),
+                    status: txn.eo_status.clone(),
+                    outcome,
+                });
+            }
+        }
+
+        Ok(Some(explanations))
+    }
+
+    // The indexer rows the report of the accounts and their lockups is decoded from, without any
+    // decoding, one query after the other.
+    pub async fn raw_receipts(
+        &self,
+        accounts: HashSet<String>,
+        start_date: u128,
+        end_date: u128,
+        tx: Sender<RawReceipt>,
+    ) -> Result<()> {
+        let wallets: HashSet<String> = accounts
+            .iter()
+            .flat_map(|acc| [acc.clone(), get_associated_lockup(acc, "near")])
+            .collect();
+        let _permit = self.semaphore.acquire().await?;
+        for txn_type in [
+            TransactionType::Outgoing,
+            TransactionType::Incoming,
+            TransactionType::FtIncoming,
+        ] {
+            let (txns_tx, mut txns_rx) = channel(self.concurrency.txns_channel_size);
+            let query = txn_type.get_transaction(
+                &self.sql_client,
+                wallets.clone(),
+                start_date,
+                end_date,
+                txns_tx,
+            );
+            let forward = async {
+                while let Some(txn) = txns_rx.recv().await {
+                    let receipt = RawReceipt {
+                        direction: txn_type.as_str(),
+                        txn,
+                    };
+                    if tx.send(receipt).await.is_err() {
+                        break;
+                    }
+                }
+            };
+            let (result, _) = tokio::join!(query, forward);
+            result?;
+        }
+
+        Ok(())
+    }
+
+    // Whether the report is large enough to be spooled to disk instead of being held in memory.
+    pub async fn spools_report(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: &HashSet<String>,
+    ) -> Result<bool> {
+        if self.spool_threshold_rows == 0 {
+            return Ok(false);
+        }
+
+        Ok(self
+            .estimate_report_rows(start_date, end_date, accounts)
+            .await?
+            .0
+            > self.spool_threshold_rows)
+    }
+
+    // Dry run of a report, without decoding anything. The balance and epoch lookups are counted
+    // as RPC calls once the hit rate of their caches is taken off.
+    pub async fn estimate_report(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: &HashSet<String>,
+        include_balances: bool,
+        include_epoch_id: bool,
+    ) -> Result<ReportEstimate> {
+        let (rows, exact_rows) = self
+            .estimate_report_rows(start_date, end_date, accounts)
+            .await?;
+
+        let misses = |cache: &str| {
+            let hit_ratio = metrics().cache_hit_ratio(cache).unwrap_or(0.0);
+            (rows as f64 * (1.0 - hit_ratio)).round() as u64
+        };
+        let mut rpc_calls = 0;
+        if include_balances && self.decode {
+            rpc_calls += misses("ft_balances");
+        }
+        if include_epoch_id {
+            rpc_calls += misses("epoch_ids");
+        }
+
+        let secs_per_row = metrics()
+            .secs_per_row(&["outgoing_txns", "incoming_txns", "ft_incoming_txns"])
+            .unwrap_or(DEFAULT_SECS_PER_ROW);
+        let rpc_call_secs = metrics()
+            .mean_call_secs("archival_rpc")
+            .unwrap_or(DEFAULT_RPC_CALL_SECS);
+        // The calls are pipelined, up to what the provider's rate limit lets through.
+        let rpc_secs = f64::max(
+            rpc_calls as f64 * rpc_call_secs / self.concurrency.rpc_pipeline_size.max(1) as f64,
+            rpc_calls as f64 / self.ft_service.archival_rate_limiter.current_rps().max(1) as f64,
+        );
+        let duration_secs = rows as f64 * secs_per_row + rpc_secs;
+
+        let spooled = self.spool_threshold_rows > 0 && rows > self.spool_threshold_rows;
+        Ok(ReportEstimate {
+            rows,
+            exact_rows,
+            rpc_calls,
+            duration_secs,
+            too_large: self.max_report_rows > 0 && rows > self.max_report_rows,
+            over_rpc_budget: self.max_report_rpc_calls > 0 && rpc_calls > self.max_report_rpc_calls,
+            spooled,
+            recommend_job: spooled || duration_secs > SYNC_REPORT_MAX_SECS,
+        })
+    }
+
+    // The materialized account activity has the exact row count, when it covers the range. The
+    // flag tells whether it did.
+    async fn estimate_report_rows(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: &HashSet<String>,
+    ) -> Result<(u64, bool)> {
+        let materialized_rows =
+            materialized_row_count(&self.sql_client, start_date, end_date, accounts)
+                .await
+                .unwrap_or_else(|e| {
+                    error!(?e, "Error reading the materialized account activity");
+                    None
+                });
+        match materialized_rows {
+            Some(rows) => Ok((rows, true)),
+            None => {
+                let wallets: HashSet<String> = accounts
+                    .iter()
+                    .flat_map(|acc| [acc.clone(), get_associated_lockup(acc, "near")])
+                    .collect();
+                // Enough to tell whether the report goes over the limit.
+                let limit = MAX_ESTIMATED_ROWS.max(self.max_report_rows + 1);
+                let rows = self
+                    .sql_client
+                    .estimate_txns_count(&wallets, start_date, end_date, limit)
+                    .await?;
+                Ok((rows, false))
+            }
+        }
+    }
+
+    // Per account and token totals read from the materialized account activity, when it covers
+    // the whole range for every account.
+    pub async fn materialized_summary(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: &HashSet<String>,
+    ) -> Result<Option<Vec<SummaryRow>>> {
+        if !self.uses_materialized_activity() {
+            return Ok(None);
+        }
+        materialized_summary(&self.sql_client, start_date, end_date, accounts).await
+    }
+
+    // Identical requests running at the same time, common right after month end, share one
+    // computation. Requests annotated with metadata, or with their own gas refund options, are
+    // computed on their own.
     pub(crate) async fn get_txns_report(
         &self,
         start_date: u128,
@@ -93,6 +676,163 @@ impl TTA {
         accounts: HashSet<String>,
         include_balances: bool,
         metadata: Arc<RwLock<TxnsReportWithMetadata>>,
+        progress: Arc<ReportProgress>,
+    ) -> Result<Vec<ReportRow>> {
+        let has_metadata = !metadata.read().unwrap().metadata.is_empty();
+        if has_metadata || !self.uses_materialized_activity() {
+            return self
+                .compute_txns_report(
+                    start_date,
+                    end_date,
+                    accounts,
+                    include_balances,
+                    metadata,
+                    progress,
+                )
+                .await;
+        }
+
+        let mut sorted_accounts: Vec<String> = accounts.iter().cloned().collect();
+        sorted_accounts.sort();
+        let key = ReportKey {
+            start_date,
+            end_date,
+            accounts: sorted_accounts,
+            include_balances,
+        };
+        let cell = {
+            let mut inflight_reports = self.inflight_reports.lock().unwrap();
+            if inflight_reports.contains_key(&key) {
+                info!(?key, "Joining an identical report in progress");
+            }
+            inflight_reports.entry(key.clone()).or_default().clone()
+        };
+
+        let report = cell
+            .get_or_init(|| async {
+                self.incremental_txns_report(
+                    start_date,
+                    end_date,
+                    accounts,
+                    include_balances,
+                    metadata,
+                    progress,
+                )
+                .await
+                .map_err(Arc::new)
+            })
+            .await
+            .clone();
+
+        // Done, later requests compute the report again.
+        {
+            let mut inflight_reports = self.inflight_reports.lock().unwrap();
+            if inflight_reports
+                .get(&key)
+                .map_or(false, |inflight| Arc::ptr_eq(inflight, &cell))
+            {
+                inflight_reports.remove(&key);
+            }
+        }
+
+        report.map_err(|e| SharedReportError(e).into())
+    }
+
+    // Accounts with materialized activity covering the start of the range are read from the
+    // stored rows, only the blocks past the last materialized day are processed.
+    async fn incremental_txns_report(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: HashSet<String>,
+        include_balances: bool,
+        metadata: Arc<RwLock<TxnsReportWithMetadata>>,
+        progress: Arc<ReportProgress>,
+    ) -> Result<Vec<ReportRow>> {
+        let mut report = vec![];
+        let mut not_stored = HashSet::new();
+        let mut tails = vec![];
+        for account in accounts {
+            let stored_until = match stored_rows_range(&self.sql_client, &account).await {
+                Ok(Some((from, until))) if from <= start_date && start_date < until => until,
+                Ok(_) => {
+                    not_stored.insert(account);
+                    continue;
+                }
+                Err(e) => {
+                    error!(?e, account, "Error reading the stored report rows");
+                    not_stored.insert(account);
+                    continue;
+                }
+            };
+
+            let mut rows = self
+                .sql_client
+                .get_report_rows(
+                    &account,
+                    start_date,
+                    end_date.min(stored_until),
+                    DECODER_VERSION,
+                )
+                .await?;
+            if !include_balances {
+                rows.iter_mut().for_each(|row| {
+                    row.onchain_balance = None;
+                    row.onchain_balance_token = None;
+                    row.balance_rpc_endpoint = None;
+                    row.balance_block_height = None;
+                    row.balance_cached = None;
+                });
+            }
+            info!(account, rows = rows.len(), "Read stored report rows");
+            report.extend(rows);
+            if stored_until < end_date {
+                tails.push((stored_until, account));
+            }
+        }
+
+        let mut computations = vec![];
+        if !not_stored.is_empty() {
+            computations.push(self.compute_txns_report(
+                start_date,
+                end_date,
+                not_stored,
+                include_balances,
+                metadata.clone(),
+                progress.clone(),
+            ));
+        }
+        for (from, account) in tails {
+            computations.push(self.compute_txns_report(
+                from,
+                end_date,
+                HashSet::from([account]),
+                include_balances,
+                metadata.clone(),
+                progress.clone(),
+            ));
+        }
+        for rows in join_all(computations).await {
+            report.extend(rows?);
+        }
+        // The legs of a transaction can fall on both sides of the stored range.
+        let mut report = pair_report_rows(report);
+        sort_report(&mut report);
+
+        Ok(report)
+    }
+}
+
+impl<S: TxnSource, C: ChainView> TTA<S, C> {
+    #[instrument(skip(self, start_date, end_date, accounts, progress))]
+    pub(crate) async fn compute_txns_report(
+        &self,
+        start_date: u128,
+        end_date: u128,
+        accounts: HashSet<String>,
+        include_balances: bool,
+        metadata: Arc<RwLock<TxnsReportWithMetadata>>,
+        progress: Arc<ReportProgress>,
     ) -> Result<Vec<ReportRow>> {
         info!(?start_date, ?end_date, ?accounts, "Got request");
 
@@ -107,6 +847,7 @@ impl TTA {
             info!(?acc, ?lockup, "Got lockup");
             wallets_for_account.insert(acc.clone());
             wallets_for_account.insert(lockup);
+            progress.start_account(acc, 3);
 
             let task_incoming = tokio::spawn({
                 info!(
@@ -122,6 +863,7 @@ impl TTA {
                 let t = t.clone();
                 let for_account = acc.clone();
                 let metadata = metadata.clone();
+                let progress = progress.clone();
 
                 async move {
                     let _s = s;
@@ -133,6 +875,7 @@ impl TTA {
                         end_date,
                         include_balances,
                         metadata,
+                        progress,
                     )
                     .await
                 }
@@ -152,6 +895,7 @@ impl TTA {
                 let t = t.clone();
                 let for_account = acc.clone();
                 let metadata = metadata.clone();
+                let progress = progress.clone();
 
                 async move {
                     let _s = s;
@@ -163,6 +907,7 @@ impl TTA {
                         end_date,
                         include_balances,
                         metadata,
+                        progress,
                     )
                     .await
                 }
@@ -182,6 +927,7 @@ impl TTA {
                 let t = t.clone();
                 let a = acc.clone();
                 let metadata = metadata.clone();
+                let progress = progress.clone();
 
                 async move {
                     let _s = s;
@@ -194,6 +940,7 @@ impl TTA {
                         end_date,
                         include_balances,
                         metadata,
+                        progress,
                     )
                     .await
                 }
@@ -204,36 +951,41 @@ impl TTA {
             join_handles.push(task_outgoing);
         }
 
-        // Wait for threads to be over.
-        for ele in join_handles {
-            match ele.await {
-                Ok(res) => match res {
-                    Ok(partial_report) => {
-                        let mut p = vec![];
-                        // Apply filtering
-                        for ele in partial_report {
-                            if let Some(ele) = assert_moves_token(ele) {
-                                p.push(ele)
-                            }
-                        }
-                        report.extend(p);
-                    }
-                    Err(e) => {
-                        error!(?e, "Error in returned value from thread");
-                    }
-                },
+        // Wait for threads to be over. A failed stream fails the report, returning it without
+        // the stream's rows would look complete.
+        let mut join_handles = join_handles.into_iter();
+        while let Some(ele) = join_handles.next() {
+            let partial_report = match ele.await {
+                Ok(Ok(partial_report)) => partial_report,
+                Ok(Err(e)) => {
+                    join_handles.for_each(|handle| handle.abort());
+                    return Err(e);
+                }
                 Err(e) => {
-                    error!(?e, "Error joining threads");
+                    join_handles.for_each(|handle| handle.abort());
+                    return Err(anyhow::Error::new(e).context("Error joining threads"));
+                }
+            };
+            let mut p = vec![];
+            // Apply filtering, undecoded rows have no token amounts to tell
+            for ele in partial_report {
+                if !self.decode {
+                    p.push(ele);
+                } else if let Some(ele) = assert_moves_token(ele) {
+                    p.push(ele)
                 }
             }
+            report.extend(p);
         }
 
-        // sort the report by account_id and block_timestamp
-        report.sort_by(|a, b| {
-            a.account_id
-                .cmp(&b.account_id)
-                .then(a.block_timestamp.cmp(&b.block_timestamp))
-        });
+        let mut report = pair_report_rows(report);
+        if self.decode {
+            if let Err(e) = self.resolve_receipt_chains(&mut report).await {
+                error!(?e, "Error resolving receipt chains");
+            }
+        }
+
+        sort_report(&mut report);
 
         let ended_at = Utc::now();
 
@@ -255,65 +1007,101 @@ impl TTA {
         end_date: u128,
         include_balances: bool,
         metadata: Arc<RwLock<TxnsReportWithMetadata>>,
+        progress: Arc<ReportProgress>,
     ) -> Result<Vec<ReportRow>> {
         let mut report: Vec<ReportRow> = vec![];
-        let (tx, mut rx) = channel(100);
+        let (tx, mut rx) = channel(self.concurrency.txns_channel_size);
 
         let t = self.clone();
-        tokio::spawn({
+        let query = tokio::spawn({
             let a = accounts.clone();
+            let progress = progress.clone();
             async move {
-                txn_type
+                let result = txn_type
                     .get_transaction(&t.sql_client, a, start_date, end_date, tx)
-                    .await
-                    .unwrap();
+                    .await;
+                progress.sql_stream_done();
+                result
             }
         });
 
+        let wallets = Arc::new(accounts);
         let mut rows_handle = vec![];
         while let Some(txn) = rx.recv().await {
-            let t2: TTA = self.clone();
+            let t2 = self.clone();
+            let wallets = wallets.clone();
             let for_account = for_account.clone();
             let metadata = metadata.clone();
+            let progress = progress.clone();
             let row = tokio::spawn(async move {
+                progress.row_processed();
                 if txn.ara_action_kind != "FUNCTION_CALL" && txn.ara_action_kind != "TRANSFER" {
                     return Ok(None);
                 }
 
                 let txn_args = decode_args(&txn)?;
 
-                // Skipping gas refunds
-                if get_near_transferred(&txn_args) < 0.5
-                    && txn.ara_receipt_predecessor_account_id == "system"
-                {
+                // Skipping gas refunds, unless asked for
+                let refund = t2.gas_refund_rule.is_refund(
+                    get_near_transferred(&txn_args),
+                    &txn.ara_receipt_predecessor_account_id,
+                );
+                if refund && !t2.include_refunds {
                     return Ok(None);
                 }
 
-                let ft_amounts = match t2
-                    .get_ft_amounts(
-                        txn_type != TransactionType::Outgoing,
-                        txn.clone(),
-                        txn_args.clone(),
-                    )
-                    .await
-                {
-                    Ok(ft_amounts) => ft_amounts,
-                    Err(e) => bail!("Error getting ft amounts: {:?}", e),
+                let ft_amounts = if !t2.decode {
+                    None
+                } else {
+                    match t2
+                        .get_ft_amounts(
+                            txn_type != TransactionType::Outgoing,
+                            txn.clone(),
+                            txn_args.clone(),
+                            &wallets,
+                        )
+                        .await
+                    {
+                        Ok(ft_amounts) => ft_amounts,
+                        Err(e) => bail!("Error getting ft amounts: {:?}", e),
+                    }
                 };
 
-                let (ft_amount_out, ft_currency_out, ft_amount_in, ft_currency_in, to_account) =
-                    ft_amounts
-                        .as_ref()
-                        .map(|ft_amounts| {
-                            (
-                                ft_amounts.ft_amount_out,
-                                ft_amounts.ft_currency_out.clone(),
-                                ft_amounts.ft_amount_in,
-                                ft_amounts.ft_currency_in.clone(),
-                                ft_amounts.to_account.clone(),
-                            )
-                        })
-                        .unwrap_or((None, None, None, None, txn.r_receiver_account_id.clone()));
+                let ft_token_id = ft_amounts
+                    .as_ref()
+                    .and_then(|ft_amounts| ft_amounts.token_id.clone());
+                let (
+                    ft_amount_out,
+                    ft_currency_out,
+                    ft_amount_in,
+                    ft_currency_in,
+                    to_account,
+                    ft_category,
+                ) = ft_amounts
+                    .as_ref()
+                    .map(|ft_amounts| {
+                        (
+                            ft_amounts.ft_amount_out,
+                            ft_amounts.ft_currency_out.clone(),
+                            ft_amounts.ft_amount_in,
+                            ft_amounts.ft_currency_in.clone(),
+                            ft_amounts.to_account.clone(),
+                            ft_amounts.category.clone(),
+                        )
+                    })
+                    .unwrap_or((
+                        None,
+                        None,
+                        None,
+                        None,
+                        txn.r_receiver_account_id.clone(),
+                        None,
+                    ));
+                let memo = ft_amounts
+                    .as_ref()
+                    .and_then(|ft_amounts| ft_amounts.memo.clone());
+                let ft_token_contract_out = ft_currency_out.as_ref().and(ft_token_id.clone());
+                let ft_token_contract_in = ft_currency_in.as_ref().and(ft_token_id);
 
                 let multiplier = if txn_type == TransactionType::Outgoing {
                     -1.0
@@ -321,43 +1109,75 @@ impl TTA {
                     1.0
                 };
 
+                // NEAR leaving a lockup through its `transfer` method, reported once from the
+                // owner's outgoing call.
+                let lockup_transfer = match txn_type {
+                    TransactionType::Outgoing => get_lockup_transfer(&txn, &txn_args),
+                    _ => None,
+                };
+                let (amount_transferred, to_account, ft_category) = match lockup_transfer {
+                    Some((amount, receiver_id)) => {
+                        (-amount, receiver_id, Some("lockup".to_string()))
+                    }
+                    None => (
+                        ft_amounts
+                            .as_ref()
+                            .and_then(|ft_amounts| ft_amounts.near_amount)
+                            .unwrap_or_else(|| get_near_transferred(&txn_args) * multiplier),
+                        to_account,
+                        ft_category,
+                    ),
+                };
+
                 let mut onchain_balance = None;
                 let mut onchain_balance_token = None;
-                if include_balances {
+                let mut balance_source = None;
+                if include_balances && t2.decode {
+                    if t2.max_report_rpc_calls > 0
+                        && progress.start_balance_lookup() as u64 > t2.max_report_rpc_calls
+                    {
+                        return Err(RpcBudgetExceeded {
+                            max_report_rpc_calls: t2.max_report_rpc_calls,
+                        }
+                        .into());
+                    }
                     if ft_amount_in.is_some() || ft_amount_out.is_some() {
                         debug!("Getting onchain balance for {}", for_account);
                         let ft_service = t2.ft_service.clone();
-                        onchain_balance = Some(
-                            ft_service
-                                .assert_ft_balance(
-                                    &txn.r_receiver_account_id,
-                                    &for_account,
-                                    txn.b_block_height
-                                        .to_u64()
-                                        .expect("Block height too large to fit in u128"),
-                                )
-                                .await?,
-                        );
+                        let (balance, source) = ft_service
+                            .sourced_ft_balance(
+                                &txn.r_receiver_account_id,
+                                &for_account,
+                                txn.b_block_height
+                                    .to_u64()
+                                    .expect("Block height too large to fit in u128"),
+                            )
+                            .await?;
+                        onchain_balance = Some(balance);
+                        balance_source = source;
                         onchain_balance_token = Some(
                             ft_service
                                 .assert_ft_metadata(&txn.r_receiver_account_id)
                                 .await?
                                 .symbol,
                         );
+                        progress.balance_lookup_done();
                     } else {
                         // It's a NEAR transfer
+                        let block_height = txn
+                            .b_block_height
+                            .to_u64()
+                            .expect("Block height too large to fit in u64");
                         let near = t2
                             .ft_service
-                            .get_near_balance(
-                                &for_account,
-                                txn.b_block_height
-                                    .to_u64()
-                                    .expect("Block height too large to fit in u64"),
-                            )
+                            .get_near_balance(&for_account, block_height)
                             .await?;
+                        progress.balance_lookup_done();
                         if let Some(near) = near {
-                            onchain_balance = Some(near.0);
+                            onchain_balance = Some(near.amount);
                             onchain_balance_token = Some("NEAR".to_string());
+                            balance_source =
+                                Some(t2.ft_service.balance_source(block_height, false));
                         }
                     }
                 }
@@ -369,45 +1189,85 @@ impl TTA {
                     .get(&for_account)
                     .and_then(|m| m.get(&txn.t_transaction_hash).cloned());
 
+                let block_time = block_datetime(txn.b_block_timestamp.to_u128().unwrap(), Tz::UTC);
+
                 Ok(Some(ReportRow {
                     account_id: for_account.clone(),
-                    date: get_transaction_date(&txn),
+                    date: block_time.format(DATE_FORMAT).to_string(),
+                    time: block_time.format(TIME_FORMAT).to_string(),
                     method_name: get_method_name(&txn, &txn_args),
+                    category: ft_category.or_else(|| {
+                        txn_args
+                            .method_name
+                            .as_deref()
+                            .and_then(|m| MethodName::from(m).category())
+                            .map(String::from)
+                    }),
+                    income_type: None,
+                    refund,
                     block_timestamp: txn.b_block_timestamp.to_u128().unwrap(),
                     from_account: txn.ara_receipt_predecessor_account_id.clone(),
                     block_height: txn.b_block_height.to_u128().unwrap(),
+                    block_hash: txn.b_block_hash.clone(),
+                    epoch_id: None,
                     args: decode_transaction_args(&txn_args),
                     transaction_hash: txn.t_transaction_hash.clone(),
-                    amount_transferred: get_near_transferred(&txn_args) * multiplier,
+                    receipt_id: txn.r_receipt_id.clone(),
+                    amount_transferred,
                     currency_transferred: "NEAR".to_string(),
                     ft_amount_out,
                     ft_currency_out,
+                    ft_token_contract_out,
                     ft_amount_in,
                     ft_currency_in,
+                    ft_token_contract_in,
+                    memo,
+                    amount_usd_in: None,
+                    amount_usd_out: None,
+                    fiat_currency: None,
+                    amount_fiat_in: None,
+                    amount_fiat_out: None,
                     to_account,
+                    signer_account_id: txn.t_signer_account_id.clone(),
+                    final_recipient: None,
+                    evm_address: aurora_evm_address(
+                        &txn.r_receiver_account_id,
+                        &get_method_name(&txn, &txn_args),
+                        &txn_args,
+                    ),
+                    counterparty_name: None,
                     amount_staked: 0.0,
                     onchain_balance,
                     onchain_balance_token,
+                    balance_rpc_endpoint: balance_source
+                        .as_ref()
+                        .map(|source| source.rpc_endpoint.clone()),
+                    balance_block_height: balance_source.as_ref().map(|source| source.block_height),
+                    balance_cached: balance_source.map(|source| source.cached),
                     metadata: data,
                 }))
             });
             rows_handle.push(row);
         }
+        // The channel also closes when the query failed, the report would miss its rows.
+        query.await??;
 
-        join_all(rows_handle)
-            .await
-            .iter()
-            .for_each(|row| match row {
+        for row in join_all(rows_handle).await {
+            match row {
                 Ok(r) => match r {
                     Ok(row) => {
                         if let Some(row) = row {
-                            report.push(row.clone())
+                            report.push(row)
                         }
                     }
+                    // Dropping the rows over the budget would leave the report incomplete.
+                    Err(err) if err.is::<RpcBudgetExceeded>() => return Err(err),
                     Err(err) => error!(?err, "Error getting row"),
                 },
                 Err(err) => error!(?err, "Error joining rows"),
-            });
+            }
+        }
+        progress.stream_processed(&for_account);
 
         Ok(report)
     }
@@ -417,6 +1277,7 @@ impl TTA {
         is_incoming: bool,
         txn: Transaction,
         txn_args: TaArgs,
+        wallets: &HashSet<String>,
     ) -> Result<Option<FtAmounts>> {
         let method_name = txn_args
             .method_name
@@ -426,6 +1287,13 @@ impl TTA {
 
         let function_call_args = decode_transaction_args(&txn_args);
 
+        if let Some(res) = self
+            .get_bridge_amounts(is_incoming, &txn, &txn_args, &function_call_args)
+            .await?
+        {
+            return Ok(Some(res));
+        }
+
         let res = match method_name {
             MethodName::FtTransfer => {
                 let metadata = self.get_metadata(&txn.r_receiver_account_id).await?;
@@ -441,6 +1309,10 @@ impl TTA {
                         ft_currency_in: Some(metadata.symbol),
                         from_account: txn.ara_receipt_predecessor_account_id.clone(),
                         to_account: ft_transfer_args.receiver_id.to_string(),
+                        category: None,
+                        near_amount: None,
+                        token_id: Some(txn.r_receiver_account_id.clone()),
+                        memo: ft_transfer_args.memo.clone(),
                     })
                 } else {
                     Some(FtAmounts {
@@ -450,6 +1322,10 @@ impl TTA {
                         ft_currency_in: None,
                         from_account: txn.ara_receipt_predecessor_account_id.clone(),
                         to_account: ft_transfer_args.receiver_id.to_string(),
+                        category: None,
+                        near_amount: None,
+                        token_id: Some(txn.r_receiver_account_id.clone()),
+                        memo: ft_transfer_args.memo.clone(),
                     })
                 }
             }
@@ -459,36 +1335,60 @@ impl TTA {
                     .context(format!("Invalid ft_transfer args {:?}", function_call_args))?;
                 let amount = safe_divide_u128(ft_transfer_args.amount.0, metadata.decimals as u32);
 
-                // No need to handle incoming. it comes as ft_transfer in case of swap.
-                Some(FtAmounts {
-                    ft_amount_out: Some(amount),
-                    ft_currency_out: Some(metadata.symbol),
-                    ft_amount_in: None,
-                    ft_currency_in: None,
-                    from_account: txn.ara_receipt_predecessor_account_id,
-                    to_account: ft_transfer_args.receiver_id.to_string(),
-                })
-            }
-            MethodName::Withdraw => {
-                if txn.r_receiver_account_id.ends_with(".factory.bridge.near") {
-                    let metadata = self.get_metadata(&txn.r_receiver_account_id).await?;
-                    let withdraw_args =
-                        serde_json::from_str::<WithdrawFromBridge>(&function_call_args)
-                            .context(format!("Invalid withdraw args {:?}", function_call_args))?;
-                    let amount = safe_divide_u128(withdraw_args.amount.0, metadata.decimals as u32);
+                if is_incoming {
+                    // The receiver may refuse part or all of the tokens in `ft_on_transfer`, only
+                    // what the token events of the transaction show as kept is received.
+                    let received = self
+                        .sql_client
+                        .get_ft_net_transferred(
+                            &txn.t_transaction_hash,
+                            &txn.r_receiver_account_id,
+                            &txn.ara_receipt_predecessor_account_id,
+                            ft_transfer_args.receiver_id.as_str(),
+                        )
+                        .await?;
+                    if received == 0 {
+                        return Ok(None);
+                    }
+                    let amount = safe_divide_u128(received, metadata.decimals as u32);
 
+                    Some(FtAmounts {
+                        ft_amount_out: None,
+                        ft_currency_out: None,
+                        ft_amount_in: Some(amount),
+                        ft_currency_in: Some(metadata.symbol),
+                        from_account: txn.ara_receipt_predecessor_account_id,
+                        to_account: ft_transfer_args.receiver_id.to_string(),
+                        category: None,
+                        near_amount: None,
+                        token_id: Some(txn.r_receiver_account_id.clone()),
+                        memo: ft_transfer_args.memo.clone(),
+                    })
+                } else {
+                    // Where deposits end up, when the receiver's msg tells.
+                    let to_account = ft_call_destination(
+                        ft_transfer_args.receiver_id.as_str(),
+                        &txn.ara_receipt_predecessor_account_id,
+                        &ft_transfer_args.msg,
+                    )
+                    .unwrap_or_else(|| ft_transfer_args.receiver_id.to_string());
+                    // Swaps come back as ft_transfer.
                     Some(FtAmounts {
                         ft_amount_out: Some(amount),
                         ft_currency_out: Some(metadata.symbol),
                         ft_amount_in: None,
                         ft_currency_in: None,
-                        from_account: txn.ara_receipt_predecessor_account_id.clone(),
-                        to_account: txn.ara_receipt_predecessor_account_id.clone(),
+                        from_account: txn.ara_receipt_predecessor_account_id,
+                        to_account,
+                        category: None,
+                        near_amount: None,
+                        token_id: Some(txn.r_receiver_account_id.clone()),
+                        memo: ft_transfer_args.memo.clone(),
                     })
-                } else {
-                    None
                 }
             }
+            // Bridge withdrawals are handled by the bridge decoders.
+            MethodName::Withdraw => None,
             MethodName::NearDeposit => {
                 let metadata = self.get_metadata(&txn.r_receiver_account_id).await?;
                 let deposit = get_near_transferred(&txn_args);
@@ -499,6 +1399,10 @@ impl TTA {
                     ft_currency_in: Some(metadata.symbol),
                     from_account: txn.ara_receipt_predecessor_account_id.clone(),
                     to_account: txn.ara_receipt_predecessor_account_id.clone(),
+                    category: None,
+                    near_amount: None,
+                    token_id: Some(txn.r_receiver_account_id.clone()),
+                    memo: None,
                 })
             }
             MethodName::NearWithdraw => {
@@ -514,6 +1418,10 @@ impl TTA {
                     ft_currency_in: None,
                     from_account: txn.ara_receipt_predecessor_account_id.clone(),
                     to_account: txn.ara_receipt_predecessor_account_id.to_string(),
+                    category: None,
+                    near_amount: None,
+                    token_id: Some(txn.r_receiver_account_id.clone()),
+                    memo: None,
                 })
             }
             MethodName::Mint => {
@@ -532,18 +1440,360 @@ impl TTA {
                         ft_currency_in: Some(metadata.symbol),
                         from_account: txn.ara_receipt_predecessor_account_id.clone(),
                         to_account: bridge_mint_args.account_id.to_string(),
+                        category: None,
+                        near_amount: None,
+                        token_id: Some(txn.r_receiver_account_id.clone()),
+                        memo: None,
                     })
                 } else {
                     error!("Minting should always comes from the bridge");
                     None
                 }
             }
+            // Only NEAR moves, it is already in `amount_transferred`.
+            MethodName::StorageDeposit | MethodName::StorageWithdraw => None,
+            // A confirming member's funds don't move, only the multisig's do.
+            MethodName::MultisigRequest if wallets.contains(&txn.r_receiver_account_id) => {
+                self.get_multisig_amounts(&txn, &function_call_args).await?
+            }
+            MethodName::MultisigRequest => None,
             MethodName::Unsupported => None,
         };
 
         Ok(res)
     }
 
+    async fn get_bridge_amounts(
+        &self,
+        is_incoming: bool,
+        txn: &Transaction,
+        txn_args: &TaArgs,
+        function_call_args: &str,
+    ) -> Result<Option<FtAmounts>> {
+        let method_name = match txn_args.method_name.as_deref() {
+            Some(method_name) => method_name,
+            None => return Ok(None),
+        };
+        let args = serde_json::from_str::<Value>(function_call_args).unwrap_or(Value::Null);
+        let transfer = match decode_bridge_transfer(&BridgeCall {
+            predecessor: &txn.ara_receipt_predecessor_account_id,
+            receiver: &txn.r_receiver_account_id,
+            method_name,
+            args: &args,
+        }) {
+            Some(transfer) => transfer,
+            None => return Ok(None),
+        };
+
+        let metadata = self.get_metadata(&transfer.token_id).await?;
+        let amount = safe_divide_u128(transfer.amount, metadata.decimals as u32);
+        let category = Some(format!("bridge:{}", transfer.bridge));
+
+        let res = match transfer.direction {
+            BridgeDirection::Out => FtAmounts {
+                ft_amount_out: Some(amount),
+                ft_currency_out: Some(metadata.symbol),
+                ft_amount_in: None,
+                ft_currency_in: None,
+                from_account: txn.ara_receipt_predecessor_account_id.clone(),
+                to_account: transfer.counterparty,
+                category,
+                near_amount: None,
+                token_id: Some(transfer.token_id.clone()),
+                memo: None,
+            },
+            BridgeDirection::In => {
+                if !is_incoming {
+                    error!("Bridged tokens should always come in");
+                    return Ok(None);
+                }
+                let recipient = args["account_id"]
+                    .as_str()
+                    .or(args["receiver_id"].as_str())
+                    .unwrap_or(&txn.r_receiver_account_id);
+                FtAmounts {
+                    ft_amount_out: None,
+                    ft_currency_out: None,
+                    ft_amount_in: Some(amount),
+                    ft_currency_in: Some(metadata.symbol),
+                    from_account: transfer.counterparty,
+                    to_account: recipient.to_string(),
+                    category,
+                    near_amount: None,
+                    token_id: Some(transfer.token_id.clone()),
+                    memo: None,
+                }
+            }
+        };
+
+        Ok(Some(res))
+    }
+}
+
+impl TTA {
+    // Fills `epoch_id` from the archival RPC, one block query per distinct block.
+    pub async fn resolve_epoch_ids(&self, report: &mut [ReportRow]) {
+        let block_heights: HashSet<u64> = report
+            .iter()
+            .filter_map(|row| row.block_height.to_u64())
+            .collect();
+        let epoch_ids: HashMap<u64, String> = stream::iter(block_heights)
+            .map(|block_height| async move {
+                (
+                    block_height,
+                    self.ft_service.get_epoch_id(block_height).await,
+                )
+            })
+            .buffer_unordered(self.concurrency.epoch_id_concurrency)
+            .filter_map(|(block_height, epoch_id)| async move {
+                match epoch_id {
+                    Ok(epoch_id) => Some((block_height, epoch_id)),
+                    Err(e) => {
+                        error!(?e, "Error getting epoch id");
+                        None
+                    }
+                }
+            })
+            .collect()
+            .await;
+
+        for row in report.iter_mut() {
+            row.epoch_id = row
+                .block_height
+                .to_u64()
+                .and_then(|block_height| epoch_ids.get(&block_height).cloned());
+        }
+    }
+
+    // Fills `amount_usd_in` and `amount_usd_out` at the price of the row's UTC day. A side with a
+    // token without a price is left empty rather than undervalued.
+    pub async fn resolve_usd_amounts(&self, report: &mut [ReportRow]) {
+        let day = |row: &ReportRow| block_datetime(row.block_timestamp, Tz::UTC).date_naive();
+        let pairs: HashSet<(String, NaiveDate)> = report
+            .iter()
+            .flat_map(|row| {
+                let (incoming, outgoing) = row.movements();
+                incoming
+                    .into_iter()
+                    .chain(outgoing)
+                    .map(move |(token_id, _)| (token_id, day(row)))
+            })
+            .collect();
+        let Some(price_service) = &self.prices else {
+            return;
+        };
+        let prices = price_service.get_prices(&pairs).await;
+
+        let value = |movements: Vec<(String, f64)>, day: NaiveDate| -> Option<f64> {
+            if movements.is_empty() {
+                return None;
+            }
+            movements
+                .into_iter()
+                .map(|(token_id, amount)| Some(prices.get(&(token_id, day))? * amount))
+                .sum()
+        };
+        for row in report.iter_mut() {
+            let (incoming, outgoing) = row.movements();
+            row.amount_usd_in = value(incoming, day(row));
+            row.amount_usd_out = value(outgoing, day(row));
+        }
+    }
+
+    // Converts the USD amounts, see `resolve_usd_amounts`, at the rate of the row's UTC day.
+    pub async fn resolve_fiat_amounts(&self, report: &mut [ReportRow], currency: &FiatCurrency) {
+        let day = |row: &ReportRow| block_datetime(row.block_timestamp, Tz::UTC).date_naive();
+        let days: HashSet<NaiveDate> = report.iter().map(day).collect();
+        let Some(price_service) = &self.prices else {
+            return;
+        };
+        let rates = price_service.get_fx_rates(currency, &days).await;
+
+        for row in report.iter_mut() {
+            let rate = rates.get(&day(row));
+            row.fiat_currency = Some(currency.to_string());
+            row.amount_fiat_in = row.amount_usd_in.zip(rate).map(|(usd, rate)| usd * rate);
+            row.amount_fiat_out = row.amount_usd_out.zip(rate).map(|(usd, rate)| usd * rate);
+        }
+    }
+
+    // Tokens the report accounts received in the period, unasked, from contracts they never
+    // called before.
+    pub async fn airdrop_report(&self, report: &[ReportRow]) -> Result<Vec<AirdropRow>> {
+        let mut rows: Vec<&ReportRow> = report
+            .iter()
+            .filter(|row| row.ft_amount_in.is_some() && row.signer_account_id != row.account_id)
+            .collect();
+        rows.sort_by_key(|row| row.block_timestamp);
+
+        let mut received: BTreeMap<(String, String), (&ReportRow, usize, f64)> = BTreeMap::new();
+        for row in rows {
+            let Some(token_id) = &row.ft_token_contract_in else {
+                continue;
+            };
+            let entry = received
+                .entry((row.account_id.clone(), token_id.clone()))
+                .or_insert((row, 0, 0.0));
+            entry.1 += 1;
+            entry.2 += row.ft_amount_in.unwrap_or_default();
+        }
+
+        let mut airdrops = vec![];
+        for ((account_id, token_id), (first, transfers, total_amount)) in received {
+            if self
+                .sql_client
+                .has_called_before(&account_id, &token_id, first.block_timestamp)
+                .await?
+            {
+                continue;
+            }
+            let metadata = self.ft_service.assert_ft_metadata(&token_id).await?;
+            airdrops.push(AirdropRow {
+                spam: self.ft_service.is_likely_spam(&token_id).await,
+                account_id,
+                symbol: metadata.symbol,
+                name: metadata.name,
+                decimals: metadata.decimals,
+                first_seen_date: first.date.clone(),
+                first_seen_block_timestamp: first.block_timestamp,
+                first_transaction_hash: first.transaction_hash.clone(),
+                from_account: first.from_account.clone(),
+                transfers,
+                total_amount,
+                token_id,
+            });
+        }
+
+        Ok(airdrops)
+    }
+
+    // FIFO gains of the report rows, see `gains::gains`, at the day prices of the movements and
+    // the price of `end_date`'s UTC day.
+    pub async fn gains_report(
+        &self,
+        report: &[ReportRow],
+        start_date: u128,
+        end_date: u128,
+    ) -> Vec<GainsRow> {
+        let end_day = block_datetime(end_date, Tz::UTC).date_naive();
+        let mut pairs: HashSet<(String, NaiveDate)> = HashSet::new();
+        for row in report {
+            let day = block_datetime(row.block_timestamp, Tz::UTC).date_naive();
+            let (incoming, outgoing) = row.movements();
+            for (token_id, _) in incoming.into_iter().chain(outgoing) {
+                pairs.insert((token_id.clone(), day));
+                pairs.insert((token_id, end_day));
+            }
+        }
+        let prices = match &self.prices {
+            Some(price_service) => price_service.get_prices(&pairs).await,
+            None => HashMap::new(),
+        };
+        let end_prices: HashMap<String, f64> = prices
+            .iter()
+            .filter(|((_, day), _)| *day == end_day)
+            .map(|((token_id, _), price)| (token_id.clone(), *price))
+            .collect();
+
+        gains(report, start_date, &prices, &end_prices)
+    }
+}
+
+impl<S: TxnSource, C: ChainView> TTA<S, C> {
+    // A multisig request moves funds only once confirmed, which for `add_request_and_confirm` with a
+    // single required confirmation happens in the same transaction. Requests still pending are
+    // skipped, the transfers of requests confirmed later show up from the multisig itself.
+    async fn get_multisig_amounts(
+        &self,
+        txn: &Transaction,
+        function_call_args: &str,
+    ) -> Result<Option<FtAmounts>> {
+        let request = serde_json::from_str::<MultisigAddRequest>(function_call_args)
+            .context(format!("Invalid add_request args {:?}", function_call_args))?
+            .request;
+        let multisig = &txn.r_receiver_account_id;
+
+        let executed = self
+            .sql_client
+            .has_receipts_between(&txn.t_transaction_hash, multisig, &request.receiver_id)
+            .await?;
+        if !executed {
+            return Ok(None);
+        }
+
+        let mut near_amount = 0.0;
+        let mut ft_amount = None;
+        for action in &request.actions {
+            match action.type_field.as_deref() {
+                Some("Transfer") => {
+                    let amount = action
+                        .amount
+                        .as_deref()
+                        .and_then(|amount| amount.parse::<u128>().ok())
+                        .unwrap_or(0);
+                    near_amount += safe_divide_u128(amount, 24);
+                }
+                Some("FunctionCall") if action.method_name.as_deref() == Some("ft_transfer") => {
+                    let args = action
+                        .args
+                        .as_deref()
+                        .and_then(|args| general_purpose::STANDARD.decode(args).ok())
+                        .and_then(|args| serde_json::from_slice::<FtTransfer>(&args).ok());
+                    if let Some(args) = args {
+                        let metadata = self.get_metadata(&request.receiver_id).await?;
+                        ft_amount = Some((
+                            safe_divide_u128(args.amount.0, metadata.decimals as u32),
+                            metadata.symbol,
+                            args.receiver_id.to_string(),
+                        ));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let (ft_amount_out, ft_currency_out, to_account) = match ft_amount {
+            Some((amount, symbol, receiver_id)) => (Some(amount), Some(symbol), receiver_id),
+            None => (None, None, request.receiver_id.clone()),
+        };
+        if ft_amount_out.is_none() && near_amount == 0.0 {
+            return Ok(None);
+        }
+
+        Ok(Some(FtAmounts {
+            ft_amount_out,
+            ft_currency_out,
+            ft_amount_in: None,
+            ft_currency_in: None,
+            from_account: multisig.clone(),
+            to_account,
+            category: Some("multisig".to_string()),
+            near_amount: (near_amount > 0.0).then_some(-near_amount),
+            token_id: ft_amount_out.map(|_| request.receiver_id.clone()),
+            memo: None,
+        }))
+    }
+
+    // Fills `final_recipient` with the last account receiving NEAR or tokens in each transaction.
+    async fn resolve_receipt_chains(&self, report: &mut [ReportRow]) -> Result<()> {
+        let transaction_hashes: Vec<String> = report
+            .iter()
+            .map(|row| row.transaction_hash.clone())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let final_recipients = self
+            .sql_client
+            .get_final_recipients(&transaction_hashes)
+            .await?;
+
+        for row in report.iter_mut() {
+            row.final_recipient = final_recipients.get(&row.transaction_hash).cloned();
+        }
+
+        Ok(())
+    }
+
     async fn get_metadata(&self, token_id: &String) -> Result<FtMetadata> {
         let ft_service = self.ft_service.clone();
         let metadata = match ft_service.assert_ft_metadata(token_id.as_str()).await {
@@ -559,6 +1809,25 @@ impl TTA {
     }
 }
 
+fn get_lockup_transfer(txn: &Transaction, txn_args: &TaArgs) -> Option<(f64, String)> {
+    if !txn.r_receiver_account_id.ends_with(".lockup.near")
+        || txn_args.method_name.as_deref() != Some("transfer")
+    {
+        return None;
+    }
+    let args = decode_transaction_args(txn_args);
+    match serde_json::from_str::<LockupTransfer>(&args) {
+        Ok(transfer) => Some((
+            safe_divide_u128(transfer.amount.0, 24),
+            transfer.receiver_id.to_string(),
+        )),
+        Err(e) => {
+            error!(?e, "Invalid lockup transfer args {:?}", args);
+            None
+        }
+    }
+}
+
 fn get_near_transferred(txn_args: &TaArgs) -> f64 {
     txn_args
         .deposit
@@ -623,16 +1892,171 @@ fn get_method_name(txn: &Transaction, txn_args: &TaArgs) -> String {
     }
 }
 
-fn get_transaction_date(txn: &Transaction) -> String {
-    let nanoseconds = txn
-        .b_block_timestamp
-        .to_u128()
-        .expect("Timestamp too large to fit in u128");
-    let seconds = (nanoseconds / 1_000_000_000) as i64;
-    let date = NaiveDateTime::from_timestamp_opt(seconds, 0)
-        .expect("Invalid timestamp")
-        .date();
-    date.format("%B %d, %Y").to_string()
+const WRAP_NEAR: &str = "wrap.near";
+
+// Folds the rows a single transfer shows up as. Pairing rows already paired changes nothing, so
+// it also runs over reports merged from stored and computed rows.
+fn pair_report_rows(report: Vec<ReportRow>) -> Vec<ReportRow> {
+    pair_multisig_requests(categorize_storage_refunds(pair_wnear_wraps(
+        pair_lockup_transfers(report),
+    )))
+}
+
+// For reports generated a chunk at a time: pairs the rows of a chunk with those held back from
+// the previous one. Returns the rows done with, and those to hold back for the next chunk,
+// starting at `next_chunk_start`, as their transaction may go on in it. None for the last chunk.
+pub(crate) fn pair_chunk_rows(
+    held_back: Vec<ReportRow>,
+    rows: Vec<ReportRow>,
+    next_chunk_start: Option<u128>,
+) -> (Vec<ReportRow>, Vec<ReportRow>) {
+    let mut rows = pair_report_rows(held_back.into_iter().chain(rows).collect());
+    sort_report(&mut rows);
+    let Some(next_chunk_start) = next_chunk_start else {
+        return (rows, vec![]);
+    };
+
+    let hold_back_from = next_chunk_start.saturating_sub(TXN_RECEIPTS_WINDOW_NANOS);
+    rows.into_iter()
+        .partition(|row| row.block_timestamp < hold_back_from)
+}
+
+// By account_id and block_timestamp, ties broken on the whole row so the output is the same from
+// one run to the next.
+fn sort_report(report: &mut [ReportRow]) {
+    report.sort_by(|a, b| {
+        a.account_id
+            .cmp(&b.account_id)
+            .then(a.block_timestamp.cmp(&b.block_timestamp))
+            .then_with(|| a.to_vec().cmp(&b.to_vec()))
+    });
+}
+
+// Unwrapping shows up twice: the `near_withdraw` call burning wNEAR and the NEAR transfer back
+// from wrap.near. Both legs are folded into the `near_withdraw` row. Wrapping is already a single
+// `near_deposit` row with NEAR out and wNEAR in.
+fn pair_wnear_wraps(report: Vec<ReportRow>) -> Vec<ReportRow> {
+    let unwraps: HashSet<(String, String)> = report
+        .iter()
+        .filter(|row| row.method_name == "near_withdraw")
+        .map(|row| (row.account_id.clone(), row.transaction_hash.clone()))
+        .collect();
+
+    report
+        .into_iter()
+        .filter_map(|mut row| {
+            let key = (row.account_id.clone(), row.transaction_hash.clone());
+            if !unwraps.contains(&key) {
+                return Some(row);
+            }
+            if row.method_name == "TRANSFER" && row.from_account == WRAP_NEAR {
+                return None;
+            }
+            if row.method_name == "near_withdraw" {
+                row.amount_transferred = row.ft_amount_out.unwrap_or(0.0);
+            }
+            Some(row)
+        })
+        .collect()
+}
+
+// The NEAR a lockup's `transfer` sends also shows up as the lockup's TRANSFER receipt, reported
+// for the owner too. Keep only that leg, in the lockup category.
+fn pair_lockup_transfers(mut report: Vec<ReportRow>) -> Vec<ReportRow> {
+    let lockup_legs: HashSet<(String, String, String)> = report
+        .iter()
+        .filter(|row| row.method_name == "TRANSFER" && row.from_account.ends_with(".lockup.near"))
+        .map(|row| {
+            (
+                row.account_id.clone(),
+                row.transaction_hash.clone(),
+                row.to_account.clone(),
+            )
+        })
+        .collect();
+    let is_lockup_call = |row: &ReportRow| {
+        row.method_name == "transfer" && row.category.as_deref() == Some("lockup")
+    };
+    let paired: HashSet<(String, String, String)> = report
+        .iter()
+        .filter(|row| is_lockup_call(row))
+        .map(|row| {
+            (
+                row.account_id.clone(),
+                row.transaction_hash.clone(),
+                row.to_account.clone(),
+            )
+        })
+        .filter(|key| lockup_legs.contains(key))
+        .collect();
+
+    report.retain(|row| {
+        !is_lockup_call(row)
+            || !paired.contains(&(
+                row.account_id.clone(),
+                row.transaction_hash.clone(),
+                row.to_account.clone(),
+            ))
+    });
+    for row in report.iter_mut() {
+        if row.method_name == "TRANSFER"
+            && row.from_account.ends_with(".lockup.near")
+            && paired.contains(&(
+                row.account_id.clone(),
+                row.transaction_hash.clone(),
+                row.to_account.clone(),
+            ))
+        {
+            row.category = Some("lockup".to_string());
+        }
+    }
+
+    report
+}
+
+// A multisig request executed in the same transaction also shows up as the transfer made by the
+// multisig, keep only the decoded request row.
+fn pair_multisig_requests(report: Vec<ReportRow>) -> Vec<ReportRow> {
+    let multisig_txns: HashSet<(String, String)> = report
+        .iter()
+        .filter(|row| row.category.as_deref() == Some("multisig"))
+        .map(|row| (row.account_id.clone(), row.transaction_hash.clone()))
+        .collect();
+
+    report
+        .into_iter()
+        .filter(|row| {
+            row.category.as_deref() == Some("multisig")
+                || !multisig_txns.contains(&(row.account_id.clone(), row.transaction_hash.clone()))
+                || !matches!(row.method_name.as_str(), "TRANSFER" | "ft_transfer")
+        })
+        .collect()
+}
+
+// The NEAR refunded by `storage_withdraw`/`storage_unregister` comes back as a plain transfer
+// from the contract, tag it so it lands in the same category as the call.
+fn categorize_storage_refunds(mut report: Vec<ReportRow>) -> Vec<ReportRow> {
+    let storage_withdrawals: HashSet<(String, String)> = report
+        .iter()
+        .filter(|row| {
+            row.category.as_deref() == Some("storage")
+                && matches!(
+                    row.method_name.as_str(),
+                    "storage_withdraw" | "storage_unregister"
+                )
+        })
+        .map(|row| (row.account_id.clone(), row.transaction_hash.clone()))
+        .collect();
+
+    for row in report.iter_mut() {
+        if row.method_name == "TRANSFER"
+            && storage_withdrawals.contains(&(row.account_id.clone(), row.transaction_hash.clone()))
+        {
+            row.category = Some("storage".to_string());
+        }
+    }
+
+    report
 }
 
 fn assert_moves_token(row: ReportRow) -> Option<ReportRow> {
@@ -653,12 +2077,18 @@ mod tests {
 
     use chrono::DateTime;
     use near_jsonrpc_client::{JsonRpcClient, NEAR_MAINNET_ARCHIVAL_RPC_URL};
-    use sqlx::postgres::PgPoolOptions;
+
+    use serde_json::json;
+    use sqlx::types::Decimal;
 
     use super::*;
+    use crate::tta::{
+        sources::{FakeChainView, FakeTxnSource},
+        sql::sql_queries::pool_options,
+    };
 
     async fn setup() -> Result<(SqlClient, FtService, TTA)> {
-        let pool = PgPoolOptions::new()
+        let pool = pool_options()
             .max_connections(30)
             .connect(env!("DATABASE_URL"))
             .await?;
@@ -672,6 +2102,88 @@ mod tests {
         Ok((sql_client, ft_service, tta_service))
     }
 
+    fn function_call(
+        transaction_hash: &str,
+        predecessor: &str,
+        receiver: &str,
+        method_name: &str,
+        args: Value,
+        deposit: &str,
+    ) -> Transaction {
+        Transaction {
+            t_transaction_hash: transaction_hash.to_string(),
+            t_signer_account_id: predecessor.to_string(),
+            r_receipt_id: format!("{}-receipt", transaction_hash),
+            r_receiver_account_id: receiver.to_string(),
+            ara_action_kind: "FUNCTION_CALL".to_string(),
+            ara_args: json!({
+                "method_name": method_name,
+                "args_json": args,
+                "args_base64": general_purpose::STANDARD.encode(args.to_string()),
+                "deposit": deposit,
+            }),
+            ara_receipt_predecessor_account_id: predecessor.to_string(),
+            ara_receipt_receiver_account_id: receiver.to_string(),
+            b_block_height: Decimal::from(100_000_000),
+            b_block_timestamp: Decimal::from(1_700_000_000_000_000_000u64),
+            eo_status: "SUCCESS_VALUE".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_fake_sources() -> Result<()> {
+        let txn_source = FakeTxnSource::default()
+            .with_txn(function_call(
+                "transfer",
+                "alice.near",
+                "usdt.tether-token.near",
+                "ft_transfer",
+                json!({"receiver_id": "bob.near", "amount": "1500000", "memo": "invoice 42"}),
+                "1",
+            ))
+            .with_txn(function_call(
+                "failed",
+                "alice.near",
+                "usdt.tether-token.near",
+                "ft_transfer",
+                json!({"receiver_id": "bob.near", "amount": "2000000"}),
+                "1",
+            ))
+            .with_txn(Transaction {
+                eo_status: "FAILURE".to_string(),
+                ..function_call(
+                    "failed",
+                    "usdt.tether-token.near",
+                    "bob.near",
+                    "",
+                    json!({}),
+                    "0",
+                )
+            });
+        let chain = FakeChainView::default().with_token("usdt.tether-token.near", "USDT", 6);
+        let tta_service = TTA::from_sources(txn_source, chain, Arc::new(Semaphore::new(3)));
+
+        let rows = tta_service
+            .compute_txns_report(
+                0,
+                u128::MAX,
+                HashSet::from(["alice.near".to_string()]),
+                false,
+                Arc::new(RwLock::new(TxnsReportWithMetadata::default())),
+                Arc::new(ReportProgress::default()),
+            )
+            .await?;
+
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].transaction_hash, "transfer");
+        assert_eq!(rows[0].ft_amount_out, Some(1.5));
+        assert_eq!(rows[0].ft_currency_out.as_deref(), Some("USDT"));
+        assert_eq!(rows[0].to_account, "bob.near");
+        assert_eq!(rows[0].memo.as_deref(), Some("invoice 42"));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn tta() -> Result<()> {
         let (_, _, tta_service) = setup().await?;
@@ -700,6 +2212,7 @@ mod tests {
 
         let metadata_struct = Arc::new(RwLock::new(TxnsReportWithMetadata {
             metadata: accounts_metadata,
+            metadata_url: None,
         }));
 
         let res = tta_service
@@ -709,6 +2222,7 @@ mod tests {
                 accounts,
                 include_balances,
                 metadata_struct,
+                Arc::new(ReportProgress::default()),
             )
             .await
             .unwrap();
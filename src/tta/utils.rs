@@ -1,3 +1,5 @@
+use chrono::{DateTime, NaiveDateTime, TimeZone};
+use chrono_tz::Tz;
 use sha2::{Digest, Sha256};
 
 pub fn get_associated_lockup(account_id: &str, master_account_id: &str) -> String {
@@ -8,6 +10,13 @@ pub fn get_associated_lockup(account_id: &str, master_account_id: &str) -> Strin
     )
 }
 
+// A block timestamp in nanoseconds, as a local time in `tz`.
+pub fn block_datetime(block_timestamp: u128, tz: Tz) -> DateTime<Tz> {
+    let seconds = (block_timestamp / 1_000_000_000) as i64;
+    let utc = NaiveDateTime::from_timestamp_opt(seconds, 0).expect("Invalid timestamp");
+    tz.from_utc_datetime(&utc)
+}
+
 fn sha256(value: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(value.as_bytes());
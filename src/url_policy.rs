@@ -0,0 +1,115 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use anyhow::{bail, Context, Result};
+use reqwest::Url;
+
+// Which URLs taken from requests the service may call, e.g. webhooks: only the listed schemes,
+// only the listed hosts when there are any, and never hosts on a private, loopback or link-local
+// address.
+#[derive(Debug, Clone)]
+pub struct UrlPolicy {
+    pub schemes: HashSet<String>,
+    pub hosts: Option<HashSet<String>>,
+}
+
+impl Default for UrlPolicy {
+    fn default() -> Self {
+        Self {
+            schemes: HashSet::from(["https".to_string()]),
+            hosts: None,
+        }
+    }
+}
+
+impl UrlPolicy {
+    // Hostnames are resolved, they are refused if any of their addresses is internal.
+    pub async fn check(&self, url: &str) -> Result<Url> {
+        let parsed = Url::parse(url).with_context(|| format!("Invalid URL {}", url))?;
+        if !self.schemes.contains(parsed.scheme()) {
+            bail!("URL scheme of {} is not allowed", url);
+        }
+        let Some(host) = parsed.host_str() else {
+            bail!("URL {} has no host", url);
+        };
+        if let Some(hosts) = &self.hosts {
+            if !hosts.contains(host) {
+                bail!("URL host of {} is not allowed", url);
+            }
+        }
+
+        let port = parsed.port_or_known_default().unwrap_or(443);
+        let addrs = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await
+            .with_context(|| format!("Could not resolve the host of {}", url))?;
+        for addr in addrs {
+            if is_internal(addr.ip()) {
+                bail!("URL {} points to an internal address", url);
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal_v4(ip),
+            None => is_internal_v6(ip),
+        },
+    }
+}
+
+fn is_internal_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        // Carrier-grade NAT, 100.64.0.0/10.
+        || (a == 100 && (b & 0xc0) == 64)
+}
+
+fn is_internal_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        // Unique local, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local, fe80::/10.
+        || (first & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_internal_and_unlisted_urls() {
+        let policy = UrlPolicy::default();
+        for url in [
+            "http://1.1.1.1/hook",
+            "https://127.0.0.1/hook",
+            "https://10.0.0.1/hook",
+            "https://169.254.169.254/latest/meta-data",
+            "https://[::1]/hook",
+            "https://[fe80::1]/hook",
+            "https://[::ffff:192.168.0.1]/hook",
+        ] {
+            assert!(policy.check(url).await.is_err(), "{}", url);
+        }
+        assert!(policy.check("https://1.1.1.1/hook").await.is_ok());
+
+        let policy = UrlPolicy {
+            hosts: Some(HashSet::from(["1.1.1.1".to_string()])),
+            ..UrlPolicy::default()
+        };
+        assert!(policy.check("https://8.8.8.8/hook").await.is_err());
+        assert!(policy.check("https://1.1.1.1/hook").await.is_ok());
+    }
+}
@@ -0,0 +1,74 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use tta_rust::url_policy::UrlPolicy;
+
+use crate::{
+    config::Config,
+    tta::sql::{models::Watch, sql_queries::SqlClient},
+};
+
+// Accounts watched for new activity, with their per account settings. Watches are managed
+// through /watchlist and stored in `tta_watchlist`; the `WATCHLIST` accounts are watched with
+// the defaults unless saved, and can't be removed.
+#[derive(Clone)]
+pub struct Watchlist {
+    sql_client: SqlClient,
+    configured: HashSet<String>,
+    webhook_url_policy: UrlPolicy,
+}
+
+impl Watchlist {
+    pub fn new(sql_client: SqlClient) -> Self {
+        Self {
+            sql_client,
+            configured: HashSet::new(),
+            webhook_url_policy: UrlPolicy::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.configured = config.watchlist.clone();
+        self.webhook_url_policy = config.webhook_url_policy.clone();
+        self
+    }
+
+    pub async fn list(&self) -> Result<Vec<Watch>> {
+        let mut watches = self.sql_client.list_watches().await?;
+        let saved: HashSet<String> = watches.iter().map(|w| w.account_id.clone()).collect();
+        watches.extend(
+            self.configured
+                .iter()
+                .filter(|account| !saved.contains(*account))
+                .map(|account| Watch::new(account.clone())),
+        );
+        watches.sort_by(|a, b| a.account_id.cmp(&b.account_id));
+
+        Ok(watches)
+    }
+
+    pub async fn get(&self, account: &str) -> Result<Option<Watch>> {
+        Ok(match self.sql_client.get_watch(account).await? {
+            Some(watch) => Some(watch),
+            None if self.configured.contains(account) => Some(Watch::new(account.to_string())),
+            None => None,
+        })
+    }
+
+    // Fails on webhook URLs refused by `WEBHOOK_URL_SCHEMES` and `WEBHOOK_URL_HOSTS`.
+    pub async fn check_webhook_urls(&self, urls: &[String]) -> Result<()> {
+        for url in urls {
+            self.webhook_url_policy.check(url).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn save(&self, watch: &Watch) -> Result<Watch> {
+        self.sql_client.save_watch(watch).await
+    }
+
+    // Whether the account was watched. `WATCHLIST` accounts fall back to the defaults.
+    pub async fn remove(&self, account: &str) -> Result<bool> {
+        self.sql_client.delete_watch(account).await
+    }
+}
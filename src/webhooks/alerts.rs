@@ -0,0 +1,126 @@
+use anyhow::Result;
+use chrono::Utc;
+use serde::Serialize;
+use tracing::error;
+
+use crate::{
+    config::{Config, BALANCE_ALERT_INTERVAL_SECS},
+    tta::{
+        ft_metadata::FtService,
+        sql::{models::BalanceAlert, sql_queries::SqlClient},
+    },
+};
+
+use super::WebhookSender;
+
+// Balances are read at the block this far in the past, so it is indexed.
+const INDEXER_LAG_NANOS: u128 = 60 * 1_000_000_000;
+
+#[derive(Serialize)]
+struct BalanceAlertNotification<'a> {
+    alert: &'a BalanceAlert,
+    balance: f64,
+    block_id: u64,
+}
+
+// Evaluates the `tta_balance_alerts` rules periodically, and notifies the webhooks once when a
+// balance drops below its threshold. The rule is armed again when the balance gets back above.
+#[derive(Clone)]
+pub struct BalanceAlerts {
+    sql_client: SqlClient,
+    ft_service: FtService,
+    sender: WebhookSender,
+    interval: std::time::Duration,
+}
+
+impl BalanceAlerts {
+    pub fn new(sql_client: SqlClient, ft_service: FtService, sender: WebhookSender) -> Self {
+        Self {
+            sql_client,
+            ft_service,
+            sender,
+            interval: std::time::Duration::from_secs(BALANCE_ALERT_INTERVAL_SECS),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.interval = std::time::Duration::from_secs(config.balance_alert_interval_secs);
+        self
+    }
+
+    pub fn spawn(self) {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.evaluate().await {
+                    error!(?e, "Error evaluating balance alerts");
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        });
+    }
+
+    async fn evaluate(&self) -> Result<()> {
+        let alerts = self.sql_client.list_balance_alerts().await?;
+        if alerts.is_empty() {
+            return Ok(());
+        }
+        let now = Utc::now().timestamp_nanos() as u128;
+        let block_id = self
+            .sql_client
+            .get_closest_block_id(now - INDEXER_LAG_NANOS)
+            .await? as u64;
+
+        for alert in &alerts {
+            let balance = match self.balance(alert, block_id).await {
+                Ok(balance) => balance,
+                Err(e) => {
+                    error!(?e, id = alert.id, "Error reading the balance of an alert");
+                    continue;
+                }
+            };
+
+            match (balance < alert.threshold, alert.triggered_at) {
+                (true, None) => {
+                    let notification = BalanceAlertNotification {
+                        alert,
+                        balance,
+                        block_id,
+                    };
+                    // Left untriggered when undelivered, so it is sent again on the next check.
+                    let failed = self
+                        .sender
+                        .send("balance_alert", &alert.webhook_urls, &notification)
+                        .await;
+                    if failed.is_empty() {
+                        self.sql_client
+                            .set_balance_alert_triggered(alert.id, Some(Utc::now()))
+                            .await?;
+                    }
+                }
+                (false, Some(_)) => {
+                    self.sql_client
+                        .set_balance_alert_triggered(alert.id, None)
+                        .await?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn balance(&self, alert: &BalanceAlert, block_id: u64) -> Result<f64> {
+        if alert.token_id == "near" {
+            // Unknown accounts have no balance.
+            let balance = self
+                .ft_service
+                .get_near_balance(&alert.account_id, block_id)
+                .await?;
+            return Ok(balance.map_or(0.0, |balance| balance.amount));
+        }
+
+        self.ft_service
+            .assert_ft_balance(&alert.token_id, &alert.account_id, block_id)
+            .await
+    }
+}
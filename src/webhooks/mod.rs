@@ -0,0 +1,351 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{error, info, warn};
+use tta_rust::{metrics::metrics, url_policy::UrlPolicy};
+
+pub mod alerts;
+
+use crate::{
+    config::Config,
+    tta::{
+        models::{ReportRow, Watermark},
+        progress::ReportProgress,
+        sql::models::Watch,
+        tta_impl::TTA,
+    },
+    watchlist::Watchlist,
+    TxnsReportWithMetadata,
+};
+
+pub const SIGNATURE_HEADER: &str = "X-TTA-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-TTA-Timestamp";
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+// Rows older than this are assumed indexed, the watermark moves past them even without new rows.
+const INDEXER_LAG_NANOS: u128 = 10 * 60 * 1_000_000_000;
+const DELIVERY_ATTEMPTS: u64 = 3;
+// Polls a notification is sent again on to the URLs that did not get it, before giving up.
+const RETRY_POLLS: u32 = 10;
+
+#[derive(Serialize)]
+struct Notification<'a> {
+    account_id: &'a str,
+    watermark: String,
+    row: &'a ReportRow,
+}
+
+impl<'a> Notification<'a> {
+    fn of(row: &'a ReportRow) -> Self {
+        Self {
+            account_id: &row.account_id,
+            watermark: Watermark::of(row).to_string(),
+            row,
+        }
+    }
+}
+
+// A notified row some URLs did not get.
+#[derive(Clone)]
+struct Undelivered {
+    row: ReportRow,
+    urls: Vec<String>,
+    polls: u32,
+}
+
+// Signed JSON POSTs to the `WEBHOOK_URLS`, and to per watch or alert URLs. `<timestamp>.<body>`
+// is signed with HMAC-SHA256 under `WEBHOOK_SECRET`, hex encoded in `X-TTA-Signature`, with the
+// Unix timestamp in seconds in `X-TTA-Timestamp` so receivers can refuse replays. Redirects are
+// not followed, and per watch or alert URLs are checked against the URL policy again before each
+// delivery, their host may resolve elsewhere since they were saved.
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+    urls: Vec<String>,
+    secret: Option<String>,
+    url_policy: UrlPolicy,
+}
+
+impl Default for WebhookSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebhookSender {
+    pub fn new() -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+            urls: vec![],
+            secret: None,
+            url_policy: UrlPolicy::default(),
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.urls = config.webhook_urls.iter().cloned().collect();
+        self.secret = config.webhook_secret.clone();
+        self.url_policy = config.webhook_url_policy.clone();
+        if self.secret.is_none() {
+            info!("WEBHOOK_SECRET is not set, webhook payloads are not signed");
+        }
+        self
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    // Delivers the payload to the configured URLs and `urls`, retrying each a few times. Failed
+    // deliveries are logged, returns the URLs that did not get the payload.
+    pub async fn send<T: Serialize>(
+        &self,
+        kind: &str,
+        urls: &[String],
+        payload: &T,
+    ) -> Vec<String> {
+        let urls: Vec<String> = self.urls.iter().chain(urls).cloned().collect();
+        self.send_to(kind, &urls, payload).await
+    }
+
+    // Like `send`, to `urls` only.
+    async fn send_to<T: Serialize>(&self, kind: &str, urls: &[String], payload: &T) -> Vec<String> {
+        let body = match serde_json::to_vec(payload) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(?e, kind, "Error serializing webhook payload");
+                return urls.to_vec();
+            }
+        };
+
+        let mut failed = vec![];
+        for url in urls {
+            // The configured URLs are trusted.
+            if !self.urls.contains(url) {
+                if let Err(e) = self.url_policy.check(url).await {
+                    error!(?e, url, kind, "Webhook URL refused");
+                    failed.push(url.clone());
+                    continue;
+                }
+            }
+            for attempt in 1..=DELIVERY_ATTEMPTS {
+                match self.post(kind, url, &body).await {
+                    Ok(()) => break,
+                    Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                        warn!(?e, url, attempt, "Webhook delivery failed, retrying");
+                        tokio::time::sleep(Duration::from_secs(attempt)).await;
+                    }
+                    Err(e) => {
+                        error!(?e, url, kind, "Webhook delivery failed");
+                        failed.push(url.clone());
+                    }
+                }
+            }
+        }
+        failed
+    }
+
+    async fn post(&self, kind: &str, url: &str, body: &[u8]) -> Result<()> {
+        let mut request = self
+            .client
+            .post(url)
+            .header("Content-Type", "application/json")
+            .body(body.to_vec());
+        if let Some(secret) = &self.secret {
+            let timestamp = chrono::Utc::now().timestamp();
+            request = request
+                .header(SIGNATURE_HEADER, sign(secret, timestamp, body))
+                .header(TIMESTAMP_HEADER, timestamp);
+        }
+
+        let started_at = Instant::now();
+        let result = request.send().await;
+        let ok = matches!(&result, Ok(response) if response.status().is_success());
+        metrics().record_call("webhook", kind, started_at.elapsed(), ok);
+
+        let response = result?;
+        if !response.status().is_success() {
+            bail!("{} returned {}", url, response.status());
+        }
+
+        Ok(())
+    }
+}
+
+// Posts the rows of the watched accounts moving at least their `min_amount` of a token of
+// interest to the webhooks once they are surely indexed, `INDEXER_LAG_NANOS` after their block.
+// Rows indexed while the service is down are not notified. Those some URLs did not get are sent
+// again to these URLs only on the next polls, up to `RETRY_POLLS`, without holding back the
+// others. Only runs with a `WEBHOOK_SECRET`, notifications are never unsigned.
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    tta: TTA,
+    watchlist: Watchlist,
+    sender: WebhookSender,
+    min_amount: f64,
+    undelivered: Vec<Undelivered>,
+}
+
+impl WebhookNotifier {
+    pub fn new(tta: TTA, watchlist: Watchlist, sender: WebhookSender) -> Self {
+        Self {
+            tta,
+            watchlist,
+            sender,
+            min_amount: 0.0,
+            undelivered: vec![],
+        }
+    }
+
+    pub fn with_config(mut self, config: &Config) -> Self {
+        self.min_amount = config.webhook_min_amount;
+        self
+    }
+
+    // Polls the watched accounts until the process exits.
+    pub fn spawn(mut self) {
+        if !self.sender.is_signed() {
+            warn!("WEBHOOK_SECRET is not set, watched accounts are not notified");
+            return;
+        }
+        tokio::spawn(async move {
+            let mut watermark = Watermark {
+                block_timestamp: now_nanos().saturating_sub(INDEXER_LAG_NANOS),
+                receipt_id: String::new(),
+            };
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                match self.poll(&watermark).await {
+                    Ok(next) => watermark = next,
+                    Err(e) => error!(?e, "Error polling watched accounts"),
+                }
+            }
+        });
+    }
+
+    // Notifies the indexed rows past `since`, and returns the watermark to poll from next.
+    async fn poll(&mut self, since: &Watermark) -> Result<Watermark> {
+        self.retry_undelivered().await;
+
+        let end_date = now_nanos().saturating_sub(INDEXER_LAG_NANOS);
+        let indexed = Watermark {
+            block_timestamp: end_date,
+            receipt_id: String::new(),
+        };
+        if indexed <= *since {
+            return Ok(since.clone());
+        }
+        let watches: HashMap<String, Watch> = self
+            .watchlist
+            .list()
+            .await?
+            .into_iter()
+            .map(|watch| (watch.account_id.clone(), watch))
+            .collect();
+        if watches.is_empty() {
+            return Ok(indexed.max(since.clone()));
+        }
+
+        let mut rows = self
+            .tta
+            .get_txns_report(
+                since.block_timestamp,
+                end_date,
+                watches.keys().cloned().collect(),
+                false,
+                Arc::new(RwLock::new(TxnsReportWithMetadata::default())),
+                Arc::new(ReportProgress::default()),
+            )
+            .await?;
+        rows.retain(|row| Watermark::of(row) > *since);
+        rows.sort_by_cached_key(Watermark::of);
+
+        for row in rows {
+            let Some(watch) = watches.get(&row.account_id) else {
+                continue;
+            };
+            if !self.is_notable(watch, &row) {
+                continue;
+            }
+            let failed = self
+                .sender
+                .send("notify", &watch.webhook_urls, &Notification::of(&row))
+                .await;
+            if !failed.is_empty() {
+                self.undelivered.push(Undelivered {
+                    row,
+                    urls: failed,
+                    polls: 0,
+                });
+            }
+        }
+
+        Ok(indexed)
+    }
+
+    async fn retry_undelivered(&mut self) {
+        let mut undelivered = vec![];
+        for mut retry in std::mem::take(&mut self.undelivered) {
+            retry.urls = self
+                .sender
+                .send_to("notify", &retry.urls, &Notification::of(&retry.row))
+                .await;
+            retry.polls += 1;
+            if retry.urls.is_empty() {
+                continue;
+            }
+            if retry.polls >= RETRY_POLLS {
+                error!(
+                    urls = ?retry.urls,
+                    watermark = %Watermark::of(&retry.row),
+                    "Giving up on a webhook notification"
+                );
+                continue;
+            }
+            undelivered.push(retry);
+        }
+        self.undelivered = undelivered;
+    }
+
+    fn is_notable(&self, watch: &Watch, row: &ReportRow) -> bool {
+        let min_amount = watch.min_amount.unwrap_or(self.min_amount);
+        [
+            (
+                Some(row.amount_transferred),
+                Some(&row.currency_transferred),
+            ),
+            (row.ft_amount_in, row.ft_currency_in.as_ref()),
+            (row.ft_amount_out, row.ft_currency_out.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(amount, currency)| Some((amount?, currency?)))
+        .any(|(amount, currency)| {
+            amount != 0.0
+                && amount.abs() >= min_amount
+                && (watch.tokens.is_empty() || watch.tokens.contains(currency))
+        })
+    }
+}
+
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+fn now_nanos() -> u128 {
+    chrono::Utc::now().timestamp_nanos() as u128
+}